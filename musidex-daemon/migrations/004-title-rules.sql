@@ -0,0 +1,13 @@
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE IF NOT EXISTS title_rules
+(
+    position    integer primary key,
+    kind        text not null, -- replace, split or feat
+    pattern     text not null, -- regex
+    replacement text not null default ''
+);
+
+INSERT INTO title_rules (position, kind, pattern, replacement)
+VALUES (0, 'replace', '(?i)(\(|\[)((official|video|hq|vidéo|officielle)\s?-?\s?)+(\]|\))', ''),
+       (1, 'split', ' - ', '');
@@ -0,0 +1,27 @@
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE IF NOT EXISTS lyrics
+(
+    music_id integer primary key references musics (id) on delete cascade,
+    plain    text not null,
+    synced   text,          -- LRC format, plain is derived from it when present
+    source   text not null  -- user, embedded or sidecar
+);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS lyrics_fts USING fts5(plain, content='lyrics', content_rowid='music_id');
+
+CREATE TRIGGER IF NOT EXISTS lyrics_fts_insert AFTER INSERT ON lyrics
+BEGIN
+    INSERT INTO lyrics_fts (rowid, plain) VALUES (new.music_id, new.plain);
+END;
+
+CREATE TRIGGER IF NOT EXISTS lyrics_fts_delete AFTER DELETE ON lyrics
+BEGIN
+    INSERT INTO lyrics_fts (lyrics_fts, rowid, plain) VALUES ('delete', old.music_id, old.plain);
+END;
+
+CREATE TRIGGER IF NOT EXISTS lyrics_fts_update AFTER UPDATE ON lyrics
+BEGIN
+    INSERT INTO lyrics_fts (lyrics_fts, rowid, plain) VALUES ('delete', old.music_id, old.plain);
+    INSERT INTO lyrics_fts (rowid, plain) VALUES (new.music_id, new.plain);
+END;
\ No newline at end of file
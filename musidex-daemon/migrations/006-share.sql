@@ -0,0 +1,7 @@
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE IF NOT EXISTS secrets
+(
+    name  text primary key,
+    value blob not null -- never sent to clients, unlike config
+);
@@ -0,0 +1,12 @@
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE IF NOT EXISTS checksums
+(
+    path         text    not null, -- relative to storage
+    window_start integer not null,
+    window_end   integer not null,
+    modified     integer not null, -- nanoseconds since the epoch, the cache is invalid once it changes
+    size         integer not null,
+    sha256       text    not null,
+    PRIMARY KEY (path, window_start, window_end)
+);
@@ -0,0 +1,24 @@
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE IF NOT EXISTS csv_imports
+(
+    id      integer primary key autoincrement,
+    user_id integer not null,
+    created integer not null -- unix timestamp in seconds
+);
+
+CREATE TABLE IF NOT EXISTS csv_import_rows
+(
+    import_id   integer not null references csv_imports (id) on delete cascade,
+    row         integer not null,
+    track       text    not null,
+    artist      text,
+    album       text,
+    duration    real, -- seconds
+    status      text    not null default 'pending',
+    video_id    text,
+    video_title text,
+    confidence  real,
+    music_id    integer,
+    PRIMARY KEY (import_id, row)
+);
@@ -0,0 +1,67 @@
+PRAGMA foreign_keys = ON;
+
+-- log of the changes to the synced metadata, so clients only fetch what changed since their revision
+CREATE TABLE IF NOT EXISTS changes
+(
+    revision integer primary key autoincrement,
+    -- both null when musics, users or the config changed
+    music_id integer,
+    key      text,
+    existed  integer not null -- whether the tag existed before the change
+);
+
+CREATE TRIGGER IF NOT EXISTS changes_tags_insert
+    AFTER INSERT ON tags WHEN new.key != 'fingerprint'
+BEGIN
+    INSERT INTO changes (music_id, key, existed) VALUES (new.music_id, new.key, 0);
+END;
+
+CREATE TRIGGER IF NOT EXISTS changes_tags_update
+    AFTER UPDATE ON tags WHEN new.key != 'fingerprint'
+BEGIN
+    INSERT INTO changes (music_id, key, existed) VALUES (old.music_id, old.key, 1);
+    INSERT INTO changes (music_id, key, existed)
+    SELECT new.music_id, new.key, 0
+    WHERE new.music_id != old.music_id OR new.key != old.key;
+END;
+
+CREATE TRIGGER IF NOT EXISTS changes_tags_delete
+    AFTER DELETE ON tags WHEN old.key != 'fingerprint'
+BEGIN
+    INSERT INTO changes (music_id, key, existed) VALUES (old.music_id, old.key, 1);
+END;
+
+CREATE TRIGGER IF NOT EXISTS changes_musics_insert AFTER INSERT ON musics
+BEGIN
+    INSERT INTO changes (existed) VALUES (0);
+END;
+
+CREATE TRIGGER IF NOT EXISTS changes_musics_delete AFTER DELETE ON musics
+BEGIN
+    INSERT INTO changes (existed) VALUES (0);
+END;
+
+CREATE TRIGGER IF NOT EXISTS changes_users_insert AFTER INSERT ON users
+BEGIN
+    INSERT INTO changes (existed) VALUES (0);
+END;
+
+CREATE TRIGGER IF NOT EXISTS changes_users_update AFTER UPDATE ON users
+BEGIN
+    INSERT INTO changes (existed) VALUES (0);
+END;
+
+CREATE TRIGGER IF NOT EXISTS changes_users_delete AFTER DELETE ON users
+BEGIN
+    INSERT INTO changes (existed) VALUES (0);
+END;
+
+CREATE TRIGGER IF NOT EXISTS changes_config_insert AFTER INSERT ON config
+BEGIN
+    INSERT INTO changes (existed) VALUES (0);
+END;
+
+CREATE TRIGGER IF NOT EXISTS changes_config_update AFTER UPDATE ON config
+BEGIN
+    INSERT INTO changes (existed) VALUES (0);
+END;
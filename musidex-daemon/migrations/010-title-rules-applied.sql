@@ -0,0 +1,13 @@
+PRAGMA foreign_keys = ON;
+
+-- the rules the library titles were last produced with, so re-applying only touches titles no one edited
+CREATE TABLE IF NOT EXISTS title_rules_applied
+(
+    position    integer primary key,
+    kind        text not null,
+    pattern     text not null,
+    replacement text not null default ''
+);
+
+INSERT INTO title_rules_applied (position, kind, pattern, replacement)
+SELECT position, kind, pattern, replacement FROM title_rules;
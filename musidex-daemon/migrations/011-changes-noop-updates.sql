@@ -0,0 +1,19 @@
+PRAGMA foreign_keys = ON;
+
+-- upserting a tag with the same values does not make clients refetch it
+DROP TRIGGER IF EXISTS changes_tags_update;
+
+CREATE TRIGGER IF NOT EXISTS changes_tags_update
+    AFTER UPDATE ON tags
+    WHEN new.key != 'fingerprint' AND (old.text IS NOT new.text
+        OR old.integer IS NOT new.integer
+        OR old.date IS NOT new.date
+        OR old.vector IS NOT new.vector
+        OR old.music_id != new.music_id
+        OR old.key != new.key)
+BEGIN
+    INSERT INTO changes (music_id, key, existed) VALUES (old.music_id, old.key, 1);
+    INSERT INTO changes (music_id, key, existed)
+    SELECT new.music_id, new.key, 0
+    WHERE new.music_id != old.music_id OR new.key != old.key;
+END;
@@ -0,0 +1,8 @@
+PRAGMA foreign_keys = ON;
+
+-- the config is synced to every client and writable by them: the yt-dlp binary and its extra
+-- args now come from the environment, the cookies and the proxy credentials are kept secret
+INSERT OR IGNORE INTO secrets (name, value)
+SELECT key, value FROM config WHERE key IN ('ytdlp_cookies', 'ytdlp_proxy') AND value != '';
+
+DELETE FROM config WHERE key IN ('ytdlp_path', 'ytdlp_extra_args', 'ytdlp_cookies', 'ytdlp_proxy');
@@ -0,0 +1,29 @@
+PRAGMA foreign_keys = ON;
+
+-- when the tags of a music last changed, so files generated from them can tell when they were modified
+ALTER TABLE musics ADD COLUMN tags_updated integer;
+
+UPDATE musics SET tags_updated = CAST(strftime('%s', 'now') AS integer);
+
+CREATE TRIGGER IF NOT EXISTS tags_updated_insert AFTER INSERT ON tags
+BEGIN
+    UPDATE musics SET tags_updated = CAST(strftime('%s', 'now') AS integer) WHERE id = new.music_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS tags_updated_update
+    AFTER UPDATE ON tags
+    WHEN old.text IS NOT new.text
+        OR old.integer IS NOT new.integer
+        OR old.date IS NOT new.date
+        OR old.vector IS NOT new.vector
+        OR old.music_id != new.music_id
+        OR old.key != new.key
+BEGIN
+    UPDATE musics SET tags_updated = CAST(strftime('%s', 'now') AS integer)
+    WHERE id IN (old.music_id, new.music_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS tags_updated_delete AFTER DELETE ON tags
+BEGIN
+    UPDATE musics SET tags_updated = CAST(strftime('%s', 'now') AS integer) WHERE id = old.music_id;
+END;
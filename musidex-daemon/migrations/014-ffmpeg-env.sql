@@ -0,0 +1,4 @@
+PRAGMA foreign_keys = ON;
+
+-- ffmpeg is run as is, so its path now comes from the FFMPEG_PATH environment variable
+DELETE FROM config WHERE key = 'ffmpeg_path';
@@ -1,34 +1,58 @@
 use std::convert::TryInto;
+use std::path::Path;
 
 use anyhow::{Context, Result};
 use hyper::{Body, Request, Response, StatusCode};
 
 use crate::domain::entity::{Music, MusicID, Tag, TagKey, User, UserID};
+use crate::domain::hls::{self, HlsMusic};
+use crate::domain::loudness::ReplayGain;
 use crate::domain::music::delete_music;
-use crate::domain::sync::{compress_meta, serve_sync_websocket, SyncBroadcastSubscriber};
-use crate::domain::{stream, sync, upload};
+use crate::domain::offline::{self, Selection};
+use crate::domain::stream::MusicSource;
+use crate::domain::sync::{
+    compress_meta, serve_sync_websocket, SyncBroadcastSubscriber, SyncScope,
+};
+use crate::domain::{
+    cover_art, csv_import, download, fingerprint, stream, sync, thumbnail, upload,
+};
+use crate::infrastructure::file_server::{serve_file, serve_source};
 use crate::infrastructure::router::RequestExt;
-use crate::utils::res_status;
+use crate::utils::{header_accepts, query_param, res_status};
 use crate::Db;
 use nanoserde::{DeJson, SerJson};
 
 pub async fn metadata(req: Request<Body>) -> Result<Response<Body>> {
+    let scope = unwrap_ret!(
+        SyncScope::from_req(&req),
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
     let db = req.state::<Db>();
     let c = db.get().await;
 
-    let metadata = sync::fetch_metadata(&c).context("failed fetching metadata")?;
+    let metadata = sync::fetch_metadata(&c, &scope).context("failed fetching metadata")?;
 
     Ok(Response::new(Body::from(metadata.serialize_json())))
 }
 
 pub async fn metadata_compressed(req: Request<Body>) -> Result<Response<Body>> {
+    let scope = unwrap_ret!(
+        SyncScope::from_req(&req),
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
     let db = req.state::<Db>();
     let c = db.get().await;
 
-    let metadata = sync::fetch_metadata(&c).context("failed fetching metadata")?;
+    let metadata = sync::fetch_metadata(&c, &scope).context("failed fetching metadata")?;
     let compressed = compress_meta(&metadata);
 
-    Ok(Response::new(Body::from(compressed)))
+    let mut r = Response::new(Body::from(compressed));
+    // already deflated, the client inflates it itself
+    r.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        "application/octet-stream".parse()?,
+    );
+    Ok(r)
 }
 
 #[derive(SerJson)]
@@ -55,11 +79,18 @@ pub async fn subscribe_sync(request: Request<Body>) -> Result<Response<Body>> {
     if !hyper_tungstenite::is_upgrade_request(&request) {
         return Ok(Response::new(Body::empty()));
     }
+    let scope = unwrap_ret!(
+        SyncScope::from_req(&request),
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
+    // the last revision the client got, to only send what changed since
+    let since = query_param(&request, "revision").and_then(|x| x.parse().ok());
     let st = request.state::<SyncBroadcastSubscriber>().clone();
+    let db = request.state::<Db>().clone();
     let (response, websocket) = hyper_tungstenite::upgrade(request, None)?;
 
     tokio::spawn(async move {
-        if let Err(e) = serve_sync_websocket(websocket, st).await {
+        if let Err(e) = serve_sync_websocket(websocket, st, db, scope, since).await {
             log::error!("error in websocket connection: {}", e);
         }
     });
@@ -130,6 +161,69 @@ pub async fn merge_music(mut req: Request<Body>) -> Result<Response<Body>> {
     Ok(Response::new(Body::empty()))
 }
 
+pub async fn duplicates(req: Request<Body>) -> Result<Response<Body>> {
+    let db = req.state::<Db>();
+    let fps = {
+        let c = db.get().await;
+        fingerprint::all_fingerprints(&c)?
+    };
+
+    let groups = tokio::task::spawn_blocking(move || fingerprint::find_duplicates(&fps)).await?;
+
+    Ok(Response::new(Body::from(groups.serialize_json())))
+}
+
+#[derive(DeJson)]
+pub struct MergeDuplicates {
+    musics: Vec<MusicID>,
+}
+
+/// Merges all the musics into the first one
+pub async fn merge_duplicates(mut req: Request<Body>) -> Result<Response<Body>> {
+    let MergeDuplicates { musics } = parse_body(&mut req).await?;
+    let (&first, rest) = match musics.split_first() {
+        Some(x) => x,
+        None => return Ok(res_status(StatusCode::BAD_REQUEST)),
+    };
+
+    let db = req.state::<Db>();
+    let mut c = db.get().await;
+
+    for &id in rest {
+        if id != first {
+            Music::merge(&mut c, first, id)?;
+        }
+    }
+
+    Ok(Response::new(Body::empty()))
+}
+
+pub async fn accept_suggestions(req: Request<Body>) -> Result<Response<Body>> {
+    let id = req.params().get("id").context("no id in url")?;
+    let id: i32 = id.parse().context("invalid id")?;
+
+    let db = req.state::<Db>();
+    let mut c = db.get().await;
+
+    if Music::accept_suggestions(&mut c, MusicID(id))? == 0 {
+        return Ok(res_status(StatusCode::NOT_FOUND));
+    }
+
+    Ok(Response::new(Body::empty()))
+}
+
+pub async fn reject_suggestions(req: Request<Body>) -> Result<Response<Body>> {
+    let id = req.params().get("id").context("no id in url")?;
+    let id: i32 = id.parse().context("invalid id")?;
+
+    let db = req.state::<Db>();
+    let c = db.get().await;
+
+    Music::reject_suggestions(&c, MusicID(id))?;
+
+    Ok(Response::new(Body::empty()))
+}
+
 pub async fn delete_music_handler(req: Request<Body>) -> Result<Response<Body>> {
     let music_id = req.params().get("id").context("missing parameter id")?;
     let db = req.state::<Db>();
@@ -149,6 +243,11 @@ pub async fn retry_on_error(req: Request<Body>) -> Result<Response<Body>> {
     let db = req.state::<Db>();
     let c = db.get().await;
 
+    // for musicbrainz 'false' means nothing was found, removing the mark makes it look up again
+    c.execute(
+        "DELETE FROM tags WHERE key='musicbrainz_worker_treated' AND text='error'",
+        [],
+    )?;
     c.execute("UPDATE tags SET text='false' WHERE text='error'", [])?;
 
     Ok(res_status(StatusCode::OK))
@@ -201,6 +300,40 @@ pub async fn youtube_upload_playlist(mut req: Request<Body>) -> Result<Response<
     Ok(r)
 }
 
+/// Imports a csv export of a playlist, like Exportify's. Tracks are searched on youtube in the
+/// background, the returned report being updated as they are.
+pub async fn youtube_upload_csv(mut req: Request<Body>) -> Result<Response<Body>> {
+    let uid = User::from_req(&req).context("no user id")?;
+    let body = hyper::body::to_bytes(req.body_mut()).await?;
+    let tracks = match csv_import::parse(&body) {
+        Ok(x) if !x.is_empty() => x,
+        Ok(_) => return Ok(res_status(StatusCode::BAD_REQUEST)),
+        Err(e) => {
+            log::warn!("invalid csv upload: {:?}", e);
+            return Ok(res_status(StatusCode::BAD_REQUEST));
+        }
+    };
+
+    let db = req.state::<Db>();
+    let mut c = db.get().await;
+    let id = csv_import::create(&mut c, uid, &tracks)?;
+    let report = csv_import::report(&c, id)?.context("import just created")?;
+    Ok(Response::new(Body::from(report.serialize_json())))
+}
+
+/// Progress of a csv import, listing the tracks that were not found or need a review
+pub async fn youtube_upload_csv_report(req: Request<Body>) -> Result<Response<Body>> {
+    let id = req.params().get("id").context("no id in url")?;
+    let id: i64 = id.parse().context("invalid id")?;
+    let db = req.state::<Db>();
+    let c = db.get().await;
+    let report = unwrap_ret!(
+        csv_import::report(&c, id)?,
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+    Ok(Response::new(Body::from(report.serialize_json())))
+}
+
 #[derive(DeJson)]
 pub struct ConfigUpdate {
     pub key: String,
@@ -229,30 +362,247 @@ pub async fn stream(req: Request<Body>) -> Result<Response<Body>> {
     let db = req.state::<Db>();
     let c = db.get().await;
 
-    let meta = stream::stream_music(c, id, req.headers().get(hyper::header::RANGE)).await?;
+    let replay_gain = ReplayGain::of(&c, id)?;
+    let file = stream::stream_music(c, id).await?;
 
-    let mut r = Response::new(Body::from(meta.buf));
+    let mut r = serve_file(req.headers(), &file.path, file.window, file.content_type).await?;
 
-    r.headers_mut()
-        .insert(hyper::header::CONTENT_TYPE, meta.content_type.parse()?);
-    r.headers_mut()
-        .insert(hyper::header::ACCEPT_RANGES, "bytes".parse()?);
-
-    if req.headers().contains_key(hyper::header::RANGE) {
+    if let Some(rg) = replay_gain {
+        r.headers_mut().insert(
+            "X-ReplayGain-Track-Gain",
+            format!("{:.2} dB", rg.gain).try_into()?,
+        );
         r.headers_mut().insert(
-            hyper::header::CONTENT_RANGE,
-            format!(
-                "bytes {}-{}/{}",
-                meta.range_size.0, meta.range_size.1, meta.range_size.2
-            )
-            .try_into()?,
+            "X-ReplayGain-Track-Peak",
+            format!("{:.6}", rg.peak).try_into()?,
         );
-        *r.status_mut() = StatusCode::PARTIAL_CONTENT;
     }
 
     Ok(r)
 }
 
+/// Serves the thumbnail resized to at least the `size` query parameter, as webp if accepted
+pub async fn thumbnail(req: Request<Body>) -> Result<Response<Body>> {
+    let music_id = req.params().get("musicid").context("invalid music id")?;
+    let id = MusicID(
+        music_id
+            .parse()
+            .context("couldn't parse music id as integer")?,
+    );
+    let size = match query_param(&req, "size") {
+        Some(x) => Some(x.parse::<u32>().context("invalid size")?),
+        None => None,
+    };
+    let webp = header_accepts(req.headers().get(hyper::header::ACCEPT), "image/webp");
+
+    let db = req.state::<Db>();
+    let picked = {
+        let c = db.get().await;
+        thumbnail::pick(&c, id, size, webp)?
+    };
+    let (fname, content_type) = unwrap_ret!(picked, Ok(res_status(StatusCode::NOT_FOUND)));
+
+    let p = Path::new("storage").join(fname);
+    let mut r = serve_file(req.headers(), &p, None, content_type).await?;
+    r.headers_mut()
+        .insert(hyper::header::VARY, "Accept".parse()?);
+    Ok(r)
+}
+
+/// Returns the audio file of the music with its tags and cover written into it
+pub async fn download(req: Request<Body>) -> Result<Response<Body>> {
+    let music_id = req.params().get("musicid").context("invalid music id")?;
+    let id = MusicID(
+        music_id
+            .parse()
+            .context("couldn't parse music id as integer")?,
+    );
+    let db = req.state::<Db>();
+    let found = {
+        let c = db.get().await;
+        match MusicSource::find(&c, id)? {
+            Some(source) => Some((
+                source,
+                download::export_tags(&c, id)?,
+                Music::tags_updated(&c, id)?,
+            )),
+            None => None,
+        }
+    };
+    let (source, tags, updated) = unwrap_ret!(found, Ok(res_status(StatusCode::NOT_FOUND)));
+
+    let dl =
+        tokio::task::spawn_blocking(move || download::build(id, &source, &tags, updated)).await??;
+
+    let mut r = serve_source(
+        req.headers(),
+        &dl,
+        dl.size(),
+        &dl.validators,
+        dl.content_type,
+    )?;
+    r.headers_mut().insert(
+        hyper::header::CONTENT_DISPOSITION,
+        dl.content_disposition().parse()?,
+    );
+    Ok(r)
+}
+
+async fn hls_music(req: &Request<Body>) -> Result<Option<HlsMusic>> {
+    let music_id = req.params().get("musicid").context("invalid music id")?;
+    let id = MusicID(
+        music_id
+            .parse()
+            .context("couldn't parse music id as integer")?,
+    );
+    let db = req.state::<Db>();
+    let c = db.get().await;
+    HlsMusic::find(&c, id)
+}
+
+fn hls_playlist_response(playlist: String) -> Result<Response<Body>> {
+    let mut r = Response::new(Body::from(playlist));
+    r.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        "application/vnd.apple.mpegurl".parse()?,
+    );
+    Ok(r)
+}
+
+/// Serves the HLS master playlist listing the bitrates the music can be streamed at
+pub async fn hls_master(req: Request<Body>) -> Result<Response<Body>> {
+    let music = unwrap_ret!(
+        hls_music(&req).await?,
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+    hls_playlist_response(music.master_playlist().await?)
+}
+
+/// Serves the HLS media playlist of a variant, segmenting or transcoding the music on first access
+pub async fn hls_playlist(req: Request<Body>) -> Result<Response<Body>> {
+    let music = unwrap_ret!(
+        hls_music(&req).await?,
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+    let variant = req.params().get("variant").context("missing variant")?;
+    let variant = unwrap_ret!(
+        music.variant(variant),
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+    let segments = music.prepare(variant).await?;
+    hls_playlist_response(hls::media_playlist(&segments))
+}
+
+pub async fn hls_segment(req: Request<Body>) -> Result<Response<Body>> {
+    let music = unwrap_ret!(
+        hls_music(&req).await?,
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+    let variant = req.params().get("variant").context("missing variant")?;
+    let variant = unwrap_ret!(
+        music.variant(variant),
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+    let segment = req.params().get("segment").context("missing segment")?;
+    let n = unwrap_ret!(
+        segment
+            .strip_suffix(".mp3")
+            .and_then(|x| x.parse::<usize>().ok()),
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+    let p = unwrap_ret!(
+        music.segment(variant, n).await?,
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+    serve_file(req.headers(), &p, None, "audio/mpeg").await
+}
+
+/// Lists the files of the offline archive of a selection, with their size, checksum and offset
+pub async fn offline_manifest(req: Request<Body>) -> Result<Response<Body>> {
+    let selection = unwrap_ret!(
+        Selection::from_req(&req)?,
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
+    let db = req.state::<Db>();
+    let package = offline::collect(db, &selection).await?;
+    let manifest = package.manifest(db).await?;
+    Ok(Response::new(Body::from(manifest.serialize_json())))
+}
+
+/// Streams the files of a selection as an uncompressed tar archive, the download being
+/// resumable with If-Range and the etag of the manifest as long as the files do not change
+pub async fn offline_archive(req: Request<Body>) -> Result<Response<Body>> {
+    let selection = unwrap_ret!(
+        Selection::from_req(&req)?,
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
+    let db = req.state::<Db>();
+    let package = offline::collect(db, &selection).await?;
+    let archive = package.archive()?;
+    let mut r = serve_source(
+        req.headers(),
+        &archive,
+        archive.size(),
+        &package.validators(),
+        "application/x-tar",
+    )?;
+    r.headers_mut().insert(
+        hyper::header::CONTENT_DISPOSITION,
+        "attachment; filename=\"musidex.tar\"".parse()?,
+    );
+    Ok(r)
+}
+
+/// Replaces the thumbnail of the music by the image in the body (jpeg, png or webp)
+pub async fn upload_thumbnail(mut req: Request<Body>) -> Result<Response<Body>> {
+    let music_id = req.params().get("id").context("missing parameter id")?;
+    let id = MusicID(
+        music_id
+            .parse()
+            .context("couldn't parse music id as integer")?,
+    );
+    let db = req.state::<Db>().clone();
+    if !Music::exists(&*db.get().await, id)? {
+        return Ok(res_status(StatusCode::NOT_FOUND));
+    }
+
+    let body = hyper::body::to_bytes(req.body_mut())
+        .await
+        .context("could not read body")?;
+    let fname = match tokio::task::spawn_blocking(move || cover_art::save_cover(id, &body)).await? {
+        Ok(x) => x,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("{:#}", e)))
+                .unwrap())
+        }
+    };
+
+    let c = db.get().await;
+    cover_art::set_thumbnail(&c, id, fname)?;
+
+    Ok(Response::new(Body::empty()))
+}
+
+pub async fn waveform(req: Request<Body>) -> Result<Response<Body>> {
+    let music_id = req.params().get("musicid").context("invalid music id")?;
+    let id = MusicID(
+        music_id
+            .parse()
+            .context("couldn't parse music id as integer")?,
+    );
+    let db = req.state::<Db>();
+    let fname = {
+        let c = db.get().await;
+        Tag::by_id_key(&c, id, TagKey::Waveform)?.and_then(|x| x.text)
+    };
+    let fname = unwrap_ret!(fname, Ok(res_status(StatusCode::NOT_FOUND)));
+
+    let p = Path::new("storage").join(fname);
+    serve_file(req.headers(), &p, None, "application/json").await
+}
+
 pub async fn parse_body<T: DeJson>(req: &mut Request<Body>) -> Result<T> {
     let f = hyper::body::to_bytes(req.body_mut())
         .await
@@ -0,0 +1,86 @@
+use crate::application::handlers::parse_body;
+use crate::domain::entity::MusicID;
+use crate::domain::lyrics::{Lyrics, SOURCE_USER};
+use crate::infrastructure::db::Db;
+use crate::infrastructure::router::RequestExt;
+use crate::utils::res_status;
+use anyhow::{Context, Result};
+use hyper::{Body, Request, Response, StatusCode};
+use nanoserde::{DeJson, SerJson};
+
+fn music_id(req: &Request<Body>) -> Result<MusicID> {
+    let id = req.params().get("musicid").context("no music id in url")?;
+    Ok(MusicID(
+        id.parse().context("couldn't parse music id as integer")?,
+    ))
+}
+
+pub async fn get(req: Request<Body>) -> Result<Response<Body>> {
+    let id = music_id(&req)?;
+    let db = req.state::<Db>();
+    let c = db.get().await;
+
+    let lyrics = unwrap_ret!(Lyrics::get(&c, id)?, Ok(res_status(StatusCode::NOT_FOUND)));
+
+    Ok(Response::new(Body::from(lyrics.serialize_json())))
+}
+
+#[derive(DeJson)]
+pub struct LyricsPUT {
+    pub plain: Option<String>,
+    /// takes precedence over plain, which is then derived from it
+    pub synced: Option<String>,
+}
+
+pub async fn update(mut req: Request<Body>) -> Result<Response<Body>> {
+    let data: LyricsPUT = parse_body(&mut req).await.context("can't decode body")?;
+    let id = music_id(&req)?;
+
+    let lyrics = match (data.synced, data.plain) {
+        (Some(synced), _) => Lyrics::from_lrc(synced, SOURCE_USER),
+        (None, Some(plain)) => Lyrics::from_plain(plain, SOURCE_USER),
+        (None, None) => None,
+    };
+    let lyrics = unwrap_ret!(
+        lyrics,
+        Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from("empty lyrics or no timestamped line"))
+            .unwrap())
+    );
+
+    let db = req.state::<Db>();
+    let c = db.get().await;
+
+    Lyrics::set(&c, id, &lyrics)?;
+
+    Ok(Response::new(Body::empty()))
+}
+
+pub async fn delete(req: Request<Body>) -> Result<Response<Body>> {
+    let id = music_id(&req)?;
+    let db = req.state::<Db>();
+    let c = db.get().await;
+
+    Lyrics::remove(&c, id)?;
+
+    Ok(Response::new(Body::empty()))
+}
+
+#[derive(DeJson)]
+pub struct LyricsSearch {
+    pub query: String,
+    pub limit: Option<u32>,
+}
+
+/// Finds musics by a line of their lyrics
+pub async fn search(mut req: Request<Body>) -> Result<Response<Body>> {
+    let data: LyricsSearch = parse_body(&mut req).await.context("can't decode body")?;
+
+    let db = req.state::<Db>();
+    let c = db.get().await;
+
+    let matches = Lyrics::search(&c, &data.query, data.limit.unwrap_or(50))?;
+
+    Ok(Response::new(Body::from(matches.serialize_json())))
+}
@@ -1,2 +1,6 @@
 pub mod handlers;
+pub mod lyrics_handlers;
+pub mod playlist_handlers;
+pub mod share_handlers;
+pub mod title_rule_handlers;
 pub mod user_handlers;
@@ -0,0 +1,121 @@
+use crate::domain::offline::Selection;
+use crate::domain::playlist::{self, Format, Location};
+use crate::infrastructure::db::Db;
+use crate::infrastructure::router::RequestExt;
+use crate::utils::{query_param, res_status};
+use anyhow::Result;
+use hyper::header::{CONTENT_DISPOSITION, CONTENT_TYPE, HOST};
+use hyper::{Body, Request, Response, StatusCode};
+use nanoserde::SerJson;
+
+/// Origin of the urls the client reached us with, empty if unknown so urls are relative
+fn origin(req: &Request<Body>) -> String {
+    let host = unwrap_ret!(
+        req.headers().get(HOST).and_then(|x| x.to_str().ok()),
+        String::new()
+    );
+    let proto = req
+        .headers()
+        .get("x-forwarded-proto")
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or("http");
+    format!("{}://{}", proto, host)
+}
+
+/// File name safe to put in a Content-Disposition header
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == ' ' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Exports the selected musics as a playlist pointing to their stream url,
+/// or with `paths=storage` to their file relative to the storage directory
+pub async fn export(req: Request<Body>) -> Result<Response<Body>> {
+    let format = unwrap_ret!(
+        req.params().get("format").and_then(Format::parse),
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+    let selection = unwrap_ret!(
+        Selection::from_req(&req)?,
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
+    let location = match query_param(&req, "paths") {
+        Some("storage") => Location::Storage,
+        _ => Location::Url(origin(&req)),
+    };
+    let name = match selection {
+        Selection::Tag(ref name) => name.clone(),
+        Selection::Library(_) => s!("Library"),
+        Selection::Musics(_) => s!("Musidex"),
+    };
+
+    let db = req.state::<Db>();
+    let entries = playlist::entries(&*db.get().await, &selection, &location)?;
+    let body = match format {
+        Format::M3u8 => playlist::m3u8(&name, &entries),
+        Format::Xspf => playlist::xspf(&name, &entries, &location),
+    };
+
+    let mut r = Response::new(Body::from(body));
+    let headers = r.headers_mut();
+    headers.insert(CONTENT_TYPE, format.content_type().parse()?);
+    headers.insert(
+        CONTENT_DISPOSITION,
+        format!(
+            "attachment; filename=\"{}.{}\"",
+            file_name(&name),
+            format.extension()
+        )
+        .parse()?,
+    );
+    Ok(r)
+}
+
+/// Imports the uploaded playlist as a user tag: Musidex has no playlist of its own, so the
+/// matched musics are tagged with user_tag:<name> and show up under that tag.
+/// The name is the `name` parameter or else the title of the playlist. Importing into an
+/// existing tag adds to it, and the order of the entries is not kept.
+/// Entries that are not found are reported.
+pub async fn import(mut req: Request<Body>) -> Result<Response<Body>> {
+    let format = unwrap_ret!(
+        req.params().get("format").and_then(Format::parse),
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+    let body = hyper::body::to_bytes(req.body_mut()).await?;
+    let text = String::from_utf8_lossy(&body);
+    let text = text.trim_start_matches('\u{feff}');
+    let parsed = match format {
+        Format::M3u8 => playlist::parse_m3u(text),
+        Format::Xspf => match playlist::parse_xspf(text) {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("invalid xspf upload: {:?}", e);
+                return Ok(res_status(StatusCode::BAD_REQUEST));
+            }
+        },
+    };
+
+    let name = match query_param(&req, "name") {
+        Some(x) => Some(
+            percent_encoding::percent_decode_str(x)
+                .decode_utf8()?
+                .into_owned(),
+        ),
+        None => parsed.name.clone(),
+    };
+    let name = unwrap_ret!(
+        name.filter(|x| !x.trim().is_empty()),
+        Ok(res_status(StatusCode::BAD_REQUEST))
+    );
+
+    let db = req.state::<Db>();
+    let report = playlist::import(&*db.get().await, &name, &parsed)?;
+    Ok(Response::new(Body::from(report.serialize_json())))
+}
@@ -0,0 +1,151 @@
+use crate::application::handlers::parse_body;
+use crate::domain::entity::{MusicID, User};
+use crate::domain::share::{self, Share, ShareTarget, DEFAULT_EXPIRY_SECS, MAX_EXPIRY_SECS};
+use crate::domain::{stream, thumbnail};
+use crate::infrastructure::db::Db;
+use crate::infrastructure::file_server::serve_file;
+use crate::infrastructure::router::RequestExt;
+use crate::utils::{header_accepts, now, res_status};
+use anyhow::{Context, Result};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, REFERRER_POLICY};
+use hyper::{Body, Request, Response, StatusCode};
+use nanoserde::{DeJson, SerJson};
+use rusqlite::Connection;
+use std::path::Path;
+
+#[derive(DeJson)]
+pub struct ShareCreate {
+    pub music_id: Option<MusicID>,
+    /// name of a user tag, to share every music having it
+    pub tag: Option<String>,
+    /// in seconds
+    pub expires_in: Option<i64>,
+}
+
+#[derive(SerJson)]
+pub struct ShareCreated {
+    pub token: String,
+    pub url: String,
+    /// unix timestamp in seconds
+    pub expires: i64,
+}
+
+/// Creates a link giving access to a music or a user tag without an account
+pub async fn create(mut req: Request<Body>) -> Result<Response<Body>> {
+    let data: ShareCreate = parse_body(&mut req).await.context("can't decode body")?;
+    let uid = User::from_req(&req).context("no user id")?;
+
+    let target = match (data.music_id, data.tag) {
+        (Some(id), None) => ShareTarget::Music(id),
+        (None, Some(tag)) if !tag.is_empty() => ShareTarget::Tag(tag),
+        _ => return Ok(res_status(StatusCode::BAD_REQUEST)),
+    };
+    let expires_in = data.expires_in.unwrap_or(DEFAULT_EXPIRY_SECS);
+    if expires_in <= 0 || expires_in > MAX_EXPIRY_SECS {
+        return Ok(res_status(StatusCode::BAD_REQUEST));
+    }
+    let share = Share {
+        target,
+        expires: now() + expires_in,
+    };
+
+    let db = req.state::<Db>();
+    let c = db.get().await;
+    if share.musics(&c)?.is_empty() {
+        return Ok(res_status(StatusCode::NOT_FOUND));
+    }
+    let token = share.sign(&share::secret(&c)?);
+    log::info!("{:?} shared {:?}", uid, share);
+
+    Ok(Response::new(Body::from(
+        ShareCreated {
+            url: format!("/s/{}", token),
+            token,
+            expires: share.expires,
+        }
+        .serialize_json(),
+    )))
+}
+
+/// The share of the token in the url, or the status to answer if it does not give access anymore
+fn verify(c: &Connection, req: &Request<Body>) -> Result<std::result::Result<Share, StatusCode>> {
+    let token = req.params().get("token").context("no token in url")?;
+    let share = match Share::verify(&share::secret(c)?, token) {
+        Some(x) => x,
+        None => return Ok(Err(StatusCode::NOT_FOUND)),
+    };
+    if share.is_expired() {
+        return Ok(Err(StatusCode::GONE));
+    }
+    Ok(Ok(share))
+}
+
+/// The music in the url if the share gives access to it
+fn shared_music(
+    c: &Connection,
+    req: &Request<Body>,
+) -> Result<std::result::Result<MusicID, StatusCode>> {
+    let share = match verify(c, req)? {
+        Ok(x) => x,
+        Err(status) => return Ok(Err(status)),
+    };
+    let id = req.params().get("musicid").context("no music id in url")?;
+    let id = MusicID(id.parse().context("couldn't parse music id as integer")?);
+    if !share.contains(c, id)? {
+        return Ok(Err(StatusCode::NOT_FOUND));
+    }
+    Ok(Ok(id))
+}
+
+pub async fn page(req: Request<Body>) -> Result<Response<Body>> {
+    let db = req.state::<Db>();
+    let c = db.get().await;
+    let share = match verify(&c, &req)? {
+        Ok(x) => x,
+        Err(status) => return Ok(res_status(status)),
+    };
+    let token = req.params().get("token").context("no token in url")?;
+    let html = unwrap_ret!(
+        share::page(&c, token, &share)?,
+        Ok(res_status(StatusCode::NOT_FOUND))
+    );
+
+    let mut r = Response::new(Body::from(html));
+    let headers = r.headers_mut();
+    headers.insert(CONTENT_TYPE, "text/html; charset=utf-8".parse()?);
+    headers.insert(CACHE_CONTROL, "no-store".parse()?);
+    // the token would leak to the sites linked from the page
+    headers.insert(REFERRER_POLICY, "no-referrer".parse()?);
+    Ok(r)
+}
+
+pub async fn stream(req: Request<Body>) -> Result<Response<Body>> {
+    let db = req.state::<Db>();
+    let c = db.get().await;
+    let id = match shared_music(&c, &req)? {
+        Ok(x) => x,
+        Err(status) => return Ok(res_status(status)),
+    };
+    let file = stream::stream_music(c, id).await?;
+    serve_file(req.headers(), &file.path, file.window, file.content_type).await
+}
+
+pub async fn thumbnail(req: Request<Body>) -> Result<Response<Body>> {
+    let db = req.state::<Db>();
+    let picked = {
+        let c = db.get().await;
+        let id = match shared_music(&c, &req)? {
+            Ok(x) => x,
+            Err(status) => return Ok(res_status(status)),
+        };
+        let webp = header_accepts(req.headers().get(hyper::header::ACCEPT), "image/webp");
+        thumbnail::pick(&c, id, Some(256), webp)?
+    };
+    let (fname, content_type) = unwrap_ret!(picked, Ok(res_status(StatusCode::NOT_FOUND)));
+
+    let p = Path::new("storage").join(fname);
+    let mut r = serve_file(req.headers(), &p, None, content_type).await?;
+    r.headers_mut()
+        .insert(hyper::header::VARY, "Accept".parse()?);
+    Ok(r)
+}
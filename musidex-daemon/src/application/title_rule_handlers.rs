@@ -0,0 +1,63 @@
+use crate::application::handlers::parse_body;
+use crate::domain::title_rules::{apply_to_library, preview, TitleRule, TitleRules};
+use crate::infrastructure::db::Db;
+use crate::infrastructure::router::RequestExt;
+use anyhow::{Context, Result};
+use hyper::{Body, Request, Response, StatusCode};
+use nanoserde::SerJson;
+
+fn bad_rules(e: anyhow::Error) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(format!("{:#}", e)))
+        .unwrap()
+}
+
+pub async fn list(req: Request<Body>) -> Result<Response<Body>> {
+    let db = req.state::<Db>();
+    let c = db.get().await;
+
+    let rules = TitleRule::list(&c)?;
+
+    Ok(Response::new(Body::from(rules.serialize_json())))
+}
+
+pub async fn update(mut req: Request<Body>) -> Result<Response<Body>> {
+    let rules: Vec<TitleRule> = parse_body(&mut req).await.context("can't decode body")?;
+    if let Err(e) = TitleRules::compile(&rules) {
+        return Ok(bad_rules(e));
+    }
+
+    let db = req.state::<Db>();
+    let mut c = db.get().await;
+
+    TitleRule::replace_all(&mut c, &rules)?;
+
+    Ok(Response::new(Body::empty()))
+}
+
+/// Shows what the given rules would do to the library, without saving anything
+pub async fn preview_rules(mut req: Request<Body>) -> Result<Response<Body>> {
+    let rules: Vec<TitleRule> = parse_body(&mut req).await.context("can't decode body")?;
+    let rules = match TitleRules::compile(&rules) {
+        Ok(x) => x,
+        Err(e) => return Ok(bad_rules(e)),
+    };
+
+    let db = req.state::<Db>();
+    let c = db.get().await;
+
+    let previews = preview(&c, &rules)?;
+
+    Ok(Response::new(Body::from(previews.serialize_json())))
+}
+
+/// Re-applies the saved rules to the whole library
+pub async fn apply(req: Request<Body>) -> Result<Response<Body>> {
+    let db = req.state::<Db>();
+    let mut c = db.get().await;
+
+    let changed = apply_to_library(&mut c)?;
+
+    Ok(Response::new(Body::from(changed.to_string())))
+}
@@ -0,0 +1,221 @@
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+/// Audio is downsampled to this rate before analysis
+pub const SAMPLE_RATE: u32 = 11025;
+/// Only the beginning of musics is analyzed
+pub const MAX_DURATION: f64 = 240.0;
+
+const ONSET_FRAME_SIZE: usize = 1024;
+const ONSET_HOP_SIZE: usize = 128;
+const MIN_BPM: f32 = 60.0;
+const MAX_BPM: f32 = 200.0;
+/// Tempos are biased towards this one to avoid picking half or double the actual tempo
+const PRIOR_BPM: f32 = 120.0;
+
+const CHROMA_FRAME_SIZE: usize = 4096;
+const CHROMA_MIN_FREQ: f32 = 55.0;
+const CHROMA_MAX_FREQ: f32 = 2000.0;
+
+const PITCH_CLASSES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+// Krumhansl-Kessler key profiles, starting from the tonic
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// Magnitude spectrum of hann-windowed frames of the signal
+fn spectrogram(samples: &[f32], frame_size: usize, hop_size: usize) -> Vec<Vec<f32>> {
+    if samples.len() < frame_size {
+        return vec![];
+    }
+    let window: Vec<f32> = (0..frame_size)
+        .map(|i| {
+            0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / (frame_size - 1) as f32).cos()
+        })
+        .collect();
+    let fft = FftPlanner::new().plan_fft_forward(frame_size);
+    let mut buf = vec![Complex::new(0.0, 0.0); frame_size];
+
+    (0..=(samples.len() - frame_size) / hop_size)
+        .map(|frame| {
+            let frame = &samples[frame * hop_size..frame * hop_size + frame_size];
+            for ((b, &x), &w) in buf.iter_mut().zip(frame).zip(&window) {
+                *b = Complex::new(x * w, 0.0);
+            }
+            fft.process(&mut buf);
+            buf[..frame_size / 2].iter().map(|x| x.norm()).collect()
+        })
+        .collect()
+}
+
+/// Estimates the tempo from the autocorrelation of the spectral flux.
+/// `samples` should be mono at SAMPLE_RATE.
+pub fn estimate_bpm(samples: &[f32]) -> Option<f32> {
+    let fps = SAMPLE_RATE as f32 / ONSET_HOP_SIZE as f32;
+
+    let mut onsets = vec![];
+    let mut prev: Option<Vec<f32>> = None;
+    for frame in spectrogram(samples, ONSET_FRAME_SIZE, ONSET_HOP_SIZE) {
+        let frame: Vec<f32> = frame.iter().map(|x| (1.0 + 100.0 * x).ln()).collect();
+        if let Some(prev) = prev {
+            let flux: f32 = frame.iter().zip(&prev).map(|(x, p)| (x - p).max(0.0)).sum();
+            onsets.push(flux);
+        }
+        prev = Some(frame);
+    }
+
+    let mean = onsets.iter().sum::<f32>() / onsets.len().max(1) as f32;
+    onsets.iter_mut().for_each(|x| *x -= mean);
+
+    let min_lag = (60.0 * fps / MAX_BPM) as usize;
+    let max_lag = (60.0 * fps / MIN_BPM).ceil() as usize;
+    if onsets.len() < max_lag * 4 {
+        return None;
+    }
+
+    let score = |lag: usize| {
+        let r: f32 = onsets.iter().zip(&onsets[lag..]).map(|(a, b)| a * b).sum();
+        let r = r / (onsets.len() - lag) as f32;
+        let octaves = (60.0 * fps / lag as f32 / PRIOR_BPM).log2();
+        r * (-0.5 * octaves * octaves).exp()
+    };
+    let scores: Vec<f32> = (min_lag - 1..=max_lag + 1).map(score).collect();
+    let (best, &best_score) = scores[1..scores.len() - 1]
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))?;
+    if best_score <= 0.0 {
+        return None;
+    }
+
+    // parabolic interpolation around the peak for sub-frame precision
+    let (l, c, r) = (scores[best], scores[best + 1], scores[best + 2]);
+    let denom = l - 2.0 * c + r;
+    let shift = if denom.abs() > f32::EPSILON {
+        (0.5 * (l - r) / denom).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    let lag = (min_lag + best) as f32 + shift;
+    Some(60.0 * fps / lag)
+}
+
+fn correlation(a: &[f32; 12], b: &[f32; 12]) -> f32 {
+    let ma = a.iter().sum::<f32>() / 12.0;
+    let mb = b.iter().sum::<f32>() / 12.0;
+    let (mut cov, mut va, mut vb) = (0.0, 0.0, 0.0);
+    for i in 0..12 {
+        cov += (a[i] - ma) * (b[i] - mb);
+        va += (a[i] - ma) * (a[i] - ma);
+        vb += (b[i] - mb) * (b[i] - mb);
+    }
+    cov / (va * vb).sqrt().max(f32::EPSILON)
+}
+
+/// Estimates the key by matching the chromagram against key profiles, e.g "A minor".
+/// `samples` should be mono at SAMPLE_RATE.
+pub fn estimate_key(samples: &[f32]) -> Option<String> {
+    let mut chroma = [0.0f32; 12];
+    let bin_freq = SAMPLE_RATE as f32 / CHROMA_FRAME_SIZE as f32;
+    for frame in spectrogram(samples, CHROMA_FRAME_SIZE, CHROMA_FRAME_SIZE / 2) {
+        for (k, &mag) in frame.iter().enumerate().skip(1) {
+            let freq = k as f32 * bin_freq;
+            if !(CHROMA_MIN_FREQ..=CHROMA_MAX_FREQ).contains(&freq) {
+                continue;
+            }
+            let pitch = 12.0 * (freq / 440.0).log2() + 69.0;
+            chroma[(pitch.round() as usize) % 12] += mag * mag;
+        }
+    }
+    if chroma.iter().all(|&x| x <= f32::EPSILON) {
+        return None;
+    }
+
+    let mut best = (f32::MIN, String::new());
+    for tonic in 0..12 {
+        let rotated: [f32; 12] = std::array::from_fn(|i| chroma[(i + tonic) % 12]);
+        for (profile, mode) in [(&MAJOR_PROFILE, "major"), (&MINOR_PROFILE, "minor")] {
+            let r = correlation(&rotated, profile);
+            if r > best.0 {
+                best = (r, format!("{} {}", PITCH_CLASSES[tonic], mode));
+            }
+        }
+    }
+    Some(best.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(midi: f32) -> f32 {
+        440.0 * 2f32.powf((midi - 69.0) / 12.0)
+    }
+
+    /// Sums sines at the given midi pitches, with their first harmonics
+    fn chord(pitches: &[f32], secs: f32) -> Vec<f32> {
+        (0..(secs * SAMPLE_RATE as f32) as usize)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                pitches
+                    .iter()
+                    .map(|&p| {
+                        let f = note(p);
+                        (2.0 * std::f32::consts::PI * f * t).sin()
+                            + 0.3 * (4.0 * std::f32::consts::PI * f * t).sin()
+                    })
+                    .sum::<f32>()
+                    * 0.1
+            })
+            .collect()
+    }
+
+    /// Short decaying noise bursts at the given tempo
+    fn clicks(bpm: f32, secs: f32) -> Vec<f32> {
+        let period = 60.0 / bpm * SAMPLE_RATE as f32;
+        let mut state = 1u32;
+        (0..(secs * SAMPLE_RATE as f32) as usize)
+            .map(|i| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                let noise = (state >> 16) as f32 / 32768.0 - 1.0;
+                let since_click = i as f32 % period;
+                noise * (-since_click / 200.0).exp() * 0.5
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bpm() {
+        for bpm in [75.0, 100.0, 128.0, 174.0] {
+            let estimated = estimate_bpm(&clicks(bpm, 30.0)).unwrap();
+            assert!((estimated - bpm).abs() < 1.0, "{} != {}", estimated, bpm);
+        }
+        assert!(estimate_bpm(&vec![0.0; SAMPLE_RATE as usize * 30]).is_none());
+        assert!(estimate_bpm(&clicks(120.0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn test_key() {
+        // C E G
+        assert_eq!(
+            estimate_key(&chord(&[60.0, 64.0, 67.0], 5.0)).as_deref(),
+            Some("C major")
+        );
+        // A C E
+        assert_eq!(
+            estimate_key(&chord(&[57.0, 60.0, 64.0], 5.0)).as_deref(),
+            Some("A minor")
+        );
+        // F# A# C#
+        assert_eq!(
+            estimate_key(&chord(&[66.0, 70.0, 73.0], 5.0)).as_deref(),
+            Some("F# major")
+        );
+        assert!(estimate_key(&vec![0.0; 10000]).is_none());
+    }
+}
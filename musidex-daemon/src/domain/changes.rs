@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+use crate::domain::entity::{MusicID, TagKey};
+
+/// Changes kept in the log, clients further behind get a full snapshot
+const KEPT_CHANGES: i64 = 100_000;
+
+/// What changed after a revision
+#[derive(Default)]
+pub struct Changes {
+    /// the last revision of the log
+    pub revision: i64,
+    /// the changed tags of each music, with whether they existed at the revision
+    pub tags: HashMap<MusicID, HashMap<TagKey, bool>>,
+    /// musics, users or the config changed
+    pub other: bool,
+}
+
+/// The last revision of the metadata, 0 if nothing changed since the log exists
+pub fn revision(c: &Connection) -> Result<i64> {
+    c.prepare_cached("SELECT coalesce(max(revision), 0) FROM changes")?
+        .query_row([], |r| r.get(0))
+        .context("failed getting revision")
+}
+
+/// Whether the log still contains all the changes after the revision
+pub fn can_resume(c: &Connection, revision: i64) -> Result<bool> {
+    let (oldest, last): (Option<i64>, i64) = c
+        .prepare_cached("SELECT min(revision), coalesce(max(revision), 0) FROM changes")?
+        .query_row([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+    Ok(revision <= last && oldest.is_none_or(|oldest| revision >= oldest - 1))
+}
+
+pub fn since(c: &Connection, revision: i64) -> Result<Changes> {
+    let mut stmt = c.prepare_cached(
+        "SELECT revision, music_id, key, existed FROM changes
+         WHERE revision > ?1
+         ORDER BY revision",
+    )?;
+    let mut rows = stmt.query([revision])?;
+    let mut changes = Changes {
+        revision,
+        ..Default::default()
+    };
+    while let Some(row) = rows.next()? {
+        changes.revision = row.get("revision")?;
+        let music_id: Option<i32> = row.get("music_id")?;
+        let key: Option<String> = row.get("key")?;
+        let (music_id, key) = match (music_id, key) {
+            (Some(id), Some(key)) => (MusicID(id), TagKey::from(key.as_str())),
+            _ => {
+                changes.other = true;
+                continue;
+            }
+        };
+        // the first change tells what was there at the revision
+        changes
+            .tags
+            .entry(music_id)
+            .or_default()
+            .entry(key)
+            .or_insert(row.get("existed")?);
+    }
+    Ok(changes)
+}
+
+pub fn prune(c: &Connection) -> Result<()> {
+    c.prepare_cached(
+        "DELETE FROM changes WHERE revision <= (SELECT max(revision) FROM changes) - ?1",
+    )?
+    .execute([KEPT_CHANGES])?;
+    Ok(())
+}
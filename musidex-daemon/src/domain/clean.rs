@@ -4,6 +4,7 @@ use anyhow::Result;
 use rusqlite::TransactionBehavior;
 
 use crate::domain::entity::{Music, MusicID};
+use crate::domain::{hls, offline, thumbnail};
 use crate::infrastructure::db::Db;
 use std::collections::HashSet;
 
@@ -47,12 +48,23 @@ pub async fn clean(db: &Db) -> Result<()> {
         }
         let fname = file.file_name();
         let name = fname.to_string_lossy();
-        if !(name.ends_with(".mp3") || name.ends_with(".jpg")) {
+        let is_music_or_thumbnail = [
+            ".mp3", ".opus", ".ogg", ".m4a", ".webm", ".flac", ".wav", ".jpg", ".webp",
+        ]
+        .iter()
+        .any(|ext| name.ends_with(ext));
+        let is_waveform = name.starts_with("waveform.") && name.ends_with(".json");
+        if !is_music_or_thumbnail && !is_waveform {
             continue;
         }
         if texts.contains(&*name) {
             continue;
         }
+        if let Some(original) = thumbnail::original_of_sized(&name) {
+            if texts.contains(original) {
+                continue;
+            }
+        }
         log::info!(
             "cleaning {:?}: {:?}",
             name,
@@ -60,6 +72,9 @@ pub async fn clean(db: &Db) -> Result<()> {
         );
     }
 
+    hls::clean(&tx)?;
+    offline::clean(&tx)?;
+
     tx.commit()?;
 
     Ok(())
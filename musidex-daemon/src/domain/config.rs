@@ -6,7 +6,19 @@ use crate::Db;
 
 #[rustfmt::skip]
 const DEFAULT_CONFIG: &[(&str, &str)] = &[
-    ("config_test", "1")
+    ("config_test", "1"),
+    // only mp3 sources can be split, so it needs ytdlp_audio_format to be mp3
+    ("youtube_split_chapters", "false"),
+    ("ytdlp_rate_limit", ""),
+    ("ytdlp_format", "bestaudio"),
+    ("ytdlp_audio_format", "mp3"),
+    ("ytdlp_subtitles_langs", ""),
+    ("thumbnail_sizes", "64,256,512"),
+    ("musicbrainz_enabled", "false"),
+    ("musicbrainz_url", "https://musicbrainz.org"),
+    ("musicbrainz_rate_limit_ms", "1000"),
+    ("musicbrainz_min_score", "90"),
+    ("hls_bitrates", ""),
 ];
 
 pub async fn init(db: &Db) -> Result<()> {
@@ -46,10 +58,17 @@ pub fn get_all(c: &Connection) -> Result<Vec<(String, String)>> {
     collect_rows(v)
 }
 
-#[allow(dead_code)]
 pub fn get(c: &Connection, key: &str) -> Result<Option<String>> {
     let v = c
         .prepare_cached("SELECT value FROM config WHERE key= ?1")?
         .query_row([&key], |v| v.get("value"));
     row_missing_opt(v).with_context(|| format!("error getting config key: {}", key))
 }
+
+/// Values of the secrets table are never sent to clients, nor writable by them
+pub fn get_secret(c: &Connection, name: &str) -> Result<Option<String>> {
+    let v = c
+        .prepare_cached("SELECT value FROM secrets WHERE name=?1")?
+        .query_row([&name], |v| v.get("value"));
+    row_missing_opt(v).with_context(|| format!("error getting secret: {}", name))
+}
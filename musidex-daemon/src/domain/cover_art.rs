@@ -0,0 +1,71 @@
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use anyhow::{Context, Result};
+use image::ImageFormat;
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SIDECAR_NAMES: [&str; 4] = ["cover.jpg", "folder.jpg", "cover.png", "folder.png"];
+
+/// Stores the image as a jpeg in the storage, returns its file name.
+/// The name changes every time so clients don't keep the previous cover in cache.
+pub fn save_cover(id: MusicID, data: &[u8]) -> Result<String> {
+    let format = image::guess_format(data).context("unknown image format")?;
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let fname = format!("cover.{}.{}.jpg", id.0, millis);
+    let path = format!("storage/{}", fname);
+
+    match format {
+        ImageFormat::Jpeg => {
+            // make sure it is not corrupted before keeping it as is
+            image::load_from_memory_with_format(data, format).context("invalid jpeg")?;
+            std::fs::write(&path, data)?;
+        }
+        ImageFormat::Png => {
+            let img = image::load_from_memory_with_format(data, format).context("invalid png")?;
+            img.to_rgb8()
+                .save_with_format(&path, ImageFormat::Jpeg)
+                .context("failed saving image to jpg")?;
+        }
+        ImageFormat::WebP => {
+            let img = webp::Decoder::new(data)
+                .decode()
+                .context("failed decoding webp image")?
+                .to_image();
+            img.to_rgb8()
+                .save_with_format(&path, ImageFormat::Jpeg)
+                .context("failed saving image to jpg")?;
+        }
+        _ => bail!("unsupported image format {:?}", format),
+    }
+    Ok(fname)
+}
+
+/// Finds a cover.jpg or folder.jpg next to the audio file. Files directly in the storage are
+/// ignored, as its root is shared by all downloads.
+pub fn find_sidecar(path: &Path) -> Option<PathBuf> {
+    let dir = path.parent()?;
+    if dir.canonicalize().ok()? == Path::new("storage").canonicalize().ok()? {
+        return None;
+    }
+    let files: Vec<_> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|x| x.ok())
+        .filter(|x| x.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .collect();
+    SIDECAR_NAMES.iter().find_map(|name| {
+        files
+            .iter()
+            .find(|f| f.file_name().to_string_lossy().eq_ignore_ascii_case(name))
+            .map(|f| f.path())
+    })
+}
+
+/// Replaces the thumbnail of the music, the resized ones get regenerated by the thumbnail worker
+pub fn set_thumbnail(c: &Connection, id: MusicID, fname: String) -> Result<()> {
+    Tag::insert(c, Tag::new_text(id, TagKey::Thumbnail, fname))?;
+    Tag::remove(c, id, TagKey::CompressedThumbnail)?;
+    Tag::remove(c, id, TagKey::Blurhash)?;
+    Tag::remove(c, id, TagKey::DominantColor)?;
+    Ok(())
+}
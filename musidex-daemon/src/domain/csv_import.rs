@@ -0,0 +1,411 @@
+use crate::domain::entity::{MusicID, UserID};
+use crate::infrastructure::youtube_dl::SingleVideo;
+use crate::utils::now;
+use anyhow::{Context, Result};
+use nanoserde::SerJson;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::collections::HashSet;
+
+/// Below this, the best search result is not downloaded
+pub const MIN_CONFIDENCE: f64 = 0.4;
+/// Below this, the downloaded search result is reported for review
+pub const REVIEW_CONFIDENCE: f64 = 0.75;
+/// Number of search results considered for each track
+const SEARCH_RESULTS: usize = 5;
+
+/// Words in video titles meaning it is not the original recording, unless the track has them too
+const VERSION_WORDS: &[&str] = &[
+    "live",
+    "cover",
+    "remix",
+    "karaoke",
+    "instrumental",
+    "acoustic",
+    "slowed",
+    "reverb",
+    "sped",
+    "nightcore",
+    "8d",
+];
+
+/// A row of a playlist export, as given by Exportify
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvTrack {
+    pub track: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// in seconds
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowStatus {
+    Pending,
+    /// downloaded, the search result being a good match
+    Matched,
+    /// downloaded but the search result might be another song
+    LowConfidence,
+    NotFound,
+    Error,
+}
+
+impl RowStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RowStatus::Pending => "pending",
+            RowStatus::Matched => "matched",
+            RowStatus::LowConfidence => "low_confidence",
+            RowStatus::NotFound => "not_found",
+            RowStatus::Error => "error",
+        }
+    }
+}
+
+/// Duration given in milliseconds, seconds or as m:ss
+fn parse_duration(v: &str, in_ms: bool) -> Option<f64> {
+    let v = v.trim();
+    if let Some((m, s)) = v.split_once(':') {
+        return Some(m.parse::<f64>().ok()? * 60.0 + s.parse::<f64>().ok()?);
+    }
+    let x: f64 = v.parse().ok()?;
+    Some(if in_ms { x / 1000.0 } else { x })
+}
+
+/// Reads tracks from a csv with a header, the columns being found by their name
+pub fn parse(data: &[u8]) -> Result<Vec<CsvTrack>> {
+    let mut r = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(data.strip_prefix(b"\xef\xbb\xbf").unwrap_or(data));
+    let headers: Vec<String> = r
+        .headers()
+        .context("failed reading csv header")?
+        .iter()
+        .map(|x| x.trim().to_lowercase())
+        .collect();
+    let column = |names: &[&str]| headers.iter().position(|h| names.contains(&h.as_str()));
+
+    let track_col =
+        column(&["track name", "track", "title", "name"]).context("no track name column")?;
+    let artist_col = column(&["artist name(s)", "artist name", "artists", "artist"]);
+    let album_col = column(&["album name", "album"]);
+    let ms_col = column(&["duration (ms)", "track duration (ms)"]);
+    let secs_col = column(&["duration", "length"]);
+
+    let mut tracks = vec![];
+    for record in r.records() {
+        let record = record.context("failed reading csv row")?;
+        let get = |col: Option<usize>| {
+            col.and_then(|i| record.get(i))
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+        };
+        let track = unwrap_cont!(get(Some(track_col)));
+        let duration = match ms_col {
+            Some(_) => get(ms_col).and_then(|x| parse_duration(x, true)),
+            None => get(secs_col).and_then(|x| parse_duration(x, false)),
+        };
+        tracks.push(CsvTrack {
+            track: s!(track),
+            // exportify separates artists with commas
+            artist: get(artist_col).map(|x| {
+                x.split([',', ';'])
+                    .map(str::trim)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }),
+            album: get(album_col).map(ToString::to_string),
+            duration,
+        });
+    }
+    Ok(tracks)
+}
+
+/// The yt-dlp search for a track
+pub fn search_query(t: &CsvTrack) -> String {
+    match t.artist {
+        Some(ref artist) => format!("ytsearch{}:{} - {}", SEARCH_RESULTS, artist, t.track),
+        None => format!("ytsearch{}:{}", SEARCH_RESULTS, t.track),
+    }
+}
+
+fn words(v: &str) -> Vec<String> {
+    v.split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Between 0 and 1, how likely a search result is the track. The words of the track have
+/// to be in the title of the video, those of the artist in its title or channel, and the
+/// durations should be close.
+pub fn confidence(t: &CsvTrack, v: &SingleVideo) -> f64 {
+    let title_words = words(&v.title);
+    let mut found: HashSet<&str> = title_words.iter().map(String::as_str).collect();
+    let recall = |expected: &[String], found: &HashSet<&str>| {
+        if expected.is_empty() {
+            return 1.0;
+        }
+        expected
+            .iter()
+            .filter(|w| found.contains(w.as_str()))
+            .count() as f64
+            / expected.len() as f64
+    };
+    let track_words = words(&t.track);
+    let track_score = recall(&track_words, &found);
+    let channel_words = v.channel.as_deref().map(words).unwrap_or_default();
+    found.extend(channel_words.iter().map(String::as_str));
+    let artist_words = t.artist.as_deref().map(words).unwrap_or_default();
+    let artist_score = recall(&artist_words, &found);
+
+    let other_versions = VERSION_WORDS
+        .iter()
+        .filter(|&&w| title_words.iter().any(|x| x == w) && !track_words.iter().any(|x| x == w))
+        .count();
+    let duration_score = match (t.duration, v.duration) {
+        (Some(a), Some(b)) => (1.0 - ((a - b).abs() - 3.0).max(0.0) / 30.0).max(0.0),
+        _ => 0.5,
+    };
+
+    let score = track_score * (0.5 + 0.2 * artist_score + 0.3 * duration_score);
+    (score - 0.35 * other_versions as f64).clamp(0.0, 1.0)
+}
+
+/// The most likely search result with its confidence
+pub fn best_candidate(
+    t: &CsvTrack,
+    results: impl IntoIterator<Item = Box<SingleVideo>>,
+) -> Option<(Box<SingleVideo>, f64)> {
+    results
+        .into_iter()
+        .map(|v| {
+            let conf = confidence(t, &v);
+            (v, conf)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+/// Saves the tracks to be searched and downloaded in the background, returns the import id
+pub fn create(c: &mut Connection, uid: UserID, tracks: &[CsvTrack]) -> Result<i64> {
+    let tx = c.transaction()?;
+    tx.execute(
+        "INSERT INTO csv_imports (user_id, created) VALUES (?1, ?2)",
+        params![uid.0, now()],
+    )
+    .context("failed creating csv import")?;
+    let id = tx.last_insert_rowid();
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO csv_import_rows (import_id, row, track, artist, album, duration)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        for (i, t) in tracks.iter().enumerate() {
+            stmt.execute(params![
+                id, i as i64, t.track, t.artist, t.album, t.duration
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(id)
+}
+
+/// A row waiting to be searched
+pub struct PendingRow {
+    pub import_id: i64,
+    pub row: i64,
+    pub uid: UserID,
+    pub track: CsvTrack,
+}
+
+/// The oldest row waiting to be searched
+pub fn next_pending(c: &Connection) -> Result<Option<PendingRow>> {
+    c.prepare_cached(
+        "SELECT r.*, i.user_id FROM csv_import_rows r
+         JOIN csv_imports i ON i.id = r.import_id
+         WHERE r.status = 'pending'
+         ORDER BY r.import_id, r.row LIMIT 1",
+    )?
+    .query_row([], |x| {
+        Ok(PendingRow {
+            import_id: x.get("import_id")?,
+            row: x.get("row")?,
+            uid: UserID(x.get("user_id")?),
+            track: CsvTrack {
+                track: x.get("track")?,
+                artist: x.get("artist")?,
+                album: x.get("album")?,
+                duration: x.get("duration")?,
+            },
+        })
+    })
+    .optional()
+    .context("failed finding pending csv row")
+}
+
+/// What the search gave for a row
+pub struct RowResult {
+    pub status: RowStatus,
+    /// id and title of the best search result
+    pub video: Option<(String, String)>,
+    pub confidence: Option<f64>,
+    pub music_id: Option<MusicID>,
+}
+
+pub fn set_result(c: &Connection, pending: &PendingRow, res: RowResult) -> Result<()> {
+    c.prepare_cached(
+        "UPDATE csv_import_rows
+         SET status=?3, video_id=?4, video_title=?5, confidence=?6, music_id=?7
+         WHERE import_id=?1 AND row=?2",
+    )?
+    .execute(params![
+        pending.import_id,
+        pending.row,
+        res.status.as_str(),
+        res.video.as_ref().map(|v| &v.0),
+        res.video.as_ref().map(|v| &v.1),
+        res.confidence,
+        res.music_id.map(|x| x.0),
+    ])
+    .context("failed saving csv row result")?;
+    Ok(())
+}
+
+#[derive(SerJson)]
+pub struct ReportRow {
+    pub row: i64,
+    pub track: String,
+    pub artist: Option<String>,
+    pub status: String,
+    pub video_id: Option<String>,
+    pub video_title: Option<String>,
+    pub confidence: Option<f64>,
+    pub music_id: Option<MusicID>,
+}
+
+impl ReportRow {
+    fn from(x: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            row: x.get("row")?,
+            track: x.get("track")?,
+            artist: x.get("artist")?,
+            status: x.get("status")?,
+            video_id: x.get("video_id")?,
+            video_title: x.get("video_title")?,
+            confidence: x.get("confidence")?,
+            music_id: x.get::<_, Option<i32>>("music_id")?.map(MusicID),
+        })
+    }
+}
+
+#[derive(SerJson)]
+pub struct Report {
+    pub id: i64,
+    pub total: usize,
+    pub pending: usize,
+    pub matched: usize,
+    pub low_confidence: usize,
+    pub not_found: usize,
+    pub errors: usize,
+    pub rows: Vec<ReportRow>,
+}
+
+pub fn report(c: &Connection, id: i64) -> Result<Option<Report>> {
+    let exists = c
+        .prepare_cached("SELECT id FROM csv_imports WHERE id=?1")?
+        .query_row([id], |_| Ok(()))
+        .optional()?;
+    unwrap_ret!(exists, Ok(None));
+
+    let rows = c
+        .prepare_cached("SELECT * FROM csv_import_rows WHERE import_id=?1 ORDER BY row")?
+        .query_map([id], ReportRow::from)?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("failed reading csv import rows")?;
+    let count = |status: RowStatus| rows.iter().filter(|x| x.status == status.as_str()).count();
+    Ok(Some(Report {
+        id,
+        total: rows.len(),
+        pending: count(RowStatus::Pending),
+        matched: count(RowStatus::Matched),
+        low_confidence: count(RowStatus::LowConfidence),
+        not_found: count(RowStatus::NotFound),
+        errors: count(RowStatus::Error),
+        rows,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(title: &str, channel: &str, duration: f64) -> SingleVideo {
+        nanoserde::DeJson::deserialize_json(&format!(
+            r#"{{"id": "x", "title": "{}", "channel": "{}", "duration": {}}}"#,
+            title, channel, duration
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let exportify = "\u{feff}Track URI,Track Name,Artist Name(s),Album Name,Disc Number,Track Duration (ms)\n\
+            spotify:track:1,Virtual Insanity,Jamiroquai,Travelling Without Moving,1,230693\n\
+            spotify:track:2,\"Get Lucky (feat. Pharrell Williams, Nile Rodgers)\",\"Daft Punk,Pharrell Williams\",Random Access Memories,1,369626\n\
+            spotify:track:3,,Nobody,,1,1000\n";
+        let tracks = parse(exportify.as_bytes()).unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(
+            tracks[0],
+            CsvTrack {
+                track: s!("Virtual Insanity"),
+                artist: Some(s!("Jamiroquai")),
+                album: Some(s!("Travelling Without Moving")),
+                duration: Some(230.693),
+            }
+        );
+        assert_eq!(
+            tracks[1].track,
+            "Get Lucky (feat. Pharrell Williams, Nile Rodgers)"
+        );
+        assert_eq!(
+            tracks[1].artist.as_deref(),
+            Some("Daft Punk, Pharrell Williams")
+        );
+
+        let simple = parse(b"track,artist,album,duration\nSong,Someone,,3:05\n").unwrap();
+        assert_eq!(simple[0].duration, Some(185.0));
+        assert_eq!(simple[0].album, None);
+        assert!(parse(b"a,b\n1,2\n").is_err());
+    }
+
+    #[test]
+    fn test_confidence() {
+        let t = CsvTrack {
+            track: s!("Virtual Insanity"),
+            artist: Some(s!("Jamiroquai")),
+            album: None,
+            duration: Some(230.0),
+        };
+        assert_eq!(search_query(&t), "ytsearch5:Jamiroquai - Virtual Insanity");
+        let official = video(
+            "Jamiroquai - Virtual Insanity (Official Video)",
+            "JamiroquaiVEVO",
+            232.0,
+        );
+        let topic = video("Virtual Insanity", "Jamiroquai - Topic", 230.0);
+        let live = video("Jamiroquai - Virtual Insanity (Live)", "someone", 330.0);
+        let cover = video("Virtual Insanity cover", "someone", 231.0);
+        let other = video("Cosmic Girl", "Jamiroquai - Topic", 230.0);
+
+        assert!(confidence(&t, &official) >= REVIEW_CONFIDENCE);
+        assert_eq!(confidence(&t, &topic), 1.0);
+        assert!(confidence(&t, &live) < MIN_CONFIDENCE);
+        assert!(confidence(&t, &cover) < REVIEW_CONFIDENCE);
+        assert_eq!(confidence(&t, &other), 0.0);
+
+        let (best, _) =
+            best_candidate(&t, vec![Box::new(live), Box::new(topic), Box::new(cover)]).unwrap();
+        assert_eq!(best.channel.as_deref(), Some("Jamiroquai - Topic"));
+        assert!(best_candidate(&t, vec![]).is_none());
+    }
+}
@@ -0,0 +1,216 @@
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::stream::{content_type, MusicSource};
+use crate::domain::thumbnail;
+use crate::infrastructure::audio::Picture;
+use crate::infrastructure::file_server::{ByteSource, ByteStream, Validators};
+use crate::infrastructure::mp3;
+use crate::infrastructure::tag_writer::{self, ExportTags};
+use crate::utils::file_chunks;
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use hyper::body::Bytes;
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Size of the cover embedded into downloaded files
+const COVER_SIZE: u32 = 512;
+
+/// A music file with its tags rewritten: the new tags are followed by the audio of the
+/// source, which is streamed untouched
+pub struct Download {
+    /// replaces the tags found at the start of the source
+    pub head: Bytes,
+    file: File,
+    /// byte window of the source holding the audio
+    pub window: (u64, u64),
+    pub validators: Validators,
+    pub content_type: &'static str,
+    pub filename: String,
+}
+
+impl Download {
+    pub fn size(&self) -> u64 {
+        self.head.len() as u64 + self.window.1 - self.window.0
+    }
+
+    /// Value of the Content-Disposition header, with an ascii fallback for old clients
+    pub fn content_disposition(&self) -> String {
+        content_disposition(&self.filename)
+    }
+}
+
+impl ByteSource for Download {
+    fn chunks(&self, start: u64, len: u64) -> Result<ByteStream> {
+        let end = start + len;
+        let head_len = self.head.len() as u64;
+        let mut pieces: Vec<ByteStream> = vec![];
+        if start < head_len {
+            let head = self.head.slice(start as usize..end.min(head_len) as usize);
+            pieces.push(futures::stream::once(async { Ok(head) }).boxed());
+        }
+        if end > head_len {
+            let from = start.max(head_len) - head_len;
+            // clones share their cursor, which is fine as streams are read one after the other
+            let f = self
+                .file
+                .try_clone()
+                .context("failed cloning file handle")?;
+            pieces.push(
+                file_chunks(
+                    tokio::fs::File::from_std(f),
+                    self.window.0 + from,
+                    end - head_len - from,
+                )
+                .boxed(),
+            );
+        }
+        Ok(futures::stream::iter(pieces).flatten().boxed())
+    }
+}
+
+/// Value of the Content-Disposition header, with an ascii fallback for old clients
+pub fn content_disposition(filename: &str) -> String {
+    let ascii: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    let mut encoded = String::new();
+    for b in filename.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii, encoded
+    )
+}
+
+/// Gathers the tags of the music that are written into downloaded files
+pub fn export_tags(c: &Connection, id: MusicID) -> Result<ExportTags> {
+    let mut tags = ExportTags::default();
+    for tag in Tag::by_id(c, id)? {
+        let integer = tag
+            .integer
+            .or_else(|| tag.text.as_ref().and_then(|x| x.parse().ok()));
+        match tag.key {
+            TagKey::Title => tags.title = tag.text,
+            TagKey::Artist => tags.artist = tag.text,
+            TagKey::Album => tags.album = tag.text,
+            TagKey::Genre => tags.genre = tag.text,
+            TagKey::Year => tags.year = integer,
+            TagKey::TrackNumber => tags.track_number = integer.and_then(|x| x.try_into().ok()),
+            TagKey::UserTag(name) => tags.comments.push((name, tag.text.unwrap_or_default())),
+            _ => {}
+        }
+    }
+
+    if let Some((fname, media_type)) = thumbnail::pick(c, id, Some(COVER_SIZE), false)? {
+        match std::fs::read(format!("storage/{}", fname)) {
+            Ok(data) => {
+                tags.cover = Some(Picture {
+                    media_type: media_type.to_string(),
+                    data: data.into(),
+                })
+            }
+            Err(e) => log::warn!("could not read thumbnail {}: {}", fname, e),
+        }
+    }
+    Ok(tags)
+}
+
+/// Name of the downloaded file, e.g "Artist - Title.mp3"
+pub fn filename(id: MusicID, tags: &ExportTags, ext: &str) -> String {
+    let name = match (&tags.artist, &tags.title) {
+        (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+        (None, Some(title)) => title.clone(),
+        _ => format!("music {}", id.0),
+    };
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    format!("{}.{}", name.trim().trim_start_matches('.'), ext)
+}
+
+/// Opens the source of the music and writes the tags in place of its own.
+/// Containers without a tag writer (m4a, webm) are served as they are.
+/// tags_updated is when the tags of the music last changed, see Music::tags_updated.
+pub fn build(
+    id: MusicID,
+    source: &MusicSource,
+    tags: &ExportTags,
+    tags_updated: Option<SystemTime>,
+) -> Result<Download> {
+    let path = source.file_path();
+    let mut file =
+        File::open(&path).with_context(|| format!("could not open source {:?}", path))?;
+    let meta = file.metadata()?;
+    let mut window = (0, meta.len());
+    if let Some(start) = source.chapter_start {
+        if !source.path.ends_with("mp3") {
+            bail!("chapters can only be cut out of mp3 sources");
+        }
+        window = mp3::file_time_range_to_bytes(&path, start, source.chapter_end)
+            .context("failed finding chapter in source")?;
+    }
+
+    let ext = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default()
+        .to_string();
+    let tagged = match ext.as_str() {
+        "mp3" => Some(tag_writer::mp3_header(&mut file, window, tags)),
+        "flac" => Some(tag_writer::flac_header(&mut file, tags).map(|(h, s)| (h, (s, window.1)))),
+        "ogg" | "opus" => {
+            Some(tag_writer::ogg_header(&mut file, tags).map(|(h, s)| (h, (s, window.1))))
+        }
+        _ => None,
+    };
+    let mut head = vec![];
+    match tagged {
+        Some(Ok((h, audio))) if audio.0 <= audio.1 => {
+            head = h;
+            window = audio;
+        }
+        Some(Ok(_)) => log::warn!("could not write tags into {:?}: truncated file", path),
+        Some(Err(e)) => log::warn!("could not write tags into {:?}: {:?}", path, e),
+        None => {}
+    }
+
+    // the tags can change without the source being modified
+    let mtime = meta.modified()?;
+    let last_modified = tags_updated.map_or(mtime, |x| x.max(mtime));
+    let modified = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut h = Sha256::new();
+    h.update(&head);
+    h.update(format!(
+        "{}:{}:{}:{}",
+        source.path,
+        window.0,
+        window.1,
+        modified.as_nanos()
+    ));
+    let etag: String = h.finalize()[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    Ok(Download {
+        head: head.into(),
+        file,
+        window,
+        validators: Validators::with_etag(&etag, last_modified),
+        content_type: content_type(&source.path),
+        filename: filename(id, tags, &ext),
+    })
+}
@@ -59,6 +59,8 @@ pub struct MusidexMetadata {
     pub users: Vec<User>,
     pub settings: Vec<(String, String)>,
     pub patches: Option<Vec<Patch>>,
+    /// of the change log, for the client to resume from
+    pub revision: i64,
 }
 
 impl Eq for Vector {}
@@ -132,6 +134,9 @@ tag_key! {
     LocalWEBM => "local_webm",
     LocalM4A => "local_m4a",
     LocalOGG => "local_ogg",
+    LocalOPUS => "local_opus",
+    LocalFLAC => "local_flac",
+    LocalWAV => "local_wav",
     YoutubeDLURL => "youtubedl_url",
     YoutubeDLVideoID => "youtube_video_id",
     YoutubeDLWorkerTreated => "youtube_worker_treated",
@@ -139,22 +144,84 @@ tag_key! {
     YoutubeDLPlaylist => "youtube_playlist",
     Title => "title",
     Artist => "artist",
+    FeaturedArtist => "featured_artist",
+    Album => "album",
+    Year => "year",
+    Genre => "genre",
+    TrackNumber => "track_number",
+    Uploader => "uploader",
+    UploadDate => "upload_date",
+    Description => "description",
+    YoutubeDLTags => "youtube_tags",
     CompressedThumbnail => "compressed_thumbnail",
     Thumbnail => "thumbnail",
+    Blurhash => "blurhash",
+    DominantColor => "dominant_color",
+    AccentColor => "accent_color",
     Duration => "duration",
+    Bitrate => "bitrate",
+    SampleRate => "sample_rate",
+    Channels => "channels",
+    Codec => "codec",
     Embedding => "embedding",
+    ChapterStart => "chapter_start",
+    ChapterEnd => "chapter_end",
+    Fingerprint => "fingerprint",
+    Loudness => "loudness",
+    TruePeak => "true_peak",
+    ReplayGainTrackGain => "replaygain_track_gain",
+    Bpm => "bpm",
+    Key => "key",
+    Waveform => "waveform",
+    CoverArtWorkerTreated => "cover_art_worker_treated",
+    MusicBrainzWorkerTreated => "musicbrainz_worker_treated",
+    MusicBrainzRecordingID => "musicbrainz_recording_id",
+    MusicBrainzArtistID => "musicbrainz_artist_id",
+    MusicBrainzReleaseID => "musicbrainz_release_id",
     ;
     nested UserLibrary => "user_library",
     nested UserTag => "user_tag",
+    nested Suggestion => "suggestion",
 }
 
 impl TagKey {
+    /// Keys of the local audio files a music can be played from
+    pub const LOCAL_SOURCES: [TagKey; 7] = [
+        TagKey::LocalMP3,
+        TagKey::LocalOPUS,
+        TagKey::LocalOGG,
+        TagKey::LocalM4A,
+        TagKey::LocalWEBM,
+        TagKey::LocalFLAC,
+        TagKey::LocalWAV,
+    ];
+
+    pub fn is_local_source(&self) -> bool {
+        Self::LOCAL_SOURCES.contains(self)
+    }
+
+    /// The local source keys, to be used in a `key IN (...)` query
+    pub fn local_sources_sql() -> String {
+        Self::LOCAL_SOURCES
+            .iter()
+            .map(|k| format!("'{}'", k))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
     pub fn as_user_library(&self) -> Option<&str> {
         match *self {
             TagKey::UserLibrary(ref x) => Some(x),
             _ => None,
         }
     }
+
+    pub fn as_suggestion(&self) -> Option<&str> {
+        match *self {
+            TagKey::Suggestion(ref x) => Some(x),
+            _ => None,
+        }
+    }
 }
 
 impl Display for TagKey {
@@ -0,0 +1,255 @@
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use anyhow::Result;
+use nanoserde::SerJson;
+use rusqlite::Connection;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::collections::HashMap;
+
+/// Audio is downsampled to this rate before fingerprinting, as only 300-2000Hz is looked at
+pub const SAMPLE_RATE: u32 = 5512;
+/// Only the beginning of musics is fingerprinted, it is enough to tell them apart
+pub const MAX_DURATION: f64 = 90.0;
+
+const FRAME_SIZE: usize = 2048;
+const HOP_SIZE: usize = 256;
+const N_BANDS: usize = 33;
+const MIN_FREQ: f32 = 300.0;
+const MAX_FREQ: f32 = 2000.0;
+
+/// Fingerprints must overlap by at least this many frames (~9s) to be compared
+const MIN_OVERLAP: usize = 200;
+/// Two fingerprints with a bit error rate below this are considered to be the same song
+const MAX_BIT_ERROR_RATE: f32 = 0.35;
+/// Sub-fingerprints present in too many places (silence...) are not used to find candidates
+const MAX_INDEX_OCCURRENCES: usize = 64;
+/// Number of exactly matching sub-fingerprints at the same offset needed to compare two musics
+const MIN_VOTES: u32 = 3;
+
+/// Computes a 32 bits sub-fingerprint per frame, each bit telling whether the energy difference
+/// between two neighbouring frequency bands increased compared to the previous frame.
+/// `samples` should be mono at SAMPLE_RATE.
+pub fn compute(samples: &[f32]) -> Vec<u32> {
+    if samples.len() < FRAME_SIZE {
+        return vec![];
+    }
+
+    let bin_of = |freq: f32| (freq * FRAME_SIZE as f32 / SAMPLE_RATE as f32) as usize;
+    let bands: Vec<(usize, usize)> = (0..N_BANDS)
+        .map(|i| {
+            let freq = |i: usize| MIN_FREQ * (MAX_FREQ / MIN_FREQ).powf(i as f32 / N_BANDS as f32);
+            let lo = bin_of(freq(i));
+            (lo, bin_of(freq(i + 1)).max(lo + 1))
+        })
+        .collect();
+
+    let window: Vec<f32> = (0..FRAME_SIZE)
+        .map(|i| {
+            0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / (FRAME_SIZE - 1) as f32).cos()
+        })
+        .collect();
+
+    let fft = FftPlanner::new().plan_fft_forward(FRAME_SIZE);
+    let mut buf = vec![Complex::new(0.0, 0.0); FRAME_SIZE];
+    let mut prev_diffs: Option<[f32; N_BANDS - 1]> = None;
+    let mut fp = Vec::with_capacity((samples.len() - FRAME_SIZE) / HOP_SIZE + 1);
+
+    for frame in 0..=(samples.len() - FRAME_SIZE) / HOP_SIZE {
+        let frame = &samples[frame * HOP_SIZE..frame * HOP_SIZE + FRAME_SIZE];
+        for ((b, &x), &w) in buf.iter_mut().zip(frame).zip(&window) {
+            *b = Complex::new(x * w, 0.0);
+        }
+        fft.process(&mut buf);
+
+        let energies: Vec<f32> = bands
+            .iter()
+            .map(|&(lo, hi)| buf[lo..hi].iter().map(|x| x.norm_sqr()).sum())
+            .collect();
+        let mut diffs = [0.0; N_BANDS - 1];
+        for (m, d) in diffs.iter_mut().enumerate() {
+            *d = energies[m] - energies[m + 1];
+        }
+
+        if let Some(prev) = prev_diffs {
+            let mut v = 0u32;
+            for m in 0..N_BANDS - 1 {
+                if diffs[m] - prev[m] > 0.0 {
+                    v |= 1 << m;
+                }
+            }
+            fp.push(v);
+        }
+        prev_diffs = Some(diffs);
+    }
+    fp
+}
+
+pub fn encode(fp: &[u32]) -> String {
+    fp.iter().map(|x| format!("{:08x}", x)).collect()
+}
+
+pub fn decode(s: &str) -> Option<Vec<u32>> {
+    s.as_bytes()
+        .chunks(8)
+        .map(|chunk| {
+            if chunk.len() != 8 {
+                return None;
+            }
+            u32::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok()
+        })
+        .collect()
+}
+
+/// Bit error rate between a and b, b being shifted by offset frames
+pub fn bit_error_rate(a: &[u32], b: &[u32], offset: isize) -> Option<f32> {
+    let (a, b) = if offset >= 0 {
+        (a.get(offset as usize..)?, b)
+    } else {
+        (a, b.get((-offset) as usize..)?)
+    };
+    let overlap = a.len().min(b.len());
+    if overlap < MIN_OVERLAP {
+        return None;
+    }
+    let errors: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+    Some(errors as f32 / (overlap * 32) as f32)
+}
+
+#[derive(Clone, Debug, PartialEq, SerJson)]
+pub struct DuplicateGroup {
+    pub musics: Vec<MusicID>,
+    /// between 0 and 1, the lowest similarity between two matched musics of the group
+    pub similarity: f32,
+}
+
+/// Groups together musics that have matching fingerprints
+pub fn find_duplicates(fps: &[(MusicID, Vec<u32>)]) -> Vec<DuplicateGroup> {
+    let mut index: HashMap<u32, Vec<(usize, usize)>> = HashMap::new();
+    for (i, (_, fp)) in fps.iter().enumerate() {
+        for (j, &v) in fp.iter().enumerate() {
+            index.entry(v).or_default().push((i, j));
+        }
+    }
+
+    let mut parent: Vec<usize> = (0..fps.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    let mut similarities: HashMap<usize, f32> = HashMap::new();
+
+    for (i, (_, fp)) in fps.iter().enumerate() {
+        let mut votes: HashMap<(usize, isize), u32> = HashMap::new();
+        for (j, v) in fp.iter().enumerate() {
+            let occurrences = unwrap_cont!(index.get(v));
+            if occurrences.len() > MAX_INDEX_OCCURRENCES {
+                continue;
+            }
+            for &(k, l) in occurrences {
+                if k > i {
+                    *votes.entry((k, j as isize - l as isize)).or_default() += 1;
+                }
+            }
+        }
+
+        let mut best: HashMap<usize, f32> = HashMap::new();
+        for ((k, offset), n) in votes {
+            if n < MIN_VOTES {
+                continue;
+            }
+            let ber = unwrap_cont!(bit_error_rate(fp, &fps[k].1, offset));
+            let b = best.entry(k).or_insert(1.0);
+            *b = b.min(ber);
+        }
+
+        for (k, ber) in best {
+            if ber > MAX_BIT_ERROR_RATE {
+                continue;
+            }
+            let similarity = 1.0 - ber;
+            let (ri, rk) = (root(&mut parent, i), root(&mut parent, k));
+            let s = similarity
+                .min(*similarities.get(&ri).unwrap_or(&1.0))
+                .min(*similarities.get(&rk).unwrap_or(&1.0));
+            parent[rk] = ri;
+            similarities.insert(ri, s);
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<MusicID>> = HashMap::new();
+    for (i, &(id, _)) in fps.iter().enumerate() {
+        let r = root(&mut parent, i);
+        groups.entry(r).or_default().push(id);
+    }
+    let mut groups: Vec<DuplicateGroup> = groups
+        .into_iter()
+        .filter(|(_, musics)| musics.len() > 1)
+        .map(|(r, mut musics)| {
+            musics.sort_by_key(|m| m.0);
+            DuplicateGroup {
+                musics,
+                similarity: similarities[&r],
+            }
+        })
+        .collect();
+    groups.sort_by_key(|g| g.musics[0].0);
+    groups
+}
+
+/// Loads all fingerprints of the library
+pub fn all_fingerprints(c: &Connection) -> Result<Vec<(MusicID, Vec<u32>)>> {
+    Ok(Tag::by_key(c, TagKey::Fingerprint)?
+        .into_iter()
+        .filter_map(|t| Some((t.music_id, decode(t.text.as_deref()?)?)))
+        .filter(|(_, fp)| !fp.is_empty())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::melody;
+
+    #[test]
+    fn test_encode_decode() {
+        let fp = vec![0, 1, 0xdeadbeef, u32::MAX];
+        assert_eq!(encode(&fp), "0000000000000001deadbeefffffffff");
+        assert_eq!(decode(&encode(&fp)), Some(fp));
+        assert_eq!(decode("123"), None);
+        assert_eq!(decode("1234567z"), None);
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let a = melody(1, SAMPLE_RATE, 40.0);
+
+        // same song, starting 3 seconds later, quieter and with some noise
+        let mut noise_state = 7u32;
+        let b: Vec<f32> = a[3 * SAMPLE_RATE as usize..]
+            .iter()
+            .map(|x| {
+                noise_state = noise_state.wrapping_mul(1103515245).wrapping_add(12345);
+                x * 0.5 + ((noise_state >> 16) as f32 / 65536.0 - 0.5) * 0.02
+            })
+            .collect();
+
+        let c = melody(2, SAMPLE_RATE, 40.0);
+
+        let fa = compute(&a);
+        let fb = compute(&b);
+        let fc = compute(&c);
+        assert!(!fa.is_empty());
+
+        let offset = (3 * SAMPLE_RATE as usize / HOP_SIZE) as isize;
+        assert!(bit_error_rate(&fa, &fb, offset).unwrap() < 0.3);
+        assert!(bit_error_rate(&fa, &fc, 0).unwrap() > 0.4);
+
+        let groups = find_duplicates(&[(MusicID(1), fa), (MusicID(2), fc), (MusicID(3), fb)]);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].musics, vec![MusicID(1), MusicID(3)]);
+        assert!(groups[0].similarity > 0.7);
+    }
+}
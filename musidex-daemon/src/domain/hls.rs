@@ -0,0 +1,441 @@
+use crate::domain::config;
+use crate::domain::entity::MusicID;
+use crate::domain::stream::MusicSource;
+use crate::infrastructure::audio;
+use crate::infrastructure::mp3::{self, Segment};
+use anyhow::{Context, Result};
+use id3::frame::{Content, Private};
+use id3::{Frame, TagLike, Version};
+use rusqlite::Connection;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Mutex;
+
+/// Segments are cut at the first frame boundary after this many seconds
+const SEGMENT_DURATION: f64 = 6.0;
+/// Bitrate in kbps sources that are not mp3 are transcoded to when hls_bitrates is empty
+const DEFAULT_BITRATE: u32 = 192;
+/// Owner of the ID3 PRIV frame giving the timestamp of a packed audio segment
+const TIMESTAMP_OWNER: &str = "com.apple.streaming.transportStreamTimestamp";
+/// RFC 6381 codec of MPEG-1/2 audio layer III
+const MP3_CODEC: &str = "mp4a.40.34";
+
+/// Locks of the cache directories being prepared, so a variant is never transcoded twice at once
+static PREPARE_LOCKS: std::sync::Mutex<BTreeMap<PathBuf, Weak<Mutex<()>>>> =
+    std::sync::Mutex::new(BTreeMap::new());
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Variant {
+    /// the mp3 source as is
+    Original,
+    /// transcoded to mp3 at this many kbps
+    Bitrate(u32),
+}
+
+impl Variant {
+    pub fn name(self) -> String {
+        match self {
+            Variant::Original => s!("original"),
+            Variant::Bitrate(kbps) => format!("{}k", kbps),
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Variant> {
+        if name == "original" {
+            return Some(Variant::Original);
+        }
+        let kbps = name.strip_suffix('k')?.parse().ok()?;
+        Some(Variant::Bitrate(kbps))
+    }
+}
+
+/// The bitrates in kbps tracks are transcoded to for adaptive streaming, from the hls_bitrates config
+pub fn bitrates(c: &Connection) -> Result<Vec<u32>> {
+    let mut bitrates: Vec<u32> = config::get(c, "hls_bitrates")?
+        .map(|x| {
+            x.split(',')
+                .filter_map(|s| s.trim().parse().ok())
+                .filter(|&s| s > 0)
+                .collect()
+        })
+        .unwrap_or_default();
+    bitrates.sort_unstable_by(|a, b| b.cmp(a));
+    bitrates.dedup();
+    Ok(bitrates)
+}
+
+/// A music that can be streamed as HLS, with the variants it is offered in
+pub struct HlsMusic {
+    id: MusicID,
+    source: MusicSource,
+    ffmpeg: String,
+    pub variants: Vec<Variant>,
+}
+
+impl HlsMusic {
+    pub fn find(c: &Connection, id: MusicID) -> Result<Option<HlsMusic>> {
+        let source = unwrap_ret!(MusicSource::find(c, id)?, Ok(None));
+        let ffmpeg = audio::ffmpeg_path();
+
+        // mp3 sources can be segmented without transcoding
+        let mut variants = vec![];
+        if source.path.ends_with(".mp3") {
+            variants.push(Variant::Original);
+        }
+        variants.extend(bitrates(c)?.into_iter().map(Variant::Bitrate));
+        if variants.is_empty() {
+            variants.push(Variant::Bitrate(DEFAULT_BITRATE));
+        }
+
+        Ok(Some(HlsMusic {
+            id,
+            source,
+            ffmpeg,
+            variants,
+        }))
+    }
+
+    /// The variant with this name if the music is offered in it
+    pub fn variant(&self, name: &str) -> Option<Variant> {
+        Variant::parse(name).filter(|v| self.variants.contains(v))
+    }
+
+    fn dir(&self, variant: Variant) -> PathBuf {
+        PathBuf::from(format!("./storage/hls/{}/{}", self.id.0, variant.name()))
+    }
+
+    /// The mp3 file the segments of a variant are cut from
+    fn audio_path(&self, variant: Variant) -> PathBuf {
+        match variant {
+            Variant::Original => self.source.file_path(),
+            Variant::Bitrate(_) => self.dir(variant).join("audio.mp3"),
+        }
+    }
+
+    /// Identifies the source the cache was made from, so it is redone when the file or chapter changes
+    async fn stamp(&self) -> Result<String> {
+        let meta = tokio::fs::metadata(self.source.file_path())
+            .await
+            .context("failed reading source metadata")?;
+        let modified = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(format!(
+            "{} {} {} {:?} {:?}",
+            self.source.path,
+            modified.as_nanos(),
+            meta.len(),
+            self.source.chapter_start,
+            self.source.chapter_end
+        ))
+    }
+
+    /// Makes sure the variant is transcoded and indexed, and returns its segments
+    pub async fn prepare(&self, variant: Variant) -> Result<Vec<Segment>> {
+        let dir = self.dir(variant);
+        let stamp = self.stamp().await?;
+        if let Some(segments) = read_index(&dir, &stamp).await {
+            return Ok(segments);
+        }
+        let lock = prepare_lock(&dir);
+        let _guard = lock.lock().await;
+        if let Some(segments) = read_index(&dir, &stamp).await {
+            return Ok(segments);
+        }
+
+        log::info!("preparing hls variant {} of {:?}", variant.name(), self.id);
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .context("could not create hls cache")?;
+
+        let audio = self.audio_path(variant);
+        let (start, end) = match variant {
+            Variant::Original => (self.source.chapter_start, self.source.chapter_end),
+            Variant::Bitrate(kbps) => {
+                let ffmpeg = self.ffmpeg.clone();
+                let src = self.source.file_path();
+                let (start, end) = (self.source.chapter_start, self.source.chapter_end);
+                let out = audio.clone();
+                tokio::task::spawn_blocking(move || {
+                    transcode(&ffmpeg, &src, start, end, kbps, &out)
+                })
+                .await??;
+                (None, None)
+            }
+        };
+
+        let segments = tokio::task::spawn_blocking(move || -> Result<Vec<Segment>> {
+            let mut r = BufReader::new(std::fs::File::open(&audio).context("could not open mp3")?);
+            let window = mp3::time_range_to_bytes(&mut r, start.unwrap_or(0.0), end)?;
+            mp3::segments(&mut r, window, SEGMENT_DURATION)
+        })
+        .await??;
+
+        // the index is written last, an interrupted preparation is started over
+        let mut index = stamp;
+        for seg in &segments {
+            index += &format!("\n{} {} {}", seg.start, seg.end, seg.duration);
+        }
+        write_atomic(&dir.join("index"), index.as_bytes()).await?;
+        Ok(segments)
+    }
+
+    /// The master playlist, listing every variant
+    pub async fn master_playlist(&self) -> Result<String> {
+        let mut out = s!("#EXTM3U\n#EXT-X-VERSION:3\n");
+        for &variant in &self.variants {
+            let bandwidth = match variant {
+                Variant::Original => peak_bandwidth(&self.prepare(variant).await?),
+                Variant::Bitrate(kbps) => kbps as u64 * 1000,
+            };
+            out += &format!(
+                "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\"\n{}/playlist.m3u8\n",
+                bandwidth,
+                MP3_CODEC,
+                variant.name()
+            );
+        }
+        Ok(out)
+    }
+
+    /// Path of the nth segment of the variant, cutting it from the audio if it is not cached yet
+    pub async fn segment(&self, variant: Variant, n: usize) -> Result<Option<PathBuf>> {
+        let segments = self.prepare(variant).await?;
+        let seg = unwrap_ret!(segments.get(n), Ok(None));
+        let p = self.dir(variant).join(format!("{}.mp3", n));
+        if tokio::fs::metadata(&p).await.is_ok() {
+            return Ok(Some(p));
+        }
+
+        let start_time: f64 = segments[..n].iter().map(|x| x.duration).sum();
+        let mut buf = timestamp_tag(start_time)?;
+        let mut f = tokio::fs::File::open(self.audio_path(variant))
+            .await
+            .context("could not open mp3")?;
+        f.seek(SeekFrom::Start(seg.start)).await?;
+        let mut frames = vec![0u8; (seg.end - seg.start) as usize];
+        f.read_exact(&mut frames)
+            .await
+            .context("segment out of the audio")?;
+        buf.extend(frames);
+        write_atomic(&p, &buf).await?;
+        Ok(Some(p))
+    }
+}
+
+/// The lock of a cache directory, shared by all the preparations of the variant in flight
+fn prepare_lock(dir: &Path) -> Arc<Mutex<()>> {
+    let mut locks = PREPARE_LOCKS.lock().unwrap();
+    if let Some(lock) = locks.get(dir).and_then(Weak::upgrade) {
+        return lock;
+    }
+    locks.retain(|_, x| x.strong_count() > 0);
+    let lock = Arc::new(Mutex::new(()));
+    locks.insert(dir.to_path_buf(), Arc::downgrade(&lock));
+    lock
+}
+
+/// The media playlist of a variant
+pub fn media_playlist(segments: &[Segment]) -> String {
+    let target = segments
+        .iter()
+        .map(|x| x.duration.ceil() as u64)
+        .max()
+        .unwrap_or(SEGMENT_DURATION as u64);
+    let mut out = format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n",
+        target
+    );
+    for (i, seg) in segments.iter().enumerate() {
+        out += &format!("#EXTINF:{:.3},\n{}.mp3\n", seg.duration, i);
+    }
+    out += "#EXT-X-ENDLIST\n";
+    out
+}
+
+/// Removes the cache of musics that do not exist anymore
+pub fn clean(c: &Connection) -> Result<()> {
+    let dir = unwrap_ret!(std::fs::read_dir("storage/hls").ok(), Ok(()));
+    let ids = c
+        .prepare("SELECT id FROM musics")?
+        .query_map([], |x| x.get::<&str, i32>("id"))?
+        .collect::<std::result::Result<HashSet<_>, _>>()?;
+    for entry in dir {
+        let entry = unwrap_cont!(entry.ok());
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.parse().is_ok_and(|id| ids.contains(&id)) {
+            continue;
+        }
+        let res = std::fs::remove_dir_all(entry.path());
+        log::info!("cleaning hls cache {:?}: {:?}", name, res);
+    }
+    Ok(())
+}
+
+/// Highest bitrate of a segment in bits per second, as the BANDWIDTH attribute expects
+fn peak_bandwidth(segments: &[Segment]) -> u64 {
+    segments
+        .iter()
+        .filter(|x| x.duration > 0.0)
+        .map(|x| ((x.end - x.start) as f64 * 8.0 / x.duration).ceil() as u64)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Packed audio segments start with an ID3 tag giving the timestamp of their first sample,
+/// on the 33 bits 90kHz clock of MPEG transport streams
+fn timestamp_tag(start: f64) -> Result<Vec<u8>> {
+    let ts = ((start * 90000.0).round() as u64) & ((1 << 33) - 1);
+    let mut tag = id3::Tag::new();
+    tag.add_frame(Frame::with_content(
+        "PRIV",
+        Content::Private(Private {
+            owner_identifier: s!(TIMESTAMP_OWNER),
+            private_data: ts.to_be_bytes().to_vec(),
+        }),
+    ));
+    let mut out = vec![];
+    tag.write_to(&mut out, Version::Id3v24)
+        .context("could not write ID3 tag")?;
+    Ok(out)
+}
+
+async fn read_index(dir: &Path, stamp: &str) -> Option<Vec<Segment>> {
+    let index = tokio::fs::read_to_string(dir.join("index")).await.ok()?;
+    let mut lines = index.lines();
+    if lines.next()? != stamp {
+        return None;
+    }
+    lines
+        .map(|line| {
+            let mut parts = line.split(' ');
+            Some(Segment {
+                start: parts.next()?.parse().ok()?,
+                end: parts.next()?.parse().ok()?,
+                duration: parts.next()?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Writes to a temporary file first so concurrent readers never see a partial file
+async fn write_atomic(p: &Path, buf: &[u8]) -> Result<()> {
+    let tmp = p.with_extension(format!(
+        "tmp{}",
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    tokio::fs::write(&tmp, buf)
+        .await
+        .with_context(|| format!("failed writing {:?}", tmp))?;
+    tokio::fs::rename(&tmp, p)
+        .await
+        .with_context(|| format!("failed renaming {:?}", tmp))?;
+    Ok(())
+}
+
+fn transcode(
+    ffmpeg: &str,
+    src: &Path,
+    start: Option<f64>,
+    end: Option<f64>,
+    kbps: u32,
+    out: &Path,
+) -> Result<()> {
+    let mut args = vec![s!("-nostdin"), s!("-v"), s!("error"), s!("-y")];
+    if let Some(start) = start {
+        args.push(s!("-ss"));
+        args.push(start.to_string());
+    }
+    if let Some(end) = end {
+        args.push(s!("-to"));
+        args.push(end.to_string());
+    }
+    args.push(s!("-i"));
+    args.push(src.to_string_lossy().to_string());
+    #[rustfmt::skip]
+    args.extend(
+        [
+            "-vn", "-map_metadata", "-1",
+            "-c:a", "libmp3lame", "-b:a", &format!("{}k", kbps),
+            "-id3v2_version", "0", "-write_xing", "0",
+            "-f", "mp3",
+        ]
+        .iter()
+        .map(ToString::to_string),
+    );
+    args.push(out.to_string_lossy().to_string());
+
+    log::info!("running {} with args: {}", ffmpeg, args.join(" "));
+    let output = Command::new(ffmpeg)
+        .args(&args)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("error starting {}, did you install it?", ffmpeg))?;
+    if !output.status.success() {
+        bail!(
+            "error transcoding with ffmpeg: {} {}",
+            output.status.code().unwrap_or(1),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variant() {
+        assert_eq!(Variant::parse("original"), Some(Variant::Original));
+        assert_eq!(Variant::parse("128k"), Some(Variant::Bitrate(128)));
+        assert_eq!(Variant::parse("128"), None);
+        assert_eq!(Variant::parse("../k"), None);
+        assert_eq!(Variant::Bitrate(64).name(), "64k");
+    }
+
+    #[test]
+    fn test_prepare_lock() {
+        let a = Path::new("./storage/hls/test-lock/original");
+        let b = Path::new("./storage/hls/test-lock/64k");
+        let lock = prepare_lock(a);
+        assert!(Arc::ptr_eq(&lock, &prepare_lock(a)));
+        assert!(!Arc::ptr_eq(&lock, &prepare_lock(b)));
+
+        let _guard = lock.try_lock().unwrap();
+        assert!(prepare_lock(a).try_lock().is_err());
+        assert!(prepare_lock(b).try_lock().is_ok());
+    }
+
+    #[test]
+    fn test_media_playlist() {
+        let segments = [
+            Segment {
+                start: 0,
+                end: 100,
+                duration: 6.01,
+            },
+            Segment {
+                start: 100,
+                end: 150,
+                duration: 3.0,
+            },
+        ];
+        assert_eq!(
+            media_playlist(&segments),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:7\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n\
+             #EXTINF:6.010,\n0.mp3\n#EXTINF:3.000,\n1.mp3\n#EXT-X-ENDLIST\n"
+        );
+        assert_eq!(peak_bandwidth(&segments), 134);
+    }
+}
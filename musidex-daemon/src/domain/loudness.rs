@@ -0,0 +1,296 @@
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use anyhow::Result;
+use rusqlite::Connection;
+
+/// ReplayGain 2.0 reference level, in LUFS
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+/// Taps of the true peak interpolation filter, per phase
+const TRUE_PEAK_TAPS: usize = 12;
+const OVERSAMPLING: usize = 4;
+
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y;
+        y
+    }
+}
+
+/// The two filters of the BS.1770 K-weighting, computed for any sample rate
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let (f0, g, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let vh = 10f64.powf(g / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+    [shelf, highpass]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoudnessResult {
+    /// integrated loudness, in LUFS
+    pub integrated: f64,
+    /// in dBTP
+    pub true_peak: f64,
+}
+
+impl LoudnessResult {
+    /// ReplayGain 2.0 track gain, in dB
+    pub fn track_gain(&self) -> f64 {
+        REFERENCE_LOUDNESS - self.integrated
+    }
+}
+
+/// Computes EBU R128 integrated loudness and true peak of an audio stream
+pub struct LoudnessMeter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    /// sum of squares of the current 100ms step, per channel
+    step_sums: Vec<f64>,
+    step_len: usize,
+    step_pos: usize,
+    /// mean squares of the last 4 steps, a 400ms block
+    last_steps: Vec<Vec<f64>>,
+    blocks: Vec<f64>,
+    interpolation: Vec<f32>,
+    history: Vec<Vec<f32>>,
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: usize, rate: u32) -> Self {
+        // 5.1 layout: the LFE channel is ignored and surround channels are weighted more
+        let weights = (0..channels)
+            .map(|c| match (channels, c) {
+                (6, 3) => 0.0,
+                (6, 4) | (6, 5) => 1.41,
+                _ => 1.0,
+            })
+            .collect();
+
+        // windowed sinc low-pass at the original nyquist frequency, split in OVERSAMPLING phases
+        let n = TRUE_PEAK_TAPS * OVERSAMPLING;
+        let center = (n - 1) as f32 / 2.0;
+        let interpolation = (0..n)
+            .map(|i| {
+                let x = (i as f32 - center) / OVERSAMPLING as f32;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (std::f32::consts::PI * x).sin() / (std::f32::consts::PI * x)
+                };
+                let window =
+                    0.5 - 0.5 * (2.0 * std::f32::consts::PI * (i as f32 + 0.5) / n as f32).cos();
+                sinc * window
+            })
+            .collect();
+
+        Self {
+            channels,
+            weights,
+            filters: vec![k_weighting(rate as f64); channels],
+            step_sums: vec![0.0; channels],
+            step_len: (rate / 10) as usize,
+            step_pos: 0,
+            last_steps: vec![],
+            blocks: vec![],
+            interpolation,
+            history: vec![vec![0.0; TRUE_PEAK_TAPS]; channels],
+            peak: 0.0,
+        }
+    }
+
+    /// Feeds interleaved samples
+    pub fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (c, &x) in frame.iter().enumerate() {
+                let [shelf, highpass] = &mut self.filters[c];
+                let y = highpass.process(shelf.process(x as f64));
+                self.step_sums[c] += y * y;
+                self.true_peak_push(c, x);
+            }
+            self.step_pos += 1;
+            if self.step_pos == self.step_len {
+                self.end_step();
+            }
+        }
+    }
+
+    fn true_peak_push(&mut self, c: usize, x: f32) {
+        let h = &mut self.history[c];
+        h.rotate_right(1);
+        h[0] = x;
+        self.peak = self.peak.max(x.abs());
+        for phase in 0..OVERSAMPLING {
+            let y: f32 = h
+                .iter()
+                .enumerate()
+                .map(|(j, &v)| v * self.interpolation[j * OVERSAMPLING + phase])
+                .sum();
+            self.peak = self.peak.max(y.abs());
+        }
+    }
+
+    fn end_step(&mut self) {
+        let means = self
+            .step_sums
+            .iter()
+            .map(|s| s / self.step_len as f64)
+            .collect();
+        self.step_sums.iter_mut().for_each(|s| *s = 0.0);
+        self.step_pos = 0;
+
+        self.last_steps.push(means);
+        if self.last_steps.len() > 4 {
+            self.last_steps.remove(0);
+        }
+        if self.last_steps.len() == 4 {
+            let block: f64 = (0..self.channels)
+                .map(|c| {
+                    let z: f64 = self.last_steps.iter().map(|s| s[c]).sum::<f64>() / 4.0;
+                    self.weights[c] * z
+                })
+                .sum();
+            self.blocks.push(block);
+        }
+    }
+
+    /// Returns None if the stream was too short or silent
+    pub fn finish(&self) -> Option<LoudnessResult> {
+        let loudness = |z: f64| -0.691 + 10.0 * z.log10();
+        let gated_mean = |threshold: f64| {
+            let gated: Vec<f64> = self
+                .blocks
+                .iter()
+                .copied()
+                .filter(|&z| loudness(z) > threshold)
+                .collect();
+            if gated.is_empty() {
+                return None;
+            }
+            Some(gated.iter().sum::<f64>() / gated.len() as f64)
+        };
+
+        let relative_threshold = loudness(gated_mean(ABSOLUTE_GATE)?) + RELATIVE_GATE;
+        let integrated = loudness(gated_mean(relative_threshold.max(ABSOLUTE_GATE))?);
+
+        Some(LoudnessResult {
+            integrated,
+            true_peak: 20.0 * (self.peak as f64).log10(),
+        })
+    }
+}
+
+pub struct ReplayGain {
+    /// in dB
+    pub gain: f64,
+    /// linear, 1.0 being full scale
+    pub peak: f64,
+}
+
+impl ReplayGain {
+    pub fn of(c: &Connection, id: MusicID) -> Result<Option<ReplayGain>> {
+        let value = |key| -> Result<Option<f64>> {
+            Ok(Tag::by_id_key(c, id, key)?
+                .and_then(|t| t.text)
+                .and_then(|t| t.parse().ok()))
+        };
+        let gain = unwrap_ret!(value(TagKey::ReplayGainTrackGain)?, Ok(None));
+        let peak = unwrap_ret!(value(TagKey::TruePeak)?, Ok(None));
+        Ok(Some(ReplayGain {
+            gain,
+            peak: 10f64.powf(peak / 20.0),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(rate: u32, freq: f32, amplitude: f32, secs: f32) -> Vec<f32> {
+        (0..(rate as f32 * secs) as usize)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * freq * i as f32 / rate as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_sine_loudness() {
+        // a 1kHz sine in both channels is as loud as its peak level
+        for rate in [44100, 48000] {
+            let mono = sine(rate, 1000.0, 0.1, 5.0);
+            let stereo: Vec<f32> = mono.iter().flat_map(|&x| [x, x]).collect();
+            let mut meter = LoudnessMeter::new(2, rate);
+            meter.push(&stereo);
+            let res = meter.finish().unwrap();
+            assert!((res.integrated + 20.0).abs() < 0.1, "{:?}", res);
+            assert!((res.true_peak + 20.0).abs() < 0.1, "{:?}", res);
+            assert!((res.track_gain() - 2.0).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn test_true_peak_between_samples() {
+        // a sine at a quarter of the sample rate, sampled at ±45°: samples never reach the peak
+        let rate = 48000;
+        let samples: Vec<f32> = (0..rate)
+            .map(|i| {
+                0.5 * (std::f32::consts::PI / 2.0 * i as f32 + std::f32::consts::PI / 4.0).sin()
+            })
+            .collect();
+        let mut meter = LoudnessMeter::new(1, rate);
+        meter.push(&samples);
+        let res = meter.finish().unwrap();
+        let sample_peak = 20.0 * (0.5f64 * std::f64::consts::FRAC_1_SQRT_2).log10();
+        assert!(res.true_peak > sample_peak + 2.0, "{:?}", res);
+        assert!(
+            (res.true_peak - 20.0 * 0.5f64.log10()).abs() < 0.5,
+            "{:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn test_gating() {
+        let mut meter = LoudnessMeter::new(1, 48000);
+        meter.push(&vec![0.0; 48000]);
+        assert!(meter.finish().is_none());
+
+        // silence does not bring the loudness down
+        let mut meter = LoudnessMeter::new(1, 48000);
+        meter.push(&sine(48000, 1000.0, 0.1, 3.0));
+        meter.push(&vec![0.0; 48000 * 3]);
+        let res = meter.finish().unwrap();
+        assert!((res.integrated + 23.0).abs() < 0.3, "{:?}", res);
+    }
+}
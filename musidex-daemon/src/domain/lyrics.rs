@@ -0,0 +1,267 @@
+use crate::domain::entity::MusicID;
+use crate::utils::{collect_rows, row_missing_opt};
+use anyhow::{Context, Result};
+use nanoserde::{DeJson, SerJson};
+use rusqlite::Connection;
+use std::path::Path;
+
+pub const SOURCE_USER: &str = "user";
+pub const SOURCE_EMBEDDED: &str = "embedded";
+pub const SOURCE_SIDECAR: &str = "sidecar";
+
+#[derive(Debug, Clone, PartialEq, SerJson, DeJson)]
+pub struct Lyrics {
+    pub plain: String,
+    /// LRC formatted lyrics, with a timestamp per line
+    pub synced: Option<String>,
+    pub source: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LrcLine {
+    /// in seconds
+    pub time: f64,
+    pub text: String,
+}
+
+#[derive(Debug, SerJson)]
+pub struct LyricsMatch {
+    pub music_id: MusicID,
+    /// the matching part of the lyrics, matched words being surrounded by brackets
+    pub snippet: String,
+}
+
+/// Parses a `[mm:ss.xx]` timestamp
+fn parse_timestamp(v: &str) -> Option<f64> {
+    let (min, sec) = v.split_once(':')?;
+    let min: u32 = min.trim().parse().ok()?;
+    let sec: f64 = sec.trim().replace(':', ".").parse().ok()?;
+    Some(min as f64 * 60.0 + sec)
+}
+
+/// Parses LRC lyrics, sorted by time. Lines can have multiple timestamps, metadata is ignored
+/// except for the global offset.
+pub fn parse_lrc(lrc: &str) -> Vec<LrcLine> {
+    let mut offset = 0.0;
+    let mut lines = vec![];
+    for line in lrc.lines() {
+        let mut rest = line.trim();
+        let mut times = vec![];
+        while let Some(stripped) = rest.strip_prefix('[') {
+            let (tag, after) = unwrap_cont!(stripped.split_once(']'));
+            if let Some(t) = parse_timestamp(tag) {
+                times.push(t);
+            } else if let Some(v) = tag.strip_prefix("offset:") {
+                // in milliseconds, a positive offset makes the lyrics appear sooner
+                offset = v.trim().parse::<f64>().unwrap_or(0.0) / 1000.0;
+            }
+            rest = after;
+        }
+        for time in times {
+            lines.push(LrcLine {
+                time,
+                text: s!(rest.trim()),
+            });
+        }
+    }
+    for line in &mut lines {
+        line.time = (line.time - offset).max(0.0);
+    }
+    lines.sort_by(|a, b| a.time.total_cmp(&b.time));
+    lines
+}
+
+pub fn format_lrc(lines: &[LrcLine]) -> String {
+    lines
+        .iter()
+        .map(|l| {
+            let centis = (l.time * 100.0).round() as u64;
+            format!(
+                "[{:02}:{:02}.{:02}]{}\n",
+                centis / 6000,
+                centis / 100 % 60,
+                centis % 100,
+                l.text
+            )
+        })
+        .collect()
+}
+
+impl Lyrics {
+    /// Returns None if the LRC doesn't contain any timed line
+    pub fn from_lrc(synced: String, source: &str) -> Option<Lyrics> {
+        let lines = parse_lrc(&synced);
+        if lines.is_empty() {
+            return None;
+        }
+        let plain = lines
+            .iter()
+            .map(|l| &*l.text)
+            .collect::<Vec<_>>()
+            .join("\n");
+        Some(Lyrics {
+            plain,
+            synced: Some(synced),
+            source: s!(source),
+        })
+    }
+
+    pub fn from_plain(plain: String, source: &str) -> Option<Lyrics> {
+        if plain.trim().is_empty() {
+            return None;
+        }
+        Some(Lyrics {
+            plain,
+            synced: None,
+            source: s!(source),
+        })
+    }
+
+    pub fn get(c: &Connection, id: MusicID) -> Result<Option<Lyrics>> {
+        let mut stmt =
+            c.prepare_cached("SELECT plain, synced, source FROM lyrics WHERE music_id = ?1")?;
+        let v = stmt.query_row([id.0], |row| {
+            Ok(Some(Lyrics {
+                plain: row.get("plain")?,
+                synced: row.get("synced")?,
+                source: row.get("source")?,
+            }))
+        });
+        row_missing_opt(v).context("error getting lyrics")
+    }
+
+    pub fn set(c: &Connection, id: MusicID, lyrics: &Lyrics) -> Result<()> {
+        c.prepare_cached(
+            "INSERT INTO lyrics (music_id, plain, synced, source)
+                    VALUES (?1, ?2, ?3, ?4)
+                    ON CONFLICT (music_id) DO UPDATE SET plain=?2, synced=?3, source=?4",
+        )?
+        .execute(rusqlite::params![
+            id.0,
+            lyrics.plain,
+            lyrics.synced,
+            lyrics.source
+        ])
+        .context("error setting lyrics")?;
+        Ok(())
+    }
+
+    pub fn remove(c: &Connection, id: MusicID) -> Result<()> {
+        c.prepare_cached("DELETE FROM lyrics WHERE music_id = ?1")?
+            .execute([id.0])?;
+        Ok(())
+    }
+
+    /// Full text search of the lyrics, best matches first
+    pub fn search(c: &Connection, query: &str, limit: u32) -> Result<Vec<LyricsMatch>> {
+        // every word is quoted so the query syntax of fts5 is not exposed
+        let query = query
+            .split_whitespace()
+            .map(|w| format!("\"{}\"", w.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut stmt = c.prepare_cached(
+            "SELECT rowid, snippet(lyrics_fts, 0, '[', ']', '…', 12) AS snippet
+             FROM lyrics_fts
+             WHERE lyrics_fts MATCH ?1
+             ORDER BY rank
+             LIMIT ?2",
+        )?;
+        let v = stmt.query_map(rusqlite::params![query, limit], |row| {
+            Ok(LyricsMatch {
+                music_id: MusicID(row.get("rowid")?),
+                snippet: row.get("snippet")?,
+            })
+        })?;
+        collect_rows(v)
+    }
+}
+
+/// Looks for lyrics next to or inside the audio file: sidecar `.lrc` files (also matching the
+/// `<name>.<lang>.lrc` files written by yt-dlp) first, then USLT/SYLT frames of mp3s.
+pub fn extract(path: &Path) -> Result<Option<Lyrics>> {
+    let stem = path
+        .file_stem()
+        .and_then(|x| x.to_str())
+        .context("invalid file name")?;
+    let dir = path.parent().context("no parent directory")?;
+
+    let mut sidecars = vec![];
+    for file in std::fs::read_dir(dir)? {
+        let name = unwrap_cont!(file.ok())
+            .file_name()
+            .to_string_lossy()
+            .to_string();
+        let middle = unwrap_cont!(name.strip_prefix(stem).and_then(|x| x.strip_suffix(".lrc")));
+        // <name>.lrc or <name>.<lang>.lrc, not <name>-other.lrc
+        if middle.is_empty() || (middle.starts_with('.') && !middle[1..].contains('.')) {
+            sidecars.push(name);
+        }
+    }
+    sidecars.sort_by_key(|x| x.len());
+
+    for name in &sidecars {
+        let lrc = std::fs::read_to_string(dir.join(name))
+            .with_context(|| format!("could not read {}", name))?;
+        if let Some(lyrics) = Lyrics::from_lrc(lrc, SOURCE_SIDECAR) {
+            return Ok(Some(lyrics));
+        }
+    }
+
+    if path.extension().and_then(|x| x.to_str()) != Some("mp3") {
+        return Ok(None);
+    }
+    let tag = match id3::Tag::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(id3::Error {
+            kind: id3::ErrorKind::NoTag,
+            ..
+        }) => return Ok(None),
+        Err(e) => return Err(e).context("could not read id3 tag"),
+    };
+    let synced = tag
+        .synchronised_lyrics()
+        .find(|x| x.timestamp_format == id3::frame::TimestampFormat::Ms)
+        .map(|x| {
+            let lines: Vec<LrcLine> = x
+                .content
+                .iter()
+                .map(|(ms, text)| LrcLine {
+                    time: *ms as f64 / 1000.0,
+                    text: s!(text.trim()),
+                })
+                .collect();
+            format_lrc(&lines)
+        })
+        .and_then(|lrc| Lyrics::from_lrc(lrc, SOURCE_EMBEDDED));
+    Ok(synced.or_else(|| {
+        tag.lyrics()
+            .find_map(|x| Lyrics::from_plain(x.text.clone(), SOURCE_EMBEDDED))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lrc() {
+        let lines = parse_lrc(
+            "[ar:Someone]\n[offset:500]\n[00:12.50]second\n[00:02.00][01:02.00] chorus \nnot timed\n[00:05:25]third",
+        );
+        let times: Vec<f64> = lines.iter().map(|l| l.time).collect();
+        let texts: Vec<&str> = lines.iter().map(|l| &*l.text).collect();
+        assert_eq!(times, vec![1.5, 4.75, 12.0, 61.5]);
+        assert_eq!(texts, vec!["chorus", "third", "second", "chorus"]);
+        assert!(parse_lrc("just some text").is_empty());
+    }
+
+    #[test]
+    fn test_format_lrc() {
+        let lrc = "[00:01.50]a\n[01:02.05]b\n";
+        assert_eq!(format_lrc(&parse_lrc(lrc)), lrc);
+    }
+}
@@ -1,12 +1,35 @@
+pub mod analysis;
+pub mod changes;
 pub mod clean;
 pub mod config;
+pub mod cover_art;
+pub mod csv_import;
+pub mod download;
 pub mod entity;
+pub mod fingerprint;
+pub mod hls;
+pub mod loudness;
+pub mod lyrics;
 pub mod music;
+pub mod offline;
+pub mod playlist;
+pub mod share;
 pub mod stream;
 pub mod sync;
 pub mod tags;
+pub mod thumbnail;
+pub mod title_rules;
 pub mod upload;
 pub mod user;
+pub mod waveform;
+pub mod worker_analysis;
+pub mod worker_cover_art;
+pub mod worker_csv_import;
+pub mod worker_fingerprint;
+pub mod worker_loudness;
+pub mod worker_musicbrainz;
 pub mod worker_neural_embed;
+pub mod worker_probe;
 pub mod worker_thumbnail_resize;
+pub mod worker_waveform;
 pub mod worker_youtube_dl;
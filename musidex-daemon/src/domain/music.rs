@@ -1,7 +1,9 @@
 use crate::domain::entity::{Music, MusicID, Tag, TagKey, UserID};
+use crate::utils::row_missing_opt;
 use anyhow::{Context, Result};
 use hyper::StatusCode;
 use rusqlite::Connection;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 impl Music {
     pub fn mk(c: &Connection) -> Result<MusicID> {
@@ -27,6 +29,23 @@ impl Music {
             .map(|x| x == 1)
     }
 
+    pub fn exists(c: &Connection, id: MusicID) -> Result<bool> {
+        let n: i32 = c
+            .prepare_cached("SELECT COUNT(1) FROM musics WHERE id=?1;")?
+            .query_row([&id.0], |v| v.get(0))?;
+        Ok(n > 0)
+    }
+
+    /// When the tags of the music last changed, to the second
+    pub fn tags_updated(c: &Connection, id: MusicID) -> Result<Option<SystemTime>> {
+        let secs: Option<i64> = row_missing_opt(
+            c.prepare_cached("SELECT tags_updated FROM musics WHERE id=?1;")?
+                .query_row([&id.0], |v| v.get(0)),
+        )?
+        .flatten();
+        Ok(secs.map(|x| UNIX_EPOCH + Duration::from_secs(x.max(0) as u64)))
+    }
+
     pub fn merge(c: &mut Connection, id1: MusicID, id2: MusicID) -> Result<()> {
         let t = c.transaction().context("transaction begin failed")?;
 
@@ -35,6 +54,11 @@ impl Music {
             .execute([&id1.0, &id2.0])
             .context("error executing merge music")?;
 
+        t.prepare_cached("UPDATE OR IGNORE lyrics SET music_id = ?1 WHERE music_id = ?2;")
+            .context("error preparing merge lyrics")?
+            .execute([&id1.0, &id2.0])
+            .context("error executing merge lyrics")?;
+
         Music::delete(&t, id2)?;
 
         t.commit().context("transaction commit failed")?;
@@ -52,12 +76,46 @@ impl Music {
             .execute([&m.0, &id.0])
             .context("error executing put on top music")?;
 
+        t.prepare_cached("UPDATE lyrics SET music_id = ?1 WHERE music_id = ?2;")
+            .context("error preparing put on top lyrics")?
+            .execute([&m.0, &id.0])
+            .context("error executing put on top lyrics")?;
+
         let song_exists = Music::delete(&t, id)?;
 
         t.commit().context("transaction commit failed")?;
 
         Ok(song_exists)
     }
+
+    /// Replaces the tags of a music by its suggested tags, returns the number of tags accepted
+    pub fn accept_suggestions(c: &mut Connection, id: MusicID) -> Result<usize> {
+        let t = c.transaction().context("transaction begin failed")?;
+
+        let mut accepted = 0;
+        for tag in Tag::by_id(&t, id)? {
+            let key = unwrap_cont!(tag.key.as_suggestion());
+            let accepted_tag = Tag {
+                key: TagKey::from(key),
+                ..tag.clone()
+            };
+            Tag::insert(&t, accepted_tag)?;
+            Tag::remove(&t, id, tag.key)?;
+            accepted += 1;
+        }
+
+        t.commit().context("transaction commit failed")?;
+
+        Ok(accepted)
+    }
+
+    pub fn reject_suggestions(c: &Connection, id: MusicID) -> Result<()> {
+        c.prepare_cached("DELETE FROM tags WHERE music_id=?1 AND key LIKE 'suggestion:%';")
+            .context("error preparing reject suggestions")?
+            .execute([&id.0])
+            .context("error executing reject suggestions")?;
+        Ok(())
+    }
 }
 
 pub fn delete_music(c: &Connection, uid: UserID, id: MusicID) -> Result<StatusCode> {
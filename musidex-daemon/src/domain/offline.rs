@@ -0,0 +1,388 @@
+use crate::domain::entity::{Music, MusicID, TagKey, User, UserID};
+use crate::domain::stream::{self, MusicSource};
+use crate::domain::thumbnail;
+use crate::infrastructure::db::Db;
+use crate::infrastructure::file_server::Validators;
+use crate::infrastructure::mp3;
+use crate::infrastructure::tar::{TarArchive, TarEntry};
+use crate::utils::query_param;
+use anyhow::{Context, Result};
+use hyper::{Body, Request};
+use nanoserde::SerJson;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Thumbnails are packaged at the size the apps display them
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// The musics to package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selection {
+    Musics(Vec<MusicID>),
+    /// every music tagged user_tag:<name>
+    Tag(String),
+    Library(UserID),
+}
+
+impl Selection {
+    /// From the query string: `ids=1,2,3`, `tag=<user tag>` or else the library of the user.
+    /// None if it is invalid.
+    pub fn from_req(req: &Request<Body>) -> Result<Option<Selection>> {
+        if let Some(ids) = query_param(req, "ids") {
+            let ids = ids
+                .split(',')
+                .map(|x| x.parse().map(MusicID))
+                .collect::<std::result::Result<_, _>>();
+            return Ok(ids.ok().map(Selection::Musics));
+        }
+        if let Some(tag) = query_param(req, "tag") {
+            let tag = percent_encoding::percent_decode_str(tag).decode_utf8()?;
+            return Ok(Some(Selection::Tag(tag.into_owned())));
+        }
+        Ok(User::from_req(req).ok().map(Selection::Library))
+    }
+
+    /// The selected musics that exist, without duplicates
+    pub fn musics(&self, c: &Connection) -> Result<Vec<MusicID>> {
+        let key = match *self {
+            Selection::Musics(ref ids) => {
+                let mut seen = HashSet::new();
+                let mut musics = vec![];
+                for &id in ids {
+                    if seen.insert(id) && Music::exists(c, id)? {
+                        musics.push(id);
+                    }
+                }
+                return Ok(musics);
+            }
+            Selection::Tag(ref name) => TagKey::UserTag(name.clone()),
+            Selection::Library(uid) => TagKey::UserLibrary(s!(uid.0)),
+        };
+        let mut stmt =
+            c.prepare_cached("SELECT music_id FROM tags WHERE key=?1 ORDER BY music_id")?;
+        let ids = stmt.query_map([&key], |x| x.get("music_id").map(MusicID))?;
+        ids.collect::<rusqlite::Result<_>>()
+            .context("failed listing selected musics")
+    }
+}
+
+/// A file of a package, or only a byte window of it for chapters
+struct PackageFile {
+    /// path in the archive
+    name: String,
+    /// relative to storage
+    source: String,
+    window: (u64, u64),
+    /// of the whole file on disk
+    file_size: u64,
+    modified: SystemTime,
+}
+
+impl PackageFile {
+    fn path(&self) -> PathBuf {
+        Path::new("storage").join(&self.source)
+    }
+
+    fn size(&self) -> u64 {
+        self.window.1 - self.window.0
+    }
+
+    fn modified_nanos(&self) -> i64 {
+        self.modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as i64
+    }
+}
+
+struct PackageMusic {
+    id: MusicID,
+    content_type: &'static str,
+    audio: PackageFile,
+    thumbnail: Option<PackageFile>,
+}
+
+/// The files of the selected musics as they are on disk right now
+pub struct Package {
+    musics: Vec<PackageMusic>,
+}
+
+#[derive(SerJson)]
+pub struct ManifestFile {
+    /// path in the archive
+    pub path: String,
+    pub size: u64,
+    /// hex encoded
+    pub sha256: String,
+    /// where the content starts in the archive
+    pub offset: u64,
+}
+
+#[derive(SerJson)]
+pub struct ManifestMusic {
+    pub id: MusicID,
+    pub content_type: String,
+    pub audio: ManifestFile,
+    pub thumbnail: Option<ManifestFile>,
+}
+
+#[derive(SerJson)]
+pub struct Manifest {
+    /// ETag of the archive, to resume its download with If-Range
+    pub etag: String,
+    /// of the archive
+    pub size: u64,
+    pub musics: Vec<ManifestMusic>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Size and modification time of a file, None if it does not exist
+fn stat(p: &Path) -> Result<Option<(u64, SystemTime)>> {
+    match std::fs::metadata(p) {
+        Ok(meta) => Ok(Some((meta.len(), meta.modified()?))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("failed reading file metadata"),
+    }
+}
+
+/// Extension of a file name, with its dot
+fn extension(name: &str) -> String {
+    Path::new(name)
+        .extension()
+        .map(|x| format!(".{}", x.to_string_lossy()))
+        .unwrap_or_default()
+}
+
+/// Lists the files of the selected musics, skipping musics whose source is missing
+pub async fn collect(db: &Db, selection: &Selection) -> Result<Package> {
+    let mut found = vec![];
+    {
+        let c = db.get().await;
+        for id in selection.musics(&c)? {
+            let source = unwrap_cont!(MusicSource::find(&c, id)?);
+            let thumbnail = thumbnail::pick(&c, id, Some(THUMBNAIL_SIZE), false)?;
+            found.push((id, source, thumbnail));
+        }
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let mut musics = vec![];
+        for (id, source, thumbnail) in found {
+            let p = source.file_path();
+            let (file_size, modified) = match stat(&p)? {
+                Some(x) => x,
+                None => {
+                    log::warn!("source of {:?} is missing: {:?}", id, p);
+                    continue;
+                }
+            };
+            let window = match source.chapter_start {
+                Some(start) => mp3::file_time_range_to_bytes(&p, start, source.chapter_end)
+                    .context("failed finding chapter in source")?,
+                None => (0, file_size),
+            };
+            let audio = PackageFile {
+                name: format!("music/{}{}", id.0, extension(&source.path)),
+                source: source.path.clone(),
+                window,
+                file_size,
+                modified,
+            };
+
+            let thumbnail = match thumbnail {
+                Some((fname, _)) => {
+                    stat(&Path::new("storage").join(&fname))?.map(|(file_size, modified)| {
+                        PackageFile {
+                            name: format!("thumbnails/{}{}", id.0, extension(&fname)),
+                            source: fname,
+                            window: (0, file_size),
+                            file_size,
+                            modified,
+                        }
+                    })
+                }
+                None => None,
+            };
+
+            musics.push(PackageMusic {
+                id,
+                content_type: stream::content_type(&source.path),
+                audio,
+                thumbnail,
+            });
+        }
+        Ok(Package { musics })
+    })
+    .await?
+}
+
+impl Package {
+    /// Files in the order of the archive
+    fn files(&self) -> impl Iterator<Item = &PackageFile> {
+        self.musics
+            .iter()
+            .flat_map(|m| std::iter::once(&m.audio).chain(m.thumbnail.as_ref()))
+    }
+
+    /// The archive only changes when one of its files or the selection does.
+    /// Musics leaving the selection do not change any modification time, so it is always
+    /// considered modified now and only the etag can validate it.
+    pub fn validators(&self) -> Validators {
+        let mut h = Sha256::new();
+        for f in self.files() {
+            h.update(format!(
+                "{}:{}:{}:{}:{}\n",
+                f.name,
+                f.source,
+                f.window.0,
+                f.window.1,
+                f.modified_nanos()
+            ));
+        }
+        Validators::with_etag(&hex(&h.finalize()[..16]), SystemTime::now())
+    }
+
+    pub fn archive(&self) -> Result<TarArchive> {
+        TarArchive::new(
+            self.files()
+                .map(|f| TarEntry {
+                    name: f.name.clone(),
+                    path: f.path(),
+                    offset: f.window.0,
+                    size: f.size(),
+                    mtime: f.modified_nanos() as u64 / 1_000_000_000,
+                })
+                .collect(),
+        )
+    }
+
+    /// Checksums are computed on first use, without holding the database
+    pub async fn manifest(&self, db: &Db) -> Result<Manifest> {
+        let files: Vec<&PackageFile> = self.files().collect();
+        let mut sums = {
+            let c = db.get().await;
+            files
+                .iter()
+                .map(|f| cached_checksum(&c, f))
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        let missing: Vec<(usize, PathBuf, (u64, u64))> = files
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| sums[*i].is_none())
+            .map(|(i, f)| (i, f.path(), f.window))
+            .collect();
+        if !missing.is_empty() {
+            let computed = tokio::task::spawn_blocking(move || {
+                missing
+                    .into_iter()
+                    .map(|(i, p, window)| Ok((i, checksum(&p, window)?)))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .await??;
+            let c = db.get().await;
+            for (i, sum) in computed {
+                store_checksum(&c, files[i], &sum)?;
+                sums[i] = Some(sum);
+            }
+        }
+
+        let archive = self.archive()?;
+        let mut sums = sums.into_iter().map(Option::unwrap_or_default);
+        let mut n = 0;
+        let mut entry = |f: &PackageFile| {
+            let offset = archive.data_offset(n);
+            n += 1;
+            ManifestFile {
+                path: f.name.clone(),
+                size: f.size(),
+                sha256: sums.next().unwrap_or_default(),
+                offset,
+            }
+        };
+        let mut musics = Vec::with_capacity(self.musics.len());
+        for m in &self.musics {
+            musics.push(ManifestMusic {
+                id: m.id,
+                content_type: s!(m.content_type),
+                audio: entry(&m.audio),
+                thumbnail: m.thumbnail.as_ref().map(&mut entry),
+            });
+        }
+
+        Ok(Manifest {
+            etag: self.validators().etag,
+            size: archive.size(),
+            musics,
+        })
+    }
+}
+
+fn checksum(p: &Path, (start, end): (u64, u64)) -> Result<String> {
+    let mut f = std::fs::File::open(p).context("failed opening file to checksum")?;
+    f.seek(SeekFrom::Start(start))?;
+    let mut h = Sha256::new();
+    let n = std::io::copy(&mut f.take(end - start), &mut h)?;
+    if n != end - start {
+        bail!("{:?} is shorter than expected", p);
+    }
+    Ok(hex(&h.finalize()))
+}
+
+fn cached_checksum(c: &Connection, f: &PackageFile) -> Result<Option<String>> {
+    c.prepare_cached(
+        "SELECT sha256 FROM checksums
+         WHERE path=?1 AND window_start=?2 AND window_end=?3 AND modified=?4 AND size=?5",
+    )?
+    .query_row(
+        params![
+            f.source,
+            f.window.0 as i64,
+            f.window.1 as i64,
+            f.modified_nanos(),
+            f.file_size as i64
+        ],
+        |x| x.get("sha256"),
+    )
+    .optional()
+    .context("failed reading checksum")
+}
+
+fn store_checksum(c: &Connection, f: &PackageFile, sum: &str) -> Result<()> {
+    c.prepare_cached(
+        "INSERT OR REPLACE INTO checksums (path, window_start, window_end, modified, size, sha256)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?
+    .execute(params![
+        f.source,
+        f.window.0 as i64,
+        f.window.1 as i64,
+        f.modified_nanos(),
+        f.file_size as i64,
+        sum
+    ])
+    .context("failed storing checksum")?;
+    Ok(())
+}
+
+/// Forgets the checksums of deleted files
+pub fn clean(c: &Connection) -> Result<()> {
+    let paths = c
+        .prepare("SELECT DISTINCT path FROM checksums")?
+        .query_map([], |x| x.get::<&str, String>("path"))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for p in paths {
+        if !Path::new("storage").join(&p).exists() {
+            c.execute("DELETE FROM checksums WHERE path=?1", [&p])?;
+        }
+    }
+    Ok(())
+}
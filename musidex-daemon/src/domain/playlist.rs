@@ -0,0 +1,397 @@
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::domain::offline::Selection;
+use crate::domain::stream::MusicSource;
+use crate::utils::escape_html;
+use anyhow::{Context, Result};
+use nanoserde::SerJson;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use rusqlite::{Connection, OptionalExtension};
+
+/// Characters escaped in the storage paths of XSPF locations, which are URIs
+const PATH_ESCAPES: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    M3u8,
+    Xspf,
+}
+
+impl Format {
+    pub fn parse(v: &str) -> Option<Format> {
+        match v {
+            "m3u8" | "m3u" => Some(Format::M3u8),
+            "xspf" => Some(Format::Xspf),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::M3u8 => "m3u8",
+            Format::Xspf => "xspf",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::M3u8 => "audio/x-mpegurl",
+            Format::Xspf => "application/xspf+xml",
+        }
+    }
+}
+
+/// Where the entries of an exported playlist point to
+pub enum Location {
+    /// stream urls under this origin, like http://host:port
+    Url(String),
+    /// paths relative to the storage directory, for players reading the files directly
+    Storage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// in seconds
+    pub duration: Option<i32>,
+}
+
+/// The playable musics of a selection, in its order
+pub fn entries(c: &Connection, selection: &Selection, location: &Location) -> Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for id in selection.musics(c)? {
+        let source = unwrap_cont!(MusicSource::find(c, id)?);
+        let mut entry = Entry {
+            location: match *location {
+                Location::Url(ref origin) => format!("{}/api/stream/{}", origin, id.0),
+                Location::Storage => source.path,
+            },
+            title: None,
+            artist: None,
+            album: None,
+            duration: None,
+        };
+        for tag in Tag::by_id(c, id)? {
+            match tag.key {
+                TagKey::Title => entry.title = tag.text,
+                TagKey::Artist => entry.artist = tag.text,
+                TagKey::Album => entry.album = tag.text,
+                TagKey::Duration => entry.duration = tag.integer,
+                _ => {}
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Extended M3U, the title line being "artist - title" as most players expect
+pub fn m3u8(name: &str, entries: &[Entry]) -> String {
+    let mut out = format!("#EXTM3U\n#PLAYLIST:{}\n", name.replace('\n', " "));
+    for e in entries {
+        let display = match (&e.artist, &e.title) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title.clone(),
+            _ => String::new(),
+        };
+        out += &format!(
+            "#EXTINF:{},{}\n{}\n",
+            e.duration.unwrap_or(-1),
+            display.replace('\n', " "),
+            e.location
+        );
+    }
+    out
+}
+
+pub fn xspf(name: &str, entries: &[Entry], location: &Location) -> String {
+    let mut out = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n\
+         <title>{}</title>\n<trackList>\n",
+        escape_html(name)
+    );
+    for e in entries {
+        let uri = match location {
+            Location::Url(_) => e.location.clone(),
+            Location::Storage => utf8_percent_encode(&e.location, PATH_ESCAPES).to_string(),
+        };
+        out += &format!("<track>\n<location>{}</location>\n", escape_html(&uri));
+        for (element, v) in [
+            ("title", &e.title),
+            ("creator", &e.artist),
+            ("album", &e.album),
+        ] {
+            if let Some(v) = v {
+                out += &format!("<{0}>{1}</{0}>\n", element, escape_html(v));
+            }
+        }
+        if let Some(d) = e.duration {
+            out += &format!("<duration>{}</duration>\n", d as i64 * 1000);
+        }
+        out += "</track>\n";
+    }
+    out + "</trackList>\n</playlist>\n"
+}
+
+/// An entry of an uploaded playlist, to be matched against the library
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportEntry {
+    pub location: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+impl ImportEntry {
+    fn describe(&self) -> String {
+        match (&self.artist, &self.title, &self.location) {
+            (Some(artist), Some(title), _) => format!("{} - {}", artist, title),
+            (None, Some(title), _) => title.clone(),
+            (_, _, Some(location)) => location.clone(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// A parsed playlist, with its name if it has one
+pub struct Playlist {
+    pub name: Option<String>,
+    pub entries: Vec<ImportEntry>,
+}
+
+pub fn parse_m3u(text: &str) -> Playlist {
+    let mut name = None;
+    let mut entries = vec![];
+    let mut cur = ImportEntry::default();
+    for line in text.lines().map(str::trim) {
+        if let Some(v) = line.strip_prefix("#PLAYLIST:") {
+            name = Some(s!(v.trim())).filter(|x| !x.is_empty());
+        } else if let Some(v) = line.strip_prefix("#EXTINF:") {
+            let display = v.split_once(',').map(|x| x.1.trim()).unwrap_or_default();
+            cur = match display.split_once(" - ") {
+                Some((artist, title)) => ImportEntry {
+                    location: None,
+                    title: Some(s!(title)),
+                    artist: Some(s!(artist)),
+                },
+                None => ImportEntry {
+                    location: None,
+                    title: Some(s!(display)).filter(|x| !x.is_empty()),
+                    artist: None,
+                },
+            };
+        } else if !line.is_empty() && !line.starts_with('#') {
+            cur.location = Some(s!(line));
+            entries.push(std::mem::take(&mut cur));
+        }
+    }
+    Playlist { name, entries }
+}
+
+pub fn parse_xspf(text: &str) -> Result<Playlist> {
+    let doc = roxmltree::Document::parse(text).context("invalid xml")?;
+    let root = doc.root_element();
+    if !root.has_tag_name("playlist") {
+        bail!("not an xspf playlist");
+    }
+    let child_text = |node: roxmltree::Node, name: &str| {
+        node.children()
+            .find(|x| x.has_tag_name(name))
+            .and_then(|x| x.text())
+            .map(|x| s!(x.trim()))
+            .filter(|x| !x.is_empty())
+    };
+    let entries = root
+        .descendants()
+        .filter(|x| x.has_tag_name("track"))
+        .map(|track| ImportEntry {
+            location: child_text(track, "location"),
+            title: child_text(track, "title"),
+            artist: child_text(track, "creator"),
+        })
+        .collect();
+    Ok(Playlist {
+        name: child_text(root, "title"),
+        entries,
+    })
+}
+
+/// Finds the music of an entry, by its stream url, the name of its file in storage, or else
+/// its title and artist. Without artist, the title has to be unique.
+pub fn find(c: &Connection, entry: &ImportEntry) -> Result<Option<MusicID>> {
+    if let Some(ref location) = entry.location {
+        if let Some((_, id)) = location.rsplit_once("/api/stream/") {
+            if let Ok(id) = id.trim_end_matches('/').parse() {
+                if Music::exists(c, MusicID(id))? {
+                    return Ok(Some(MusicID(id)));
+                }
+            }
+        }
+        let fname = location.rsplit(['/', '\\']).next().unwrap_or_default();
+        let fname = percent_decode_str(fname).decode_utf8_lossy();
+        let by_file = c
+            .prepare_cached(&format!(
+                "SELECT music_id FROM tags
+                 WHERE key IN ({})
+                 AND text=?1
+                 ORDER BY music_id LIMIT 1",
+                TagKey::local_sources_sql()
+            ))?
+            .query_row([&*fname], |x| x.get("music_id").map(MusicID))
+            .optional()?;
+        if by_file.is_some() {
+            return Ok(by_file);
+        }
+    }
+
+    let title = unwrap_ret!(entry.title.as_ref(), Ok(None));
+    let ids: Vec<MusicID> = match entry.artist {
+        Some(ref artist) => c
+            .prepare_cached(
+                "SELECT t.music_id FROM tags t
+                 JOIN tags a ON a.music_id = t.music_id AND a.key = 'artist'
+                 WHERE t.key = 'title' AND t.text = ?1 COLLATE NOCASE AND a.text = ?2 COLLATE NOCASE
+                 ORDER BY t.music_id",
+            )?
+            .query_map([title, artist], |x| x.get("music_id").map(MusicID))?
+            .collect::<rusqlite::Result<_>>()?,
+        None => c
+            .prepare_cached(
+                "SELECT music_id FROM tags WHERE key = 'title' AND text = ?1 COLLATE NOCASE
+                 ORDER BY music_id",
+            )?
+            .query_map([title], |x| x.get("music_id").map(MusicID))?
+            .collect::<rusqlite::Result<_>>()?,
+    };
+    match (entry.artist.is_some(), &ids[..]) {
+        (true, [first, ..]) => Ok(Some(*first)),
+        (false, [only]) => Ok(Some(*only)),
+        _ => Ok(None),
+    }
+}
+
+#[derive(SerJson, Debug)]
+pub struct ImportReport {
+    pub tag: String,
+    pub matched: Vec<MusicID>,
+    /// entries that could not be found, as "artist - title" or their location
+    pub unmatched: Vec<String>,
+}
+
+/// Tags the musics of the playlist with user_tag:<name>, which is how playlists are kept
+pub fn import(c: &Connection, name: &str, playlist: &Playlist) -> Result<ImportReport> {
+    let mut report = ImportReport {
+        tag: s!(name),
+        matched: vec![],
+        unmatched: vec![],
+    };
+    for entry in &playlist.entries {
+        match find(c, entry)? {
+            Some(id) => {
+                if !report.matched.contains(&id) {
+                    Tag::insert(c, Tag::new_key(id, TagKey::UserTag(s!(name))))?;
+                    report.matched.push(id);
+                }
+            }
+            None => report.unmatched.push(entry.describe()),
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(location: &str, title: Option<&str>, artist: Option<&str>) -> Entry {
+        Entry {
+            location: s!(location),
+            title: title.map(|x| s!(x)),
+            artist: artist.map(|x| s!(x)),
+            album: None,
+            duration: Some(61),
+        }
+    }
+
+    #[test]
+    fn test_m3u_roundtrip() {
+        let entries = [
+            entry("a b.mp3", Some("Song"), Some("Some - One")),
+            entry("c.mp3", Some("Only title"), None),
+            entry("d.mp3", None, None),
+        ];
+        let text = m3u8("Road trip", &entries);
+        assert_eq!(
+            text,
+            "#EXTM3U\n#PLAYLIST:Road trip\n\
+             #EXTINF:61,Some - One - Song\na b.mp3\n\
+             #EXTINF:61,Only title\nc.mp3\n\
+             #EXTINF:61,\nd.mp3\n"
+        );
+        let parsed = parse_m3u(&text);
+        assert_eq!(parsed.name.as_deref(), Some("Road trip"));
+        // the first " - " is taken as the separator
+        assert_eq!(parsed.entries[0].artist.as_deref(), Some("Some"));
+        assert_eq!(parsed.entries[0].title.as_deref(), Some("One - Song"));
+        assert_eq!(parsed.entries[1].title.as_deref(), Some("Only title"));
+        assert_eq!(parsed.entries[1].artist, None);
+        assert_eq!(parsed.entries[2].title, None);
+        assert_eq!(parsed.entries[2].location.as_deref(), Some("d.mp3"));
+
+        // plain m3u
+        let parsed = parse_m3u("/music/x.mp3\r\n\r\n# comment\nC:\\music\\y.mp3\n");
+        assert_eq!(parsed.name, None);
+        assert_eq!(
+            parsed
+                .entries
+                .iter()
+                .map(|x| x.location.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["/music/x.mp3", "C:\\music\\y.mp3"]
+        );
+    }
+
+    #[test]
+    fn test_xspf_roundtrip() {
+        let entries = [
+            entry("a b#1.mp3", Some("<Song> & co"), Some("Someone")),
+            entry("c.mp3", None, None),
+        ];
+        let text = xspf("Mine", &entries, &Location::Storage);
+        assert!(text.contains("<location>a%20b%231.mp3</location>"));
+        assert!(text.contains("<title>&lt;Song&gt; &amp; co</title>"));
+        assert!(text.contains("<duration>61000</duration>"));
+        let parsed = parse_xspf(&text).unwrap();
+        assert_eq!(parsed.name.as_deref(), Some("Mine"));
+        assert_eq!(
+            parsed.entries[0],
+            ImportEntry {
+                location: Some(s!("a%20b%231.mp3")),
+                title: Some(s!("<Song> & co")),
+                artist: Some(s!("Someone")),
+            }
+        );
+        assert_eq!(
+            parsed.entries[1],
+            ImportEntry {
+                location: Some(s!("c.mp3")),
+                title: None,
+                artist: None,
+            }
+        );
+        assert!(parse_xspf("<html></html>").is_err());
+        assert!(parse_xspf("not xml").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::utils::{escape_html, now};
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rusqlite::{params, Connection};
+use sha2::Sha256;
+
+/// Links expire after a week unless asked otherwise
+pub const DEFAULT_EXPIRY_SECS: i64 = 7 * 24 * 3600;
+pub const MAX_EXPIRY_SECS: i64 = 365 * 24 * 3600;
+/// Name of the signing key in the secrets table. Deleting it revokes every link.
+const SECRET_NAME: &str = "share";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShareTarget {
+    Music(MusicID),
+    /// every music tagged user_tag:<name>
+    Tag(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub target: ShareTarget,
+    /// unix timestamp in seconds
+    pub expires: i64,
+}
+
+impl Share {
+    pub fn is_expired(&self) -> bool {
+        self.expires <= now()
+    }
+
+    fn payload(&self) -> String {
+        match self.target {
+            ShareTarget::Music(id) => format!("{}:m:{}", self.expires, id.0),
+            ShareTarget::Tag(ref name) => format!("{}:t:{}", self.expires, name),
+        }
+    }
+
+    fn from_payload(payload: &str) -> Option<Share> {
+        let mut parts = payload.splitn(3, ':');
+        let expires = parts.next()?.parse().ok()?;
+        let target = match (parts.next()?, parts.next()?) {
+            ("m", id) => ShareTarget::Music(MusicID(id.parse().ok()?)),
+            ("t", name) => ShareTarget::Tag(name.to_string()),
+            _ => return None,
+        };
+        Some(Share { target, expires })
+    }
+
+    /// The token is the payload followed by its HMAC-SHA256, both base64url encoded
+    pub fn sign(&self, secret: &[u8]) -> String {
+        let payload = self.payload();
+        let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts any key length");
+        mac.update(payload.as_bytes());
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+        )
+    }
+
+    /// Returns the share of a token if it was signed with this secret, expired or not
+    pub fn verify(secret: &[u8], token: &str) -> Option<Share> {
+        let (payload, sig) = token.split_once('.')?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+        let sig = URL_SAFE_NO_PAD.decode(sig).ok()?;
+        let mut mac = HmacSha256::new_from_slice(secret).ok()?;
+        mac.update(&payload);
+        mac.verify_slice(&sig).ok()?;
+        Share::from_payload(std::str::from_utf8(&payload).ok()?)
+    }
+
+    /// The shared musics, in the order they were added
+    pub fn musics(&self, c: &Connection) -> Result<Vec<MusicID>> {
+        match self.target {
+            ShareTarget::Music(id) => Ok(if Music::exists(c, id)? {
+                vec![id]
+            } else {
+                vec![]
+            }),
+            ShareTarget::Tag(ref name) => {
+                let key = TagKey::UserTag(name.clone());
+                let mut stmt =
+                    c.prepare_cached("SELECT music_id FROM tags WHERE key=?1 ORDER BY music_id")?;
+                let ids = stmt.query_map([&key], |x| x.get("music_id").map(MusicID))?;
+                ids.collect::<rusqlite::Result<_>>()
+                    .context("failed listing shared musics")
+            }
+        }
+    }
+
+    /// Whether the share gives access to this music
+    pub fn contains(&self, c: &Connection, id: MusicID) -> Result<bool> {
+        match self.target {
+            ShareTarget::Music(shared) => Ok(shared == id),
+            ShareTarget::Tag(ref name) => {
+                Ok(Tag::by_id_key(c, id, TagKey::UserTag(name.clone()))?.is_some())
+            }
+        }
+    }
+}
+
+/// The key links are signed with, generated on first use
+pub fn secret(c: &Connection) -> Result<Vec<u8>> {
+    let fresh: [u8; 32] = rand::random();
+    c.prepare_cached("INSERT OR IGNORE INTO secrets (name, value) VALUES (?1, ?2)")?
+        .execute(params![SECRET_NAME, &fresh[..]])
+        .context("failed creating share secret")?;
+    c.prepare_cached("SELECT value FROM secrets WHERE name=?1")?
+        .query_row([SECRET_NAME], |x| x.get("value"))
+        .context("failed reading share secret")
+}
+
+/// A standalone page playing the shared musics, its resources being served under /s/<token>/.
+/// Returns None if none of them exist anymore.
+pub fn page(c: &Connection, token: &str, share: &Share) -> Result<Option<String>> {
+    let text = |id, key| -> Result<Option<String>> {
+        Ok(Tag::by_id_key(c, id, key)?.and_then(|x| x.text))
+    };
+
+    let mut items = String::new();
+    let mut first_title = None;
+    for id in share.musics(c)? {
+        let title = text(id, TagKey::Title)?.unwrap_or_else(|| s!("Untitled"));
+        let artist = text(id, TagKey::Artist)?.unwrap_or_default();
+        let thumbnail = match text(id, TagKey::Thumbnail)? {
+            Some(_) => format!("<img src=\"/s/{}/thumbnail/{}\" alt=\"\">", token, id.0),
+            None => s!("<div class=\"noimg\"></div>"),
+        };
+        items += &format!(
+            "<li>{}<div><b>{}</b><br>{}<audio controls preload=\"none\" src=\"/s/{}/stream/{}\"></audio></div></li>\n",
+            thumbnail,
+            escape_html(&title),
+            escape_html(&artist),
+            token,
+            id.0
+        );
+        first_title.get_or_insert(title);
+    }
+
+    let first_title = unwrap_ret!(first_title, Ok(None));
+    let title = match share.target {
+        ShareTarget::Music(_) => first_title,
+        ShareTarget::Tag(ref name) => name.clone(),
+    };
+    Ok(Some(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 40em; margin: 2em auto; padding: 0 1em; }}
+ul {{ padding: 0; }}
+li {{ list-style: none; display: flex; gap: 1em; align-items: center; margin: 1em 0; }}
+li > div {{ flex: 1; }}
+img, .noimg {{ width: 80px; height: 80px; object-fit: cover; background: #ddd; flex-shrink: 0; }}
+audio {{ width: 100%; margin-top: 0.5em; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<ul>
+{items}</ul>
+</body>
+</html>
+"#,
+        title = escape_html(&title),
+        items = items
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let secret = b"secret";
+        for target in [
+            ShareTarget::Music(MusicID(42)),
+            ShareTarget::Tag(s!("road trip: 2024")),
+        ] {
+            let share = Share {
+                target,
+                expires: 1700000000,
+            };
+            let token = share.sign(secret);
+            assert!(!token.contains(['/', '+', '=']));
+            assert_eq!(Share::verify(secret, &token), Some(share.clone()));
+            assert_eq!(Share::verify(b"other secret", &token), None);
+
+            // the payload cannot be changed without the secret
+            let (_, sig) = token.split_once('.').unwrap();
+            let forged = Share {
+                expires: 1900000000,
+                ..share
+            };
+            let forged_payload = URL_SAFE_NO_PAD.encode(forged.payload());
+            assert_eq!(
+                Share::verify(secret, &format!("{}.{}", forged_payload, sig)),
+                None
+            );
+        }
+        assert_eq!(Share::verify(b"secret", "garbage"), None);
+        assert_eq!(Share::verify(b"secret", "a.b"), None);
+    }
+}
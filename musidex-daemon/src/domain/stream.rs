@@ -1,86 +1,93 @@
-use crate::domain::entity::TagKey::{LocalM4A, LocalMP3, LocalOGG, LocalWEBM};
+use crate::domain::entity::TagKey::{ChapterEnd, ChapterStart};
 use crate::domain::entity::{MusicID, Tag};
 use crate::infrastructure::db::Client;
-use crate::utils::get_file_range;
+use crate::infrastructure::mp3;
 use anyhow::{Context, Result};
-use hyper::http::HeaderValue;
+use rusqlite::Connection;
+use std::path::PathBuf;
 
-pub struct MusicMetadata {
-    pub buf: Vec<u8>,
-    pub range_size: (u64, u64, u64),
+pub struct MusicFile {
+    pub path: PathBuf,
+    /// chapters are served as if their byte window of the source was a file on its own
+    pub window: Option<(u64, u64)>,
     pub content_type: &'static str,
 }
 
-pub async fn stream_music(
-    c: Client<'_>,
-    id: MusicID,
-    range: Option<&HeaderValue>,
-) -> Result<MusicMetadata> {
-    let mut source_path = None;
-    {
-        let tags = Tag::by_id(&c, id)?;
+/// The local file a music can be read from, and for chapters the time window within it
+pub struct MusicSource {
+    pub path: String,
+    pub chapter_start: Option<f64>,
+    pub chapter_end: Option<f64>,
+}
 
-        for tag in tags {
-            if (tag.key == LocalMP3
-                || tag.key == LocalOGG
-                || tag.key == LocalM4A
-                || tag.key == LocalWEBM)
-                && tag.text.is_some()
-            {
+impl MusicSource {
+    pub fn find(c: &Connection, id: MusicID) -> Result<Option<MusicSource>> {
+        let mut source_path = None;
+        let mut chapter_start = None;
+        let mut chapter_end = None;
+        for tag in Tag::by_id(c, id)? {
+            if tag.key.is_local_source() && tag.text.is_some() && source_path.is_none() {
                 source_path = tag.text;
-                break;
+            } else if tag.key == ChapterStart {
+                chapter_start = tag.text.and_then(|x| x.parse::<f64>().ok());
+            } else if tag.key == ChapterEnd {
+                chapter_end = tag.text.and_then(|x| x.parse::<f64>().ok());
             }
         }
+        Ok(source_path.map(|path| MusicSource {
+            path,
+            chapter_start,
+            chapter_end,
+        }))
+    }
+
+    /// Path of the source relative to the working directory
+    pub fn file_path(&self) -> PathBuf {
+        PathBuf::from(format!("./storage/{}", self.path))
     }
-    let source_path = source_path.context("no streamable source found")?;
+}
 
-    let content_type = if source_path.ends_with("mp3") {
+/// Mime type of an audio file, from its extension
+pub fn content_type(path: &str) -> &'static str {
+    if path.ends_with("mp3") {
         "audio/mpeg"
-    } else if source_path.ends_with("ogg") {
+    } else if path.ends_with("ogg") || path.ends_with("opus") {
         "audio/ogg"
-    } else if source_path.ends_with("m4a") {
+    } else if path.ends_with("flac") {
+        "audio/flac"
+    } else if path.ends_with("m4a") {
         "audio/mp4"
-    } else if source_path.ends_with("webm") {
+    } else if path.ends_with("webm") {
         "audio/webm"
+    } else if path.ends_with("wav") {
+        "audio/wav"
     } else {
         ""
-    };
-
-    let file_path = format!("./storage/{}", source_path);
-
-    if let Some(rangev) = range {
-        let range = http_range::HttpRange::parse_bytes(rangev.as_bytes(), u32::MAX as u64)
-            .map_err(|_| anyhow!("could not decode range"))?;
-        log::info!(
-            "asked with range {:?}: {}",
-            range[0],
-            rangev.to_str().unwrap()
-        );
-        let r = range.get(0).context("no ranges")?;
-        let (buf, len) = get_file_range(file_path, (r.start, r.start + r.length)).await?;
-        let range_size = (
-            r.start,
-            (r.start + r.length)
-                .saturating_sub(1)
-                .min(len.saturating_sub(1)),
-            len,
-        );
-        return Ok(MusicMetadata {
-            buf,
-            range_size,
-            content_type,
-        });
     }
+}
+
+/// Resolves the file a music is streamed from
+pub async fn stream_music(c: Client<'_>, id: MusicID) -> Result<MusicFile> {
+    let source = MusicSource::find(&c, id)?.context("no streamable source found")?;
+    drop(c);
+    let path = source.file_path();
 
-    let buf = tokio::fs::read(file_path)
-        .await
-        .context("failed reading source")?;
+    let window = match source.chapter_start {
+        Some(start) => {
+            let p = path.clone();
+            let end = source.chapter_end;
+            let window =
+                tokio::task::spawn_blocking(move || mp3::file_time_range_to_bytes(&p, start, end))
+                    .await?
+                    .context("failed finding chapter in source")?;
+            Some(window)
+        }
+        None => None,
+    };
 
-    let l = buf.len() as u64;
-    let range_size = (0, l.saturating_sub(1), l);
-    Ok(MusicMetadata {
-        buf,
-        range_size,
-        content_type,
+    Ok(MusicFile {
+        path,
+        window,
+        content_type: content_type(&source.path),
     })
 }
@@ -1,10 +1,9 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use anyhow::Result;
 use futures::{sink::SinkExt, stream::StreamExt};
+use hyper::{Body, Request};
 use hyper_tungstenite::{tungstenite, HyperWebsocket};
 use nanoserde::SerJson;
 use rusqlite::Connection;
@@ -12,21 +11,86 @@ use tokio::sync::mpsc;
 use tokio::sync::watch;
 use tungstenite::Message;
 
-use crate::domain::config;
-use crate::domain::entity::{Music, MusicID, MusidexMetadata, Patch, Tag, TagKey, User};
+use crate::domain::entity::{Music, MusicID, MusidexMetadata, Patch, Tag, TagKey, User, UserID};
+use crate::domain::{changes, config};
 use crate::infrastructure::db::Db;
-use crate::utils::collect_rows;
-use std::collections::HashMap;
+use crate::utils::{collect_rows, query_param};
+use std::collections::{HashMap, HashSet};
+
+/// The part of the metadata a client is interested in
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct SyncScope {
+    /// only the musics in the library of this user
+    pub library: Option<UserID>,
+    /// embeddings are big and only used by the neural radio
+    pub vectors: bool,
+}
+
+impl Default for SyncScope {
+    fn default() -> Self {
+        Self {
+            library: None,
+            vectors: true,
+        }
+    }
+}
+
+impl SyncScope {
+    /// Reads `scope=library|all` and `vectors=false` from the query, the user of the library
+    /// being given with `user=` or taken from the cookie
+    pub fn from_req(req: &Request<Body>) -> Option<SyncScope> {
+        let vectors = !matches!(query_param(req, "vectors"), Some("false" | "0"));
+        let library = match query_param(req, "scope") {
+            None | Some("all") => None,
+            Some("library") => Some(match query_param(req, "user") {
+                Some(uid) => UserID(uid.parse().ok()?),
+                None => User::from_req(req).ok()?,
+            }),
+            Some(_) => return None,
+        };
+        Some(SyncScope { library, vectors })
+    }
+}
+
+/// The patches of a scope from one revision to the next
+pub struct Update {
+    pub from: i64,
+    pub meta: MusidexMetadata,
+}
+
+/// The scopes listened to by clients, with the channel their updates are sent on
+type Listened = Arc<Mutex<HashMap<SyncScope, watch::Sender<Option<Arc<Update>>>>>>;
 
 #[derive(Clone)]
 pub struct SyncBroadcastSubscriber {
-    rx: watch::Receiver<Arc<(MusidexMetadata, Option<MusidexMetadata>)>>,
+    listened: Listened,
     refresh_tx: mpsc::Sender<()>,
 }
 
+impl SyncBroadcastSubscriber {
+    /// Receives the updates of the scope as the change log grows
+    pub fn subscribe(&self, scope: SyncScope) -> watch::Receiver<Option<Arc<Update>>> {
+        let rx = {
+            let mut listened = self.listened.lock().unwrap();
+            match listened.get(&scope) {
+                Some(tx) => tx.subscribe(),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    listened.insert(scope, tx);
+                    rx
+                }
+            }
+        };
+        let _ = self.refresh_tx.try_send(());
+        rx
+    }
+}
+
 pub struct SyncBroadcast {
     c: Connection,
-    tx: watch::Sender<Arc<(MusidexMetadata, Option<MusidexMetadata>)>>,
+    listened: Listened,
+    /// revision of the last update of each scope
+    last: HashMap<SyncScope, i64>,
     refresh_tx: mpsc::Sender<()>,
     refresh_rx: mpsc::Receiver<()>,
 }
@@ -38,126 +102,109 @@ pub fn compress_meta(x: &MusidexMetadata) -> Vec<u8> {
 
 impl SyncBroadcast {
     pub fn new() -> Result<(Self, SyncBroadcastSubscriber)> {
-        let (tx, rx) = watch::channel(Arc::new((MusidexMetadata::default(), None)));
+        Ok(Self::with_conn(Db::mk_conn()?))
+    }
+
+    pub fn with_conn(c: Connection) -> (Self, SyncBroadcastSubscriber) {
+        let listened = Listened::default();
         let (refresh_tx, refresh_rx) = mpsc::channel(16);
-        Ok((
+        (
             Self {
-                c: Db::mk_conn()?,
-                tx,
+                c,
+                listened: listened.clone(),
+                last: HashMap::default(),
                 refresh_tx: refresh_tx.clone(),
                 refresh_rx,
             },
-            SyncBroadcastSubscriber { rx, refresh_tx },
-        ))
+            SyncBroadcastSubscriber {
+                listened,
+                refresh_tx,
+            },
+        )
     }
 
-    pub fn start_workers(self) {
-        let refresh_tx = self.refresh_tx;
+    pub fn start_workers(mut self) {
+        let refresh_tx = self.refresh_tx.clone();
         tokio::spawn(async move {
             loop {
                 tokio::time::sleep(Duration::from_secs(2)).await;
                 let _ = refresh_tx.send(()).await;
             }
         });
-        let mut r = self.refresh_rx;
-        let db = self.c;
-        let tx = self.tx;
         tokio::spawn(async move {
-            let mut last_hash = 1234;
-            let mut last_map: Option<TagMap> = None;
-            while let Some(()) = r.recv().await {
-                let m = fetch_metadata(&db);
-                let m = match m {
-                    Ok(x) => x,
-                    Err(e) => {
-                        log::error!("error fetching metadata to send to subscribers: {}", e);
-                        continue;
-                    }
-                };
-                let mut s = DefaultHasher::new();
-                m.hash(&mut s);
-                let hash = s.finish();
-
-                if hash == last_hash {
-                    continue;
+            while let Some(()) = self.refresh_rx.recv().await {
+                if let Err(e) = self.refresh() {
+                    log::error!("error fetching metadata to send to subscribers: {}", e);
                 }
-                last_hash = hash;
-
-                let (last_mapp, musipatch) = mk_patches(&last_map, &m);
-                last_map = Some(last_mapp);
-
-                let _ = tx.send(Arc::new((m, musipatch)));
             }
         });
     }
-}
-
-type TagMap = HashMap<(i32, TagKey), Tag>;
 
-pub fn mk_patches(
-    last_map: &Option<TagMap>,
-    new: &MusidexMetadata,
-) -> (TagMap, Option<MusidexMetadata>) {
-    let newmap = mk_tag_map(new);
-
-    if let Some(last_map) = last_map {
-        let mut patches = Vec::with_capacity(5);
+    /// Sends the patches of each listened scope if the change log grew since its last update,
+    /// forgetting the scopes nobody listens to anymore
+    pub fn refresh(&mut self) -> Result<()> {
+        let scopes: Vec<SyncScope> = {
+            let mut listened = self.listened.lock().unwrap();
+            listened.retain(|_, tx| tx.receiver_count() > 0);
+            listened.keys().cloned().collect()
+        };
+        self.last.retain(|scope, _| scopes.contains(scope));
 
-        for k in last_map.keys() {
-            if newmap.contains_key(k) {
-                continue;
-            }
-            patches.push(Patch {
-                kind: s!("remove"),
-                tag: Tag::new_key(MusicID(k.0), k.1.clone()),
-            })
+        let revision = changes::revision(&self.c)?;
+        if scopes
+            .iter()
+            .all(|scope| self.last.get(scope) == Some(&revision))
+        {
+            return Ok(());
         }
+        changes::prune(&self.c)?;
 
-        for (k, t) in &newmap {
-            let last_get = last_map.get(k);
-            if last_get == Some(t) {
+        for scope in scopes {
+            // clients of a new scope catch up by themselves
+            let from = *self.last.entry(scope.clone()).or_insert(revision);
+            if from == revision {
                 continue;
             }
-            if last_get.is_none() {
-                patches.push(Patch {
-                    kind: s!("add"),
-                    tag: t.clone(),
-                });
-                continue;
+            match catch_up(&self.c, &scope, Some(from))? {
+                Some(meta) => {
+                    self.last.insert(scope.clone(), meta.revision);
+                    self.send(&scope, Arc::new(Update { from, meta }));
+                }
+                None => {
+                    self.last.insert(scope, revision);
+                }
             }
-            patches.push(Patch {
-                kind: s!("update"),
-                tag: t.clone(),
-            })
         }
-
-        let newpatch = MusidexMetadata {
-            musics: new.musics.clone(),
-            tags: None,
-            users: new.users.clone(),
-            settings: new.settings.clone(),
-            patches: Some(patches),
-        };
-        return (newmap, Some(newpatch));
+        Ok(())
     }
-    (newmap, None)
-}
 
-pub fn mk_tag_map(x: &MusidexMetadata) -> TagMap {
-    let tags = unwrap_ret!(x.tags.as_ref(), HashMap::default());
-    let mut v = HashMap::with_capacity(tags.len());
-    for t in tags {
-        v.insert((t.music_id.0, t.key.clone()), t.clone());
+    fn send(&self, scope: &SyncScope, update: Arc<Update>) {
+        if let Some(tx) = self.listened.lock().unwrap().get(scope) {
+            tx.send_replace(Some(update));
+        }
     }
-    v
 }
 
 pub async fn serve_sync_websocket(
     websocket: HyperWebsocket,
-    mut b: SyncBroadcastSubscriber,
+    b: SyncBroadcastSubscriber,
+    db: Db,
+    scope: SyncScope,
+    since: Option<i64>,
 ) -> Result<()> {
     let mut websocket = websocket.await?;
-    let mut first_msg = true;
+    let mut rx = b.subscribe(scope.clone());
+
+    let meta = catch_up(&*db.get().await, &scope, since)?;
+    let mut revision = match meta {
+        Some(meta) => {
+            websocket
+                .send(Message::Binary(compress_meta(&meta)))
+                .await?;
+            meta.revision
+        }
+        None => since.unwrap_or_default(),
+    };
 
     loop {
         tokio::select! {
@@ -179,44 +226,159 @@ pub async fn serve_sync_websocket(
                     }
                 }
             }
-            Ok(_) = b.rx.changed() => {
-                let meta = b.rx.borrow().clone();
-
-                let (musi, musipatch) = &*meta;
-
-                let chosen = if first_msg {
-                    first_msg = false;
-                    &musi
-                } else {
-                    musipatch.as_ref().unwrap_or(&musi)
-                };
+            Ok(_) = rx.changed() => {
+                let update = unwrap_cont!(rx.borrow_and_update().clone());
+                if update.meta.revision <= revision {
+                    continue;
+                }
+                if update.from == revision {
+                    revision = update.meta.revision;
+                    websocket.send(Message::Binary(compress_meta(&update.meta))).await?;
+                    continue;
+                }
 
-                websocket.send(Message::Binary(compress_meta(chosen))).await?;
+                // an update was missed or the client subscribed in between
+                let meta = catch_up(&*db.get().await, &scope, Some(revision))?;
+                if let Some(meta) = meta {
+                    revision = meta.revision;
+                    websocket.send(Message::Binary(compress_meta(&meta))).await?;
+                }
             }
         }
     }
 }
 
-pub fn fetch_metadata(c: &Connection) -> Result<MusidexMetadata> {
-    let mut musics = c.prepare_cached("SELECT * FROM musics")?;
-    let mut tags = c.prepare_cached("SELECT * FROM tags")?;
-
-    let musics = musics.query_map([], |r| Ok(Into::into(r)))?;
-    let tags = tags.query_map([], |r| Ok(Into::into(r)))?;
+/// What a client at the revision is missing: the patches since then if the change log still
+/// has them, a full snapshot otherwise. None if nothing changed for the scope.
+pub fn catch_up(
+    c: &Connection,
+    scope: &SyncScope,
+    revision: Option<i64>,
+) -> Result<Option<MusidexMetadata>> {
+    if let Some(revision) = revision {
+        if changes::can_resume(c, revision)? {
+            return fetch_patch(c, scope, revision);
+        }
+    }
+    fetch_metadata(c, scope).map(Some)
+}
 
-    let musics = collect_rows(musics.map(|x| x.map(|v: Music| v.id)))?;
-    let tags = collect_rows(tags)?;
+fn scope_musics(c: &Connection, library: &Option<TagKey>) -> Result<Vec<MusicID>> {
+    let mut musics = c.prepare_cached(
+        "SELECT * FROM musics
+         WHERE ?1 IS NULL OR id IN (SELECT music_id FROM tags WHERE key = ?1)",
+    )?;
+    let musics = musics.query_map([library], |r| Ok(Into::into(r)))?;
+    collect_rows(musics.map(|x| x.map(|v: Music| v.id)))
+}
 
+fn users(c: &Connection) -> Result<Vec<User>> {
     let mut users = User::list(c)?;
-    let config = config::get_all(c)?;
-
     users.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(users)
+}
+
+pub fn fetch_metadata(c: &Connection, scope: &SyncScope) -> Result<MusidexMetadata> {
+    // a read transaction so that the revision matches what is read
+    let tx = c.unchecked_transaction()?;
+    let library = scope.library.map(|uid| TagKey::UserLibrary(s!(uid)));
+
+    // fingerprints are big and only useful to the server
+    let mut tags = tx.prepare_cached(
+        "SELECT * FROM tags
+         WHERE key != 'fingerprint'
+           AND (?2 OR vector IS NULL)
+           AND (?1 IS NULL OR music_id IN (SELECT music_id FROM tags WHERE key = ?1))",
+    )?;
+    let tags = tags.query_map(rusqlite::params![&library, scope.vectors], |r| {
+        Ok(Into::into(r))
+    })?;
+    let tags = collect_rows(tags)?;
 
     Ok(MusidexMetadata {
-        musics,
+        musics: scope_musics(&tx, &library)?,
         tags: Some(tags),
-        users,
-        settings: config,
+        users: users(&tx)?,
+        settings: config::get_all(&tx)?,
         patches: None,
+        revision: changes::revision(&tx)?,
     })
 }
+
+/// The patches bringing a client of the scope from the revision to the current one,
+/// None if nothing it can see changed
+pub fn fetch_patch(
+    c: &Connection,
+    scope: &SyncScope,
+    revision: i64,
+) -> Result<Option<MusidexMetadata>> {
+    let tx = c.unchecked_transaction()?;
+    let changes = changes::since(&tx, revision)?;
+    let library = scope.library.map(|uid| TagKey::UserLibrary(s!(uid)));
+    let musics = scope_musics(&tx, &library)?;
+    let in_scope: HashSet<MusicID> = musics.iter().copied().collect();
+
+    let visible = |t: Tag| -> Option<Tag> {
+        if t.key == TagKey::Fingerprint || (!scope.vectors && t.vector.is_some()) {
+            return None;
+        }
+        Some(t)
+    };
+    let patch = |kind: &str, tag: Tag| Patch {
+        kind: s!(kind),
+        tag,
+    };
+
+    let mut touched: Vec<_> = changes.tags.iter().collect();
+    touched.sort_by_key(|(id, _)| id.0);
+
+    let mut patches = vec![];
+    for (&id, keys) in touched {
+        let now = in_scope.contains(&id);
+        let before = match library {
+            Some(ref lib) => keys.get(lib).copied().unwrap_or(now),
+            None => true,
+        };
+        match (before, now) {
+            (false, false) => {}
+            (false, true) => {
+                for t in Tag::by_id(&tx, id)?.into_iter().filter_map(visible) {
+                    patches.push(patch("add", t));
+                }
+            }
+            (true, false) => {
+                // removing what the client does not have is harmless
+                let current = Tag::by_id(&tx, id)?.into_iter().map(|t| t.key);
+                let keys: HashSet<TagKey> = keys.keys().cloned().chain(current).collect();
+                for key in keys {
+                    patches.push(patch("remove", Tag::new_key(id, key)));
+                }
+            }
+            (true, true) => {
+                for (key, &existed) in keys {
+                    let tag = Tag::by_id_key(&tx, id, key.clone())?.and_then(visible);
+                    match (existed, tag) {
+                        (true, Some(t)) => patches.push(patch("update", t)),
+                        (false, Some(t)) => patches.push(patch("add", t)),
+                        (true, None) => {
+                            patches.push(patch("remove", Tag::new_key(id, key.clone())))
+                        }
+                        (false, None) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    if patches.is_empty() && !changes.other {
+        return Ok(None);
+    }
+    Ok(Some(MusidexMetadata {
+        musics,
+        tags: None,
+        users: users(&tx)?,
+        settings: config::get_all(&tx)?,
+        patches: Some(patches),
+        revision: changes.revision,
+    }))
+}
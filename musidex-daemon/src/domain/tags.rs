@@ -27,7 +27,6 @@ impl Tag {
         }
     }
 
-    #[allow(dead_code)]
     pub fn new_parse(id: MusicID, key: TagKey, value: String) -> Tag {
         let integer = value.parse().ok();
         let mut date = dateparser::parse(&*value).ok();
@@ -78,6 +77,28 @@ impl Tag {
         Ok(())
     }
 
+    /// Workers fill a key for the musics with a local source. When they fail on a music, it
+    /// gets an empty tag of the key instead so that it isn't picked again and retried forever.
+    pub fn mark_treated(c: &Connection, id: MusicID, key: TagKey) -> Result<()> {
+        Tag::insert(c, Tag::new_key(id, key))
+    }
+
+    /// A music with a local source that has no tag of the key yet, see `mark_treated`
+    pub fn find_untreated(c: &Connection, key: TagKey) -> Result<Option<MusicID>> {
+        let mut stmt = c.prepare_cached(&format!(
+            "
+            SELECT music_id as id2 FROM tags
+            WHERE key IN ({})
+            AND
+                (SELECT COUNT(1) FROM tags
+                 WHERE music_id = id2 AND key=?1) = 0
+            LIMIT 1;",
+            TagKey::local_sources_sql()
+        ))?;
+        let v = stmt.query_row([&key], |x| x.get("id2").map(MusicID).map(Some));
+        row_missing_opt(v).context("failed getting untreated music")
+    }
+
     pub fn remove(c: &Connection, id: MusicID, key: TagKey) -> Result<()> {
         log::info!("removing tag {} {}", id.0, &key);
         let mut stmt = c.prepare_cached("DELETE FROM tags WHERE music_id=?1 AND key=?2;")?;
@@ -0,0 +1,335 @@
+use crate::domain::config;
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use anyhow::{Context, Result};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat, RgbImage};
+use rusqlite::Connection;
+use std::path::Path;
+
+const DEFAULT_SIZES: [u32; 3] = [64, 256, 512];
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+/// Rows and columns darker than this (out of 255) on average are considered letterboxing
+const LETTERBOX_MAX_LUMA: f32 = 20.0;
+/// Images closer to a square than this ratio are left alone
+const SQUARE_TOLERANCE: f32 = 1.05;
+
+/// Name of the resized version of a thumbnail
+pub fn sized_name(thumb: &str, size: u32, webp: bool) -> String {
+    if webp {
+        format!("thumb{}.{}.webp", size, thumb)
+    } else {
+        format!("thumb{}.{}", size, thumb)
+    }
+}
+
+/// Returns the name of the thumbnail a resized version was generated from
+pub fn original_of_sized(name: &str) -> Option<&str> {
+    let (prefix, rest) = name.split_once('.')?;
+    let size = prefix.strip_prefix("thumb")?;
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(rest.strip_suffix(".webp").unwrap_or(rest))
+}
+
+/// The sizes in pixels thumbnails are resized to, from the thumbnail_sizes config
+pub fn sizes(c: &Connection) -> Result<Vec<u32>> {
+    let mut sizes: Vec<u32> = config::get(c, "thumbnail_sizes")?
+        .map(|x| {
+            x.split(',')
+                .filter_map(|s| s.trim().parse().ok())
+                .filter(|&s| s > 0)
+                .collect()
+        })
+        .unwrap_or_default();
+    if sizes.is_empty() {
+        sizes = DEFAULT_SIZES.to_vec();
+    }
+    sizes.sort_unstable();
+    sizes.dedup();
+    Ok(sizes)
+}
+
+fn luma(p: &image::Rgb<u8>) -> f32 {
+    0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32
+}
+
+/// Bounds (x, y, width, height) of the image once the black bars around it are removed
+fn content_bounds(img: &RgbImage) -> (u32, u32, u32, u32) {
+    let (w, h) = img.dimensions();
+    let row_dark = |y: u32| {
+        (0..w).map(|x| luma(img.get_pixel(x, y))).sum::<f32>() / w as f32 <= LETTERBOX_MAX_LUMA
+    };
+    let col_dark = |x: u32| {
+        (0..h).map(|y| luma(img.get_pixel(x, y))).sum::<f32>() / h as f32 <= LETTERBOX_MAX_LUMA
+    };
+
+    let top = (0..h).find(|&y| !row_dark(y));
+    let top = unwrap_ret!(top, (0, 0, w, h)); // all black
+    let bottom = (0..h).rev().find(|&y| !row_dark(y)).unwrap_or(h - 1);
+    let left = (0..w).find(|&x| !col_dark(x)).unwrap_or(0);
+    let right = (0..w).rev().find(|&x| !col_dark(x)).unwrap_or(w - 1);
+    (left, top, right - left + 1, bottom - top + 1)
+}
+
+/// Offset of the window of the given length containing the most detail, ties going to the
+/// most centered window
+fn best_window(energy: &[f32], len: usize) -> usize {
+    if len >= energy.len() {
+        return 0;
+    }
+    let center = (energy.len() - len) / 2;
+    let mut sum: f32 = energy[..len].iter().sum();
+    let (mut best, mut best_sum) = (0usize, sum);
+    for start in 1..=energy.len() - len {
+        sum += energy[start + len - 1] - energy[start - 1];
+        let better = sum > best_sum * 1.001
+            || (sum >= best_sum * 0.999 && start.abs_diff(center) < best.abs_diff(center));
+        if better {
+            best = start;
+            best_sum = sum;
+        }
+    }
+    best
+}
+
+/// Crops the image to a square: letterboxing is removed, then the most detailed part of the
+/// longest side is kept. Returns None if the image is already square.
+pub fn crop_square(img: &DynamicImage) -> Option<DynamicImage> {
+    let rgb = img.to_rgb8();
+    let (x, y, w, h) = content_bounds(&rgb);
+    let ratio = w.max(h) as f32 / w.min(h) as f32;
+    if ratio <= SQUARE_TOLERANCE && (w, h) == rgb.dimensions() {
+        return None;
+    }
+
+    let side = w.min(h);
+    let gradient = |(x0, y0): (u32, u32), (x1, y1): (u32, u32)| {
+        (luma(rgb.get_pixel(x1, y1)) - luma(rgb.get_pixel(x0, y0))).abs()
+    };
+    let (x, y) = if w > h {
+        let energy: Vec<f32> = (x..x + w)
+            .map(|cx| {
+                let next = (cx + 1).min(x + w - 1);
+                (y..y + h).map(|cy| gradient((cx, cy), (next, cy))).sum()
+            })
+            .collect();
+        (x + best_window(&energy, side as usize) as u32, y)
+    } else {
+        let energy: Vec<f32> = (y..y + h)
+            .map(|cy| {
+                let next = (cy + 1).min(y + h - 1);
+                (x..x + w).map(|cx| gradient((cx, cy), (cx, next))).sum()
+            })
+            .collect();
+        (x, y + best_window(&energy, side as usize) as u32)
+    };
+    Some(img.crop_imm(x, y, side, side))
+}
+
+pub struct Palette {
+    pub dominant: [u8; 3],
+    /// the most common saturated colour, if any
+    pub accent: Option<[u8; 3]>,
+}
+
+pub fn hex_color(c: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2])
+}
+
+fn saturation(c: [f32; 3]) -> f32 {
+    let max = c[0].max(c[1]).max(c[2]);
+    let min = c[0].min(c[1]).min(c[2]);
+    if max <= 0.0 {
+        0.0
+    } else {
+        (max - min) / max
+    }
+}
+
+/// Extracts the dominant and accent colours by bucketing the pixels by colour
+pub fn palette(img: &DynamicImage) -> Palette {
+    let small = img.resize(64, 64, FilterType::Triangle).to_rgb8();
+    // 4 bits per channel, with the sum of the colours falling in each bucket and their count
+    let mut buckets = vec![([0.0f32; 3], 0u32); 1 << 12];
+    for p in small.pixels() {
+        let idx = ((p[0] as usize >> 4) << 8) | ((p[1] as usize >> 4) << 4) | (p[2] as usize >> 4);
+        let (sum, count) = &mut buckets[idx];
+        for (s, &v) in sum.iter_mut().zip(p.0.iter()) {
+            *s += v as f32;
+        }
+        *count += 1;
+    }
+    let colors: Vec<([f32; 3], u32)> = buckets
+        .into_iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(sum, count)| (sum.map(|s| s / count as f32), count))
+        .collect();
+    let to_rgb = |c: [f32; 3]| c.map(|v| v.round() as u8);
+
+    let dominant = colors
+        .iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(c, _)| *c)
+        .unwrap_or_default();
+    // weighted by saturation so that a vivid detail beats a large dull area,
+    // too dark or too bright colours don't make good accents
+    let score = |(c, n): &([f32; 3], u32)| *n as f32 * saturation(*c) * saturation(*c);
+    let accent = colors
+        .iter()
+        .filter(|(c, _)| {
+            saturation(*c) > 0.3 && (40.0..=230.0).contains(&luma(&image::Rgb(to_rgb(*c))))
+        })
+        .max_by(|a, b| score(a).total_cmp(&score(b)))
+        .map(|(c, _)| to_rgb(*c));
+
+    Palette {
+        dominant: to_rgb(dominant),
+        accent,
+    }
+}
+
+/// Writes a jpeg and a webp version of the image for every size to the storage, images are
+/// never upscaled. Returns the blurhash of the image.
+pub fn generate(img: &DynamicImage, thumb: &str, sizes: &[u32]) -> Result<String> {
+    for &size in sizes {
+        let (w, h) = img.dimensions();
+        let resized = if w > size || h > size {
+            img.resize(size, size, FilterType::Lanczos3)
+        } else {
+            img.clone()
+        };
+        let resized = DynamicImage::ImageRgb8(resized.to_rgb8());
+
+        let mut buf = vec![];
+        resized.write_to(
+            &mut std::io::Cursor::new(&mut buf),
+            ImageOutputFormat::Jpeg(75),
+        )?;
+        std::fs::write(
+            Path::new("storage").join(sized_name(thumb, size, false)),
+            &buf,
+        )?;
+
+        let webp = webp::Encoder::from_image(&resized)
+            .map_err(|e| anyhow!("could not encode webp: {}", e))?
+            .encode(75.0);
+        std::fs::write(
+            Path::new("storage").join(sized_name(thumb, size, true)),
+            &*webp,
+        )?;
+    }
+
+    let small = img.resize(32, 32, FilterType::Triangle).to_rgba8();
+    blurhash::encode(
+        BLURHASH_COMPONENTS.0,
+        BLURHASH_COMPONENTS.1,
+        small.width(),
+        small.height(),
+        small.as_raw(),
+    )
+    .map_err(|e| anyhow!("could not compute blurhash: {:?}", e))
+}
+
+/// Finds the file to serve for a thumbnail of at least the requested size, falling back to
+/// the biggest one. Returns the file name and its content type.
+pub fn pick(
+    c: &Connection,
+    id: MusicID,
+    size: Option<u32>,
+    webp: bool,
+) -> Result<Option<(String, &'static str)>> {
+    let thumb = unwrap_ret!(
+        Tag::by_id_key(c, id, TagKey::Thumbnail)?.and_then(|x| x.text),
+        Ok(None)
+    );
+    let sizes = sizes(c)?;
+    let size = size
+        .and_then(|s| sizes.iter().copied().find(|&x| x >= s))
+        .or_else(|| sizes.last().copied())
+        .context("no thumbnail size")?;
+
+    if Tag::by_id_key(c, id, TagKey::Blurhash)?.is_some() {
+        let name = sized_name(&thumb, size, webp);
+        if Path::new("storage").join(&name).exists() {
+            let content_type = if webp { "image/webp" } else { "image/jpeg" };
+            return Ok(Some((name, content_type)));
+        }
+    }
+    // not resized yet
+    Ok(Some((thumb, "image/jpeg")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sized_names() {
+        assert_eq!(sized_name("abc.jpg", 64, false), "thumb64.abc.jpg");
+        assert_eq!(sized_name("abc.jpg", 64, true), "thumb64.abc.jpg.webp");
+        assert_eq!(original_of_sized("thumb64.abc.jpg"), Some("abc.jpg"));
+        assert_eq!(original_of_sized("thumb512.abc.jpg.webp"), Some("abc.jpg"));
+        assert_eq!(original_of_sized("thumbnail.abc.jpg"), None);
+        assert_eq!(original_of_sized("compressed.abc.jpg"), None);
+    }
+
+    /// 16:9 frame with a square cover in the middle and black bars on the sides
+    fn letterboxed() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(160, 90, |x, y| {
+            if (35..125).contains(&x) {
+                image::Rgb([(x * 2) as u8, (y * 2) as u8, 128])
+            } else {
+                image::Rgb([5, 5, 5])
+            }
+        }))
+    }
+
+    #[test]
+    fn test_crop_square() {
+        let cropped = crop_square(&letterboxed()).unwrap();
+        assert_eq!(cropped.dimensions(), (90, 90));
+        assert_eq!(cropped.to_rgb8().get_pixel(0, 0)[0], 70);
+
+        // the detailed part of the image is kept
+        let wide = DynamicImage::ImageRgb8(RgbImage::from_fn(300, 100, |x, y| {
+            if x > 180 && (x + y) % 2 == 0 {
+                image::Rgb([255, 255, 255])
+            } else {
+                image::Rgb([100, 100, 100])
+            }
+        }));
+        let cropped = crop_square(&wide).unwrap();
+        assert_eq!(cropped.dimensions(), (100, 100));
+        let white = cropped.to_rgb8().pixels().filter(|p| p[0] == 255).count();
+        assert!(white > 100 * 100 * 45 / 100, "{}", white);
+
+        // no detail anywhere, the center is kept
+        let flat = DynamicImage::ImageRgb8(RgbImage::from_pixel(100, 300, image::Rgb([90, 0, 0])));
+        assert_eq!(crop_square(&flat).unwrap().dimensions(), (100, 100));
+
+        let square =
+            DynamicImage::ImageRgb8(RgbImage::from_pixel(100, 102, image::Rgb([90, 0, 0])));
+        assert!(crop_square(&square).is_none());
+    }
+
+    #[test]
+    fn test_palette() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(100, 100, |x, y| {
+            if x < 20 && y < 20 {
+                image::Rgb([220, 20, 30])
+            } else {
+                image::Rgb([120, 120, 120])
+            }
+        }));
+        let p = palette(&img);
+        assert_eq!(hex_color(p.dominant), "#787878");
+        let accent = p.accent.unwrap();
+        assert!(accent[0] > 200 && accent[1] < 40, "{:?}", accent);
+
+        let grey = DynamicImage::ImageRgb8(RgbImage::from_pixel(10, 10, image::Rgb([30, 30, 30])));
+        let p = palette(&grey);
+        assert_eq!(hex_color(p.dominant), "#1e1e1e");
+        assert!(p.accent.is_none());
+    }
+}
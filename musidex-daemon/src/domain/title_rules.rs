@@ -0,0 +1,277 @@
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::utils::collect_rows;
+use anyhow::{Context, Result};
+use nanoserde::{DeJson, SerJson};
+use regex::Regex;
+use rusqlite::Connection;
+
+/// A user editable rule used to clean titles, applied in order.
+///  - replace: replaces all matches of the pattern with the replacement
+///  - split: splits the title in two at the first match of the pattern, the left part being the artist
+///  - feat: removes all matches of the pattern, the first capture group being a featured artist
+#[derive(Clone, Debug, PartialEq, Eq, SerJson, DeJson)]
+pub struct TitleRule {
+    pub kind: String,
+    pub pattern: String,
+    #[nserde(default)]
+    pub replacement: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, SerJson)]
+pub struct GuessedTitle {
+    pub title: String,
+    pub artist: Option<String>,
+    pub featured: Vec<String>,
+}
+
+enum RuleKind {
+    Replace(String),
+    Split,
+    Feat,
+}
+
+pub struct TitleRules(Vec<(RuleKind, Regex)>);
+
+impl TitleRule {
+    pub fn list(c: &Connection) -> Result<Vec<TitleRule>> {
+        Self::list_from(c, "title_rules")
+    }
+
+    /// The rules the titles of the library were last produced with
+    pub fn list_applied(c: &Connection) -> Result<Vec<TitleRule>> {
+        Self::list_from(c, "title_rules_applied")
+    }
+
+    fn list_from(c: &Connection, table: &str) -> Result<Vec<TitleRule>> {
+        let mut stmt = c.prepare_cached(&format!(
+            "SELECT kind, pattern, replacement FROM {} ORDER BY position;",
+            table
+        ))?;
+        let v = stmt.query_map([], |row| {
+            Ok(TitleRule {
+                kind: row.get("kind")?,
+                pattern: row.get("pattern")?,
+                replacement: row.get("replacement")?,
+            })
+        })?;
+        collect_rows(v)
+    }
+
+    /// Replaces the whole rule list, rules must have been validated with TitleRules::compile before
+    pub fn replace_all(c: &mut Connection, rules: &[TitleRule]) -> Result<()> {
+        let tx = c.transaction().context("transaction begin failed")?;
+        Self::write_to(&tx, "title_rules", rules)?;
+        tx.commit().context("transaction commit failed")?;
+        Ok(())
+    }
+
+    fn write_to(c: &Connection, table: &str, rules: &[TitleRule]) -> Result<()> {
+        c.execute(&format!("DELETE FROM {};", table), [])?;
+        for (position, rule) in rules.iter().enumerate() {
+            c.prepare_cached(&format!(
+                "INSERT INTO {} (position, kind, pattern, replacement)
+                 VALUES (?1, ?2, ?3, ?4);",
+                table
+            ))?
+            .execute(rusqlite::params![
+                position as i32,
+                rule.kind,
+                rule.pattern,
+                rule.replacement
+            ])?;
+        }
+        Ok(())
+    }
+}
+
+impl TitleRules {
+    pub fn compile(rules: &[TitleRule]) -> Result<Self> {
+        let mut compiled = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let re = Regex::new(&rule.pattern)
+                .with_context(|| format!("invalid pattern: {}", &rule.pattern))?;
+            let kind = match &*rule.kind {
+                "replace" => RuleKind::Replace(rule.replacement.clone()),
+                "split" => RuleKind::Split,
+                "feat" => RuleKind::Feat,
+                x => bail!("unknown rule kind: {}", x),
+            };
+            compiled.push((kind, re));
+        }
+        Ok(Self(compiled))
+    }
+
+    pub fn load(c: &Connection) -> Result<Self> {
+        Self::compile(&TitleRule::list(c)?)
+    }
+
+    pub fn load_applied(c: &Connection) -> Result<Self> {
+        Self::compile(&TitleRule::list_applied(c)?)
+    }
+
+    /// Guess track, artist and featured artists from a title
+    pub fn apply(&self, title: &str) -> GuessedTitle {
+        let mut title = s!(title.trim());
+        let mut artist: Option<String> = None;
+        let mut featured = vec![];
+
+        for (kind, re) in &self.0 {
+            match kind {
+                RuleKind::Replace(replacement) => {
+                    title = s!(re.replace_all(&title, replacement.as_str()).trim());
+                }
+                RuleKind::Split => {
+                    if artist.is_some() {
+                        continue;
+                    }
+                    let sp: Vec<_> = re.splitn(&title, 2).collect();
+                    if sp.len() != 2 {
+                        continue;
+                    }
+                    let (a, t) = (sp[0].trim(), sp[1].trim());
+                    if a.is_empty() || t.is_empty() {
+                        continue;
+                    }
+                    artist = Some(s!(a));
+                    title = s!(t);
+                }
+                RuleKind::Feat => {
+                    for text in std::iter::once(&mut title).chain(artist.as_mut()) {
+                        for cap in re.captures_iter(text) {
+                            if let Some(f) = cap.get(1) {
+                                let f = f.as_str().trim();
+                                if !f.is_empty() {
+                                    featured.push(s!(f));
+                                }
+                            }
+                        }
+                        *text = s!(re.replace_all(text, "").trim());
+                    }
+                }
+            }
+        }
+
+        GuessedTitle {
+            title,
+            artist,
+            featured,
+        }
+    }
+}
+
+#[derive(SerJson)]
+pub struct TitlePreview {
+    pub music_id: MusicID,
+    pub original: String,
+    pub current_title: Option<String>,
+    pub current_artist: Option<String>,
+    pub guessed: GuessedTitle,
+}
+
+/// Applies the rules to the original title of every music of the library.
+/// Long videos are skipped since their title is usually the name of a mix.
+pub fn preview(c: &Connection, rules: &TitleRules) -> Result<Vec<TitlePreview>> {
+    let mut previews = vec![];
+    for original in Tag::by_key(c, TagKey::YoutubeDLOriginalTitle)? {
+        let id = original.music_id;
+        let text = unwrap_cont!(original.text);
+        if let Some(d) = Tag::by_id_key(c, id, TagKey::Duration)? {
+            if d.integer.unwrap_or(0) > 20 * 60 {
+                continue;
+            }
+        }
+        let current = |key| -> Result<Option<String>> {
+            Ok(Tag::by_id_key(c, id, key)?.and_then(|x| x.text))
+        };
+        previews.push(TitlePreview {
+            music_id: id,
+            current_title: current(TagKey::Title)?,
+            current_artist: current(TagKey::Artist)?,
+            guessed: rules.apply(&text),
+            original: text,
+        });
+    }
+    Ok(previews)
+}
+
+/// Re-applies the saved rules to the library, returns the number of musics that changed.
+/// Titles, artists and featured artists that differ from what the previously applied rules
+/// produced were edited by hand, so they are left alone.
+pub fn apply_to_library(c: &mut Connection) -> Result<usize> {
+    let saved = TitleRule::list(c)?;
+    let rules = TitleRules::compile(&saved)?;
+    let previous = TitleRules::load_applied(c)?;
+    let previews = preview(c, &rules)?;
+
+    let tx = c.transaction().context("transaction begin failed")?;
+    let mut changed = 0;
+    for p in previews {
+        let id = p.music_id;
+        let old = previous.apply(&p.original);
+        let g = p.guessed;
+        if let Some(ref current) = p.current_title {
+            if *current != old.title && *current != g.title {
+                continue;
+            }
+        }
+
+        let mut did_change = false;
+        if p.current_title.as_ref() != Some(&g.title) {
+            Tag::insert(&tx, Tag::new_text(id, TagKey::Title, g.title))?;
+            did_change = true;
+        }
+        // the artist and featured artists are only touched if they were not edited by hand either
+        if p.current_artist.is_none() || p.current_artist == old.artist {
+            match g.artist {
+                Some(artist) => {
+                    if p.current_artist.as_ref() != Some(&artist) {
+                        Tag::insert(&tx, Tag::new_text(id, TagKey::Artist, artist))?;
+                        did_change = true;
+                    }
+                }
+                None => {
+                    if p.current_artist.is_some() {
+                        Tag::remove(&tx, id, TagKey::Artist)?;
+                        did_change = true;
+                    }
+                }
+            }
+        }
+        let joined = |featured: Vec<String>| Some(featured.join(", ")).filter(|x| !x.is_empty());
+        let current_feat = Tag::by_id_key(&tx, id, TagKey::FeaturedArtist)?.and_then(|x| x.text);
+        let feat = joined(g.featured);
+        let feat_untouched = current_feat.is_none() || current_feat == joined(old.featured);
+        if feat_untouched && current_feat != feat {
+            match feat {
+                Some(feat) => Tag::insert(&tx, Tag::new_text(id, TagKey::FeaturedArtist, feat))?,
+                None => Tag::remove(&tx, id, TagKey::FeaturedArtist)?,
+            }
+            did_change = true;
+        }
+        if did_change {
+            changed += 1;
+        }
+    }
+    TitleRule::write_to(&tx, "title_rules_applied", &saved)?;
+    tx.commit().context("transaction commit failed")?;
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(kind: &str, pattern: &str, replacement: &str) -> TitleRule {
+        TitleRule {
+            kind: s!(kind),
+            pattern: s!(pattern),
+            replacement: s!(replacement),
+        }
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(TitleRules::compile(&[rule("replace", "(", "")]).is_err());
+        assert!(TitleRules::compile(&[rule("explode", "a", "")]).is_err());
+    }
+}
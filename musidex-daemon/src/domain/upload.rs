@@ -1,11 +1,15 @@
 use crate::domain::entity::{Music, MusicID, Tag, TagKey, UserID};
-use crate::infrastructure::youtube_dl::{ytdl_run_with_args, SingleVideo, YoutubeDlOutput};
+use crate::domain::title_rules::{GuessedTitle, TitleRules};
+use crate::infrastructure::youtube_dl::{
+    ytdl_run_with_args, SingleVideo, YoutubeDlConfig, YoutubeDlOutput,
+};
 use anyhow::{Context, Result};
 use hyper::StatusCode;
 use rusqlite::Connection;
 
 pub async fn youtube_upload(c: &mut Connection, url: String, uid: UserID) -> Result<StatusCode> {
-    let metadata = ytdl_run_with_args(vec!["--no-playlist", "-J", "--", &url])
+    let cfg = YoutubeDlConfig::load(c)?;
+    let metadata = ytdl_run_with_args(&cfg, vec!["--no-playlist", "-J", "--", &url])
         .await
         .context("error downloading metadata")?;
     let mut v = match metadata {
@@ -21,8 +25,9 @@ pub async fn youtube_upload(c: &mut Connection, url: String, uid: UserID) -> Res
         return Ok(StatusCode::OK);
     }
     let wp = v.webpage_url.take().context("no webpage url")?;
+    let rules = TitleRules::load(c)?;
     let tx = c.transaction()?;
-    push_for_treatment(&tx, v, wp, uid).context("error pushing for treatment")?;
+    push_for_treatment(&tx, &rules, v, wp, uid).context("error pushing for treatment")?;
     tx.commit()?;
     Ok(StatusCode::OK)
 }
@@ -39,16 +44,42 @@ fn id_exists(c: &Connection, id: &str) -> Result<Option<MusicID>> {
         }))
 }
 
-fn push_for_treatment(c: &Connection, v: Box<SingleVideo>, url: String, uid: UserID) -> Result<()> {
+/// Adds a video found by a search to the library of the user, enqueuing it for download
+/// if it is not known yet
+pub fn enqueue_video(c: &mut Connection, mut v: Box<SingleVideo>, uid: UserID) -> Result<MusicID> {
+    if let Some(mid) = id_exists(c, &v.id)? {
+        Tag::insert(c, Tag::new_key(mid, TagKey::UserLibrary(s!(uid))))?;
+        return Ok(mid);
+    }
+    let url = v
+        .url
+        .take()
+        .or_else(|| v.webpage_url.take())
+        .context("no url")?;
+    let rules = TitleRules::load(c)?;
+    let tx = c.transaction()?;
+    let id = push_for_treatment(&tx, &rules, v, url, uid).context("error pushing for treatment")?;
+    tx.commit()?;
+    Ok(id)
+}
+
+fn push_for_treatment(
+    c: &Connection,
+    rules: &TitleRules,
+    v: Box<SingleVideo>,
+    url: String,
+    uid: UserID,
+) -> Result<MusicID> {
     let id = Music::mk(&c)?;
 
     let mk_tag = |key, v| Tag::insert(&c, Tag::new_text(id, key, v));
 
-    let (title, artist) = parse_title(&v.title, &v);
+    let guessed = parse_title(rules, &v.title, &v);
+    insert_video_metadata(c, id, &v)?;
     mk_tag(TagKey::YoutubeDLURL, url)?;
     mk_tag(TagKey::YoutubeDLVideoID, v.id)?;
     mk_tag(TagKey::YoutubeDLWorkerTreated, s!("false"))?;
-    mk_tag(TagKey::Title, title)?;
+    mk_tag(TagKey::Title, guessed.title)?;
     if let Some(v) = v.duration {
         Tag::insert(
             &c,
@@ -65,56 +96,88 @@ fn push_for_treatment(c: &Connection, v: Box<SingleVideo>, url: String, uid: Use
     if let Some(p) = v.playlist_title {
         mk_tag(TagKey::YoutubeDLPlaylist, p)?;
     }
-    if let Some(artist) = artist {
+    if let Some(artist) = guessed.artist {
         mk_tag(TagKey::Artist, artist)?;
     }
+    if !guessed.featured.is_empty() {
+        mk_tag(TagKey::FeaturedArtist, guessed.featured.join(", "))?;
+    }
     mk_tag(TagKey::YoutubeDLOriginalTitle, v.title)?;
     Tag::insert(&c, Tag::new_key(id, TagKey::UserLibrary(s!(uid))))?;
+    Ok(id)
+}
+
+/// Imports the upload metadata given by yt-dlp (album, year, uploader...) as tags
+pub fn insert_video_metadata(c: &Connection, id: MusicID, v: &SingleVideo) -> Result<()> {
+    let mk_tag = |key, v: String| Tag::insert(c, Tag::new_parse(id, key, v));
+
+    if let Some(ref album) = v.album {
+        mk_tag(TagKey::Album, album.clone())?;
+    }
+    if let Some(year) = v.release_year {
+        mk_tag(TagKey::Year, year.to_string())?;
+    }
+    if let Some(ref genre) = v.genre {
+        mk_tag(TagKey::Genre, genre.clone())?;
+    }
+    if let Some(n) = v.track_number {
+        mk_tag(TagKey::TrackNumber, n.to_string())?;
+    }
+    if let Some(uploader) = v.channel.as_ref().or(v.uploader.as_ref()) {
+        mk_tag(TagKey::Uploader, uploader.clone())?;
+    }
+    if let Some(ref date) = v.upload_date {
+        mk_tag(TagKey::UploadDate, format_upload_date(date))?;
+    }
+    if let Some(ref desc) = v.description {
+        if !desc.trim().is_empty() {
+            Tag::insert(c, Tag::new_text(id, TagKey::Description, desc.clone()))?;
+        }
+    }
+    if let Some(ref tags) = v.tags {
+        let tags: Vec<&str> = tags.iter().flatten().map(|x| x.as_str()).collect();
+        if !tags.is_empty() {
+            Tag::insert(c, Tag::new_text(id, TagKey::YoutubeDLTags, tags.join(", ")))?;
+        }
+    }
     Ok(())
 }
 
-lazy_static::lazy_static! {
-    static ref OFFICIAL_REMOVER: regex::Regex = regex::RegexBuilder::new(r"(\(|\[)((official|video|hq|vidéo|officielle)\s?-?\s?)+(\]|\))").case_insensitive(true).build().unwrap();
+/// yt-dlp gives dates as YYYYMMDD, which are not understood by the date parser
+fn format_upload_date(date: &str) -> String {
+    if date.len() == 8 && date.chars().all(|x| x.is_ascii_digit()) {
+        return format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..]);
+    }
+    s!(date)
 }
 
-// Returns title and artist from title
-fn parse_title(title: &str, v: &SingleVideo) -> (String, Option<String>) {
+// Returns title, artist and featured artists from title
+fn parse_title(rules: &TitleRules, title: &str, v: &SingleVideo) -> GuessedTitle {
     if let Some(d) = v.duration {
         if d > 20.0 * 60.0 {
-            return (String::from(title), None);
+            return GuessedTitle {
+                title: String::from(title),
+                artist: None,
+                featured: vec![],
+            };
         }
     }
     if let (Some(track), Some(artist)) = (&v.track, &v.artist) {
-        return (track.clone(), Some(artist.clone()));
+        return GuessedTitle {
+            title: track.clone(),
+            artist: Some(artist.clone()),
+            featured: vec![],
+        };
     }
-    let (mut gtrack, mut gartist) = guess_title(title);
+    let mut guessed = rules.apply(title);
     if let Some(ref x) = v.artist {
-        gartist = Some(x.clone());
+        guessed.artist = Some(x.clone());
     }
     if let Some(ref x) = v.track {
-        gtrack = x.clone();
+        guessed.title = x.clone();
     }
 
-    (gtrack, gartist)
-}
-
-/// Guess track and artist from title
-fn guess_title(title: &str) -> (String, Option<String>) {
-    let title = OFFICIAL_REMOVER.replace_all(title, "");
-    let title = title.trim();
-    let sp: Vec<_> = title.splitn(2, " - ").collect();
-
-    if sp.len() == 2 {
-        let artist = sp[0].trim();
-        let actual_title = sp[1].trim();
-
-        if artist.is_empty() || actual_title.is_empty() {
-            return (s!(title), None);
-        }
-
-        return (s!(actual_title), Some(s!(artist)));
-    }
-    (s!(title), None)
+    guessed
 }
 
 pub async fn youtube_upload_playlist(
@@ -148,10 +211,12 @@ pub async fn youtube_upload_playlist(
     args.extend_from_slice(&["--flat-playlist", "--yes-playlist", "-J", "--"]);
     args.push(&url);
 
-    let metadata = ytdl_run_with_args(args)
+    let cfg = YoutubeDlConfig::load(c)?;
+    let metadata = ytdl_run_with_args(&cfg, args)
         .await
         .context("failed reading playlist metadata")?;
 
+    let rules = TitleRules::load(c)?;
     match metadata {
         YoutubeDlOutput::Playlist(p) => {
             if p.entries.as_ref().map(|x| x.is_empty()).unwrap_or(true) {
@@ -182,7 +247,7 @@ pub async fn youtube_upload_playlist(
                 entry.playlist_title = p.title.clone();
                 let url = entry.url.take().context("no url?")?;
 
-                push_for_treatment(&tx, entry, url, uid)?;
+                push_for_treatment(&tx, &rules, entry, url, uid)?;
                 tx.commit()?;
             }
             return Ok((StatusCode::OK, count));
@@ -198,84 +263,9 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_official_remover_regex() {
-        let should_match = &[
-            "(Official)",
-            "[Official]",
-            "[Official - Video]",
-            "(Video)",
-            "[HQ]",
-            "[HQ - Video]",
-            "(official video)",
-            "(official - video)",
-        ];
-
-        let should_not_match = &[
-            "",
-            "official",
-            "official video",
-            " - ",
-            "(official",
-            "official)",
-            "Video",
-            "(ft. Lil B)",
-            "(homer)",
-        ];
-
-        for v in should_match {
-            assert!(OFFICIAL_REMOVER.is_match(v), "should match: {}", v);
-        }
-
-        for v in should_not_match {
-            assert!(!OFFICIAL_REMOVER.is_match(v), "should not match: {}", v);
-        }
-    }
-
-    #[test]
-    fn test_artist_split() {
-        let should_split = &[
-            (
-                "Jamiroquai - Virtual Insanity (Official Video)",
-                "Jamiroquai",
-                "Virtual Insanity",
-            ),
-            (
-                "Earth, Wind & Fire - September (Official Video)",
-                "Earth, Wind & Fire",
-                "September",
-            ),
-            (
-                "Breakbot - Baby I'm Yours (feat. Irfane) [Official Video]",
-                "Breakbot",
-                "Baby I'm Yours (feat. Irfane)",
-            ),
-            (" a - b ", "a", "b"),
-            ("a - b", "a", "b"),
-            (" a - b - c ", "a", "b - c"),
-        ];
-
-        let should_not_split = &[
-            "Baby I'm Yours (feat. Irfane)",
-            "Mr. Blue. Sky",
-            "a -",
-            "-",
-            "a-a",
-            "a -ba",
-            "a- ba",
-            "Princess Mononoke Suite「03. TA-TA-RI-GAMI (The Demon God)」",
-            "",
-        ];
-
-        for (title, artist, track) in should_split {
-            let (gtrack, gartist) = guess_title(title);
-            assert_eq!(&*gtrack, *track);
-            assert_eq!(&*gartist.unwrap(), *artist);
-        }
-
-        for v in should_not_split {
-            let (gtrack, gartist) = guess_title(v);
-            assert!(gartist.is_none());
-            assert_eq!(&*gtrack, *v);
-        }
+    fn test_format_upload_date() {
+        assert_eq!(format_upload_date("20210312"), "2021-03-12");
+        assert_eq!(format_upload_date("2021-03-12"), "2021-03-12");
+        assert_eq!(format_upload_date("2021"), "2021");
     }
 }
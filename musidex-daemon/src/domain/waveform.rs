@@ -0,0 +1,120 @@
+use nanoserde::SerJson;
+
+/// Number of min/max pairs of a waveform
+pub const WAVEFORM_LENGTH: usize = 1000;
+/// Resolution at which peaks are kept while decoding, before being reduced to WAVEFORM_LENGTH
+const SAMPLES_PER_PEAK: usize = 256;
+
+/// Waveform in the audiowaveform JSON format, so it can be used directly by players like peaks.js
+#[derive(Clone, Debug, PartialEq, SerJson)]
+pub struct Waveform {
+    pub version: u32,
+    pub channels: u32,
+    pub sample_rate: u32,
+    pub samples_per_pixel: u32,
+    pub bits: u32,
+    pub length: u32,
+    /// min and max of each pixel, interleaved, between -128 and 127
+    pub data: Vec<i8>,
+}
+
+/// Accumulates min/max peaks of a mono downmix of a stream
+pub struct PeaksBuilder {
+    sample_rate: u32,
+    peaks: Vec<(f32, f32)>,
+    current: (f32, f32),
+    count: usize,
+    total: usize,
+}
+
+impl PeaksBuilder {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            peaks: vec![],
+            current: (f32::MAX, f32::MIN),
+            count: 0,
+            total: 0,
+        }
+    }
+
+    /// Feeds interleaved samples
+    pub fn push(&mut self, samples: &[f32], channels: usize) {
+        for frame in samples.chunks_exact(channels) {
+            let x = frame.iter().sum::<f32>() / channels as f32;
+            self.current = (self.current.0.min(x), self.current.1.max(x));
+            self.count += 1;
+            self.total += 1;
+            if self.count == SAMPLES_PER_PEAK {
+                self.peaks.push(self.current);
+                self.current = (f32::MAX, f32::MIN);
+                self.count = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Option<Waveform> {
+        if self.count > 0 {
+            self.peaks.push(self.current);
+        }
+        if self.peaks.is_empty() {
+            return None;
+        }
+
+        let length = WAVEFORM_LENGTH.min(self.peaks.len());
+        let quantize = |x: f32| (x.clamp(-1.0, 1.0) * 127.0).round() as i8;
+        let mut data = Vec::with_capacity(length * 2);
+        for i in 0..length {
+            let chunk =
+                &self.peaks[i * self.peaks.len() / length..(i + 1) * self.peaks.len() / length];
+            let min = chunk.iter().map(|x| x.0).fold(f32::MAX, f32::min);
+            let max = chunk.iter().map(|x| x.1).fold(f32::MIN, f32::max);
+            data.push(quantize(min));
+            data.push(quantize(max));
+        }
+
+        Some(Waveform {
+            version: 2,
+            channels: 1,
+            sample_rate: self.sample_rate,
+            samples_per_pixel: (self.total / length) as u32,
+            bits: 8,
+            length: length as u32,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peaks() {
+        let mut b = PeaksBuilder::new(44100);
+        // one second of silence then one second of a stereo square wave at half volume
+        b.push(&vec![0.0; 2 * 44100], 2);
+        let square: Vec<f32> = (0..44100)
+            .flat_map(|i| {
+                let x = if i % 100 < 50 { 0.5 } else { -0.5 };
+                [x, x]
+            })
+            .collect();
+        b.push(&square, 2);
+
+        let w = b.finish().unwrap();
+        assert_eq!(w.length, 345);
+        assert_eq!(w.data.len(), 345 * 2);
+        assert_eq!(w.samples_per_pixel, 88200 / 345);
+        assert_eq!(&w.data[..4], &[0, 0, 0, 0]);
+        assert_eq!(&w.data[w.data.len() - 2..], &[-64, 64]);
+
+        let mut b = PeaksBuilder::new(44100);
+        b.push(&vec![0.1; 44100 * 60], 1);
+        let w = b.finish().unwrap();
+        assert_eq!(w.length as usize, WAVEFORM_LENGTH);
+        assert!(w.data.iter().all(|&x| x == 13));
+
+        assert!(PeaksBuilder::new(44100).finish().is_none());
+    }
+}
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+use crate::domain::analysis;
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::stream::MusicSource;
+use crate::infrastructure::audio;
+use crate::infrastructure::db::Db;
+use crate::utils::row_missing_opt;
+
+/// Estimates the tempo and key of musics.
+/// Codecs symphonia cannot decode (Opus) need ffmpeg, otherwise the music is left without them.
+pub struct AnalysisWorker {
+    db: Db,
+}
+
+impl AnalysisWorker {
+    pub fn new(db: Db) -> Self {
+        AnalysisWorker { db }
+    }
+
+    pub fn start(mut self) {
+        tokio::spawn(async move {
+            loop {
+                let v = self
+                    .step()
+                    .await
+                    .context("error while running analysis worker");
+                match v {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => log::error!("{:?}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+
+    /// Analyzes one music, returns whether there was one to analyze
+    pub async fn step(&mut self) -> Result<bool> {
+        let (candidate, source) = {
+            let c = self.db.get().await;
+            let candidate = unwrap_ret!(find_candidate(&c)?, Ok(false));
+            (candidate, MusicSource::find(&c, candidate)?)
+        };
+
+        let res = match source {
+            Some(source) => {
+                tokio::task::spawn_blocking(move || -> Result<_> {
+                    let samples = audio::decode_mono(
+                        &source.file_path(),
+                        source.chapter_start.unwrap_or(0.0),
+                        source.chapter_end,
+                        analysis::SAMPLE_RATE,
+                        analysis::MAX_DURATION,
+                    )?;
+                    Ok((
+                        analysis::estimate_bpm(&samples),
+                        analysis::estimate_key(&samples),
+                    ))
+                })
+                .await?
+            }
+            None => Err(anyhow!("no source")),
+        };
+
+        let c = self.db.get().await;
+        let (bpm, key) = match res {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("could not analyze {:?}: {:?}", candidate, e);
+                (None, None)
+            }
+        };
+        log::info!("analyzed {:?}: bpm={:?} key={:?}", candidate, bpm, key);
+
+        if let Some(key) = key {
+            Tag::insert(&c, Tag::new_text(candidate, TagKey::Key, key))?;
+        }
+        match bpm.map(|x| x.round() as i32) {
+            Some(bpm) => Tag::insert(
+                &c,
+                Tag {
+                    text: Some(bpm.to_string()),
+                    integer: Some(bpm),
+                    ..Tag::new_key(candidate, TagKey::Bpm)
+                },
+            )?,
+            None => Tag::mark_treated(&c, candidate, TagKey::Bpm)?,
+        }
+        Ok(true)
+    }
+}
+
+pub fn find_candidate(c: &Connection) -> Result<Option<MusicID>> {
+    let mut stmt = c.prepare_cached(&format!(
+        "
+    SELECT music_id as id2 FROM tags
+    WHERE key IN ({})
+    AND
+        (SELECT COUNT(1) FROM tags
+         WHERE music_id = id2 AND key='bpm') = 0
+    AND
+        (SELECT COUNT(1) FROM tags
+         WHERE music_id = id2 AND key='duration' AND integer>30*60) = 0
+    LIMIT 1;
+    ",
+        TagKey::local_sources_sql()
+    ))?;
+    let v = stmt.query_row([], |x| x.get("id2").map(MusicID).map(Some));
+    row_missing_opt(v).context("failed getting id")
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+use crate::domain::cover_art::{find_sidecar, save_cover, set_thumbnail};
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::stream::MusicSource;
+use crate::infrastructure::audio;
+use crate::infrastructure::db::Db;
+use crate::utils::row_missing_opt;
+
+pub struct CoverArtWorker {
+    db: Db,
+}
+
+impl CoverArtWorker {
+    pub fn new(db: Db) -> Self {
+        CoverArtWorker { db }
+    }
+
+    pub fn start(mut self) {
+        tokio::spawn(async move {
+            loop {
+                let v = self
+                    .step()
+                    .await
+                    .context("error while running cover art worker");
+                match v {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => log::error!("{:?}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+
+    /// Looks for the cover of one music without thumbnail, returns whether there was one to look for
+    pub async fn step(&mut self) -> Result<bool> {
+        let (candidate, source) = {
+            let c = self.db.get().await;
+            let candidate = unwrap_ret!(find_candidate(&c)?, Ok(false));
+            (candidate, MusicSource::find(&c, candidate)?)
+        };
+
+        let res = match source {
+            Some(source) => {
+                tokio::task::spawn_blocking(move || -> Result<Option<String>> {
+                    let path = source.file_path();
+                    if let Some(pic) = audio::embedded_picture(&path)? {
+                        return save_cover(candidate, &pic.data)
+                            .with_context(|| format!("invalid embedded {}", pic.media_type))
+                            .map(Some);
+                    }
+                    match find_sidecar(&path) {
+                        Some(sidecar) => save_cover(candidate, &std::fs::read(sidecar)?).map(Some),
+                        None => Ok(None),
+                    }
+                })
+                .await?
+            }
+            None => Err(anyhow!("no source")),
+        };
+
+        let c = self.db.get().await;
+        match res {
+            Ok(Some(fname)) => {
+                log::info!("found cover art of {:?}", candidate);
+                set_thumbnail(&c, candidate, fname)?;
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("could not extract cover art of {:?}: {:?}", candidate, e),
+        }
+        Tag::insert(&c, Tag::new_key(candidate, TagKey::CoverArtWorkerTreated))?;
+        Ok(true)
+    }
+}
+
+pub fn find_candidate(c: &Connection) -> Result<Option<MusicID>> {
+    let mut stmt = c.prepare_cached(&format!(
+        "
+    SELECT music_id as id2 FROM tags
+    WHERE key IN ({})
+    AND
+        (SELECT COUNT(1) FROM tags
+         WHERE music_id = id2 AND key IN ('thumbnail', 'cover_art_worker_treated')) = 0
+    LIMIT 1;
+    ",
+        TagKey::local_sources_sql()
+    ))?;
+    let v = stmt.query_row([], |x| x.get("id2").map(MusicID).map(Some));
+    row_missing_opt(v).context("failed getting id")
+}
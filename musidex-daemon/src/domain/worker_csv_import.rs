@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::domain::csv_import::{
+    self, PendingRow, RowResult, RowStatus, MIN_CONFIDENCE, REVIEW_CONFIDENCE,
+};
+use crate::domain::upload::enqueue_video;
+use crate::infrastructure::db::Db;
+use crate::infrastructure::youtube_dl::{ytdl_run_with_args, YoutubeDlConfig, YoutubeDlOutput};
+
+/// Pause between two searches, not to hammer youtube when importing large playlists
+const SEARCH_DELAY: Duration = Duration::from_secs(2);
+
+/// Searches the rows of csv imports on youtube one by one and enqueues the best results
+pub struct CsvImportWorker {
+    db: Db,
+}
+
+impl CsvImportWorker {
+    pub fn new(db: Db) -> Self {
+        CsvImportWorker { db }
+    }
+
+    pub fn start(mut self) {
+        tokio::spawn(async move {
+            loop {
+                let v = self
+                    .step()
+                    .await
+                    .context("error while running csv import worker");
+                let wait = match v {
+                    Ok(true) => SEARCH_DELAY,
+                    Ok(false) => Duration::from_secs(5),
+                    Err(e) => {
+                        log::error!("{:?}", e);
+                        Duration::from_secs(5)
+                    }
+                };
+                tokio::time::sleep(wait).await;
+            }
+        });
+    }
+
+    /// Searches one row, returns whether there was one.
+    /// A row that fails is marked as an error so that it isn't picked again.
+    pub async fn step(&mut self) -> Result<bool> {
+        let (cfg, pending) = {
+            let c = self.db.get().await;
+            let pending = unwrap_ret!(csv_import::next_pending(&c)?, Ok(false));
+            (YoutubeDlConfig::load(&c)?, pending)
+        };
+
+        let saved = match self.search(&cfg, &pending).await {
+            Ok(res) => csv_import::set_result(&*self.db.get().await, &pending, res),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            log::warn!(
+                "csv import {}: failed treating row {}: {:?}",
+                pending.import_id,
+                pending.row,
+                e
+            );
+            csv_import::set_result(
+                &*self.db.get().await,
+                &pending,
+                RowResult {
+                    status: RowStatus::Error,
+                    video: None,
+                    confidence: None,
+                    music_id: None,
+                },
+            )?;
+        }
+        Ok(true)
+    }
+
+    /// Searches the row on youtube and enqueues the best result if it is close enough
+    async fn search(&self, cfg: &YoutubeDlConfig, pending: &PendingRow) -> Result<RowResult> {
+        let query = csv_import::search_query(&pending.track);
+        let results = match ytdl_run_with_args(cfg, vec!["--flat-playlist", "-J", "--", &query])
+            .await
+            .with_context(|| format!("failed searching {}", &query))?
+        {
+            YoutubeDlOutput::Playlist(p) => p.entries.unwrap_or_default(),
+            YoutubeDlOutput::SingleVideo(v) => vec![v],
+        };
+
+        let (video, confidence) = match csv_import::best_candidate(&pending.track, results) {
+            Some((v, conf)) if conf >= MIN_CONFIDENCE => (v, conf),
+            best => {
+                return Ok(RowResult {
+                    status: RowStatus::NotFound,
+                    video: best.as_ref().map(|x| (x.0.id.clone(), x.0.title.clone())),
+                    confidence: best.as_ref().map(|x| x.1),
+                    music_id: None,
+                });
+            }
+        };
+        let status = if confidence >= REVIEW_CONFIDENCE {
+            RowStatus::Matched
+        } else {
+            RowStatus::LowConfidence
+        };
+
+        let (id, title) = (video.id.clone(), video.title.clone());
+        let music_id = enqueue_video(&mut *self.db.get().await, video, pending.uid)?;
+        log::info!(
+            "csv import {}: {:?} found as {} ({:.2})",
+            pending.import_id,
+            &pending.track.track,
+            &title,
+            confidence
+        );
+        Ok(RowResult {
+            status,
+            video: Some((id, title)),
+            confidence: Some(confidence),
+            music_id: Some(music_id),
+        })
+    }
+}
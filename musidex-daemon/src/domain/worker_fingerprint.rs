@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::fingerprint;
+use crate::domain::stream::MusicSource;
+use crate::infrastructure::audio;
+use crate::infrastructure::db::Db;
+
+pub struct FingerprintWorker {
+    db: Db,
+}
+
+impl FingerprintWorker {
+    pub fn new(db: Db) -> Self {
+        FingerprintWorker { db }
+    }
+
+    pub fn start(mut self) {
+        tokio::spawn(async move {
+            loop {
+                let v = self
+                    .step()
+                    .await
+                    .context("error while running fingerprint worker");
+                match v {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => log::error!("{:?}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+
+    /// Fingerprints one music, returns whether there was one to fingerprint
+    pub async fn step(&mut self) -> Result<bool> {
+        let (candidate, source) = {
+            let c = self.db.get().await;
+            let candidate = unwrap_ret!(find_candidate(&c)?, Ok(false));
+            (candidate, MusicSource::find(&c, candidate)?)
+        };
+        let source = match source {
+            Some(x) => x,
+            None => {
+                let c = self.db.get().await;
+                Tag::mark_treated(&c, candidate, TagKey::Fingerprint)?;
+                return Ok(true);
+            }
+        };
+
+        let fp = tokio::task::spawn_blocking(move || -> Result<Vec<u32>> {
+            let start = source.chapter_start.unwrap_or(0.0);
+            let samples = audio::decode_mono(
+                &source.file_path(),
+                start,
+                source.chapter_end,
+                fingerprint::SAMPLE_RATE,
+                fingerprint::MAX_DURATION,
+            )?;
+            Ok(fingerprint::compute(&samples))
+        })
+        .await?;
+
+        let c = self.db.get().await;
+        match fp {
+            Ok(fp) => {
+                log::info!("fingerprinted {:?}", candidate);
+                Tag::insert(
+                    &c,
+                    Tag::new_text(candidate, TagKey::Fingerprint, fingerprint::encode(&fp)),
+                )?;
+            }
+            Err(e) => {
+                log::error!("could not fingerprint {:?}: {:?}", candidate, e);
+                Tag::mark_treated(&c, candidate, TagKey::Fingerprint)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+pub fn find_candidate(c: &Connection) -> Result<Option<MusicID>> {
+    Tag::find_untreated(c, TagKey::Fingerprint)
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::loudness::{LoudnessMeter, LoudnessResult};
+use crate::domain::stream::MusicSource;
+use crate::infrastructure::audio;
+use crate::infrastructure::db::Db;
+
+/// Measures the loudness of musics for ReplayGain. Opus sources are decoded through the ffmpeg
+/// of FFMPEG_PATH, without it they are marked as treated with no loudness.
+pub struct LoudnessWorker {
+    db: Db,
+}
+
+impl LoudnessWorker {
+    pub fn new(db: Db) -> Self {
+        LoudnessWorker { db }
+    }
+
+    pub fn start(mut self) {
+        tokio::spawn(async move {
+            loop {
+                let v = self
+                    .step()
+                    .await
+                    .context("error while running loudness worker");
+                match v {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => log::error!("{:?}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+
+    /// Analyzes one music, returns whether there was one to analyze
+    pub async fn step(&mut self) -> Result<bool> {
+        let (candidate, source) = {
+            let c = self.db.get().await;
+            let candidate = unwrap_ret!(find_candidate(&c)?, Ok(false));
+            (candidate, MusicSource::find(&c, candidate)?)
+        };
+
+        let res = match source {
+            Some(source) => tokio::task::spawn_blocking(move || analyze(&source)).await?,
+            None => Err(anyhow!("no source")),
+        };
+
+        let c = self.db.get().await;
+        match res {
+            Ok(Some(res)) => {
+                log::info!(
+                    "loudness of {:?}: {:.2} LUFS, true peak {:.2} dBTP",
+                    candidate,
+                    res.integrated,
+                    res.true_peak
+                );
+                let insert = |key, v: f64| {
+                    Tag::insert(&c, Tag::new_text(candidate, key, format!("{:.2}", v)))
+                };
+                insert(TagKey::Loudness, res.integrated)?;
+                insert(TagKey::TruePeak, res.true_peak)?;
+                insert(TagKey::ReplayGainTrackGain, res.track_gain())?;
+            }
+            Ok(None) => {
+                log::info!("{:?} is silent, cannot compute loudness", candidate);
+                Tag::mark_treated(&c, candidate, TagKey::Loudness)?;
+            }
+            Err(e) => {
+                log::error!("could not compute loudness of {:?}: {:?}", candidate, e);
+                Tag::mark_treated(&c, candidate, TagKey::Loudness)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn analyze(source: &MusicSource) -> Result<Option<LoudnessResult>> {
+    let mut meter: Option<LoudnessMeter> = None;
+    audio::decode(
+        &source.file_path(),
+        source.chapter_start.unwrap_or(0.0),
+        source.chapter_end,
+        |samples, channels, rate| {
+            meter
+                .get_or_insert_with(|| LoudnessMeter::new(channels, rate))
+                .push(samples);
+            true
+        },
+    )?;
+    Ok(meter.and_then(|m| m.finish()))
+}
+
+pub fn find_candidate(c: &Connection) -> Result<Option<MusicID>> {
+    Tag::find_untreated(c, TagKey::Loudness)
+}
@@ -0,0 +1,185 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+use crate::domain::config;
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::infrastructure::db::Db;
+use crate::infrastructure::musicbrainz::{MusicBrainzClient, Recording, Unavailable};
+use crate::utils::row_missing_opt;
+
+/// Recordings whose length differ by more than this from the music are not considered
+const MAX_LENGTH_DIFFERENCE_SECS: i64 = 10;
+
+pub struct MusicBrainzWorker {
+    db: Db,
+    client: MusicBrainzClient,
+    rate_limit: Duration,
+}
+
+impl MusicBrainzWorker {
+    pub fn new(db: Db) -> Self {
+        MusicBrainzWorker {
+            db,
+            client: MusicBrainzClient::default(),
+            rate_limit: Duration::from_secs(1),
+        }
+    }
+
+    pub fn start(mut self) {
+        tokio::spawn(async move {
+            loop {
+                let v = self
+                    .step()
+                    .await
+                    .context("error while running musicbrainz worker");
+                let wait = match v {
+                    Ok(true) => self.rate_limit,
+                    Ok(false) => Duration::from_secs(5),
+                    Err(e) => {
+                        log::error!("{:?}", e);
+                        Duration::from_secs(5).max(self.rate_limit)
+                    }
+                };
+                tokio::time::sleep(wait).await;
+            }
+        });
+    }
+
+    /// Looks up one music, returns whether a request was made to the web service
+    pub async fn step(&mut self) -> Result<bool> {
+        let (base_url, min_score, candidate, title, artist, duration) = {
+            let c = self.db.get().await;
+            if config::get(&c, "musicbrainz_enabled")?.as_deref() != Some("true") {
+                return Ok(false);
+            }
+            let base_url = unwrap_ret!(config::get(&c, "musicbrainz_url")?, Ok(false));
+            let min_score: i64 = config::get(&c, "musicbrainz_min_score")?
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(90);
+            if let Some(ms) =
+                config::get(&c, "musicbrainz_rate_limit_ms")?.and_then(|x| x.parse().ok())
+            {
+                self.rate_limit = Duration::from_millis(ms);
+            }
+
+            let candidate = unwrap_ret!(find_candidate(&c)?, Ok(false));
+            let text = |key| -> Result<Option<String>> {
+                Ok(Tag::by_id_key(&c, candidate, key)?.and_then(|x| x.text))
+            };
+            let title = unwrap_ret!(text(TagKey::Title)?, Ok(false));
+            let artist = text(TagKey::Artist)?;
+            let duration = Tag::by_id_key(&c, candidate, TagKey::Duration)?.and_then(|x| x.integer);
+            (base_url, min_score, candidate, title, artist, duration)
+        };
+
+        log::info!("looking up {:?} on musicbrainz: {}", candidate, &title);
+        let recordings = self
+            .client
+            .search_recording(&base_url, &title, artist.as_deref())
+            .await;
+
+        let c = self.db.get().await;
+        let recordings = match recordings {
+            Ok(v) => v,
+            // the music is looked up again once the loop has backed off
+            Err(e) if e.is::<Unavailable>() => return Err(e),
+            Err(e) => {
+                log::error!("{:?}", e);
+                Tag::insert(
+                    &c,
+                    Tag::new_text(candidate, TagKey::MusicBrainzWorkerTreated, s!("error")),
+                )?;
+                return Ok(true);
+            }
+        };
+        let found = best_match(&recordings, duration, min_score);
+        if let Some(rec) = found {
+            propose(&c, candidate, rec)?;
+        }
+        Tag::insert(
+            &c,
+            Tag::new_text(
+                candidate,
+                TagKey::MusicBrainzWorkerTreated,
+                s!(found.is_some()),
+            ),
+        )?;
+
+        Ok(true)
+    }
+}
+
+pub fn find_candidate(c: &Connection) -> Result<Option<MusicID>> {
+    let mut stmt = c.prepare_cached(
+        "
+    SELECT music_id as id2 FROM tags
+    WHERE key='title'
+    AND
+        (SELECT COUNT(1) FROM tags
+         WHERE music_id = id2 AND key IN ('musicbrainz_worker_treated', 'musicbrainz_recording_id')) = 0
+    AND
+        (SELECT COUNT(1) FROM tags
+         WHERE music_id = id2 AND key='duration' AND integer>20*60) = 0
+    LIMIT 1;
+    ",
+    )?;
+    let v = stmt.query_row([], |x| x.get("id2").map(MusicID).map(Some));
+    row_missing_opt(v).context("failed getting id")
+}
+
+/// Picks the best scoring recording, ignoring those too far from the known duration
+pub fn best_match(
+    recordings: &[Recording],
+    duration: Option<i32>,
+    min_score: i64,
+) -> Option<&Recording> {
+    recordings
+        .iter()
+        .filter(|r| r.score >= min_score)
+        .find(|r| match (r.length, duration) {
+            (Some(length), Some(duration)) => {
+                (length / 1000 - duration as i64).abs() <= MAX_LENGTH_DIFFERENCE_SECS
+            }
+            _ => true,
+        })
+}
+
+/// Inserts the recording information as suggestion tags, to be accepted or rejected by the user.
+/// Information that is already known is not proposed again.
+pub fn propose(c: &Connection, id: MusicID, rec: &Recording) -> Result<()> {
+    let mut proposals = vec![
+        (TagKey::Title, Some(rec.title.clone())),
+        (TagKey::Artist, rec.artist()),
+        (TagKey::Album, rec.album().map(|r| r.title.clone())),
+        (TagKey::Year, rec.year().map(|y| y.to_string())),
+        (TagKey::MusicBrainzRecordingID, Some(rec.id.clone())),
+        (
+            TagKey::MusicBrainzReleaseID,
+            rec.album().map(|r| r.id.clone()),
+        ),
+    ];
+    if let Some(credits) = &rec.artist_credit {
+        let ids: Vec<_> = credits.iter().map(|x| &*x.artist.id).collect();
+        proposals.push((TagKey::MusicBrainzArtistID, Some(ids.join(","))));
+    }
+
+    for (key, value) in proposals {
+        let value = unwrap_cont!(value);
+        if let Some(existing) = Tag::by_id_key(c, id, key.clone())? {
+            if existing.text.as_ref() == Some(&value) {
+                continue;
+            }
+        }
+        let is_year = key == TagKey::Year;
+        let key = TagKey::Suggestion((&key).into());
+        let tag = if is_year {
+            Tag::new_parse(id, key, value)
+        } else {
+            Tag::new_text(id, key, value)
+        };
+        Tag::insert(c, tag)?;
+    }
+    Ok(())
+}
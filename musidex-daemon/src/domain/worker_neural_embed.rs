@@ -1,3 +1,4 @@
+use crate::domain::entity::TagKey;
 use crate::infrastructure::db::Db;
 use crate::utils::collect_rows;
 use anyhow::{Context, Result};
@@ -85,7 +86,7 @@ impl NeuralEmbedWorker {
 }
 
 pub fn needs_embedding(c: &Connection) -> Result<bool> {
-    let mut v = c.prepare_cached(
+    let mut v = c.prepare_cached(&format!(
         "
     SELECT * FROM musics
     WHERE 
@@ -93,13 +94,14 @@ pub fn needs_embedding(c: &Connection) -> Result<bool> {
          WHERE music_id = id AND key='embedding') = 0
     AND
         (SELECT COUNT(1) FROM tags 
-         WHERE music_id = id AND key='local_mp3') = 1
+         WHERE music_id = id AND key IN ({})) = 1
     AND
         (SELECT COUNT(1) FROM tags 
          WHERE music_id = id AND key='duration' AND integer>30*60) = 0
     LIMIT 1;
     ",
-    )?;
+        TagKey::local_sources_sql()
+    ))?;
     let v = v.query([])?.mapped(|row| row.get(0));
     let v: Vec<i32> = collect_rows(v)?;
 
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::stream::MusicSource;
+use crate::infrastructure::audio;
+use crate::infrastructure::db::Db;
+
+/// Durations further than this from the probed one (in seconds) are corrected
+const DURATION_TOLERANCE: f64 = 1.5;
+
+pub struct ProbeWorker {
+    db: Db,
+}
+
+impl ProbeWorker {
+    pub fn new(db: Db) -> Self {
+        ProbeWorker { db }
+    }
+
+    pub fn start(mut self) {
+        tokio::spawn(async move {
+            loop {
+                let v = self
+                    .step()
+                    .await
+                    .context("error while running probe worker");
+                match v {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => log::error!("{:?}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+
+    /// Probes one music, returns whether there was one to probe
+    pub async fn step(&mut self) -> Result<bool> {
+        let (candidate, source) = {
+            let c = self.db.get().await;
+            let candidate = unwrap_ret!(find_candidate(&c)?, Ok(false));
+            (candidate, MusicSource::find(&c, candidate)?)
+        };
+
+        let res = match source {
+            Some(source) => {
+                tokio::task::spawn_blocking(move || {
+                    audio::probe(&source.file_path()).map(|probe| (source, probe))
+                })
+                .await?
+            }
+            None => Err(anyhow!("no source")),
+        };
+
+        let c = self.db.get().await;
+        let (source, probe) = match res {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("could not probe {:?}: {:?}", candidate, e);
+                Tag::mark_treated(&c, candidate, TagKey::Codec)?;
+                return Ok(true);
+            }
+        };
+        log::info!("probed {:?}: {:?}", candidate, probe);
+
+        // chapters only span part of the file
+        let start = source.chapter_start.unwrap_or(0.0);
+        let end = source
+            .chapter_end
+            .map_or(probe.duration, |end| end.min(probe.duration));
+        let duration = (end - start).max(0.0);
+        let known = Tag::by_id_key(&c, candidate, TagKey::Duration)?
+            .and_then(|x| x.text)
+            .and_then(|x| x.parse::<f64>().ok());
+        match known {
+            Some(known) if (known - duration).abs() <= DURATION_TOLERANCE => {}
+            _ => {
+                if let Some(known) = known {
+                    log::warn!(
+                        "correcting duration of {:?}: {} -> {}",
+                        candidate,
+                        known,
+                        duration
+                    );
+                }
+                insert_integer(&c, candidate, TagKey::Duration, duration.ceil() as i32)?;
+            }
+        }
+
+        insert_integer(
+            &c,
+            candidate,
+            TagKey::Bitrate,
+            (probe.bitrate / 1000) as i32,
+        )?;
+        insert_integer(&c, candidate, TagKey::SampleRate, probe.sample_rate as i32)?;
+        insert_integer(&c, candidate, TagKey::Channels, probe.channels as i32)?;
+        Tag::insert(
+            &c,
+            Tag {
+                text: Some(s!(probe.codec.unwrap_or("unknown"))),
+                ..Tag::new_key(candidate, TagKey::Codec)
+            },
+        )?;
+        Ok(true)
+    }
+}
+
+fn insert_integer(c: &Connection, id: MusicID, key: TagKey, v: i32) -> Result<()> {
+    Tag::insert(
+        c,
+        Tag {
+            text: Some(v.to_string()),
+            integer: Some(v),
+            ..Tag::new_key(id, key)
+        },
+    )
+}
+
+pub fn find_candidate(c: &Connection) -> Result<Option<MusicID>> {
+    Tag::find_untreated(c, TagKey::Codec)
+}
@@ -4,6 +4,7 @@ use anyhow::{Context, Result};
 use rusqlite::Connection;
 
 use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::thumbnail;
 use crate::infrastructure::db::Db;
 use crate::utils::row_missing_opt;
 use image::imageops::FilterType;
@@ -43,8 +44,38 @@ impl SmallThumbnailWorker {
         let buf = tokio::fs::read(format!("storage/{}", thumb_p)).await?;
         let img = image::load_from_memory(&buf)?;
 
+        let sizes = thumbnail::sizes(&c)?;
+        let name = thumb_p.clone();
+        let (img, cropped, blurhash, palette) = tokio::task::spawn_blocking(move || {
+            // video frame grabs are 16:9 with black bars, covers are square
+            let (img, cropped) = match thumbnail::crop_square(&img) {
+                Some(x) => (x, true),
+                None => (img, false),
+            };
+            let blurhash = thumbnail::generate(&img, &name, &sizes)?;
+            let palette = thumbnail::palette(&img);
+            Ok::<_, anyhow::Error>((img, cropped, blurhash, palette))
+        })
+        .await??;
+        Tag::insert(&c, Tag::new_text(candidate, TagKey::Blurhash, blurhash))?;
+        Tag::insert(
+            &c,
+            Tag::new_text(
+                candidate,
+                TagKey::DominantColor,
+                thumbnail::hex_color(palette.dominant),
+            ),
+        )?;
+        match palette.accent {
+            Some(accent) => Tag::insert(
+                &c,
+                Tag::new_text(candidate, TagKey::AccentColor, thumbnail::hex_color(accent)),
+            )?,
+            None => Tag::remove(&c, candidate, TagKey::AccentColor)?,
+        }
+
         let (w, h) = img.dimensions();
-        if w < 256 && h <= 256 {
+        if w < 256 && h <= 256 && !cropped {
             log::info!(
                 "thumbnail {:?} already fit within bound, using it as compressed thumbnail",
                 candidate
@@ -72,9 +103,15 @@ impl SmallThumbnailWorker {
 }
 
 pub fn find_candidate(c: &Connection) -> Result<Option<MusicID>> {
-    let mut stmt = c.prepare_cached("SELECT music_id as id2 FROM tags WHERE key=?1 AND 0 = (SELECT COUNT(1) FROM tags WHERE key=?2 AND music_id=id2) LIMIT 1;")?;
-    let v = stmt.query_row([TagKey::Thumbnail, TagKey::CompressedThumbnail], |x| {
-        x.get("id2").map(MusicID).map(Some)
-    });
+    let mut stmt = c.prepare_cached("SELECT music_id as id2 FROM tags WHERE key=?1 AND 3 > (SELECT COUNT(1) FROM tags WHERE key IN (?2, ?3, ?4) AND music_id=id2) LIMIT 1;")?;
+    let v = stmt.query_row(
+        [
+            TagKey::Thumbnail,
+            TagKey::CompressedThumbnail,
+            TagKey::Blurhash,
+            TagKey::DominantColor,
+        ],
+        |x| x.get("id2").map(MusicID).map(Some),
+    );
     row_missing_opt(v).context("failed getting id")
 }
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use nanoserde::SerJson;
+use rusqlite::Connection;
+
+use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::stream::MusicSource;
+use crate::domain::waveform::{PeaksBuilder, Waveform};
+use crate::infrastructure::audio;
+use crate::infrastructure::db::Db;
+
+/// Computes the seek bar peaks of musics, going through ffmpeg for Opus sources.
+/// If it cannot be run the music gets no waveform and is not tried again.
+pub struct WaveformWorker {
+    db: Db,
+}
+
+impl WaveformWorker {
+    pub fn new(db: Db) -> Self {
+        WaveformWorker { db }
+    }
+
+    pub fn start(mut self) {
+        tokio::spawn(async move {
+            loop {
+                let v = self
+                    .step()
+                    .await
+                    .context("error while running waveform worker");
+                match v {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => log::error!("{:?}", e),
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+
+    /// Generates the waveform of one music, returns whether there was one to generate
+    pub async fn step(&mut self) -> Result<bool> {
+        let (candidate, source) = {
+            let c = self.db.get().await;
+            let candidate = unwrap_ret!(find_candidate(&c)?, Ok(false));
+            (candidate, MusicSource::find(&c, candidate)?)
+        };
+
+        let res = match source {
+            Some(source) => {
+                let fname = match source.chapter_start {
+                    Some(start) => {
+                        format!("waveform.{}.{}.json", source.path, (start * 1000.0) as u64)
+                    }
+                    None => format!("waveform.{}.json", source.path),
+                };
+                let waveform = tokio::task::spawn_blocking(move || peaks(&source)).await?;
+                match waveform {
+                    Ok(Some(waveform)) => {
+                        tokio::fs::write(format!("storage/{}", fname), waveform.serialize_json())
+                            .await?;
+                        Ok(Some(fname))
+                    }
+                    Ok(None) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+            None => Err(anyhow!("no source")),
+        };
+
+        let c = self.db.get().await;
+        match res {
+            Ok(Some(fname)) => {
+                log::info!("generated waveform of {:?}", candidate);
+                Tag::insert(&c, Tag::new_text(candidate, TagKey::Waveform, fname))?;
+            }
+            Ok(None) => {
+                log::info!("{:?} is empty, cannot generate waveform", candidate);
+                Tag::mark_treated(&c, candidate, TagKey::Waveform)?;
+            }
+            Err(e) => {
+                log::error!("could not generate waveform of {:?}: {:?}", candidate, e);
+                Tag::mark_treated(&c, candidate, TagKey::Waveform)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn peaks(source: &MusicSource) -> Result<Option<Waveform>> {
+    let mut builder: Option<PeaksBuilder> = None;
+    audio::decode(
+        &source.file_path(),
+        source.chapter_start.unwrap_or(0.0),
+        source.chapter_end,
+        |samples, channels, rate| {
+            builder
+                .get_or_insert_with(|| PeaksBuilder::new(rate))
+                .push(samples, channels);
+            true
+        },
+    )?;
+    Ok(builder.and_then(|b| b.finish()))
+}
+
+pub fn find_candidate(c: &Connection) -> Result<Option<MusicID>> {
+    Tag::find_untreated(c, TagKey::Waveform)
+}
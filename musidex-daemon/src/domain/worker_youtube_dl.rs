@@ -1,9 +1,16 @@
-use crate::domain::entity::{MusicID, Tag, TagKey};
+use crate::domain::config;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::domain::lyrics::{self, Lyrics};
+use crate::domain::title_rules::TitleRules;
+use crate::domain::upload::insert_video_metadata;
 use crate::infrastructure::db::Db;
-use crate::infrastructure::youtube_dl::{ytdl_run_with_args, SingleVideo, YoutubeDlOutput};
+use crate::infrastructure::youtube_dl::{
+    ytdl_run_with_args, Chapter, SingleVideo, YoutubeDlConfig, YoutubeDlOutput,
+};
 use anyhow::{Context, Result};
 use image::ImageFormat;
 use rusqlite::Connection;
+use std::path::Path;
 use std::time::Duration;
 
 pub struct YoutubeDLWorker {
@@ -42,11 +49,12 @@ impl YoutubeDLWorker {
         log::info!("{}", vid_url);
 
         let metadata;
+        let cfg = YoutubeDlConfig::load(&*db.get().await)?;
 
         let mut tries = 5;
         loop {
             tries -= 1;
-            metadata = match download(&vid_url)
+            metadata = match download(&cfg, &vid_url)
                 .await
                 .context("error downloading metadata") {
                 Ok(v) => v,
@@ -65,6 +73,11 @@ impl YoutubeDLWorker {
         }
         log::info!("downloaded metadata");
 
+        let found_lyrics = metadata
+            .ext
+            .as_deref()
+            .and_then(|ext| find_lyrics(&metadata, ext));
+
         let mut c = db.get().await;
         let tx = c.transaction()?;
 
@@ -78,9 +91,11 @@ impl YoutubeDLWorker {
             Ok(())
         };
 
+        insert_video_metadata(txb, id, &metadata)?;
+
         let ext = metadata.ext.context("no extension")?;
         add_tag(
-            TagKey::Other(format!("local_{}", ext)),
+            TagKey::from(&*format!("local_{}", ext)),
             format!("{}.{}", metadata.id, ext),
         )?;
         add_tag_opt(TagKey::Thumbnail, metadata.thumbnail_filename)?;
@@ -98,6 +113,10 @@ impl YoutubeDLWorker {
         }
         add_tag(TagKey::YoutubeDLWorkerTreated, s!("true"))?;
 
+        if let Some(ref v) = found_lyrics {
+            Lyrics::set(txb, id, v)?;
+        }
+
         if let Some(v) = metadata.duration {
             Tag::insert(
                 txb,
@@ -112,6 +131,15 @@ impl YoutubeDLWorker {
             )?;
         }
 
+        if let Some(chapters) = metadata.chapters.as_deref() {
+            if chapters.len() > 1
+                && config::get(txb, "youtube_split_chapters")?.as_deref() == Some("true")
+            {
+                let n = split_chapters(txb, id, chapters)?;
+                log::info!("split {} into {} chapters", vid_url, n);
+            }
+        }
+
         tx.commit()?;
         log::info!("success downloaded {}", vid_url);
         Ok(())
@@ -133,23 +161,130 @@ impl YoutubeDLWorker {
     }
 }
 
-pub async fn download(vid_url: &str) -> Result<Box<SingleVideo>> {
-    let metadata = ytdl_run_with_args(vec![
+/// Creates a music for each chapter of a video. They share the source file of the parent music
+/// and are delimited using the chapter_start and chapter_end tags.
+pub fn split_chapters(c: &Connection, parent: MusicID, chapters: &[Chapter]) -> Result<usize> {
+    let parent_tags = Tag::by_id(c, parent)?;
+    if !parent_tags.iter().any(|t| t.key == TagKey::LocalMP3) {
+        log::warn!("cannot split {:?} into chapters: source is not an mp3", parent);
+        return Ok(0);
+    }
+
+    let rules = TitleRules::load(c)?;
+    let mut count = 0;
+    for (i, chapter) in chapters.iter().enumerate() {
+        let (start, end) = match (chapter.start_time, chapter.end_time) {
+            (Some(start), Some(end)) if end > start => (start, end),
+            _ => continue,
+        };
+        let id = Music::mk(c)?;
+
+        for t in &parent_tags {
+            match t.key {
+                TagKey::LocalMP3
+                | TagKey::Thumbnail
+                | TagKey::YoutubeDLURL
+                | TagKey::YoutubeDLPlaylist
+                | TagKey::UserLibrary(_) => Tag::insert(
+                    c,
+                    Tag {
+                        music_id: id,
+                        ..t.clone()
+                    },
+                )?,
+                _ => {}
+            }
+        }
+
+        let chapter_title = chapter
+            .title
+            .clone()
+            .unwrap_or_else(|| format!("Chapter {}", i + 1));
+        let guessed = rules.apply(&chapter_title);
+        Tag::insert(c, Tag::new_text(id, TagKey::Title, guessed.title))?;
+        if let Some(artist) = guessed.artist {
+            Tag::insert(c, Tag::new_text(id, TagKey::Artist, artist))?;
+        }
+        if !guessed.featured.is_empty() {
+            Tag::insert(
+                c,
+                Tag::new_text(id, TagKey::FeaturedArtist, guessed.featured.join(", ")),
+            )?;
+        }
+        Tag::insert(
+            c,
+            Tag::new_text(id, TagKey::YoutubeDLOriginalTitle, chapter_title),
+        )?;
+
+        for (key, v) in [
+            (TagKey::ChapterStart, start),
+            (TagKey::ChapterEnd, end),
+            (TagKey::Duration, end - start),
+        ] {
+            Tag::insert(
+                c,
+                Tag {
+                    music_id: id,
+                    key,
+                    text: Some(v.to_string()),
+                    integer: Some(v as i32),
+                    date: None,
+                    vector: None,
+                },
+            )?;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Extensions of the audio files yt-dlp can produce, see --audio-format
+const AUDIO_EXTS: &[&str] = &["mp3", "opus", "ogg", "m4a", "webm", "flac", "wav"];
+
+/// Reads the lyrics embedded in the downloaded file or written next to it by yt-dlp
+fn find_lyrics(v: &SingleVideo, ext: &str) -> Option<Lyrics> {
+    let found = lyrics::extract(Path::new(&format!("storage/{}.{}", v.id, ext)));
+    // the subtitles are not needed anymore once imported
+    for lang in v.requested_subtitles.iter().flat_map(|x| x.keys()) {
+        let _ = std::fs::remove_file(format!("storage/{}.{}.lrc", v.id, lang));
+    }
+    match found {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("could not extract lyrics of {}: {:?}", v.id, e);
+            None
+        }
+    }
+}
+
+/// Extension of the file produced by yt-dlp when extracting audio to the given format
+fn audio_format_ext(format: &str) -> &str {
+    match format {
+        "vorbis" => "ogg",
+        "aac" | "alac" => "m4a",
+        x => x,
+    }
+}
+
+pub async fn download(cfg: &YoutubeDlConfig, vid_url: &str) -> Result<Box<SingleVideo>> {
+    let mut args = vec![
         "-o",
         "storage/%(id)s.%(ext)s",
         "-f",
-        "bestaudio",
+        &cfg.format,
         "--audio-format",
-        "mp3",
+        &cfg.audio_format,
         "--extract-audio",
         "--no-playlist",
         "--write-thumbnail",
         "--no-progress",
         "--print-json",
-        "--",
-        vid_url,
-    ])
-    .await?;
+    ];
+    if let Some(ref langs) = cfg.subtitles_langs {
+        args.extend_from_slice(&["--write-subs", "--sub-langs", langs, "--convert-subs", "lrc"]);
+    }
+    args.extend_from_slice(&["--", vid_url]);
+    let metadata = ytdl_run_with_args(cfg, args).await?;
 
     match metadata {
         YoutubeDlOutput::Playlist(_) => {
@@ -165,11 +300,17 @@ pub async fn download(vid_url: &str) -> Result<Box<SingleVideo>> {
                     Err(err) => log::error!("{:?}", err),
                 }
             }
-            if tokio::fs::metadata(format!("storage/{}.mp3", &v.id))
-                .await
-                .is_ok()
-            {
-                v.ext = Some(s!("mp3"));
+            // the json is printed before post-processing, so the extension is the one of the
+            // downloaded stream and not the one of the extracted audio
+            let preferred = audio_format_ext(&cfg.audio_format);
+            for ext in std::iter::once(preferred).chain(AUDIO_EXTS.iter().copied()) {
+                if tokio::fs::metadata(format!("storage/{}.{}", &v.id, ext))
+                    .await
+                    .is_ok()
+                {
+                    v.ext = Some(s!(ext));
+                    break;
+                }
             }
             return Ok(v);
         }
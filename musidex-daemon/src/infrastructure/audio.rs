@@ -0,0 +1,492 @@
+use crate::utils::env_or;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL, CODEC_TYPE_OPUS};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardVisualKey, Visual};
+use symphonia::core::probe::{Hint, ProbeResult as Probed};
+use symphonia::core::units::{Time, TimeBase};
+
+/// ffmpeg decodes what symphonia cannot, mostly Opus. It is run as is so it can only be set
+/// through the environment, never by clients.
+pub fn ffmpeg_path() -> String {
+    env_or("FFMPEG_PATH", s!("ffmpeg"))
+}
+
+fn open(path: &Path) -> Result<Probed> {
+    let file = File::open(path).with_context(|| format!("could not open {:?}", path))?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|x| x.to_str()) {
+        hint.with_extension(ext);
+    }
+    symphonia::default::get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .context("unsupported format")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeResult {
+    /// in seconds
+    pub duration: f64,
+    /// average over the whole file, in bits per second
+    pub bitrate: u64,
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub codec: Option<&'static str>,
+}
+
+/// Reads the audio properties of a file from its container, without decoding it
+pub fn probe(path: &Path) -> Result<ProbeResult> {
+    let file_size = std::fs::metadata(path)
+        .with_context(|| format!("could not stat {:?}", path))?
+        .len();
+    let mut format = open(path)?.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .context("no audio track")?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+    let sample_rate = params.sample_rate.context("unknown sample rate")?;
+    let time_base = params
+        .time_base
+        .unwrap_or_else(|| TimeBase::new(1, sample_rate));
+
+    let n_frames = match params.n_frames {
+        Some(n) => n,
+        None => {
+            // some containers don't store the length, sum up the packets instead
+            let mut n = 0;
+            loop {
+                match format.next_packet() {
+                    Ok(packet) if packet.track_id() == track_id => n += packet.dur,
+                    Ok(_) => {}
+                    Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        break
+                    }
+                    Err(Error::ResetRequired) => break,
+                    Err(e) => return Err(e).context("error reading packet"),
+                }
+            }
+            n
+        }
+    };
+    let t = time_base.calc_time(n_frames);
+    let duration = t.seconds as f64 + t.frac;
+    if duration <= 0.0 {
+        bail!("empty audio track");
+    }
+
+    let codec = symphonia::default::get_codecs()
+        .get_codec(params.codec)
+        .map(|x| x.short_name)
+        .or(match params.codec {
+            // no decoder is available for opus, but the containers still recognize it
+            CODEC_TYPE_OPUS => Some("opus"),
+            _ => None,
+        });
+
+    Ok(ProbeResult {
+        duration,
+        bitrate: (file_size as f64 * 8.0 / duration) as u64,
+        sample_rate,
+        channels: params.channels.map(|x| x.count()).unwrap_or(1),
+        codec,
+    })
+}
+
+pub struct Picture {
+    pub media_type: String,
+    pub data: Box<[u8]>,
+}
+
+/// Returns the picture embedded in the file (ID3 APIC frames, FLAC and vorbis pictures,
+/// MP4 covr atoms), preferring the front cover
+pub fn embedded_picture(path: &Path) -> Result<Option<Picture>> {
+    let mut probed = open(path)?;
+    let mut visuals: Vec<Visual> = vec![];
+    if let Some(rev) = probed.metadata.get().as_mut().and_then(|m| m.skip_to_latest()) {
+        visuals.extend(rev.visuals().iter().cloned());
+    }
+    if let Some(rev) = probed.format.metadata().skip_to_latest() {
+        visuals.extend(rev.visuals().iter().cloned());
+    }
+    let best = visuals
+        .iter()
+        .position(|v| v.usage == Some(StandardVisualKey::FrontCover))
+        .unwrap_or(0);
+    if best >= visuals.len() {
+        return Ok(None);
+    }
+    let v = visuals.swap_remove(best);
+    Ok(Some(Picture {
+        media_type: v.media_type,
+        data: v.data,
+    }))
+}
+
+/// Decodes the audio file between start and end (in seconds, None meaning until the end of the file).
+/// f is called with interleaved samples, the number of channels and the sample rate,
+/// it can return false to stop decoding early.
+/// Codecs without a decoder, like Opus, are decoded through ffmpeg.
+pub fn decode(
+    path: &Path,
+    start: f64,
+    end: Option<f64>,
+    mut f: impl FnMut(&[f32], usize, u32) -> bool,
+) -> Result<()> {
+    let mut format = open(path)?.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .context("no audio track")?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+    let time_base = params.time_base;
+    let mut decoder =
+        match symphonia::default::get_codecs().make(&params, &DecoderOptions::default()) {
+            Ok(decoder) => decoder,
+            Err(Error::Unsupported(_)) => {
+                let channels = params.channels.map(|x| x.count()).unwrap_or(2);
+                let rate = params.sample_rate.unwrap_or(48_000);
+                return decode_ffmpeg(&ffmpeg_path(), path, start, end, channels, rate, f);
+            }
+            Err(e) => return Err(e).context("unsupported codec"),
+        };
+
+    if start > 0.0 {
+        format
+            .seek(
+                SeekMode::Coarse,
+                SeekTo::Time {
+                    time: Time::from(start),
+                    track_id: Some(track_id),
+                },
+            )
+            .context("could not seek to start")?;
+    }
+
+    let mut sample_buf: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(Error::ResetRequired) => break,
+            Err(e) => return Err(e).context("error reading packet"),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(Error::DecodeError(e)) => {
+                log::warn!("skipping undecodable packet in {:?}: {}", path, e);
+                continue;
+            }
+            Err(e) => return Err(e).context("error decoding packet"),
+        };
+
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+        let rate = spec.rate;
+        let buf = match sample_buf {
+            Some(ref mut buf) if buf.capacity() >= decoded.capacity() * channels => buf,
+            _ => sample_buf.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buf.copy_interleaved_ref(decoded);
+        let mut samples = buf.samples();
+
+        let packet_start = match time_base {
+            Some(tb) => {
+                let t = tb.calc_time(packet.ts());
+                t.seconds as f64 + t.frac
+            }
+            None => start,
+        };
+        if packet_start < start {
+            let skip = ((start - packet_start) * rate as f64) as usize * channels;
+            samples = &samples[skip.min(samples.len())..];
+        }
+        if let Some(end) = end {
+            let packet_end = packet_start + (buf.samples().len() / channels) as f64 / rate as f64;
+            if packet_end > end {
+                let keep = ((end - packet_start).max(0.0) * rate as f64) as usize * channels;
+                let already_skipped = buf.samples().len() - samples.len();
+                samples = &samples[..keep.saturating_sub(already_skipped).min(samples.len())];
+                f(samples, channels, rate);
+                break;
+            }
+        }
+        if !f(samples, channels, rate) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Same as decode, ffmpeg outputting raw interleaved samples with these channels and rate
+fn decode_ffmpeg(
+    ffmpeg: &str,
+    path: &Path,
+    start: f64,
+    end: Option<f64>,
+    channels: usize,
+    rate: u32,
+    mut f: impl FnMut(&[f32], usize, u32) -> bool,
+) -> Result<()> {
+    let mut args = vec![s!("-nostdin"), s!("-v"), s!("error")];
+    if start > 0.0 {
+        args.push(s!("-ss"));
+        args.push(start.to_string());
+    }
+    if let Some(end) = end {
+        args.push(s!("-to"));
+        args.push(end.to_string());
+    }
+    args.push(s!("-i"));
+    args.push(path.to_string_lossy().to_string());
+    #[rustfmt::skip]
+    args.extend(
+        [
+            "-vn", "-f", "f32le",
+            "-ac", &channels.to_string(), "-ar", &rate.to_string(),
+            "-",
+        ]
+        .iter()
+        .map(ToString::to_string),
+    );
+
+    log::info!("running {} with args: {}", ffmpeg, args.join(" "));
+    let mut child = Command::new(ffmpeg)
+        .args(&args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("error starting {}, did you install it?", ffmpeg))?;
+    let mut stdout = child.stdout.take().context("no ffmpeg output")?;
+
+    let frame_len = channels * 4;
+    let mut buf = vec![0; 4096 * frame_len];
+    let mut filled = 0;
+    let mut samples = Vec::with_capacity(4096 * channels);
+    loop {
+        let n = stdout
+            .read(&mut buf[filled..])
+            .context("error reading ffmpeg output")?;
+        filled += n;
+        let whole = filled - filled % frame_len;
+        if whole > 0 {
+            samples.clear();
+            samples.extend(
+                buf[..whole]
+                    .chunks_exact(4)
+                    .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]])),
+            );
+            if !f(&samples, channels, rate) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Ok(());
+            }
+            buf.copy_within(whole..filled, 0);
+            filled -= whole;
+        }
+        if n == 0 {
+            break;
+        }
+    }
+
+    let status = child.wait().context("error while waiting for ffmpeg")?;
+    if !status.success() {
+        let mut stderr = vec![];
+        if let Some(mut reader) = child.stderr.take() {
+            reader.read_to_end(&mut stderr)?;
+        }
+        bail!(
+            "error decoding with ffmpeg: {} {}",
+            status.code().unwrap_or(1),
+            String::from_utf8_lossy(&stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Averages the input samples falling into each output sample, which is enough of a
+/// low-pass filter for analysis purposes.
+pub struct Downsampler {
+    ratio: f64,
+    pos: f64,
+    acc: f32,
+    count: u32,
+    last: f32,
+}
+
+impl Downsampler {
+    pub fn new(in_rate: u32, out_rate: u32) -> Self {
+        Self {
+            ratio: in_rate as f64 / out_rate as f64,
+            pos: 0.0,
+            acc: 0.0,
+            count: 0,
+            last: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, x: f32, out: &mut Vec<f32>) {
+        self.acc += x;
+        self.count += 1;
+        self.pos += 1.0;
+        if self.pos >= self.ratio {
+            self.last = self.acc / self.count as f32;
+            self.acc = 0.0;
+            self.count = 0;
+        }
+        while self.pos >= self.ratio {
+            out.push(self.last);
+            self.pos -= self.ratio;
+        }
+    }
+}
+
+/// Decodes the file to mono samples at the given sample rate, stopping after max_secs of audio
+pub fn decode_mono(
+    path: &Path,
+    start: f64,
+    end: Option<f64>,
+    rate: u32,
+    max_secs: f64,
+) -> Result<Vec<f32>> {
+    let max_len = (max_secs * rate as f64) as usize;
+    let mut out = Vec::with_capacity(max_len);
+    let mut resampler: Option<(u32, Downsampler)> = None;
+    decode(path, start, end, |samples, channels, in_rate| {
+        let r = match resampler {
+            Some((r_rate, ref mut r)) if r_rate == in_rate => r,
+            _ => {
+                &mut resampler
+                    .insert((in_rate, Downsampler::new(in_rate, rate)))
+                    .1
+            }
+        };
+        for frame in samples.chunks_exact(channels) {
+            r.push(frame.iter().sum::<f32>() / channels as f32, &mut out);
+        }
+        if out.len() >= max_len {
+            out.truncate(max_len);
+            return false;
+        }
+        true
+    })?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downsampler() {
+        let mut out = vec![];
+        let mut d = Downsampler::new(4, 2);
+        for x in [1.0, 3.0, 2.0, 2.0, 5.0] {
+            d.push(x, &mut out);
+        }
+        assert_eq!(out, vec![2.0, 2.0]);
+
+        let mut out = vec![];
+        let mut d = Downsampler::new(44100, 5512);
+        for _ in 0..44100 {
+            d.push(1.0, &mut out);
+        }
+        assert_eq!(out.len(), 5512);
+        assert!(out.iter().all(|&x| x == 1.0));
+    }
+
+    #[test]
+    fn test_decode_ffmpeg() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("musidex-fake-ffmpeg");
+        std::fs::create_dir_all(&dir).unwrap();
+        let expected: Vec<f32> = (0..10_000).map(|i| (i % 100) as f32 / 100.0).collect();
+        let raw: Vec<u8> = expected.iter().flat_map(|x| x.to_le_bytes()).collect();
+        std::fs::write(dir.join("raw"), raw).unwrap();
+        let ffmpeg = dir.join("ffmpeg");
+        std::fs::write(
+            &ffmpeg,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {0}/args\ncat {0}/raw\n[ \"$4\" = \"-ss\" ]",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let ffmpeg = ffmpeg.to_string_lossy();
+
+        let mut got = vec![];
+        decode_ffmpeg(
+            &ffmpeg,
+            Path::new("a.opus"),
+            1.5,
+            Some(3.0),
+            2,
+            48000,
+            |x, c, r| {
+                assert_eq!((c, r), (2, 48000));
+                assert_eq!(x.len() % 2, 0);
+                got.extend_from_slice(x);
+                true
+            },
+        )
+        .unwrap();
+        assert_eq!(got, expected);
+        let args = std::fs::read_to_string(dir.join("args")).unwrap();
+        assert_eq!(
+            args.trim(),
+            "-nostdin -v error -ss 1.5 -to 3 -i a.opus -vn -f f32le -ac 2 -ar 48000 -"
+        );
+
+        // stopping early does not wait for the whole output
+        let mut calls = 0;
+        decode_ffmpeg(
+            &ffmpeg,
+            Path::new("a.opus"),
+            1.5,
+            None,
+            1,
+            8000,
+            |_, _, _| {
+                calls += 1;
+                false
+            },
+        )
+        .unwrap();
+        assert_eq!(calls, 1);
+
+        // the fake fails without a start
+        assert!(decode_ffmpeg(
+            &ffmpeg,
+            Path::new("a.opus"),
+            0.0,
+            None,
+            1,
+            8000,
+            |_, _, _| true
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZlibEncoder};
+use async_compression::Level;
+use futures::TryStreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY,
+};
+use hyper::{Body, Response, StatusCode};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Bodies smaller than this are not worth compressing
+const MIN_SIZE: u64 = 256;
+/// Brotli's default quality is too slow to compress on the fly
+const BROTLI_QUALITY: i32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Picks the encoding with the highest q-value in an Accept-Encoding header,
+/// ties going to the one compressing the most
+pub fn negotiate(header: Option<&HeaderValue>) -> Option<Encoding> {
+    let header = header?.to_str().ok()?;
+    let mut qs = vec![];
+    for item in header.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let q = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .map(|q| q.parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        qs.push((name, q));
+    }
+    let q_of = |name: &str| {
+        qs.iter()
+            .find(|x| x.0 == name)
+            .or_else(|| qs.iter().find(|x| x.0 == "*"))
+            .map(|x| x.1)
+            .unwrap_or(0.0)
+    };
+
+    let mut best = None;
+    let mut best_q = 0.0;
+    for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+        let q = q_of(encoding.name());
+        if q > best_q {
+            best = Some(encoding);
+            best_q = q;
+        }
+    }
+    best
+}
+
+/// Whether compressing the content type is worth it, audio and images being compressed already
+fn is_compressible(content_type: Option<&HeaderValue>) -> bool {
+    let content_type = match content_type.and_then(|x| x.to_str().ok()) {
+        Some(x) => x.split(';').next().unwrap_or_default().trim(),
+        // API handlers answer json without a content type
+        None => return true,
+    };
+    let content_type = content_type.to_ascii_lowercase();
+    content_type.starts_with("text/")
+        || content_type.ends_with("+json")
+        || content_type.ends_with("+xml")
+        || matches!(
+            content_type.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/wasm"
+                | "application/xml"
+                | "image/svg+xml"
+        )
+}
+
+/// Compresses the body of a response on the fly with the negotiated encoding.
+/// Partial, already encoded and incompressible responses are left as is.
+pub fn compress(mut r: Response<Body>, encoding: Option<Encoding>) -> Response<Body> {
+    let h = r.headers();
+    if r.status() != StatusCode::OK
+        || h.contains_key(CONTENT_ENCODING)
+        || h.contains_key(CONTENT_RANGE)
+        || !is_compressible(h.get(CONTENT_TYPE))
+    {
+        return r;
+    }
+    r.headers_mut()
+        .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+
+    let encoding = unwrap_ret!(encoding, r);
+    if r.body().size_hint().exact().is_some_and(|x| x < MIN_SIZE) {
+        return r;
+    }
+
+    let (mut parts, body) = r.into_parts();
+    let h = &mut parts.headers;
+    h.remove(CONTENT_LENGTH);
+    h.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    // the compressed bytes depend on the encoder, so the entity tag can only be weak
+    if let Some(etag) = h.get(ETAG).and_then(|x| x.to_str().ok()) {
+        if !etag.starts_with("W/") {
+            if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                h.insert(ETAG, weak);
+            }
+        }
+    }
+
+    let reader = StreamReader::new(TryStreamExt::map_err(body, std::io::Error::other));
+    let body = match encoding {
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::with_quality(
+            reader,
+            Level::Precise(BROTLI_QUALITY),
+        ))),
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        Encoding::Deflate => Body::wrap_stream(ReaderStream::new(ZlibEncoder::new(reader))),
+    };
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiate_str(x: &'static str) -> Option<Encoding> {
+        negotiate(Some(&HeaderValue::from_static(x)))
+    }
+
+    #[test]
+    fn test_negotiate() {
+        use Encoding::*;
+        assert_eq!(negotiate_str("gzip, deflate, br"), Some(Brotli));
+        assert_eq!(negotiate_str("gzip, deflate"), Some(Gzip));
+        assert_eq!(negotiate_str("deflate"), Some(Deflate));
+        assert_eq!(negotiate_str("br;q=0.5, gzip;q=0.8"), Some(Gzip));
+        assert_eq!(negotiate_str("br;q=0, *"), Some(Gzip));
+        assert_eq!(negotiate_str("*;q=0.1, deflate;q=0.2"), Some(Deflate));
+        assert_eq!(negotiate_str("identity"), None);
+        assert_eq!(negotiate_str("gzip;q=0"), None);
+        assert_eq!(negotiate(None), None);
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        let ct = |x: &'static str| is_compressible(Some(&HeaderValue::from_static(x)));
+        assert!(is_compressible(None));
+        assert!(ct("text/html; charset=utf-8"));
+        assert!(ct("application/json"));
+        assert!(ct("application/wasm"));
+        assert!(ct("application/manifest+json"));
+        assert!(!ct("audio/mpeg"));
+        assert!(!ct("image/jpeg"));
+        assert!(!ct("application/octet-stream"));
+    }
+}
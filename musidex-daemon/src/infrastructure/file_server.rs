@@ -0,0 +1,235 @@
+use crate::utils::{file_chunks, res_status};
+use anyhow::{Context, Result};
+use futures::{Stream, StreamExt};
+use http_range::{HttpRange, HttpRangeParseError};
+use hyper::body::Bytes;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use hyper::{Body, Response, StatusCode};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::pin::Pin;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Requests asking for more ranges than this get the whole file
+const MAX_RANGES: usize = 16;
+
+/// Content type of a file from its extension
+pub fn guess_content_type(p: &Path) -> &'static str {
+    mime_guess::from_path(p)
+        .first_raw()
+        .unwrap_or("application/octet-stream")
+}
+
+/// Validators of a file, or of a byte window of it
+pub struct Validators {
+    pub etag: String,
+    /// truncated to the second, as it is sent
+    pub last_modified: SystemTime,
+}
+
+impl Validators {
+    fn new(modified: SystemTime, len: u64, window: Option<(u64, u64)>) -> Self {
+        let t = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut etag = format!("{:x}.{:x}-{:x}", t.as_secs(), t.subsec_nanos(), len);
+        if let Some((start, end)) = window {
+            etag += &format!("-{:x}-{:x}", start, end);
+        }
+        Self::with_etag(&etag, modified)
+    }
+
+    /// Validators of a generated representation, the etag being quoted here
+    pub fn with_etag(etag: &str, modified: SystemTime) -> Self {
+        let t = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self {
+            etag: format!("\"{}\"", etag),
+            last_modified: UNIX_EPOCH + Duration::from_secs(t.as_secs()),
+        }
+    }
+
+    /// Whether a GET request with these headers can be answered with 304 Not Modified
+    fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(tags) = headers.get(IF_NONE_MATCH) {
+            let tags = unwrap_ret!(tags.to_str().ok(), false);
+            return tags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == self.etag);
+        }
+        headers
+            .get(IF_MODIFIED_SINCE)
+            .and_then(|x| httpdate::parse_http_date(x.to_str().ok()?).ok())
+            .is_some_and(|since| self.last_modified <= since)
+    }
+
+    /// Whether the Range header should be honored, as If-Range only allows it if the
+    /// representation did not change. A date only matches the exact modification time.
+    fn range_allowed(&self, headers: &HeaderMap) -> bool {
+        let if_range = match headers.get(IF_RANGE) {
+            Some(x) => unwrap_ret!(x.to_str().ok(), false).trim(),
+            None => return true,
+        };
+        if if_range.starts_with('"') {
+            return if_range == self.etag;
+        }
+        if if_range.starts_with("W/") {
+            return false;
+        }
+        httpdate::parse_http_date(if_range).is_ok_and(|date| date == self.last_modified)
+    }
+}
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+/// Content that can be read from any offset, to be served by ranges
+pub trait ByteSource {
+    /// Streams `len` bytes from `start`
+    fn chunks(&self, start: u64, len: u64) -> Result<ByteStream>;
+}
+
+/// A byte window of a file
+struct FileSource {
+    f: std::fs::File,
+    offset: u64,
+}
+
+impl ByteSource for FileSource {
+    fn chunks(&self, start: u64, len: u64) -> Result<ByteStream> {
+        // clones share their cursor, which is fine as streams are read one after the other
+        let f = self.f.try_clone().context("failed cloning file handle")?;
+        Ok(file_chunks(tokio::fs::File::from_std(f), self.offset + start, len).boxed())
+    }
+}
+
+/// Serves a file with caching validators and range requests support.
+/// If window is given, only those bytes of the file are served as if they were a file on their own.
+pub async fn serve_file(
+    headers: &HeaderMap,
+    p: &Path,
+    window: Option<(u64, u64)>,
+    content_type: &str,
+) -> Result<Response<Body>> {
+    let f = match tokio::fs::File::open(p).await {
+        Ok(x) => x,
+        Err(e) => {
+            if e.kind() == ErrorKind::NotFound {
+                return Ok(res_status(StatusCode::NOT_FOUND));
+            }
+            return Err(e).context("failed opening file");
+        }
+    };
+    let meta = f.metadata().await.context("failed reading file metadata")?;
+    if !meta.is_file() {
+        return Ok(res_status(StatusCode::NOT_FOUND));
+    }
+    let (offset, size) = match window {
+        Some((start, end)) if start <= end && end <= meta.len() => (start, end - start),
+        Some(_) => bail!("window out of the file"),
+        None => (0, meta.len()),
+    };
+    let validators = Validators::new(meta.modified()?, meta.len(), window);
+    let source = FileSource {
+        f: f.into_std().await,
+        offset,
+    };
+    serve_source(headers, &source, size, &validators, content_type)
+}
+
+/// Serves `size` bytes of a source, answering conditional and range requests
+pub fn serve_source(
+    headers: &HeaderMap,
+    source: &dyn ByteSource,
+    size: u64,
+    validators: &Validators,
+    content_type: &str,
+) -> Result<Response<Body>> {
+    let mut r = res_status(StatusCode::OK);
+    let h = r.headers_mut();
+    h.insert(ETAG, validators.etag.parse()?);
+    h.insert(
+        LAST_MODIFIED,
+        httpdate::fmt_http_date(validators.last_modified).parse()?,
+    );
+    h.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    if validators.not_modified(headers) {
+        *r.status_mut() = StatusCode::NOT_MODIFIED;
+        return Ok(r);
+    }
+
+    let range = headers
+        .get(RANGE)
+        .filter(|_| validators.range_allowed(headers))
+        .map(|x| HttpRange::parse_bytes(x.as_bytes(), size));
+    let ranges = match range {
+        Some(Ok(ranges)) if !ranges.is_empty() && ranges.len() <= MAX_RANGES => ranges,
+        Some(Err(HttpRangeParseError::NoOverlap)) => {
+            *r.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            r.headers_mut()
+                .insert(CONTENT_RANGE, format!("bytes */{}", size).parse()?);
+            return Ok(r);
+        }
+        // invalid ranges are ignored
+        _ => {
+            r.headers_mut().insert(CONTENT_TYPE, content_type.parse()?);
+            r.headers_mut().insert(CONTENT_LENGTH, size.into());
+            *r.body_mut() = Body::wrap_stream(source.chunks(0, size)?);
+            return Ok(r);
+        }
+    };
+
+    *r.status_mut() = StatusCode::PARTIAL_CONTENT;
+    let content_range = |r: &HttpRange| {
+        format!(
+            "bytes {}-{}/{}",
+            r.start,
+            r.start + r.length.saturating_sub(1),
+            size
+        )
+    };
+
+    if let [range] = ranges[..] {
+        let h = r.headers_mut();
+        h.insert(CONTENT_TYPE, content_type.parse()?);
+        h.insert(CONTENT_RANGE, content_range(&range).parse()?);
+        h.insert(CONTENT_LENGTH, range.length.into());
+        *r.body_mut() = Body::wrap_stream(source.chunks(range.start, range.length)?);
+        return Ok(r);
+    }
+
+    let boundary = format!(
+        "{:x}{:x}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+        size
+    );
+    let mut parts: Vec<ByteStream> = vec![];
+    let mut len = 0;
+    for range in &ranges {
+        let head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            boundary,
+            content_type,
+            content_range(range)
+        );
+        len += head.len() as u64 + range.length;
+        parts.push(futures::stream::once(async { Ok(Bytes::from(head)) }).boxed());
+        parts.push(source.chunks(range.start, range.length)?);
+    }
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    len += tail.len() as u64;
+    parts.push(futures::stream::once(async { Ok(Bytes::from(tail)) }).boxed());
+
+    let h = r.headers_mut();
+    h.insert(
+        CONTENT_TYPE,
+        format!("multipart/byteranges; boundary={}", boundary).parse()?,
+    );
+    h.insert(CONTENT_LENGTH, len.into());
+    *r.body_mut() = Body::wrap_stream(futures::stream::iter(parts).flatten());
+    Ok(r)
+}
@@ -1,4 +1,11 @@
+pub mod audio;
+pub mod compression;
 pub mod db;
+pub mod file_server;
 pub mod migrate;
+pub mod mp3;
+pub mod musicbrainz;
 pub mod router;
+pub mod tar;
+pub mod tag_writer;
 pub mod youtube_dl;
@@ -0,0 +1,254 @@
+use anyhow::{Context, Result};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const BITRATES_V1: [u32; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+const BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub len: u64,
+    pub samples: u32,
+    pub sample_rate: u32,
+}
+
+/// Parses a MPEG audio layer III frame header, returns None if it is not a valid one
+pub fn parse_header(h: [u8; 4]) -> Option<FrameHeader> {
+    if h[0] != 0xFF || h[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+    let version = (h[1] >> 3) & 3;
+    let layer = (h[1] >> 1) & 3;
+    if version == 1 || layer != 1 {
+        return None;
+    }
+    let bitrate_idx = (h[2] >> 4) as usize;
+    let sr_idx = ((h[2] >> 2) & 3) as usize;
+    if bitrate_idx == 0 || bitrate_idx == 15 || sr_idx == 3 {
+        return None;
+    }
+    let padding = ((h[2] >> 1) & 1) as u64;
+
+    let (bitrate, sample_rate, samples) = match version {
+        3 => (
+            BITRATES_V1[bitrate_idx],
+            [44100, 48000, 32000][sr_idx],
+            1152,
+        ),
+        2 => (BITRATES_V2[bitrate_idx], [22050, 24000, 16000][sr_idx], 576),
+        _ => (BITRATES_V2[bitrate_idx], [11025, 12000, 8000][sr_idx], 576),
+    };
+
+    let len = (samples as u64 / 8) * bitrate as u64 * 1000 / sample_rate as u64 + padding;
+    Some(FrameHeader {
+        len,
+        samples,
+        sample_rate,
+    })
+}
+
+/// Returns the size of the ID3v2 tag at the start of the stream, 0 if there is none
+pub fn id3v2_size<R: Read + Seek>(r: &mut R) -> Result<u64> {
+    let mut h = [0u8; 10];
+    r.seek(SeekFrom::Start(0))?;
+    if r.read_exact(&mut h).is_err() || &h[..3] != b"ID3" {
+        return Ok(0);
+    }
+    let size = h[6..10]
+        .iter()
+        .fold(0u64, |acc, &b| (acc << 7) | (b & 0x7F) as u64);
+    let footer = if h[5] & 0x10 != 0 { 10 } else { 0 };
+    Ok(10 + size + footer)
+}
+
+/// Converts a time range in seconds to a byte range by walking the frame headers.
+/// Both ends are aligned on frame boundaries, and an end of None means until the end of the stream.
+pub fn time_range_to_bytes<R: Read + Seek>(
+    r: &mut R,
+    start: f64,
+    end: Option<f64>,
+) -> Result<(u64, u64)> {
+    let total = r.seek(SeekFrom::End(0))?;
+    let mut pos = id3v2_size(r)?;
+    r.seek(SeekFrom::Start(pos))?;
+
+    let mut time = 0.0;
+    let mut start_byte = None;
+    let mut h = [0u8; 4];
+    while pos + 4 <= total {
+        if start_byte.is_none() && time >= start {
+            start_byte = Some(pos);
+        }
+        if let Some(end) = end {
+            if time >= end {
+                return Ok((start_byte.unwrap_or(pos), pos));
+            }
+        }
+        r.read_exact(&mut h)?;
+        match parse_header(h) {
+            Some(frame) => {
+                time += frame.samples as f64 / frame.sample_rate as f64;
+                pos += frame.len;
+                r.seek(SeekFrom::Start(pos))?;
+            }
+            None => {
+                // lost sync, look for the next frame one byte further
+                pos += 1;
+                r.seek(SeekFrom::Start(pos))?;
+            }
+        }
+    }
+
+    Ok((start_byte.unwrap_or(total), total))
+}
+
+/// A run of consecutive frames, as a byte range and its duration in seconds
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Segment {
+    pub start: u64,
+    pub end: u64,
+    pub duration: f64,
+}
+
+/// Cuts the frames within a byte range into segments of at least `target` seconds, the last one being shorter.
+/// Segments are aligned on frame boundaries so they can be decoded on their own.
+pub fn segments<R: Read + Seek>(
+    r: &mut R,
+    (start, end): (u64, u64),
+    target: f64,
+) -> Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let mut pos = start;
+    let mut cur = Segment {
+        start,
+        end: start,
+        duration: 0.0,
+    };
+    let mut h = [0u8; 4];
+    r.seek(SeekFrom::Start(pos))?;
+    while pos + 4 <= end {
+        r.read_exact(&mut h)?;
+        match parse_header(h) {
+            Some(frame) => {
+                cur.duration += frame.samples as f64 / frame.sample_rate as f64;
+                pos += frame.len;
+                if cur.duration >= target {
+                    cur.end = pos.min(end);
+                    segments.push(cur);
+                    cur = Segment {
+                        start: cur.end,
+                        end: cur.end,
+                        duration: 0.0,
+                    };
+                }
+            }
+            None => pos += 1,
+        }
+        r.seek(SeekFrom::Start(pos))?;
+    }
+    if cur.duration > 0.0 {
+        cur.end = end;
+        segments.push(cur);
+    }
+    Ok(segments)
+}
+
+pub fn file_time_range_to_bytes(path: &Path, start: f64, end: Option<f64>) -> Result<(u64, u64)> {
+    let f = std::fs::File::open(path).context("could not open mp3 file")?;
+    time_range_to_bytes(&mut BufReader::new(f), start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // MPEG1 layer III, 128kbps, 44100Hz, no padding: 417 bytes and 1152 samples per frame
+    fn mk_mp3(id3: bool, n_frames: usize) -> Vec<u8> {
+        let mut v = vec![];
+        if id3 {
+            v.extend_from_slice(b"ID3\x04\x00\x00\x00\x00\x01\x00");
+            v.extend_from_slice(&[0; 128]);
+        }
+        for _ in 0..n_frames {
+            let mut frame = vec![0u8; 417];
+            frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+            v.extend(frame);
+        }
+        v
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let h = parse_header([0xFF, 0xFB, 0x90, 0x00]).unwrap();
+        assert_eq!(h.len, 417);
+        assert_eq!(h.samples, 1152);
+        assert_eq!(h.sample_rate, 44100);
+
+        let h = parse_header([0xFF, 0xFB, 0x92, 0x00]).unwrap();
+        assert_eq!(h.len, 418);
+
+        assert!(parse_header([0xFF, 0xFB, 0xF0, 0x00]).is_none());
+        assert!(parse_header([0x00, 0xFB, 0x90, 0x00]).is_none());
+    }
+
+    #[test]
+    fn test_time_range_to_bytes() {
+        let frame_dur = 1152.0 / 44100.0;
+        let mp3 = mk_mp3(true, 100);
+        let id3len = 138;
+
+        let (s, e) = time_range_to_bytes(&mut Cursor::new(&mp3), 0.0, None).unwrap();
+        assert_eq!((s, e), (id3len, mp3.len() as u64));
+
+        let (s, e) = time_range_to_bytes(
+            &mut Cursor::new(&mp3),
+            10.5 * frame_dur,
+            Some(20.5 * frame_dur),
+        )
+        .unwrap();
+        assert_eq!(s, id3len + 11 * 417);
+        assert_eq!(e, id3len + 21 * 417);
+
+        let mp3 = mk_mp3(false, 10);
+        let (s, e) = time_range_to_bytes(&mut Cursor::new(&mp3), 100.0, None).unwrap();
+        assert_eq!((s, e), (mp3.len() as u64, mp3.len() as u64));
+    }
+
+    #[test]
+    fn test_segments() {
+        let frame_dur = 1152.0 / 44100.0;
+        let mp3 = mk_mp3(true, 25);
+        let id3len = 138;
+        let end = mp3.len() as u64;
+
+        let segs = segments(&mut Cursor::new(&mp3), (id3len, end), 10.0 * frame_dur).unwrap();
+        let frames: Vec<_> = segs
+            .iter()
+            .map(|s| ((s.end - s.start) / 417, (s.duration / frame_dur).round()))
+            .collect();
+        assert_eq!(frames, vec![(10, 10.0), (10, 10.0), (5, 5.0)]);
+        assert_eq!(segs[0].start, id3len);
+        assert_eq!(segs[1].start, segs[0].end);
+        assert_eq!(segs[2].end, end);
+
+        let segs = segments(
+            &mut Cursor::new(&mp3),
+            (id3len + 417, id3len + 3 * 417),
+            1.0,
+        )
+        .unwrap();
+        assert_eq!(segs.len(), 1);
+        assert_eq!(
+            (segs[0].start, segs[0].end),
+            (id3len + 417, id3len + 3 * 417)
+        );
+        assert!((segs[0].duration - 2.0 * frame_dur).abs() < 1e-9);
+
+        assert!(segments(&mut Cursor::new(&mp3), (end, end), 1.0)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{Context, Result};
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use nanoserde::DeJson;
+use std::fmt::{Display, Formatter};
+
+const USER_AGENT: &str = concat!(
+    "musidex/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/Uriopass/Musidex )"
+);
+
+/// Responses bigger than this are not plausible for a search and are rejected
+const MAX_RESPONSE_SIZE: usize = 4 * 1024 * 1024;
+
+/// The web service could not be reached or asked to slow down, the search can be tried again later
+#[derive(Debug)]
+pub struct Unavailable;
+
+impl Display for Unavailable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("musicbrainz is unavailable")
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+#[derive(Clone, Debug, DeJson)]
+pub struct RecordingSearch {
+    #[nserde(default)]
+    pub recordings: Vec<Recording>,
+}
+
+#[derive(Clone, Debug, DeJson)]
+pub struct Recording {
+    pub id: String,
+    #[nserde(default)]
+    pub score: i64,
+    pub title: String,
+    /// in milliseconds
+    pub length: Option<i64>,
+    #[nserde(rename = "artist-credit")]
+    pub artist_credit: Option<Vec<ArtistCredit>>,
+    #[nserde(rename = "first-release-date")]
+    pub first_release_date: Option<String>,
+    pub releases: Option<Vec<Release>>,
+}
+
+#[derive(Clone, Debug, DeJson)]
+pub struct ArtistCredit {
+    pub name: String,
+    pub joinphrase: Option<String>,
+    pub artist: Artist,
+}
+
+#[derive(Clone, Debug, DeJson)]
+pub struct Artist {
+    pub id: String,
+}
+
+#[derive(Clone, Debug, DeJson)]
+pub struct Release {
+    pub id: String,
+    pub title: String,
+    pub status: Option<String>,
+    pub date: Option<String>,
+    #[nserde(rename = "release-group")]
+    pub release_group: Option<ReleaseGroup>,
+}
+
+#[derive(Clone, Debug, DeJson)]
+pub struct ReleaseGroup {
+    #[nserde(rename = "primary-type")]
+    pub primary_type: Option<String>,
+}
+
+impl Recording {
+    /// The artist as it should be displayed, e.g "Daft Punk feat. Pharrell Williams"
+    pub fn artist(&self) -> Option<String> {
+        let credits = self.artist_credit.as_ref()?;
+        if credits.is_empty() {
+            return None;
+        }
+        let mut s = String::new();
+        for credit in credits {
+            s += &credit.name;
+            s += credit.joinphrase.as_deref().unwrap_or("");
+        }
+        Some(s)
+    }
+
+    /// The official album this recording first appeared on, falling back to any release
+    pub fn album(&self) -> Option<&Release> {
+        let releases = self.releases.as_ref()?;
+        let is_album = |r: &&Release| {
+            r.status.as_deref() == Some("Official")
+                && r.release_group
+                    .as_ref()
+                    .and_then(|g| g.primary_type.as_deref())
+                    == Some("Album")
+        };
+        releases
+            .iter()
+            .filter(is_album)
+            .min_by_key(|r| {
+                r.date
+                    .as_deref()
+                    .filter(|d| !d.is_empty())
+                    .unwrap_or("9999")
+            })
+            .or_else(|| releases.first())
+    }
+
+    pub fn year(&self) -> Option<i32> {
+        let date = self.first_release_date.as_deref()?;
+        date.get(..4)?.parse().ok()
+    }
+}
+
+pub struct MusicBrainzClient {
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Default for MusicBrainzClient {
+    fn default() -> Self {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Self {
+            client: Client::builder().build(https),
+        }
+    }
+}
+
+impl MusicBrainzClient {
+    /// Searches for recordings on the MusicBrainz-compatible web service at base_url
+    /// Results are sorted by decreasing score
+    pub async fn search_recording(
+        &self,
+        base_url: &str,
+        title: &str,
+        artist: Option<&str>,
+    ) -> Result<Vec<Recording>> {
+        let mut query = format!("recording:\"{}\"", escape_lucene(title));
+        if let Some(artist) = artist {
+            query += &format!(" AND artist:\"{}\"", escape_lucene(artist));
+        }
+        let url = format!(
+            "{}/ws/2/recording?fmt=json&limit=10&query={}",
+            base_url.trim_end_matches('/'),
+            url_encode(&query)
+        );
+
+        let req = Request::get(&url)
+            .header("User-Agent", USER_AGENT)
+            .header("Accept", "application/json")
+            .body(Body::empty())?;
+        let resp = self
+            .client
+            .request(req)
+            .await
+            .context(Unavailable)
+            .with_context(|| format!("error requesting {}", url))?;
+
+        let status = resp.status();
+        let mut body = resp.into_body();
+        let mut buf = vec![];
+        while let Some(chunk) = body.data().await {
+            buf.extend_from_slice(&chunk.context(Unavailable)?);
+            if buf.len() > MAX_RESPONSE_SIZE {
+                bail!("musicbrainz response too big");
+            }
+        }
+        if status != StatusCode::OK {
+            let err = anyhow!(
+                "musicbrainz returned {}: {}",
+                status,
+                String::from_utf8_lossy(&buf)
+            );
+            if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                return Err(err.context(Unavailable));
+            }
+            return Err(err);
+        }
+
+        let body = String::from_utf8(buf).context("musicbrainz response is not utf8")?;
+        let mut search: RecordingSearch =
+            DeJson::deserialize_json(&body).context("failed parsing musicbrainz response")?;
+        search.recordings.sort_by_key(|r| -r.score);
+        Ok(search.recordings)
+    }
+}
+
+fn escape_lucene(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "+-&|!(){}[]^\"~*?:\\/".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn url_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded += &format!("%{:02X}", b),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape_lucene("AC/DC: T.N.T"), "AC\\/DC\\: T.N.T");
+        assert_eq!(url_encode("a b\"é"), "a%20b%22%C3%A9");
+    }
+
+    #[test]
+    fn test_parse_recording() {
+        let body = r#"{"created":"2023-01-01T00:00:00.000Z","count":1,"offset":0,"recordings":[
+            {"id":"rec","score":97,"title":"Get Lucky","length":248000,"video":null,
+             "artist-credit":[
+                {"name":"Daft Punk","joinphrase":" feat. ","artist":{"id":"dp","name":"Daft Punk","sort-name":"Daft Punk"}},
+                {"name":"Pharrell Williams","artist":{"id":"pw","name":"Pharrell Williams"}}],
+             "first-release-date":"2013-04-19",
+             "releases":[
+                {"id":"single","title":"Get Lucky","status":"Official","date":"2013-04-19",
+                 "release-group":{"id":"g1","primary-type":"Single"}},
+                {"id":"compil","title":"Now 85","status":"Official","date":"2013-07-22",
+                 "release-group":{"id":"g2","primary-type":"Album","secondary-types":["Compilation"]}},
+                {"id":"ram","title":"Random Access Memories","status":"Official","date":"2013-05-17",
+                 "release-group":{"id":"g3","primary-type":"Album"}}]}]}"#;
+        let search: RecordingSearch = DeJson::deserialize_json(body).unwrap();
+        let rec = &search.recordings[0];
+        assert_eq!(rec.score, 97);
+        assert_eq!(rec.length, Some(248000));
+        assert_eq!(
+            rec.artist().as_deref(),
+            Some("Daft Punk feat. Pharrell Williams")
+        );
+        assert_eq!(rec.album().unwrap().title, "Random Access Memories");
+        assert_eq!(rec.year(), Some(2013));
+    }
+}
@@ -21,7 +21,6 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use anyhow::Context as _c;
 use anyhow::Result;
 use std::collections::HashMap;
 use std::error::Error;
@@ -30,20 +29,19 @@ use std::future::Future;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 
-use crate::utils::res_status;
-use futures::FutureExt;
-use hyper::body::Bytes;
+use crate::infrastructure::compression;
+use crate::infrastructure::file_server::{guess_content_type, serve_file};
+use futures::TryFutureExt;
 use hyper::header::{
     HeaderValue, ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
-    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
-    ACCESS_CONTROL_REQUEST_METHOD, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, COOKIE, ORIGIN,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, CACHE_CONTROL, COOKIE, ORIGIN,
     USER_AGENT,
 };
 use hyper::http::Extensions;
 use hyper::service::Service;
-use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::{Body, Method, Request, Response};
 use route_recognizer::Router as InnerRouter;
-use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use tokio::time::Instant;
@@ -152,7 +150,10 @@ impl Router {
         mut req: Request<Body>,
     ) -> Pin<Box<dyn Future<Output = Result<Response<Body>>> + Send>> {
         if req.uri().path() == "/" {
-            return Box::pin(async { serve_file("./web/index.html".as_ref()).await });
+            return Box::pin(async move {
+                let p = Path::new("./web/index.html");
+                serve_file(req.headers(), p, None, guess_content_type(p)).await
+            });
         }
         match self.inner.get(req.method()) {
             Some(inner_router) => match inner_router.recognize(req.uri().path()) {
@@ -169,35 +170,9 @@ impl Router {
                         req.extensions_mut().insert(cookies);
                     }
                     req.extensions_mut().insert(self.state.clone());
-                    let wants_compression = req
-                        .headers()
-                        .get(ACCEPT_ENCODING)
-                        .and_then(|x| x.to_str().ok())
-                        .map(|x| x.contains("deflate"))
-                        .unwrap_or(false);
+                    let encoding = compression::negotiate(req.headers().get(ACCEPT_ENCODING));
                     let f = handler.call(req);
-                    if !wants_compression {
-                        return f;
-                    }
-                    Box::pin(f.then(|r| async {
-                        match r {
-                            Ok(x) => {
-                                let (mut parts, b) = x.into_parts();
-                                let bytes = hyper::body::to_bytes(b).await?;
-
-                                let bytes =
-                                    Bytes::from(miniz_oxide::deflate::compress_to_vec(&*bytes, 3));
-
-                                let b = Body::from(bytes);
-
-                                parts
-                                    .headers
-                                    .insert(CONTENT_ENCODING, HeaderValue::from_static("deflate"));
-                                Ok(Response::from_parts(parts, b))
-                            }
-                            Err(e) => Err(e),
-                        }
-                    }))
+                    Box::pin(f.map_ok(move |r| compression::compress(r, encoding)))
                 }
                 Err(_) => match &self.not_found {
                     Some(handler) => {
@@ -251,38 +226,21 @@ impl Handler for StaticHandle {
             || url.ends_with("js")
             || url.ends_with("wasm")
             || url.ends_with("css");
-        let is_wasm = url.ends_with("wasm");
         Box::pin((move || async move {
-            let mut r = serve_file(&p).await?;
-            if is_wasm {
-                r.headers_mut()
-                    .insert(CONTENT_TYPE, "application/wasm".parse().unwrap());
-            }
-            if should_cache {
-                r.headers_mut().insert(
-                    CACHE_CONTROL,
-                    "public, max-age=604800, immutable".parse().unwrap(),
-                );
-            }
+            let mut r = serve_file(req.headers(), &p, None, guess_content_type(&p)).await?;
+            // everything else is revalidated using its ETag
+            let cache_control = if should_cache {
+                "public, max-age=604800, immutable"
+            } else {
+                "no-cache"
+            };
+            r.headers_mut()
+                .insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
             Ok(r)
         })())
     }
 }
 
-async fn serve_file(p: &Path) -> Result<Response<Body>> {
-    let f = match tokio::fs::read(p).await {
-        Ok(x) => x,
-        Err(e) => {
-            if e.kind() == ErrorKind::NotFound {
-                return Ok(res_status(StatusCode::NOT_FOUND));
-            }
-            return Err(e).context("failed reading file");
-        }
-    };
-
-    return Ok(Response::new(Body::from(f)));
-}
-
 pub(crate) trait Handler: Send + Sync + 'static {
     fn call(
         &self,
@@ -381,6 +339,9 @@ impl Service<Request<Body>> for RouterService {
     }
 }
 
+/// Headers set by the handlers that the web player reads
+const EXPOSED_HEADERS: &str = "X-ReplayGain-Track-Gain, X-ReplayGain-Track-Peak";
+
 fn set_nocors(req: &mut Response<Body>) {
     req.headers_mut()
         .insert(ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
@@ -398,6 +359,10 @@ fn set_nocors(req: &mut Response<Body>) {
             .parse()
             .unwrap(),
     );
+    req.headers_mut().insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        EXPOSED_HEADERS.parse().unwrap(),
+    );
 }
 
 fn set_defaultcors(origin: &str, req: &mut Response<Body>) {
@@ -422,6 +387,10 @@ fn set_defaultcors(origin: &str, req: &mut Response<Body>) {
                 .parse()
                 .unwrap(),
         );
+        req.headers_mut().insert(
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            EXPOSED_HEADERS.parse().unwrap(),
+        );
     }
 }
 
@@ -0,0 +1,670 @@
+use crate::infrastructure::audio::Picture;
+use crate::infrastructure::mp3;
+use anyhow::{Context, Result};
+use base64::Engine;
+use id3::frame::{Comment, Picture as Id3Picture, PictureType};
+use id3::{TagLike, Version};
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom};
+
+const VENDOR: &str = "Musidex";
+
+const FLAC_STREAMINFO: u8 = 0;
+const FLAC_PADDING: u8 = 1;
+const FLAC_VORBIS_COMMENT: u8 = 4;
+const FLAC_PICTURE: u8 = 6;
+
+/// Picture type of the front cover, shared by ID3 and FLAC pictures
+const FRONT_COVER: u32 = 3;
+
+/// Tags written into exported files
+#[derive(Default)]
+pub struct ExportTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    pub track_number: Option<u32>,
+    /// (description, text) pairs
+    pub comments: Vec<(String, String)>,
+    pub cover: Option<Picture>,
+}
+
+impl ExportTags {
+    /// FLAC stores the cover in its own block, ogg files base64-encode it into a comment
+    fn vorbis_comments(&self, embed_cover: bool) -> Vec<String> {
+        let mut v = vec![];
+        let mut add = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                v.push(format!("{}={}", key, value));
+            }
+        };
+        add("TITLE", self.title.clone());
+        add("ARTIST", self.artist.clone());
+        add("ALBUM", self.album.clone());
+        add("DATE", self.year.map(|x| x.to_string()));
+        add("GENRE", self.genre.clone());
+        add("TRACKNUMBER", self.track_number.map(|x| x.to_string()));
+        for (desc, text) in &self.comments {
+            if text.is_empty() {
+                add("COMMENT", Some(desc.clone()));
+            } else {
+                add("COMMENT", Some(format!("{}: {}", desc, text)));
+            }
+        }
+        if let Some(cover) = self.cover.as_ref().filter(|_| embed_cover) {
+            let block = flac_picture(cover);
+            add(
+                "METADATA_BLOCK_PICTURE",
+                Some(base64::engine::general_purpose::STANDARD.encode(block)),
+            );
+        }
+        v
+    }
+}
+
+/// ID3v2.4 tag holding the given tags, to replace the ID3 tags of a window of an mp3 file.
+/// Returns it with the part of the window holding the audio frames.
+pub fn mp3_header<R: Read + Seek>(
+    r: &mut R,
+    window: (u64, u64),
+    tags: &ExportTags,
+) -> Result<(Vec<u8>, (u64, u64))> {
+    let start = window.0.max(mp3::id3v2_size(r)?);
+    let mut end = window.1;
+    if end < start {
+        bail!("truncated ID3v2 tag");
+    }
+    if end - start >= 128 {
+        let mut v1 = [0u8; 3];
+        r.seek(SeekFrom::Start(end - 128))?;
+        r.read_exact(&mut v1)?;
+        if &v1 == b"TAG" {
+            end -= 128;
+        }
+    }
+
+    let mut tag = id3::Tag::new();
+    if let Some(ref x) = tags.title {
+        tag.set_title(x);
+    }
+    if let Some(ref x) = tags.artist {
+        tag.set_artist(x);
+    }
+    if let Some(ref x) = tags.album {
+        tag.set_album(x);
+    }
+    if let Some(x) = tags.year {
+        tag.set_year(x);
+    }
+    if let Some(ref x) = tags.genre {
+        tag.set_genre(x);
+    }
+    if let Some(x) = tags.track_number {
+        tag.set_track(x);
+    }
+    for (desc, text) in &tags.comments {
+        tag.add_frame(Comment {
+            lang: s!("eng"),
+            description: desc.clone(),
+            text: if text.is_empty() { desc } else { text }.clone(),
+        });
+    }
+    if let Some(ref cover) = tags.cover {
+        tag.add_frame(Id3Picture {
+            mime_type: cover.media_type.clone(),
+            picture_type: PictureType::CoverFront,
+            description: String::new(),
+            data: cover.data.to_vec(),
+        });
+    }
+
+    let mut out = vec![];
+    tag.write_to(&mut out, Version::Id3v24)
+        .context("could not write ID3 tag")?;
+    Ok((out, (start, end)))
+}
+
+fn push_u32_be(out: &mut Vec<u8>, x: u32) {
+    out.extend_from_slice(&x.to_be_bytes());
+}
+
+/// Body of a FLAC PICTURE block, also base64-encoded into vorbis comments by ogg files
+fn flac_picture(cover: &Picture) -> Vec<u8> {
+    let (width, height) = image::io::Reader::new(Cursor::new(&cover.data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|r| r.into_dimensions().ok())
+        .unwrap_or((0, 0));
+    let mut out = Vec::with_capacity(cover.data.len() + 64);
+    push_u32_be(&mut out, FRONT_COVER);
+    push_u32_be(&mut out, cover.media_type.len() as u32);
+    out.extend_from_slice(cover.media_type.as_bytes());
+    push_u32_be(&mut out, 0); // description
+    push_u32_be(&mut out, width);
+    push_u32_be(&mut out, height);
+    push_u32_be(&mut out, 24); // color depth
+    push_u32_be(&mut out, 0); // indexed colors
+    push_u32_be(&mut out, cover.data.len() as u32);
+    out.extend_from_slice(&cover.data);
+    out
+}
+
+/// Vendor string of a vorbis comment structure
+fn read_vendor(comment: &[u8]) -> Option<String> {
+    let len = u32::from_le_bytes(comment.get(..4)?.try_into().ok()?) as usize;
+    let vendor = comment.get(4..4 + len)?;
+    String::from_utf8(vendor.to_vec()).ok()
+}
+
+/// Vorbis comment structure, as used by FLAC, Vorbis and Opus
+fn vorbis_comment(vendor: &str, comments: &[String]) -> Vec<u8> {
+    let mut out = vec![];
+    out.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    out.extend_from_slice(vendor.as_bytes());
+    out.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for c in comments {
+        out.extend_from_slice(&(c.len() as u32).to_le_bytes());
+        out.extend_from_slice(c.as_bytes());
+    }
+    out
+}
+
+/// Metadata of a FLAC stream with the VORBIS_COMMENT and PICTURE blocks replaced, and the
+/// offset the audio frames start at. Padding is dropped as the metadata is rewritten anyway.
+pub fn flac_header<R: Read + Seek>(r: &mut R, tags: &ExportTags) -> Result<(Vec<u8>, u64)> {
+    let mut pos = mp3::id3v2_size(r)?;
+    r.seek(SeekFrom::Start(pos))?;
+    let mut magic = [0u8; 4];
+    if r.read_exact(&mut magic).is_err() || &magic != b"fLaC" {
+        bail!("not a flac stream");
+    }
+    pos += 4;
+
+    let mut blocks: Vec<(u8, Vec<u8>)> = vec![];
+    let mut vendor = None;
+    loop {
+        let mut header = [0u8; 4];
+        r.read_exact(&mut header)
+            .context("truncated metadata block")?;
+        let last = header[0] & 0x80 != 0;
+        let kind = header[0] & 0x7F;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        if kind == FLAC_PADDING || kind == FLAC_PICTURE {
+            r.seek(SeekFrom::Current(len as i64))?;
+        } else {
+            let mut body = vec![0; len];
+            r.read_exact(&mut body)
+                .context("truncated metadata block")?;
+            match kind {
+                FLAC_VORBIS_COMMENT => vendor = read_vendor(&body),
+                _ => blocks.push((kind, body)),
+            }
+        }
+        pos += 4 + len as u64;
+        if last {
+            break;
+        }
+    }
+    if blocks.first().map(|x| x.0) != Some(FLAC_STREAMINFO) {
+        bail!("flac stream does not start with STREAMINFO");
+    }
+
+    let comment = vorbis_comment(
+        vendor.as_deref().unwrap_or(VENDOR),
+        &tags.vorbis_comments(false),
+    );
+    blocks.push((FLAC_VORBIS_COMMENT, comment));
+    if let Some(ref cover) = tags.cover {
+        blocks.push((FLAC_PICTURE, flac_picture(cover)));
+    }
+
+    let mut out = b"fLaC".to_vec();
+    let n_blocks = blocks.len();
+    for (i, (kind, body)) in blocks.into_iter().enumerate() {
+        if body.len() >= 1 << 24 {
+            bail!("metadata block too large");
+        }
+        let last = if i + 1 == n_blocks { 0x80 } else { 0 };
+        out.push(kind | last);
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        out.extend_from_slice(&body);
+    }
+    Ok((out, pos))
+}
+
+const OGG_CONTINUED: u8 = 0x01;
+const OGG_BOS: u8 = 0x02;
+
+struct OggPage {
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    seq: u32,
+    segments: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl OggPage {
+    fn len(&self) -> u64 {
+        (27 + self.segments.len() + self.data.len()) as u64
+    }
+}
+
+const OGG_CRC_TABLE: [u32; 256] = ogg_crc_table();
+
+const fn ogg_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut r = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            r = if r & 0x8000_0000 != 0 {
+                (r << 1) ^ 0x04C1_1DB7
+            } else {
+                r << 1
+            };
+            bit += 1;
+        }
+        table[i] = r;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 of ogg pages: no reflection, initial value and final xor of 0
+fn ogg_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |crc, &b| {
+        (crc << 8) ^ OGG_CRC_TABLE[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+/// Reads the next page of an ogg stream, None at its end
+fn read_ogg_page<R: Read>(r: &mut R) -> Result<Option<OggPage>> {
+    let mut h = [0u8; 27];
+    match r.read_exact(&mut h[..1]) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    r.read_exact(&mut h[1..]).context("truncated ogg page")?;
+    if &h[..4] != b"OggS" {
+        bail!("invalid ogg page");
+    }
+    let mut segments = vec![0; h[26] as usize];
+    r.read_exact(&mut segments).context("truncated ogg page")?;
+    let mut data = vec![0; segments.iter().map(|&x| x as usize).sum()];
+    r.read_exact(&mut data).context("truncated ogg page")?;
+    Ok(Some(OggPage {
+        header_type: h[5],
+        granule: u64::from_le_bytes(h[6..14].try_into()?),
+        serial: u32::from_le_bytes(h[14..18].try_into()?),
+        seq: u32::from_le_bytes(h[18..22].try_into()?),
+        segments,
+        data,
+    }))
+}
+
+fn write_ogg_page(out: &mut Vec<u8>, page: &OggPage) {
+    let start = out.len();
+    out.extend_from_slice(b"OggS");
+    out.push(0);
+    out.push(page.header_type);
+    out.extend_from_slice(&page.granule.to_le_bytes());
+    out.extend_from_slice(&page.serial.to_le_bytes());
+    out.extend_from_slice(&page.seq.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.push(page.segments.len() as u8);
+    out.extend_from_slice(&page.segments);
+    out.extend_from_slice(&page.data);
+    let crc = ogg_crc(&out[start..]);
+    out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Header pages of an Ogg Vorbis or Opus stream with the comment header replaced, and the
+/// offset the audio pages start at. The new headers take as many pages as the old ones so
+/// that the audio pages keep their sequence numbers and can be served untouched, the cover
+/// being left out when it does not fit.
+pub fn ogg_header<R: Read>(r: &mut R, tags: &ExportTags) -> Result<(Vec<u8>, u64)> {
+    let mut pages = vec![read_ogg_page(r)?.context("empty ogg stream")?];
+    let (n_headers, comment_prefix, framing_bit): (usize, &[u8], bool) =
+        if pages[0].data.starts_with(b"\x01vorbis") {
+            (3, b"\x03vorbis", true)
+        } else if pages[0].data.starts_with(b"OpusHead") {
+            (2, b"OpusTags", false)
+        } else {
+            bail!("unsupported ogg codec");
+        };
+
+    // gather the header packets, which must end on a page boundary
+    let mut packets: Vec<Vec<u8>> = vec![];
+    let mut current = vec![];
+    loop {
+        let page = pages.last().unwrap();
+        if pages.len() > 1 && (page.serial != pages[0].serial || page.header_type & OGG_BOS != 0) {
+            bail!("multiplexed ogg streams are not supported");
+        }
+        let mut pos = 0;
+        for &seg in &page.segments {
+            current.extend_from_slice(&page.data[pos..pos + seg as usize]);
+            pos += seg as usize;
+            if seg < 255 {
+                packets.push(std::mem::take(&mut current));
+            }
+        }
+        if packets.len() >= n_headers {
+            if packets.len() > n_headers || !current.is_empty() {
+                bail!("audio data shares a page with the headers");
+            }
+            break;
+        }
+        pages.push(read_ogg_page(r)?.context("truncated ogg headers")?);
+    }
+    let first = &pages[0];
+    if packets[0] != first.data {
+        bail!("identification header is not alone on its page");
+    }
+    let audio_start = pages.iter().map(OggPage::len).sum();
+
+    let old_comment = packets[1]
+        .strip_prefix(comment_prefix)
+        .context("invalid comment header")?;
+    let vendor = read_vendor(old_comment);
+    let header_pages = &pages[1..];
+
+    for embed_cover in [true, false] {
+        let mut comment = comment_prefix.to_vec();
+        comment.extend(vorbis_comment(
+            vendor.as_deref().unwrap_or(VENDOR),
+            &tags.vorbis_comments(embed_cover),
+        ));
+        if framing_bit {
+            comment.push(1);
+        }
+
+        // lay the packets out as lacing values, to be spread evenly on the header pages
+        let mut lacing: Vec<(u8, bool)> = vec![];
+        let mut data = vec![];
+        for p in std::iter::once(&comment).chain(&packets[2..]) {
+            let n = p.len();
+            lacing.extend(vec![(255, false); n / 255]);
+            lacing.push(((n % 255) as u8, true));
+            data.extend_from_slice(p);
+        }
+        let n_pages = header_pages.len();
+        if lacing.len() < n_pages || lacing.len() > n_pages * 255 {
+            continue;
+        }
+
+        let mut out = vec![];
+        write_ogg_page(&mut out, first);
+        let mut seg_pos = 0;
+        let mut data_pos = 0;
+        let mut continued = false;
+        for (i, old) in header_pages.iter().enumerate() {
+            let n = lacing.len() / n_pages + usize::from(i < lacing.len() % n_pages);
+            let chunk = &lacing[seg_pos..seg_pos + n];
+            let segments: Vec<u8> = chunk.iter().map(|x| x.0).collect();
+            let len: usize = segments.iter().map(|&x| x as usize).sum();
+            let ends_packet = chunk.iter().any(|x| x.1);
+            let page = OggPage {
+                header_type: if continued { OGG_CONTINUED } else { 0 },
+                // no packet completes on this page
+                granule: if ends_packet { 0 } else { u64::MAX },
+                serial: first.serial,
+                seq: old.seq,
+                segments,
+                data: data[data_pos..data_pos + len].to_vec(),
+            };
+            write_ogg_page(&mut out, &page);
+            seg_pos += n;
+            data_pos += len;
+            continued = !chunk.last().is_some_and(|x| x.1);
+        }
+        return Ok((out, audio_start));
+    }
+    bail!("the tags do not fit in the header pages")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags() -> ExportTags {
+        ExportTags {
+            title: Some(s!("Title")),
+            artist: Some(s!("Artist")),
+            year: Some(1999),
+            comments: vec![(s!("favorite"), s!("")), (s!("mood"), s!("calm"))],
+            cover: Some(Picture {
+                media_type: s!("image/jpeg"),
+                // large enough for the comment header to span several pages
+                data: vec![7u8; 100_000].into(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Appends a page holding whole packets
+    fn ogg_page(out: &mut Vec<u8>, header_type: u8, granule: u64, seq: u32, packets: &[&[u8]]) {
+        let mut segments = vec![];
+        let mut data = vec![];
+        for p in packets {
+            segments.extend(vec![255; p.len() / 255]);
+            segments.push((p.len() % 255) as u8);
+            data.extend_from_slice(p);
+        }
+        let page = OggPage {
+            header_type,
+            granule,
+            serial: 42,
+            seq,
+            segments,
+            data,
+        };
+        write_ogg_page(out, &page);
+    }
+
+    /// Checks the pages are consistent and returns the packets with the granule of their page
+    fn ogg_packets(file: &[u8]) -> Vec<(Vec<u8>, u64)> {
+        let mut packets = vec![];
+        let mut current = vec![];
+        let mut pos = 0;
+        let mut r = Cursor::new(file);
+        let mut seq = 0;
+        while let Some(page) = read_ogg_page(&mut r).unwrap() {
+            let len = page.len() as usize;
+            let mut raw = file[pos..pos + len].to_vec();
+            assert_eq!(page.seq, seq);
+            seq += 1;
+            let crc = u32::from_le_bytes(raw[22..26].try_into().unwrap());
+            raw[22..26].copy_from_slice(&[0; 4]);
+            assert_eq!(ogg_crc(&raw), crc);
+            assert_eq!(page.header_type & OGG_CONTINUED != 0, !current.is_empty());
+            pos += len;
+
+            let mut data_pos = 0;
+            for &seg in &page.segments {
+                current.extend_from_slice(&page.data[data_pos..data_pos + seg as usize]);
+                data_pos += seg as usize;
+                if seg < 255 {
+                    packets.push((std::mem::take(&mut current), page.granule));
+                }
+            }
+        }
+        packets
+    }
+
+    fn comments_of(comment: &[u8]) -> Vec<String> {
+        let vendor_len = u32::from_le_bytes(comment[..4].try_into().unwrap()) as usize;
+        let mut pos = 4 + vendor_len;
+        let n = u32::from_le_bytes(comment[pos..pos + 4].try_into().unwrap());
+        pos += 4;
+        (0..n)
+            .map(|_| {
+                let len = u32::from_le_bytes(comment[pos..pos + 4].try_into().unwrap()) as usize;
+                pos += 4 + len;
+                String::from_utf8(comment[pos - len..pos].to_vec()).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_ogg_crc() {
+        assert_eq!(ogg_crc(b""), 0);
+        assert_eq!(ogg_crc(b"123456789"), 0x89A1_897F);
+    }
+
+    #[test]
+    fn test_vorbis_comment() {
+        let c = vorbis_comment("v", &[s!("A=b")]);
+        assert_eq!(c, b"\x01\0\0\0v\x01\0\0\0\x03\0\0\0A=b");
+        assert_eq!(read_vendor(&c).as_deref(), Some("v"));
+        assert_eq!(read_vendor(b"\x05\0\0\0ab"), None);
+    }
+
+    #[test]
+    fn test_flac_header() {
+        let mut file = b"fLaC".to_vec();
+        file.extend_from_slice(&[FLAC_STREAMINFO, 0, 0, 34]);
+        file.extend_from_slice(&[1; 34]);
+        let old_comment = vorbis_comment("reference", &[s!("TITLE=old")]);
+        file.extend_from_slice(&[FLAC_VORBIS_COMMENT, 0, 0, old_comment.len() as u8]);
+        file.extend_from_slice(&old_comment);
+        file.extend_from_slice(&[0x80 | FLAC_PADDING, 0, 0, 8]);
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(b"frames");
+
+        let (out, audio_start) = flac_header(&mut Cursor::new(&file), &tags()).unwrap();
+        assert_eq!(&file[audio_start as usize..], b"frames");
+        assert!(out.starts_with(b"fLaC"));
+        let mut blocks = vec![];
+        let mut pos = 4;
+        loop {
+            let h = &out[pos..pos + 4];
+            let len = u32::from_be_bytes([0, h[1], h[2], h[3]]) as usize;
+            blocks.push((h[0] & 0x7F, h[0] & 0x80 != 0, &out[pos + 4..pos + 4 + len]));
+            pos += 4 + len;
+            if h[0] & 0x80 != 0 {
+                break;
+            }
+        }
+        assert_eq!(pos, out.len());
+
+        let kinds: Vec<_> = blocks.iter().map(|x| (x.0, x.1)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (FLAC_STREAMINFO, false),
+                (FLAC_VORBIS_COMMENT, false),
+                (FLAC_PICTURE, true)
+            ]
+        );
+        assert_eq!(blocks[0].2, &[1; 34]);
+        assert_eq!(read_vendor(blocks[1].2).as_deref(), Some("reference"));
+        assert_eq!(
+            comments_of(blocks[1].2),
+            vec![
+                "TITLE=Title",
+                "ARTIST=Artist",
+                "DATE=1999",
+                "COMMENT=favorite",
+                "COMMENT=mood: calm"
+            ]
+        );
+        assert!(blocks[2].2.ends_with(&[7; 100]));
+
+        let header = |file: &[u8]| flac_header(&mut Cursor::new(file), &tags());
+        assert!(header(b"fLaC\x80\0\0\x01").is_err());
+        assert!(header(b"RIFF").is_err());
+    }
+
+    #[test]
+    fn test_ogg_header() {
+        let ident = [b"\x01vorbis".as_slice(), &[3; 23]].concat();
+        let old_comment = [
+            b"\x03vorbis".as_slice(),
+            &vorbis_comment("reference", &[s!("TITLE=old")]),
+            &[1],
+        ]
+        .concat();
+        let setup = [b"\x05vorbis".as_slice(), &[5; 600]].concat();
+        let audio: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 100 + i as usize]).collect();
+        let header = |file: &[u8], tags: &ExportTags| {
+            let (mut out, audio_start) = ogg_header(&mut Cursor::new(file), tags)?;
+            out.extend_from_slice(&file[audio_start as usize..]);
+            Ok::<_, anyhow::Error>(out)
+        };
+
+        // the comment and setup headers span two pages, enough room for a smaller cover
+        let mut file = vec![];
+        ogg_page(&mut file, OGG_BOS, 0, 0, &[&ident]);
+        ogg_page(&mut file, 0, 0, 1, &[&old_comment]);
+        ogg_page(&mut file, 0, 0, 2, &[&setup]);
+        let audio_at = file.len();
+        ogg_page(&mut file, 0, 1000, 3, &[&audio[0], &audio[1]]);
+        ogg_page(&mut file, 0x04, 2000, 4, &[&audio[2], &audio[3]]);
+        let audio_pages = file[audio_at..].to_vec();
+
+        let small_cover = ExportTags {
+            cover: Some(Picture {
+                media_type: s!("image/jpeg"),
+                data: vec![7u8; 40_000].into(),
+            }),
+            ..tags()
+        };
+        let out = header(&file, &small_cover).unwrap();
+        assert!(out.ends_with(&audio_pages));
+        let packets = ogg_packets(&out);
+        assert_eq!(packets.len(), 7);
+        assert_eq!(packets[0].0, ident);
+        assert_eq!(packets[2], (setup.clone(), 0));
+        let granules: Vec<_> = packets[3..].iter().map(|x| x.1).collect();
+        assert_eq!(granules, vec![1000, 1000, 2000, 2000]);
+        for (p, a) in packets[3..].iter().zip(&audio) {
+            assert_eq!(&p.0, a);
+        }
+
+        let comment = packets[1].0.strip_prefix(b"\x03vorbis").unwrap();
+        assert_eq!(comment.last(), Some(&1));
+        assert_eq!(read_vendor(comment).as_deref(), Some("reference"));
+        let comments = comments_of(comment);
+        assert_eq!(comments[0], "TITLE=Title");
+        let picture = comments
+            .last()
+            .unwrap()
+            .strip_prefix("METADATA_BLOCK_PICTURE=")
+            .unwrap();
+        let picture = base64::engine::general_purpose::STANDARD
+            .decode(picture)
+            .unwrap();
+        assert_eq!(
+            u32::from_be_bytes(picture[..4].try_into().unwrap()),
+            FRONT_COVER
+        );
+        assert!(picture.ends_with(&[7; 100]));
+
+        // a single header page can't hold the cover, which is left out
+        let mut file = vec![];
+        ogg_page(&mut file, OGG_BOS, 0, 0, &[&ident]);
+        ogg_page(&mut file, 0, 0, 1, &[&old_comment, &setup]);
+        ogg_page(&mut file, 0, 1000, 2, &[&audio[0], &audio[1]]);
+        let packets = ogg_packets(&header(&file, &tags()).unwrap());
+        assert_eq!(packets.len(), 5);
+        assert_eq!(packets[2].0, setup);
+        let comments = comments_of(packets[1].0.strip_prefix(b"\x03vorbis").unwrap());
+        assert_eq!(comments[0], "TITLE=Title");
+        assert!(comments
+            .iter()
+            .all(|x| !x.starts_with("METADATA_BLOCK_PICTURE=")));
+
+        // audio sharing a page with the setup header
+        let mut file = vec![];
+        ogg_page(&mut file, OGG_BOS, 0, 0, &[&ident]);
+        ogg_page(&mut file, 0, 1000, 1, &[&old_comment, &setup, &audio[0]]);
+        assert!(header(&file, &tags()).is_err());
+
+        assert!(header(b"OggS", &tags()).is_err());
+    }
+}
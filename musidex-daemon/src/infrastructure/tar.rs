@@ -0,0 +1,235 @@
+use crate::infrastructure::file_server::{ByteSource, ByteStream};
+use crate::utils::file_chunks;
+use anyhow::Result;
+use futures::{StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use std::path::PathBuf;
+
+const BLOCK: u64 = 512;
+/// Archives end with two empty blocks
+const TRAILER: u64 = 2 * BLOCK;
+
+/// A file in the archive, read from a byte window of a file on disk
+pub struct TarEntry {
+    /// path in the archive, at most 100 bytes
+    pub name: String,
+    pub path: PathBuf,
+    pub offset: u64,
+    pub size: u64,
+    /// unix timestamp in seconds
+    pub mtime: u64,
+}
+
+/// An uncompressed ustar archive generated on the fly. The same entries always give the
+/// same bytes, so a download can be resumed with a range request.
+pub struct TarArchive {
+    entries: Vec<TarEntry>,
+    /// offset of the header of each entry
+    offsets: Vec<u64>,
+    size: u64,
+}
+
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK) * BLOCK
+}
+
+/// Writes `v` as a zero padded octal number followed by a NUL, filling the field
+fn octal(field: &mut [u8], v: u64) {
+    let digits = format!("{:0width$o}", v, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+fn header(entry: &TarEntry) -> [u8; BLOCK as usize] {
+    let mut h = [0u8; BLOCK as usize];
+    h[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+    octal(&mut h[100..108], 0o644);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], entry.size);
+    octal(&mut h[136..148], entry.mtime);
+    h[156] = b'0';
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+
+    // computed with the checksum field filled with spaces
+    h[148..156].copy_from_slice(b"        ");
+    let sum: u64 = h.iter().map(|&x| x as u64).sum();
+    octal(&mut h[148..155], sum);
+    h
+}
+
+/// The part of `bytes`, placed at `at` in the archive, that is within [start, end)
+fn intersect(bytes: &[u8], at: u64, start: u64, end: u64) -> Option<Bytes> {
+    let from = start.max(at);
+    let to = end.min(at + bytes.len() as u64);
+    if from >= to {
+        return None;
+    }
+    Some(Bytes::copy_from_slice(
+        &bytes[(from - at) as usize..(to - at) as usize],
+    ))
+}
+
+fn zeros(at: u64, len: u64, start: u64, end: u64) -> Option<Bytes> {
+    let from = start.max(at);
+    let to = end.min(at + len);
+    if from >= to {
+        return None;
+    }
+    Some(Bytes::from(vec![0u8; (to - from) as usize]))
+}
+
+impl TarArchive {
+    pub fn new(entries: Vec<TarEntry>) -> Result<Self> {
+        let mut offsets = Vec::with_capacity(entries.len());
+        let mut size = 0;
+        for entry in &entries {
+            if entry.name.len() > 100 {
+                bail!("name too long for a tar entry: {}", entry.name);
+            }
+            offsets.push(size);
+            size += BLOCK + padded(entry.size);
+        }
+        Ok(Self {
+            entries,
+            offsets,
+            size: size + TRAILER,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Offset of the content of the nth entry in the archive
+    pub fn data_offset(&self, n: usize) -> u64 {
+        self.offsets[n] + BLOCK
+    }
+}
+
+impl ByteSource for TarArchive {
+    fn chunks(&self, start: u64, len: u64) -> Result<ByteStream> {
+        let end = start + len;
+        let once = |b: Bytes| -> ByteStream { futures::stream::once(async { Ok(b) }).boxed() };
+        let mut pieces: Vec<ByteStream> = vec![];
+        for (entry, &at) in self.entries.iter().zip(&self.offsets) {
+            let data = at + BLOCK;
+            let next = data + padded(entry.size);
+            if next <= start {
+                continue;
+            }
+            if at >= end {
+                break;
+            }
+            pieces.extend(intersect(&header(entry), at, start, end).map(once));
+
+            let from = start.max(data);
+            let to = end.min(data + entry.size);
+            if from < to {
+                let path = entry.path.clone();
+                let offset = entry.offset + from - data;
+                // files are opened only once the stream gets to them
+                let file = futures::stream::once(async move { tokio::fs::File::open(path).await })
+                    .map_ok(move |f| file_chunks(f, offset, to - from))
+                    .try_flatten();
+                pieces.push(file.boxed());
+            }
+            let padding = next - data - entry.size;
+            pieces.extend(zeros(data + entry.size, padding, start, end).map(once));
+        }
+        pieces.extend(zeros(self.size - TRAILER, TRAILER, start, end).map(once));
+        Ok(futures::stream::iter(pieces).flatten().boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(archive: &TarArchive, start: u64, len: u64) -> Vec<u8> {
+        let chunks: Vec<Bytes> = archive
+            .chunks(start, len)
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        chunks.concat()
+    }
+
+    #[test]
+    fn test_header() {
+        let h = header(&TarEntry {
+            name: s!("music/1.mp3"),
+            path: PathBuf::new(),
+            offset: 0,
+            size: 1000,
+            mtime: 1700000000,
+        });
+        assert_eq!(&h[..11], b"music/1.mp3");
+        assert_eq!(&h[124..136], b"00000001750\0");
+        assert_eq!(&h[136..148], b"14524770400\0");
+        assert_eq!(&h[257..265], b"ustar\x0000");
+        let mut unsummed = h;
+        unsummed[148..156].copy_from_slice(b"        ");
+        let sum: u64 = unsummed.iter().map(|&x| x as u64).sum();
+        let stored = std::str::from_utf8(&h[148..154]).unwrap();
+        assert_eq!(u64::from_str_radix(stored, 8).unwrap(), sum);
+        assert_eq!(&h[154..156], b"\0 ");
+    }
+
+    #[tokio::test]
+    async fn test_tar_archive() {
+        let a: Vec<u8> = (0..1500u32).map(|x| (x % 251) as u8).collect();
+        std::fs::write("./storage/test-tar-a.bin", &a).unwrap();
+        let archive = TarArchive::new(vec![
+            TarEntry {
+                name: s!("a.bin"),
+                path: PathBuf::from("./storage/test-tar-a.bin"),
+                offset: 0,
+                size: 1500,
+                mtime: 1,
+            },
+            TarEntry {
+                name: s!("dir/window.bin"),
+                path: PathBuf::from("./storage/test-tar-a.bin"),
+                offset: 100,
+                size: 512,
+                mtime: 2,
+            },
+        ])
+        .unwrap();
+        assert_eq!(archive.size(), 512 + 1536 + 512 + 512 + 1024);
+        assert_eq!(archive.data_offset(1), 512 + 1536 + 512);
+
+        let full = read(&archive, 0, archive.size()).await;
+        assert_eq!(full.len() as u64, archive.size());
+        assert_eq!(&full[..5], b"a.bin");
+        assert_eq!(&full[512..2012], a.as_slice());
+        assert!(full[2012..2048].iter().all(|&x| x == 0));
+        assert_eq!(&full[2048..2062], b"dir/window.bin");
+        assert_eq!(&full[2560..3072], &a[100..612]);
+        assert!(full[3072..].iter().all(|&x| x == 0));
+
+        // any range gives the same bytes as the whole archive
+        for (start, len) in [(0, 1), (500, 30), (2000, 600), (2559, 2), (3000, 1096)] {
+            assert_eq!(
+                read(&archive, start, len).await,
+                &full[start as usize..(start + len) as usize],
+                "{} {}",
+                start,
+                len
+            );
+        }
+
+        assert!(TarArchive::new(vec![TarEntry {
+            name: "x".repeat(101),
+            path: PathBuf::new(),
+            offset: 0,
+            size: 0,
+            mtime: 0,
+        }])
+        .is_err());
+        std::fs::remove_file("./storage/test-tar-a.bin").unwrap();
+    }
+}
@@ -7,7 +7,6 @@ pub enum YoutubeDlOutput {
     SingleVideo(Box<SingleVideo>),
 }
 
-/*
 #[derive(Clone, SerJson, DeJson, Debug, Default)]
 pub struct Chapter {
     pub end_time: Option<f64>,
@@ -15,6 +14,7 @@ pub struct Chapter {
     pub title: Option<String>,
 }
 
+/*
 #[derive(Clone, SerJson, DeJson, Debug, Default)]
 pub struct Comment {
     pub author: Option<String>,
@@ -181,7 +181,7 @@ pub struct SingleVideo {
     //pub abr: Option<f64>,
     //pub acodec: Option<String>,
     //pub age_limit: Option<i64>,
-    //pub album: Option<String>,
+    pub album: Option<String>,
     //pub album_artist: Option<String>,
     //pub album_type: Option<String>,
     //pub alt_title: Option<String>,
@@ -190,18 +190,18 @@ pub struct SingleVideo {
     //pub automatic_captions: Option<BTreeMap<String, Vec<Subtitle>>>,
     //pub average_rating: Option<Value>,
     //pub categories: Option<Vec<Option<String>>>,
-    //pub channel: Option<String>,
+    pub channel: Option<String>,
     //pub channel_id: Option<String>,
     //pub channel_url: Option<String>,
     //pub chapter: Option<String>,
     //pub chapter_id: Option<String>,
     //pub chapter_number: Option<String>,
-    //pub chapters: Option<Vec<Chapter>>,
+    pub chapters: Option<Vec<Chapter>>,
     //pub comment_count: Option<i64>,
     //pub comments: Option<Vec<Comment>>,
     //pub container: Option<String>,
     //pub creator: Option<String>,
-    //pub description: Option<String>,
+    pub description: Option<String>,
     //pub disc_number: Option<i64>,
     //pub dislike_count: Option<i64>,
     //pub display_id: Option<String>,
@@ -225,7 +225,7 @@ pub struct SingleVideo {
     //pub fps: Option<f64>,
     //pub fragment_base_url: Option<String>,
     //pub fragments: Option<Vec<Fragment>>,
-    //pub genre: Option<String>,
+    pub genre: Option<String>,
     //pub height: Option<i64>,
     //pub http_headers: Option<BTreeMap<String, Option<String>>>,
     pub id: String,
@@ -249,9 +249,9 @@ pub struct SingleVideo {
     //pub protocol: Option<Protocol>,
     //pub quality: Option<i64>,
     //pub release_date: Option<String>,
-    //pub release_year: Option<i64>,
+    pub release_year: Option<i64>,
     //pub repost_count: Option<i64>,
-    //pub requested_subtitles: Option<BTreeMap<String, Subtitle>>,
+    pub requested_subtitles: Option<HashMap<String, Subtitle>>,
     //pub resolution: Option<String>,
     //pub season: Option<String>,
     //pub season_id: Option<String>,
@@ -261,7 +261,7 @@ pub struct SingleVideo {
     //pub start_time: Option<String>,
     //pub stretched_ratio: Option<f64>,
     //pub subtitles: Option<BTreeMap<String, Option<Vec<Subtitle>>>>,
-    //pub tags: Option<Vec<Option<String>>>,
+    pub tags: Option<Vec<Option<String>>>,
     //pub tbr: Option<f64>,
     pub thumbnail: Option<String>,
     //pub thumbnails: Option<Vec<Thumbnail>>,
@@ -270,9 +270,9 @@ pub struct SingleVideo {
     pub title: String,
     pub track: Option<String>,
     //pub track_id: Option<String>,
-    //pub track_number: Option<String>,
-    //pub upload_date: Option<String>,
-    //pub uploader: Option<String>,
+    pub track_number: Option<i64>,
+    pub upload_date: Option<String>,
+    pub uploader: Option<String>,
     //pub uploader_id: Option<String>,
     //pub uploader_url: Option<String>,
     pub url: Option<String>,
@@ -327,6 +327,10 @@ pub enum Protocol {
     HttpDashSegments,
 } */
 
+use crate::domain::config;
+use crate::utils::env_or;
+use rusqlite::Connection;
+use std::collections::HashMap;
 use std::io::{copy, Read};
 use std::process::{Command, Stdio};
 use std::str::Chars;
@@ -336,18 +340,97 @@ pub struct JustType {
     _type: Option<String>,
 }
 
-pub async fn ytdl_run_with_args(args: Vec<&str>) -> Result<YoutubeDlOutput> {
-    let args: Vec<_> = args.into_iter().map(ToString::to_string).collect();
+/// How yt-dlp gets invoked. The binary and extra args come from the YTDLP_PATH and
+/// YTDLP_EXTRA_ARGS environment variables, the cookies file and the proxy (which may hold
+/// credentials) from the ytdlp_cookies and ytdlp_proxy secrets, the rest from the ytdlp_* keys
+/// of the config.
+#[derive(Clone, Debug)]
+pub struct YoutubeDlConfig {
+    pub path: String,
+    pub extra_args: Vec<String>,
+    pub cookies: Option<String>,
+    pub proxy: Option<String>,
+    pub rate_limit: Option<String>,
+    pub format: String,
+    pub audio_format: String,
+    /// subtitles in these languages are downloaded as lyrics, e.g "en.*,fr"
+    pub subtitles_langs: Option<String>,
+}
+
+impl Default for YoutubeDlConfig {
+    fn default() -> Self {
+        Self {
+            path: s!("yt-dlp"),
+            extra_args: vec![],
+            cookies: None,
+            proxy: None,
+            rate_limit: None,
+            format: s!("bestaudio"),
+            audio_format: s!("mp3"),
+            subtitles_langs: None,
+        }
+    }
+}
+
+impl YoutubeDlConfig {
+    pub fn load(c: &Connection) -> Result<Self> {
+        let get = |key: &str| -> Result<Option<String>> {
+            Ok(config::get(c, key)?.filter(|x| !x.trim().is_empty()))
+        };
+        let secret = |name: &str| -> Result<Option<String>> {
+            Ok(config::get_secret(c, name)?.filter(|x| !x.trim().is_empty()))
+        };
+        let def = Self::default();
+        Ok(Self {
+            path: env_or("YTDLP_PATH", def.path),
+            extra_args: env_or("YTDLP_EXTRA_ARGS", s!(""))
+                .split_whitespace()
+                .map(ToString::to_string)
+                .collect(),
+            cookies: secret("ytdlp_cookies")?,
+            proxy: secret("ytdlp_proxy")?,
+            rate_limit: get("ytdlp_rate_limit")?,
+            format: get("ytdlp_format")?.unwrap_or(def.format),
+            audio_format: get("ytdlp_audio_format")?.unwrap_or(def.audio_format),
+            subtitles_langs: get("ytdlp_subtitles_langs")?,
+        })
+    }
+
+    /// Arguments passed to every invocation, before the command specific ones
+    fn common_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(ref cookies) = self.cookies {
+            args.push(s!("--cookies"));
+            args.push(cookies.clone());
+        }
+        if let Some(ref proxy) = self.proxy {
+            args.push(s!("--proxy"));
+            args.push(proxy.clone());
+        }
+        if let Some(ref rate) = self.rate_limit {
+            args.push(s!("--limit-rate"));
+            args.push(rate.clone());
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+pub async fn ytdl_run_with_args(cfg: &YoutubeDlConfig, args: Vec<&str>) -> Result<YoutubeDlOutput> {
+    let mut all_args = cfg.common_args();
+    all_args.extend(args.into_iter().map(ToString::to_string));
+    let args = all_args;
+    let path = cfg.path.clone();
 
-    log::info!("running yt dl with args: {}", args.join(" "));
+    log::info!("running {} with args: {}", &path, args.join(" "));
 
     tokio::task::spawn_blocking(move || {
-        let mut child = Command::new("yt-dlp")
+        let mut child = Command::new(&path)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .args(args)
             .spawn()
-            .context("error starting yt-dlp, did you install it?")?;
+            .with_context(|| format!("error starting {}, did you install it?", &path))?;
         // Continually read from stdout so that it does not fill up with large output and hang forever.
         // We don't need to do this for stderr since only stdout has potentially giant JSON.
         let mut stdout = Vec::new();
@@ -10,12 +10,23 @@ mod infrastructure;
 #[cfg(test)]
 mod tests;
 
-use crate::application::{handlers, user_handlers};
+use crate::application::{
+    handlers, lyrics_handlers, playlist_handlers, share_handlers, title_rule_handlers,
+    user_handlers,
+};
 use crate::domain::clean::clean;
 use crate::domain::config;
 use crate::domain::sync::SyncBroadcast;
+use crate::domain::worker_analysis::AnalysisWorker;
+use crate::domain::worker_cover_art::CoverArtWorker;
+use crate::domain::worker_csv_import::CsvImportWorker;
+use crate::domain::worker_fingerprint::FingerprintWorker;
+use crate::domain::worker_loudness::LoudnessWorker;
+use crate::domain::worker_musicbrainz::MusicBrainzWorker;
 use crate::domain::worker_neural_embed::NeuralEmbedWorker;
+use crate::domain::worker_probe::ProbeWorker;
 use crate::domain::worker_thumbnail_resize::SmallThumbnailWorker;
+use crate::domain::worker_waveform::WaveformWorker;
 use crate::domain::worker_youtube_dl::YoutubeDLWorker;
 use crate::infrastructure::db::Db;
 use crate::infrastructure::migrate::migrate;
@@ -46,6 +57,14 @@ async fn start() -> anyhow::Result<()> {
     let ytdl_worker = YoutubeDLWorker::new(db.clone());
     let neuralembed_worker = NeuralEmbedWorker::new(db.clone());
     let small_thumbnail_worker = SmallThumbnailWorker::new(db.clone());
+    let musicbrainz_worker = MusicBrainzWorker::new(db.clone());
+    let fingerprint_worker = FingerprintWorker::new(db.clone());
+    let loudness_worker = LoudnessWorker::new(db.clone());
+    let analysis_worker = AnalysisWorker::new(db.clone());
+    let waveform_worker = WaveformWorker::new(db.clone());
+    let probe_worker = ProbeWorker::new(db.clone());
+    let cover_art_worker = CoverArtWorker::new(db.clone());
+    let csv_import_worker = CsvImportWorker::new(db.clone());
     let (broadcast, sub) = SyncBroadcast::new()?;
 
     let mut router = Router::new();
@@ -74,16 +93,54 @@ async fn start() -> anyhow::Result<()> {
             "/api/youtube_upload/playlist",
             handlers::youtube_upload_playlist,
         )
+        .post("/api/youtube_upload/csv", handlers::youtube_upload_csv)
+        .get(
+            "/api/youtube_upload/csv/:id",
+            handlers::youtube_upload_csv_report,
+        )
         .get("/api/stream/:musicid", handlers::stream)
+        .get("/api/waveform/:musicid", handlers::waveform)
+        .get("/api/thumbnail/:musicid", handlers::thumbnail)
+        .get("/api/download/:musicid", handlers::download)
+        .get("/api/hls/:musicid/playlist.m3u8", handlers::hls_master)
+        .get(
+            "/api/hls/:musicid/:variant/playlist.m3u8",
+            handlers::hls_playlist,
+        )
+        .get("/api/hls/:musicid/:variant/:segment", handlers::hls_segment)
+        .get("/api/offline/manifest", handlers::offline_manifest)
+        .get("/api/offline/archive", handlers::offline_archive)
+        .get("/api/export/:format", playlist_handlers::export)
+        .post("/api/import/:format", playlist_handlers::import)
+        .post("/api/share", share_handlers::create)
+        .get("/s/:token", share_handlers::page)
+        .get("/s/:token/stream/:musicid", share_handlers::stream)
+        .get("/s/:token/thumbnail/:musicid", share_handlers::thumbnail)
+        .get("/api/lyrics/:musicid", lyrics_handlers::get)
+        .put("/api/lyrics/:musicid", lyrics_handlers::update)
+        .delete("/api/lyrics/:musicid", lyrics_handlers::delete)
+        .post("/api/lyrics/search", lyrics_handlers::search)
         .delete("/api/music/:id", handlers::delete_music_handler)
         .post("/api/music/retry_errors", handlers::retry_on_error)
         .post("/api/music/merge", handlers::merge_music)
+        .post(
+            "/api/music/:id/suggestions/accept",
+            handlers::accept_suggestions,
+        )
+        .delete("/api/music/:id/suggestions", handlers::reject_suggestions)
+        .put("/api/music/:id/thumbnail", handlers::upload_thumbnail)
+        .get("/api/duplicates", handlers::duplicates)
+        .post("/api/duplicates/merge", handlers::merge_duplicates)
         .post("/api/tag/create", handlers::create_tag)
         .delete("/api/tag", handlers::delete_tag)
         .put("/api/putontop/:id", handlers::put_on_top)
         .post("/api/user/create", user_handlers::create)
         .post("/api/user/update/:id", user_handlers::update)
         .delete("/api/user/:id", user_handlers::delete)
+        .get("/api/title_rules", title_rule_handlers::list)
+        .post("/api/title_rules", title_rule_handlers::update)
+        .post("/api/title_rules/preview", title_rule_handlers::preview_rules)
+        .post("/api/title_rules/apply", title_rule_handlers::apply)
         .static_files("/storage/", "./storage/")
         .static_files("/", "./web/")
         .nocors(env_or("NO_CORS", false));
@@ -97,6 +154,14 @@ async fn start() -> anyhow::Result<()> {
     ytdl_worker.start();
     neuralembed_worker.start();
     small_thumbnail_worker.start();
+    musicbrainz_worker.start();
+    fingerprint_worker.start();
+    loudness_worker.start();
+    analysis_worker.start();
+    waveform_worker.start();
+    probe_worker.start();
+    cover_art_worker.start();
+    csv_import_worker.start();
     broadcast.start_workers();
 
     let server = Server::builder(incoming).serve(service);
@@ -0,0 +1,66 @@
+use super::*;
+use crate::domain::entity::{Music, Tag, TagKey};
+use crate::domain::worker_youtube_dl::split_chapters;
+use crate::infrastructure::youtube_dl::Chapter;
+use anyhow::Result;
+
+fn chapter(start: f64, end: f64, title: &str) -> Chapter {
+    Chapter {
+        start_time: Some(start),
+        end_time: Some(end),
+        title: Some(s!(title)),
+    }
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_split_chapters() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let parent = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(parent, TagKey::LocalMP3, s!("mix.mp3")))?;
+    Tag::insert(&c, Tag::new_key(parent, TagKey::UserLibrary(s!("1"))))?;
+    Tag::insert(&c, Tag::new_text(parent, TagKey::Title, s!("Best of mix")))?;
+
+    let chapters = vec![
+        chapter(0.0, 120.5, "Jamiroquai - Virtual Insanity"),
+        chapter(120.5, 300.0, "September"),
+        chapter(300.0, 300.0, "empty"),
+    ];
+    assert_eq!(split_chapters(&c, parent, &chapters)?, 2);
+
+    let ids: Vec<_> = Tag::by_key(&c, TagKey::ChapterStart)?
+        .into_iter()
+        .map(|t| t.music_id)
+        .collect();
+    assert_eq!(ids.len(), 2);
+
+    let title = Tag::by_id_key(&c, ids[0], TagKey::Title)?.unwrap();
+    assert_eq!(title.text.as_deref(), Some("Virtual Insanity"));
+    let artist = Tag::by_id_key(&c, ids[0], TagKey::Artist)?.unwrap();
+    assert_eq!(artist.text.as_deref(), Some("Jamiroquai"));
+
+    let source = Tag::by_id_key(&c, ids[1], TagKey::LocalMP3)?.unwrap();
+    assert_eq!(source.text.as_deref(), Some("mix.mp3"));
+    assert!(Tag::has(&c, ids[1], TagKey::UserLibrary(s!("1")))?);
+    let end = Tag::by_id_key(&c, ids[1], TagKey::ChapterEnd)?.unwrap();
+    assert_eq!(end.text.as_deref(), Some("300"));
+    let duration = Tag::by_id_key(&c, ids[1], TagKey::Duration)?.unwrap();
+    assert_eq!(duration.integer, Some(179));
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_split_chapters_not_mp3() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let parent = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(parent, TagKey::LocalM4A, s!("mix.m4a")))?;
+
+    let chapters = vec![chapter(0.0, 10.0, "a"), chapter(10.0, 20.0, "b")];
+    assert_eq!(split_chapters(&c, parent, &chapters)?, 0);
+
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder};
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, ETAG, RANGE, VARY,
+};
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::io::AsyncReadExt;
+
+fn json() -> String {
+    let items: Vec<String> = (0..2000).map(|i| format!("{{\"id\":{}}}", i)).collect();
+    format!("[{}]", items.join(","))
+}
+
+async fn api(_: Request<Body>) -> Result<Response<Body>> {
+    Ok(Response::new(Body::from(json())))
+}
+
+async fn small(_: Request<Body>) -> Result<Response<Body>> {
+    Ok(Response::new(Body::from("[]")))
+}
+
+async fn audio(_: Request<Body>) -> Result<Response<Body>> {
+    let mut r = Response::new(Body::from(vec![0u8; 10000]));
+    r.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("audio/mpeg"));
+    Ok(r)
+}
+
+async fn get(
+    router: &Router,
+    path: &str,
+    headers: &[(hyper::header::HeaderName, &'static str)],
+) -> Result<(hyper::http::response::Parts, Vec<u8>)> {
+    let mut req = Request::get(path);
+    for (k, v) in headers {
+        req = req.header(k, *v);
+    }
+    let r = router.serve(req.body(Body::empty())?).await?;
+    let (parts, body) = r.into_parts();
+    let body = hyper::body::to_bytes(body).await?.to_vec();
+    Ok((parts, body))
+}
+
+async fn decode(encoding: &str, body: &[u8]) -> Result<String> {
+    let mut out = String::new();
+    match encoding {
+        "br" => BrotliDecoder::new(body).read_to_string(&mut out).await?,
+        "gzip" => GzipDecoder::new(body).read_to_string(&mut out).await?,
+        "deflate" => ZlibDecoder::new(body).read_to_string(&mut out).await?,
+        _ => panic!("unknown encoding {}", encoding),
+    };
+    Ok(out)
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_compression() -> Result<()> {
+    std::fs::write("./storage/test-compression.json", json())?;
+    let mut router = Router::new();
+    router
+        .get("/api/json", api)
+        .get("/api/small", small)
+        .get("/api/audio", audio)
+        .static_files("/storage/", "./storage/");
+
+    for (accept, expected) in [
+        ("gzip, deflate, br", "br"),
+        ("gzip, deflate", "gzip"),
+        ("deflate", "deflate"),
+        ("br;q=0.1, gzip;q=0.9", "gzip"),
+    ] {
+        let (parts, body) = get(&router, "/api/json", &[(ACCEPT_ENCODING, accept)]).await?;
+        assert_eq!(parts.headers.get(CONTENT_ENCODING).unwrap(), expected);
+        assert_eq!(parts.headers.get(VARY).unwrap(), "Accept-Encoding");
+        assert!(body.len() < json().len());
+        assert_eq!(decode(expected, &body).await?, json());
+    }
+
+    let (parts, body) = get(&router, "/api/json", &[]).await?;
+    assert!(parts.headers.get(CONTENT_ENCODING).is_none());
+    assert_eq!(parts.headers.get(VARY).unwrap(), "Accept-Encoding");
+    assert_eq!(body, json().as_bytes());
+
+    let (parts, _) = get(&router, "/api/small", &[(ACCEPT_ENCODING, "gzip")]).await?;
+    assert!(parts.headers.get(CONTENT_ENCODING).is_none());
+
+    let (parts, body) = get(&router, "/api/audio", &[(ACCEPT_ENCODING, "gzip")]).await?;
+    assert!(parts.headers.get(CONTENT_ENCODING).is_none());
+    assert!(parts.headers.get(VARY).is_none());
+    assert_eq!(body.len(), 10000);
+
+    // files are compressed while streamed, with a weakened entity tag
+    let (plain, _) = get(&router, "/storage/test-compression.json", &[]).await?;
+    let etag = plain.headers.get(ETAG).unwrap().to_str()?;
+    let (parts, body) = get(
+        &router,
+        "/storage/test-compression.json",
+        &[(ACCEPT_ENCODING, "gzip")],
+    )
+    .await?;
+    assert_eq!(parts.status, StatusCode::OK);
+    assert_eq!(parts.headers.get(CONTENT_ENCODING).unwrap(), "gzip");
+    assert!(parts.headers.get(hyper::header::CONTENT_LENGTH).is_none());
+    assert_eq!(
+        parts.headers.get(ETAG).unwrap().to_str()?,
+        format!("W/{}", etag)
+    );
+    assert_eq!(decode("gzip", &body).await?, json());
+
+    // ranges are never compressed
+    let (parts, body) = get(
+        &router,
+        "/storage/test-compression.json",
+        &[(ACCEPT_ENCODING, "gzip"), (RANGE, "bytes=0-9")],
+    )
+    .await?;
+    assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+    assert!(parts.headers.get(CONTENT_ENCODING).is_none());
+    assert_eq!(body, &json().as_bytes()[..10]);
+
+    std::fs::remove_file("./storage/test-compression.json")?;
+    Ok(())
+}
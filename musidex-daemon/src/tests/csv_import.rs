@@ -0,0 +1,219 @@
+use super::*;
+use crate::application::handlers;
+use crate::domain::config;
+use crate::domain::entity::{MusicID, Tag, TagKey, User};
+use crate::domain::worker_csv_import::CsvImportWorker;
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use hyper::header::COOKIE;
+use hyper::StatusCode;
+use nanoserde::DeJson;
+use std::os::unix::fs::PermissionsExt;
+
+#[derive(DeJson)]
+struct ReportRow {
+    track: String,
+    status: String,
+    video_id: Option<String>,
+    confidence: Option<f64>,
+    music_id: Option<i32>,
+}
+
+#[derive(DeJson)]
+struct Report {
+    id: i64,
+    total: usize,
+    pending: usize,
+    matched: usize,
+    low_confidence: usize,
+    not_found: usize,
+    errors: usize,
+    rows: Vec<ReportRow>,
+}
+
+const SEARCHES: &str = r#"
+case "$*" in
+  *"Jamiroquai - Virtual Insanity"*) cat <<'EOF'
+{"_type": "playlist", "entries": [
+  {"id": "vi-live", "title": "Jamiroquai - Virtual Insanity (Live at Montreux)", "channel": "someone", "duration": 330, "url": "https://www.youtube.com/watch?v=vi-live", "ie_key": "Youtube"},
+  {"id": "vi", "title": "Jamiroquai - Virtual Insanity (Official Video)", "channel": "JamiroquaiVEVO", "duration": 232, "url": "https://www.youtube.com/watch?v=vi", "ie_key": "Youtube"}
+]}
+EOF
+  ;;
+  *"Cosmic Girl"*) cat <<'EOF'
+{"_type": "playlist", "entries": [
+  {"id": "cg", "title": "Cosmic Girl (Remastered)", "channel": "someone", "duration": 260, "url": "https://www.youtube.com/watch?v=cg", "ie_key": "Youtube"}
+]}
+EOF
+  ;;
+  *"Broken"*) echo "ERROR: no network" >&2; exit 1 ;;
+  *"No Url"*) cat <<'EOF'
+{"_type": "playlist", "entries": [
+  {"id": "nu", "title": "Nobody - No Url", "channel": "Nobody", "duration": 180, "ie_key": "Youtube"}
+]}
+EOF
+  ;;
+  *) cat <<'EOF'
+{"_type": "playlist", "entries": [
+  {"id": "zz", "title": "Totally unrelated", "channel": "someone", "duration": 100, "url": "https://www.youtube.com/watch?v=zz", "ie_key": "Youtube"}
+]}
+EOF
+  ;;
+esac
+"#;
+
+async fn upload(router: &Router, uid: i32, csv: &str) -> Result<(StatusCode, Option<Report>)> {
+    let req = Request::post("/api/youtube_upload/csv")
+        .header(COOKIE, format!("cur_user={}", uid))
+        .body(Body::from(csv.to_string()))?;
+    let r = router.serve(req).await?;
+    let status = r.status();
+    let body = hyper::body::to_bytes(r.into_body()).await?;
+    let report = Report::deserialize_json(std::str::from_utf8(&body)?).ok();
+    Ok((status, report))
+}
+
+async fn report(router: &Router, id: i64) -> Result<Report> {
+    let req = Request::get(format!("/api/youtube_upload/csv/{}", id)).body(Body::empty())?;
+    let body = hyper::body::to_bytes(router.serve(req).await?.into_body()).await?;
+    Ok(Report::deserialize_json(std::str::from_utf8(&body)?)?)
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_csv_import() -> Result<()> {
+    let db = mk_db().await?;
+    config::init(&db).await?;
+    let dir = std::env::temp_dir().join("musidex-fake-ytdlp-csv");
+    std::fs::create_dir_all(&dir)?;
+    let script = dir.join("yt-dlp");
+    std::fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> {}\n{}",
+            dir.join("args").display(),
+            SEARCHES
+        ),
+    )?;
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+    let _ = std::fs::remove_file(dir.join("args"));
+
+    let _ytdlp = fake_ytdlp_env(&script, "").await;
+    let uid = User::create(&*db.get().await, s!("importer"))?;
+
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .post("/api/youtube_upload/csv", handlers::youtube_upload_csv)
+        .get(
+            "/api/youtube_upload/csv/:id",
+            handlers::youtube_upload_csv_report,
+        );
+
+    let csv = "Track URI,Track Name,Artist Name(s),Album Name,Track Duration (ms)\n\
+        spotify:track:1,Virtual Insanity,Jamiroquai,Travelling Without Moving,230693\n\
+        spotify:track:2,Cosmic Girl,Jamiroquai,Travelling Without Moving,240000\n\
+        spotify:track:3,Nothing Like It,Nobody,,180000\n\
+        spotify:track:4,Broken,Nobody,,180000\n";
+    let (status, created) = upload(&router, uid.0, csv).await?;
+    assert_eq!(status, StatusCode::OK);
+    let created = created.unwrap();
+    assert_eq!((created.total, created.pending), (4, 4));
+
+    let mut worker = CsvImportWorker::new(db.clone());
+    for _ in 0..4 {
+        assert!(worker.step().await?);
+    }
+    assert!(!worker.step().await?);
+
+    let args = std::fs::read_to_string(dir.join("args"))?;
+    assert_eq!(
+        args.lines().next().unwrap(),
+        "--flat-playlist -J -- ytsearch5:Jamiroquai - Virtual Insanity"
+    );
+
+    let r = report(&router, created.id).await?;
+    assert_eq!(
+        (
+            r.pending,
+            r.matched,
+            r.low_confidence,
+            r.not_found,
+            r.errors
+        ),
+        (0, 1, 1, 1, 1)
+    );
+    let statuses: Vec<(&str, &str)> = r
+        .rows
+        .iter()
+        .map(|x| (x.track.as_str(), x.status.as_str()))
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![
+            ("Virtual Insanity", "matched"),
+            ("Cosmic Girl", "low_confidence"),
+            ("Nothing Like It", "not_found"),
+            ("Broken", "error"),
+        ]
+    );
+    assert_eq!(r.rows[0].video_id.as_deref(), Some("vi"));
+    assert_eq!(r.rows[2].video_id.as_deref(), Some("zz"));
+    assert!(r.rows[2].music_id.is_none());
+    assert!(r.rows[1].confidence.unwrap() < r.rows[0].confidence.unwrap());
+
+    // matched videos are enqueued in the library of the user
+    let vi = r.rows[0].music_id.unwrap();
+    {
+        let c = db.get().await;
+        let id = MusicID(vi);
+        let url = Tag::by_id_key(&c, id, TagKey::YoutubeDLURL)?.unwrap();
+        assert_eq!(
+            url.text.as_deref(),
+            Some("https://www.youtube.com/watch?v=vi")
+        );
+        let treated = Tag::by_id_key(&c, id, TagKey::YoutubeDLWorkerTreated)?.unwrap();
+        assert_eq!(treated.text.as_deref(), Some("false"));
+        assert!(Tag::by_id_key(&c, id, TagKey::UserLibrary(s!(uid)))?.is_some());
+    }
+
+    // known videos are not downloaded again
+    let other = {
+        let c = db.get().await;
+        User::create(&c, s!("other"))?
+    };
+    let (_, created) = upload(
+        &router,
+        other.0,
+        "track,artist\nVirtual Insanity,Jamiroquai\n",
+    )
+    .await?;
+    assert!(worker.step().await?);
+    let r = report(&router, created.unwrap().id).await?;
+    assert_eq!(r.rows[0].music_id, Some(vi));
+    {
+        let c = db.get().await;
+        let id = MusicID(vi);
+        assert!(Tag::by_id_key(&c, id, TagKey::UserLibrary(s!(other)))?.is_some());
+        assert_eq!(Tag::by_key(&c, TagKey::YoutubeDLVideoID)?.len(), 2);
+    }
+
+    // rows failing after the search are not picked again
+    let (_, created) = upload(&router, uid.0, "track,artist\nNo Url,Nobody\n").await?;
+    assert!(worker.step().await?);
+    assert!(!worker.step().await?);
+    let r = report(&router, created.unwrap().id).await?;
+    assert_eq!(r.rows[0].status, "error");
+    assert!(r.rows[0].music_id.is_none());
+
+    assert_eq!(
+        upload(&router, uid.0, "a,b\n1,2\n").await?.0,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        upload(&router, uid.0, "track\n").await?.0,
+        StatusCode::BAD_REQUEST
+    );
+    let req = Request::get("/api/youtube_upload/csv/999").body(Body::empty())?;
+    assert_eq!(router.serve(req).await?.status(), StatusCode::NOT_FOUND);
+    Ok(())
+}
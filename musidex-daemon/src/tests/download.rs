@@ -0,0 +1,154 @@
+use super::*;
+use crate::domain::download::{build, content_disposition, export_tags, filename, Download};
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::domain::stream::MusicSource;
+use crate::infrastructure::file_server::ByteSource;
+use crate::infrastructure::tag_writer::ExportTags;
+use anyhow::Result;
+use futures::TryStreamExt;
+use hyper::body::Bytes;
+use id3::TagLike;
+use image::{ImageOutputFormat, Rgb, RgbImage};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+async fn read(dl: &Download, start: u64, len: u64) -> Result<Vec<u8>> {
+    let chunks: Vec<Bytes> = dl.chunks(start, len)?.try_collect().await?;
+    Ok(chunks.concat())
+}
+
+/// Silent MPEG-1 layer III frames at 128kbps
+fn silent_mp3() -> Vec<u8> {
+    let mut frame = vec![0u8; 417];
+    frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+    frame.repeat(40)
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_download_mp3() -> Result<()> {
+    let db = mk_db().await?;
+
+    let mut old = id3::Tag::new();
+    old.set_title("from youtube");
+    old.set_album("some album");
+    let mut file = vec![];
+    old.write_to(&mut file, id3::Version::Id3v23)?;
+    file.extend(silent_mp3());
+    std::fs::write("./storage/test-download.mp3", &file)?;
+
+    let mut cover = vec![];
+    RgbImage::from_pixel(16, 16, Rgb([10, 200, 10])).write_to(
+        &mut std::io::Cursor::new(&mut cover),
+        ImageOutputFormat::Jpeg(90),
+    )?;
+    std::fs::write("./storage/test-download-cover.jpg", &cover)?;
+
+    let (id, source, tags) = {
+        let c = db.get().await;
+        let id = Music::mk(&c)?;
+        let text = |key, v: &str| Tag::insert(&c, Tag::new_text(id, key, s!(v)));
+        text(TagKey::LocalMP3, "test-download.mp3")?;
+        text(TagKey::Title, "Song")?;
+        text(TagKey::Artist, "AC/DC")?;
+        text(TagKey::Thumbnail, "test-download-cover.jpg")?;
+        text(TagKey::UserTag(s!("driving")), "")?;
+        text(TagKey::UserTag(s!("mood")), "happy")?;
+        Tag::insert(
+            &c,
+            Tag {
+                integer: Some(1980),
+                ..Tag::new_key(id, TagKey::Year)
+            },
+        )?;
+        (
+            id,
+            MusicSource::find(&c, id)?.unwrap(),
+            export_tags(&c, id)?,
+        )
+    };
+    assert_eq!(tags.cover.as_ref().unwrap().data.as_ref(), cover.as_slice());
+
+    let dl = build(id, &source, &tags, None)?;
+    assert_eq!(dl.content_type, "audio/mpeg");
+    assert_eq!(dl.filename, "AC_DC - Song.mp3");
+    assert_eq!(
+        dl.content_disposition(),
+        "attachment; filename=\"AC_DC - Song.mp3\"; filename*=UTF-8''AC_DC%20-%20Song.mp3"
+    );
+
+    let buf = read(&dl, 0, dl.size()).await?;
+    assert_eq!(buf.len() as u64, dl.size());
+    let written = id3::Tag::read_from2(std::io::Cursor::new(&buf))?;
+    assert_eq!(written.version(), id3::Version::Id3v24);
+    assert_eq!(written.title(), Some("Song"));
+    assert_eq!(written.artist(), Some("AC/DC"));
+    assert_eq!(written.year(), Some(1980));
+    // tags that are not in the library are dropped
+    assert_eq!(written.album(), None);
+    let comments: Vec<_> = written
+        .comments()
+        .map(|x| (x.description.as_str(), x.text.as_str()))
+        .collect();
+    assert_eq!(comments, vec![("driving", "driving"), ("mood", "happy")]);
+    let picture = written.pictures().next().unwrap();
+    assert_eq!(picture.mime_type, "image/jpeg");
+    assert_eq!(picture.data, cover);
+    assert!(buf.ends_with(&silent_mp3()));
+    assert_eq!(
+        dl.window,
+        ((file.len() - silent_mp3().len()) as u64, file.len() as u64)
+    );
+
+    // ranges can span the tags and the audio
+    let at = dl.head.len() as u64 - 10;
+    assert_eq!(
+        read(&dl, at, 500).await?,
+        &buf[at as usize..at as usize + 500]
+    );
+
+    // last modified when either the source or the tags changed, the same for every request
+    let mtime = std::fs::metadata("./storage/test-download.mp3")?.modified()?;
+    let since_epoch = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let updated = Music::tags_updated(&*db.get().await, id)?.unwrap();
+    assert!(since_epoch(updated) + 60 > since_epoch(SystemTime::now()));
+    let last_modified = |updated| -> Result<u64> {
+        Ok(since_epoch(
+            build(id, &source, &tags, updated)?.validators.last_modified,
+        ))
+    };
+    assert_eq!(last_modified(None)?, since_epoch(mtime));
+    assert_eq!(last_modified(None)?, last_modified(None)?);
+    let later = mtime + Duration::from_secs(3600);
+    assert_eq!(last_modified(Some(later))?, since_epoch(later));
+    let earlier = mtime - Duration::from_secs(3600);
+    assert_eq!(last_modified(Some(earlier))?, since_epoch(mtime));
+
+    std::fs::remove_file("./storage/test-download.mp3")?;
+    std::fs::remove_file("./storage/test-download-cover.jpg")?;
+    Ok(())
+}
+
+#[test]
+pub fn test_download_filename() {
+    let tags = |artist: Option<&str>, title: Option<&str>| ExportTags {
+        artist: artist.map(String::from),
+        title: title.map(String::from),
+        ..Default::default()
+    };
+    assert_eq!(
+        filename(MusicID(3), &tags(Some("A"), Some("B?")), "flac"),
+        "A - B_.flac"
+    );
+    assert_eq!(
+        filename(MusicID(3), &tags(None, Some("..hidden")), "ogg"),
+        "hidden.ogg"
+    );
+    assert_eq!(
+        filename(MusicID(3), &tags(Some("A"), None), "mp3"),
+        "music 3.mp3"
+    );
+
+    assert_eq!(
+        content_disposition("Café.mp3"),
+        "attachment; filename=\"Caf_.mp3\"; filename*=UTF-8''Caf%C3%A9.mp3"
+    );
+}
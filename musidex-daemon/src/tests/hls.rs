@@ -0,0 +1,195 @@
+use super::*;
+use crate::application::handlers;
+use crate::domain::config;
+use crate::domain::entity::{Music, Tag, TagKey};
+use crate::domain::hls;
+use crate::infrastructure::router::Router;
+use anyhow::{Context, Result};
+use hyper::header::CONTENT_TYPE;
+use hyper::StatusCode;
+
+const FRAME_LEN: usize = 417;
+const FRAME_DUR: f64 = 1152.0 / 44100.0;
+
+/// Silent MPEG-1 layer III frames at 128kbps
+fn silent_mp3(n_frames: usize) -> Vec<u8> {
+    let mut frame = vec![0u8; FRAME_LEN];
+    frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+    frame.repeat(n_frames)
+}
+
+async fn get(router: &Router, path: &str) -> Result<(StatusCode, String, Vec<u8>)> {
+    let r = router
+        .serve(Request::get(path).body(Body::empty())?)
+        .await?;
+    let (parts, body) = r.into_parts();
+    let content_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .map(|x| s!(x.to_str().unwrap()))
+        .unwrap_or_default();
+    let body = hyper::body::to_bytes(body).await?.to_vec();
+    Ok((parts.status, content_type, body))
+}
+
+async fn get_text(router: &Router, path: &str) -> Result<String> {
+    let (status, content_type, body) = get(router, path).await?;
+    assert_eq!(status, StatusCode::OK, "{}", path);
+    assert_eq!(content_type, "application/vnd.apple.mpegurl");
+    Ok(String::from_utf8(body)?)
+}
+
+/// Splits a segment into the timestamp of its ID3 tag in 90kHz ticks and its frames
+fn split_segment(body: &[u8]) -> Result<(u64, &[u8])> {
+    let mut cursor = std::io::Cursor::new(body);
+    let tag = id3::Tag::read_from2(&mut cursor)?;
+    let private = tag
+        .frames()
+        .find_map(|x| match x.content() {
+            id3::Content::Private(p) => Some(p),
+            _ => None,
+        })
+        .context("no PRIV frame")?;
+    assert_eq!(
+        private.owner_identifier,
+        "com.apple.streaming.transportStreamTimestamp"
+    );
+    let ts = u64::from_be_bytes(private.private_data.as_slice().try_into()?);
+    let tag_len = crate::infrastructure::mp3::id3v2_size(&mut cursor)? as usize;
+    Ok((ts, &body[tag_len..]))
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_hls() -> Result<()> {
+    let db = mk_db().await?;
+    config::init(&db).await?;
+    let content = silent_mp3(600);
+    std::fs::write("./storage/test-hls.mp3", &content)?;
+
+    let (id, chapter_id) = {
+        let c = db.get().await;
+        let id = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(id, TagKey::LocalMP3, s!("test-hls.mp3")))?;
+        let chapter_id = Music::mk(&c)?;
+        let text = |key, v: &str| Tag::insert(&c, Tag::new_text(chapter_id, key, s!(v)));
+        text(TagKey::LocalMP3, "test-hls.mp3")?;
+        text(TagKey::ChapterStart, "2")?;
+        text(TagKey::ChapterEnd, "10")?;
+        (id, chapter_id)
+    };
+    // the cache of an interrupted run is not reused
+    for music in [id, chapter_id] {
+        let _ = std::fs::remove_dir_all(format!("./storage/hls/{}", music.0));
+    }
+
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .get("/api/hls/:musicid/playlist.m3u8", handlers::hls_master)
+        .get(
+            "/api/hls/:musicid/:variant/playlist.m3u8",
+            handlers::hls_playlist,
+        )
+        .get("/api/hls/:musicid/:variant/:segment", handlers::hls_segment);
+    let base = format!("/api/hls/{}", id.0);
+
+    let master = get_text(&router, &format!("{}/playlist.m3u8", base)).await?;
+    assert_eq!(
+        master,
+        "#EXTM3U\n#EXT-X-VERSION:3\n\
+         #EXT-X-STREAM-INF:BANDWIDTH=127707,CODECS=\"mp4a.40.34\"\noriginal/playlist.m3u8\n"
+    );
+
+    // segments are cut at the first frame after 6 seconds
+    let playlist = get_text(&router, &format!("{}/original/playlist.m3u8", base)).await?;
+    assert_eq!(
+        playlist,
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:7\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n\
+         #EXTINF:6.008,\n0.mp3\n#EXTINF:6.008,\n1.mp3\n#EXTINF:3.657,\n2.mp3\n#EXT-X-ENDLIST\n"
+    );
+    assert!(std::path::Path::new(&format!("./storage/hls/{}/original/index", id.0)).exists());
+
+    for (n, frames) in [(0, 0..230), (1, 230..460), (2, 460..600)] {
+        let (status, content_type, body) =
+            get(&router, &format!("{}/original/{}.mp3", base, n)).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "audio/mpeg");
+        let (ts, audio) = split_segment(&body)?;
+        assert_eq!(
+            ts,
+            (frames.start as f64 * FRAME_DUR * 90000.0).round() as u64
+        );
+        assert_eq!(
+            audio,
+            &content[frames.start * FRAME_LEN..frames.end * FRAME_LEN]
+        );
+    }
+    for path in [
+        "original/3.mp3",
+        "original/x.mp3",
+        "original/0.ts",
+        "64k/playlist.m3u8",
+        "../original/playlist.m3u8",
+    ] {
+        assert_eq!(
+            get(&router, &format!("{}/{}", base, path)).await?.0,
+            StatusCode::NOT_FOUND,
+            "{}",
+            path
+        );
+    }
+    assert_eq!(
+        get(&router, "/api/hls/999/playlist.m3u8").await?.0,
+        StatusCode::NOT_FOUND
+    );
+
+    // chapters only keep their own frames, starting at timestamp 0
+    let chapter_base = format!("/api/hls/{}", chapter_id.0);
+    let playlist = get_text(&router, &format!("{}/original/playlist.m3u8", chapter_base)).await?;
+    assert!(playlist.contains("#EXTINF:6.008,\n0.mp3\n#EXTINF:1.985,\n1.mp3\n#EXT-X-ENDLIST"));
+    let (_, _, body) = get(&router, &format!("{}/original/0.mp3", chapter_base)).await?;
+    let (ts, audio) = split_segment(&body)?;
+    assert_eq!(ts, 0);
+    assert_eq!(audio, &content[77 * FRAME_LEN..307 * FRAME_LEN]);
+
+    // the cache is redone when the source changes
+    let content = silent_mp3(100);
+    std::fs::write("./storage/test-hls.mp3", &content)?;
+    let playlist = get_text(&router, &format!("{}/original/playlist.m3u8", base)).await?;
+    assert!(playlist.contains("#EXTINF:2.612,\n0.mp3\n#EXT-X-ENDLIST"));
+    let (_, _, body) = get(&router, &format!("{}/original/0.mp3", base)).await?;
+    assert_eq!(split_segment(&body)?.1, content.as_slice());
+
+    // bitrates are offered through ffmpeg
+    {
+        let c = db.get().await;
+        config::update(&c, "hls_bitrates", "64, 128,64,x")?;
+    }
+    std::env::set_var("FFMPEG_PATH", "./storage/no-ffmpeg-here");
+    let master = get_text(&router, &format!("{}/playlist.m3u8", base)).await?;
+    assert!(master.ends_with(
+        "#EXT-X-STREAM-INF:BANDWIDTH=128000,CODECS=\"mp4a.40.34\"\n128k/playlist.m3u8\n\
+         #EXT-X-STREAM-INF:BANDWIDTH=64000,CODECS=\"mp4a.40.34\"\n64k/playlist.m3u8\n"
+    ));
+    let err = router
+        .serve(Request::get(format!("{}/64k/playlist.m3u8", base)).body(Body::empty())?)
+        .await
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("did you install it?"));
+    std::env::remove_var("FFMPEG_PATH");
+
+    // the cache of deleted musics is cleaned
+    {
+        let c = db.get().await;
+        Music::delete(&c, chapter_id)?;
+        hls::clean(&c)?;
+    }
+    assert!(std::path::Path::new(&format!("./storage/hls/{}", id.0)).exists());
+    assert!(!std::path::Path::new(&format!("./storage/hls/{}", chapter_id.0)).exists());
+
+    std::fs::remove_dir_all(format!("./storage/hls/{}", id.0))?;
+    // only removed if no other cache is left
+    let _ = std::fs::remove_dir("./storage/hls");
+    std::fs::remove_file("./storage/test-hls.mp3")?;
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use super::*;
+use crate::domain::entity::Music;
+use crate::domain::lyrics::{self, Lyrics, SOURCE_EMBEDDED, SOURCE_SIDECAR, SOURCE_USER};
+use anyhow::Result;
+use id3::TagLike;
+use std::path::Path;
+
+#[test_log::test(tokio::test)]
+pub async fn test_lyrics_search() -> Result<()> {
+    let db = mk_db().await?;
+    let mut c = db.get().await;
+
+    let m1 = Music::mk(&c)?;
+    let m2 = Music::mk(&c)?;
+    let m3 = Music::mk(&c)?;
+
+    let synced = Lyrics::from_lrc(
+        s!("[00:01.00]Is this the real life\n[00:05.00]Is this just fantasy"),
+        SOURCE_USER,
+    )
+    .unwrap();
+    assert_eq!(synced.plain, "Is this the real life\nIs this just fantasy");
+    Lyrics::set(&c, m1, &synced)?;
+    Lyrics::set(
+        &c,
+        m2,
+        &Lyrics::from_plain(s!("We will, we will rock you"), SOURCE_USER).unwrap(),
+    )?;
+    assert!(Lyrics::from_plain(s!("  \n"), SOURCE_USER).is_none());
+    assert!(Lyrics::from_lrc(s!("no timestamps"), SOURCE_USER).is_none());
+
+    assert_eq!(Lyrics::get(&c, m1)?, Some(synced));
+    assert_eq!(Lyrics::get(&c, m3)?, None);
+
+    let found = Lyrics::search(&c, "real LIFE", 10)?;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].music_id, m1);
+    assert!(
+        found[0].snippet.contains("[real] [life]"),
+        "{}",
+        found[0].snippet
+    );
+    assert!(Lyrics::search(&c, "real rock", 10)?.is_empty());
+    // fts syntax is escaped
+    assert!(Lyrics::search(&c, "\"rock NOT", 10)?.is_empty());
+    assert!(Lyrics::search(&c, "  ", 10)?.is_empty());
+
+    // updates are indexed
+    Lyrics::set(
+        &c,
+        m2,
+        &Lyrics::from_plain(s!("Another one bites the dust"), SOURCE_USER).unwrap(),
+    )?;
+    assert!(Lyrics::search(&c, "rock", 10)?.is_empty());
+    assert_eq!(Lyrics::search(&c, "dust", 10)?[0].music_id, m2);
+
+    // lyrics follow the music
+    assert!(Music::put_on_top(&mut c, m2)?);
+    let found = Lyrics::search(&c, "dust", 10)?;
+    assert_eq!(found.len(), 1);
+    assert_ne!(found[0].music_id, m2);
+    assert!(Lyrics::get(&c, found[0].music_id)?.is_some());
+
+    Lyrics::remove(&c, m1)?;
+    assert!(Lyrics::search(&c, "fantasy", 10)?.is_empty());
+    Lyrics::set(
+        &c,
+        m3,
+        &Lyrics::from_plain(s!("fantasy"), SOURCE_USER).unwrap(),
+    )?;
+    Music::delete(&c, m3)?;
+    assert!(Lyrics::search(&c, "fantasy", 10)?.is_empty());
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_lyrics_extract() -> Result<()> {
+    std::fs::create_dir_all("./storage/")?;
+
+    // yt-dlp subtitles next to the file
+    let audio = write_wav("lyrics-sidecar", 8000, 1, &[0.0; 100])?;
+    std::fs::write(
+        "./storage/test-lyrics-sidecar.en.lrc",
+        "[00:01.00]from subtitles",
+    )?;
+    std::fs::write(
+        "./storage/test-lyrics-sidecar-other.lrc",
+        "[00:01.00]wrong file",
+    )?;
+    let found = lyrics::extract(Path::new(&format!("./storage/{}", audio)))?.unwrap();
+    assert_eq!(found.plain, "from subtitles");
+    assert_eq!(found.source, SOURCE_SIDECAR);
+    std::fs::remove_file(format!("./storage/{}", audio))?;
+    std::fs::remove_file("./storage/test-lyrics-sidecar.en.lrc")?;
+    std::fs::remove_file("./storage/test-lyrics-sidecar-other.lrc")?;
+
+    // id3 frames, synced lyrics are preferred
+    let path = Path::new("./storage/test-lyrics-embedded.mp3");
+    std::fs::write(path, [0u8; 1024])?;
+    assert!(lyrics::extract(path)?.is_none());
+
+    let mut tag = id3::Tag::new();
+    tag.add_frame(id3::frame::Lyrics {
+        lang: s!("eng"),
+        description: s!(""),
+        text: s!("plain line"),
+    });
+    tag.write_to_path(path, id3::Version::Id3v24)?;
+    let found = lyrics::extract(path)?.unwrap();
+    assert_eq!(found.plain, "plain line");
+    assert_eq!(found.synced, None);
+    assert_eq!(found.source, SOURCE_EMBEDDED);
+
+    tag.add_frame(id3::frame::SynchronisedLyrics {
+        lang: s!("eng"),
+        timestamp_format: id3::frame::TimestampFormat::Ms,
+        content_type: id3::frame::SynchronisedLyricsType::Lyrics,
+        description: s!(""),
+        content: vec![(1500, s!("first")), (62050, s!("second"))],
+    });
+    tag.write_to_path(path, id3::Version::Id3v24)?;
+    let found = lyrics::extract(path)?.unwrap();
+    assert_eq!(found.plain, "first\nsecond");
+    assert_eq!(
+        found.synced.as_deref(),
+        Some("[00:01.50]first\n[01:02.05]second\n")
+    );
+
+    std::fs::remove_file(path)?;
+    Ok(())
+}
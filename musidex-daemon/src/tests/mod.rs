@@ -3,13 +3,34 @@ use crate::infrastructure::migrate::migrate;
 use crate::MIGRATIONS;
 use hyper::http::Extensions;
 use hyper::{Body, Request};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+mod chapters;
+mod compression;
+mod csv_import;
+mod download;
+mod hls;
+mod lyrics;
 mod music;
+mod offline;
+mod playlist;
+mod share;
+mod stream;
+mod sync;
 mod tags;
+mod title_rules;
+mod upload;
 mod user;
+mod worker_analysis;
+mod worker_cover_art;
+mod worker_fingerprint;
+mod worker_loudness;
+mod worker_musicbrainz;
 mod worker_neural_embed;
+mod worker_probe;
 mod worker_thumbnail_resize;
+mod worker_waveform;
 
 async fn mk_db() -> anyhow::Result<Db> {
     let db = Db::connect_in_memory().await;
@@ -23,3 +44,62 @@ fn mk_db_extension(req: &mut Request<Body>, db: Db) {
     e.insert(db);
     req.extensions_mut().insert(Arc::new(e));
 }
+
+/// yt-dlp is found through the environment, the tests replacing it with a fake one take turns
+async fn fake_ytdlp_env(path: &Path, extra_args: &str) -> tokio::sync::MutexGuard<'static, ()> {
+    static LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let guard = LOCK.lock().await;
+    std::env::set_var("YTDLP_PATH", path);
+    std::env::set_var("YTDLP_EXTRA_ARGS", extra_args);
+    guard
+}
+
+/// A melody of random notes between 300Hz and 2000Hz, the same seed giving the same melody
+pub fn melody(seed: u64, rate: u32, secs: f32) -> Vec<f32> {
+    let mut state = seed;
+    let mut rand = move || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (state >> 33) as f32 / (1u64 << 31) as f32
+    };
+    let note_len = rate as usize / 4;
+    let n = (secs * rate as f32) as usize;
+    let mut v = Vec::with_capacity(n);
+    let mut freqs = (0.0, 0.0);
+    for i in 0..n {
+        if i % note_len == 0 {
+            freqs = (300.0 + 1700.0 * rand(), 300.0 + 1700.0 * rand());
+        }
+        let t = i as f32 / rate as f32;
+        let x = (2.0 * std::f32::consts::PI * freqs.0 * t).sin()
+            + 0.5 * (2.0 * std::f32::consts::PI * freqs.1 * t).sin();
+        v.push(x * 0.3);
+    }
+    v
+}
+
+/// Writes a 16 bits PCM wav file in the storage, returns its name within the storage
+pub fn write_wav(name: &str, rate: u32, channels: u16, samples: &[f32]) -> anyhow::Result<String> {
+    let data_len = samples.len() as u32 * 2;
+    let mut buf = Vec::with_capacity(44 + data_len as usize);
+    buf.extend_from_slice(b"RIFF");
+    buf.extend_from_slice(&(36 + data_len).to_le_bytes());
+    buf.extend_from_slice(b"WAVEfmt ");
+    buf.extend_from_slice(&16u32.to_le_bytes());
+    buf.extend_from_slice(&1u16.to_le_bytes());
+    buf.extend_from_slice(&channels.to_le_bytes());
+    buf.extend_from_slice(&rate.to_le_bytes());
+    buf.extend_from_slice(&(rate * channels as u32 * 2).to_le_bytes());
+    buf.extend_from_slice(&(channels * 2).to_le_bytes());
+    buf.extend_from_slice(&16u16.to_le_bytes());
+    buf.extend_from_slice(b"data");
+    buf.extend_from_slice(&data_len.to_le_bytes());
+    for x in samples {
+        buf.extend_from_slice(&((x.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes());
+    }
+    let name = format!("test-{}.wav", name);
+    std::fs::create_dir_all("./storage/")?;
+    std::fs::write(PathBuf::from("./storage/").join(&name), buf)?;
+    Ok(name)
+}
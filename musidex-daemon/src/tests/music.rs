@@ -1,7 +1,7 @@
 use super::*;
 use crate::domain::entity::{Music, Tag, TagKey, UserID};
 use crate::domain::music::delete_music;
-use crate::domain::sync::fetch_metadata;
+use crate::domain::sync::{fetch_metadata, SyncScope};
 use anyhow::Result;
 
 #[test_log::test(tokio::test)]
@@ -31,7 +31,7 @@ pub async fn test_merge_music() -> Result<()> {
 
     Music::merge(&mut c, v, v2)?;
 
-    let meta = fetch_metadata(&c)?;
+    let meta = fetch_metadata(&c, &SyncScope::default())?;
 
     assert_eq!(meta.musics.len(), 1);
     assert_eq!(meta.users.len(), 1);
@@ -55,7 +55,7 @@ pub async fn test_update_cascade() -> Result<()> {
     Tag::insert(&c, Tag::new_text(id, TagKey::Duration, s!("v")))?;
 
     Music::put_on_top(&mut c, id)?;
-    let meta = fetch_metadata(&c)?;
+    let meta = fetch_metadata(&c, &SyncScope::default())?;
 
     assert_eq!(meta.musics.len(), 1);
     assert_eq!(meta.tags.unwrap().len(), 1);
@@ -72,7 +72,7 @@ pub async fn test_delete_cascade() -> Result<()> {
     Tag::insert(&c, Tag::new_text(id, TagKey::Duration, s!("v")))?;
 
     Music::delete(&c, id)?;
-    let meta = fetch_metadata(&c)?;
+    let meta = fetch_metadata(&c, &SyncScope::default())?;
 
     assert_eq!(meta.musics.len(), 0);
     assert_eq!(meta.tags.unwrap().len(), 0);
@@ -87,12 +87,12 @@ pub async fn test_delete_request_zero_user() -> Result<()> {
     let c = db.get().await;
     let v = Music::mk(&c)?;
 
-    let meta = fetch_metadata(&c)?;
+    let meta = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(meta.musics.len(), 1);
 
     delete_music(&c, UserID(0), v)?;
 
-    let meta = fetch_metadata(&c)?;
+    let meta = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(meta.musics.len(), 0);
 
     Ok(())
@@ -105,12 +105,12 @@ pub async fn test_delete_request_one_user() -> Result<()> {
     let v = Music::mk(&c)?;
     Tag::insert(&c, Tag::new_key(v, TagKey::UserLibrary(s!("0"))))?;
 
-    let meta = fetch_metadata(&c)?;
+    let meta = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(meta.musics.len(), 1);
 
     delete_music(&c, UserID(0), v)?;
 
-    let meta = fetch_metadata(&c)?;
+    let meta = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(meta.musics.len(), 0);
 
     Ok(())
@@ -124,17 +124,17 @@ pub async fn test_delete_request_two_user() -> Result<()> {
     Tag::insert(&c, Tag::new_key(v, TagKey::UserLibrary(s!("0"))))?;
     Tag::insert(&c, Tag::new_key(v, TagKey::UserLibrary(s!("1"))))?;
 
-    let meta = fetch_metadata(&c)?;
+    let meta = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(meta.musics.len(), 1);
 
     delete_music(&c, UserID(0), v)?;
 
-    let meta = fetch_metadata(&c)?;
+    let meta = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(meta.musics.len(), 1);
 
     delete_music(&c, UserID(1), v)?;
 
-    let meta = fetch_metadata(&c)?;
+    let meta = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(meta.musics.len(), 0);
 
     Ok(())
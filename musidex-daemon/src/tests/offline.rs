@@ -0,0 +1,203 @@
+use super::*;
+use crate::application::handlers;
+use crate::domain::config;
+use crate::domain::entity::{Music, Tag, TagKey, User};
+use crate::domain::offline;
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use hyper::header::{
+    CONTENT_DISPOSITION, CONTENT_RANGE, CONTENT_TYPE, COOKIE, ETAG, IF_RANGE, RANGE,
+};
+use hyper::{HeaderMap, StatusCode};
+use nanoserde::DeJson;
+use sha2::{Digest, Sha256};
+
+#[derive(DeJson)]
+struct ManifestFile {
+    path: String,
+    size: u64,
+    sha256: String,
+    offset: u64,
+}
+
+#[derive(DeJson)]
+struct ManifestMusic {
+    id: i32,
+    content_type: String,
+    audio: ManifestFile,
+    thumbnail: Option<ManifestFile>,
+}
+
+#[derive(DeJson)]
+struct Manifest {
+    etag: String,
+    size: u64,
+    musics: Vec<ManifestMusic>,
+}
+
+async fn get(
+    router: &Router,
+    path: &str,
+    headers: &[(hyper::header::HeaderName, &str)],
+) -> Result<(StatusCode, HeaderMap, Vec<u8>)> {
+    let mut req = Request::get(path);
+    for (k, v) in headers {
+        req = req.header(k, *v);
+    }
+    let r = router.serve(req.body(Body::empty())?).await?;
+    let (parts, body) = r.into_parts();
+    let body = hyper::body::to_bytes(body).await?.to_vec();
+    Ok((parts.status, parts.headers, body))
+}
+
+async fn manifest(router: &Router, query: &str) -> Result<Manifest> {
+    let (status, _, body) = get(router, &format!("/api/offline/manifest?{}", query), &[]).await?;
+    assert_eq!(status, StatusCode::OK);
+    Ok(Manifest::deserialize_json(std::str::from_utf8(&body)?)?)
+}
+
+fn sha256(v: &[u8]) -> String {
+    Sha256::digest(v)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_offline() -> Result<()> {
+    let db = mk_db().await?;
+    config::init(&db).await?;
+    let mut frame = vec![0u8; 417];
+    frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+    let audio = frame.repeat(600);
+    std::fs::write("./storage/test-offline.mp3", &audio)?;
+    std::fs::write("./storage/test-offline.jpg", b"not really a jpeg")?;
+
+    let (uid, song, chapter, missing) = {
+        let c = db.get().await;
+        let uid = User::create(&c, s!("offline"))?;
+        let text = |id, key, v: &str| Tag::insert(&c, Tag::new_text(id, key, s!(v)));
+        let song = Music::mk(&c)?;
+        text(song, TagKey::LocalMP3, "test-offline.mp3")?;
+        text(song, TagKey::Thumbnail, "test-offline.jpg")?;
+        text(song, TagKey::UserTag(s!("on the go")), "")?;
+        let chapter = Music::mk(&c)?;
+        text(chapter, TagKey::LocalMP3, "test-offline.mp3")?;
+        text(chapter, TagKey::ChapterStart, "2")?;
+        text(chapter, TagKey::ChapterEnd, "10")?;
+        text(chapter, TagKey::UserTag(s!("on the go")), "")?;
+        let missing = Music::mk(&c)?;
+        text(missing, TagKey::LocalMP3, "test-offline-missing.mp3")?;
+        for id in [song, chapter, missing] {
+            Tag::insert(&c, Tag::new_key(id, TagKey::UserLibrary(s!(uid.0))))?;
+        }
+        (uid, song, chapter, missing)
+    };
+
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .get("/api/offline/manifest", handlers::offline_manifest)
+        .get("/api/offline/archive", handlers::offline_archive);
+
+    // selections
+    let ids = |m: &Manifest| m.musics.iter().map(|x| x.id).collect::<Vec<_>>();
+    let m = manifest(
+        &router,
+        &format!("ids={},{},{},{}", chapter.0, song.0, chapter.0, missing.0),
+    )
+    .await?;
+    assert_eq!(ids(&m), vec![chapter.0, song.0]);
+    let m = manifest(&router, "tag=on%20the%20go").await?;
+    assert_eq!(ids(&m), vec![song.0, chapter.0]);
+    let cookie = format!("cur_user={}", uid.0);
+    let (_, _, body) = get(&router, "/api/offline/manifest", &[(COOKIE, &cookie)]).await?;
+    let library = Manifest::deserialize_json(std::str::from_utf8(&body)?)?;
+    assert_eq!(ids(&library), vec![song.0, chapter.0]);
+    for query in ["", "?ids=1,x"] {
+        let path = format!("/api/offline/manifest{}", query);
+        assert_eq!(get(&router, &path, &[]).await?.0, StatusCode::BAD_REQUEST);
+    }
+
+    // the manifest describes the archive
+    let m = manifest(&router, "tag=on%20the%20go").await?;
+    let song_m = &m.musics[0];
+    assert_eq!(song_m.content_type, "audio/mpeg");
+    assert_eq!(song_m.audio.path, format!("music/{}.mp3", song.0));
+    assert_eq!(song_m.audio.size, audio.len() as u64);
+    assert_eq!(song_m.audio.sha256, sha256(&audio));
+    let thumb = song_m.thumbnail.as_ref().unwrap();
+    assert_eq!(thumb.path, format!("thumbnails/{}.jpg", song.0));
+    assert_eq!(thumb.sha256, sha256(b"not really a jpeg"));
+    let chapter_m = &m.musics[1];
+    assert!(chapter_m.thumbnail.is_none());
+    assert_eq!(chapter_m.audio.size, (383 - 77) * 417);
+    assert_eq!(chapter_m.audio.sha256, sha256(&audio[77 * 417..383 * 417]));
+
+    let archive_url = "/api/offline/archive?tag=on%20the%20go";
+    let (status, headers, full) = get(&router, archive_url, &[]).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers.get(CONTENT_TYPE).unwrap(), "application/x-tar");
+    assert_eq!(
+        headers.get(CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"musidex.tar\""
+    );
+    assert_eq!(headers.get(ETAG).unwrap().to_str()?, m.etag);
+    assert_eq!(full.len() as u64, m.size);
+    for f in [&song_m.audio, thumb, &chapter_m.audio] {
+        let content = &full[f.offset as usize..(f.offset + f.size) as usize];
+        assert_eq!(sha256(content), f.sha256);
+        let header = &full[f.offset as usize - 512..];
+        assert_eq!(&header[..f.path.len()], f.path.as_bytes());
+    }
+
+    // checksums are cached
+    {
+        let c = db.get().await;
+        let n: i64 = c.query_row("SELECT count(1) FROM checksums", [], |x| x.get(0))?;
+        assert_eq!(n, 3);
+    }
+
+    // resuming
+    let (status, headers, rest) = get(
+        &router,
+        archive_url,
+        &[(RANGE, "bytes=1000-"), (IF_RANGE, m.etag.as_str())],
+    )
+    .await?;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        headers.get(CONTENT_RANGE).unwrap().to_str()?,
+        format!("bytes 1000-{}/{}", m.size - 1, m.size)
+    );
+    assert_eq!(rest, &full[1000..]);
+
+    // the download restarts once the files changed
+    std::fs::write("./storage/test-offline.jpg", b"another jpeg")?;
+    let (status, _, body) = get(
+        &router,
+        archive_url,
+        &[(RANGE, "bytes=1000-"), (IF_RANGE, m.etag.as_str())],
+    )
+    .await?;
+    assert_eq!(status, StatusCode::OK);
+    let m2 = manifest(&router, "tag=on%20the%20go").await?;
+    assert_ne!(m2.etag, m.etag);
+    assert_eq!(body.len() as u64, m2.size);
+    assert_eq!(
+        m2.musics[0].thumbnail.as_ref().unwrap().sha256,
+        sha256(b"another jpeg")
+    );
+
+    // checksums of deleted files are forgotten
+    std::fs::remove_file("./storage/test-offline.jpg")?;
+    {
+        let c = db.get().await;
+        offline::clean(&c)?;
+        let n: i64 = c.query_row("SELECT count(1) FROM checksums", [], |x| x.get(0))?;
+        assert_eq!(n, 2);
+    }
+
+    std::fs::remove_file("./storage/test-offline.mp3")?;
+    Ok(())
+}
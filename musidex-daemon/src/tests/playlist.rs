@@ -0,0 +1,156 @@
+use super::*;
+use crate::application::playlist_handlers;
+use crate::domain::entity::{Music, Tag, TagKey};
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use hyper::header::{CONTENT_DISPOSITION, CONTENT_TYPE, HOST};
+use hyper::{HeaderMap, StatusCode};
+use nanoserde::DeJson;
+
+#[derive(DeJson)]
+struct Report {
+    tag: String,
+    matched: Vec<i32>,
+    unmatched: Vec<String>,
+}
+
+async fn export(router: &Router, path: &str) -> Result<(StatusCode, HeaderMap, String)> {
+    let req = Request::get(path)
+        .header(HOST, "musidex.local:3200")
+        .body(Body::empty())?;
+    let (parts, body) = router.serve(req).await?.into_parts();
+    let body = String::from_utf8(hyper::body::to_bytes(body).await?.to_vec())?;
+    Ok((parts.status, parts.headers, body))
+}
+
+async fn import(router: &Router, path: &str, body: &str) -> Result<(StatusCode, String)> {
+    let req = Request::post(path).body(Body::from(body.to_string()))?;
+    let r = router.serve(req).await?;
+    let status = r.status();
+    let body = String::from_utf8(hyper::body::to_bytes(r.into_body()).await?.to_vec())?;
+    Ok((status, body))
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_playlist_export_import() -> Result<()> {
+    let db = mk_db().await?;
+    let (a, b, c_id) = {
+        let c = db.get().await;
+        let text = |id, key, v: &str| Tag::insert(&c, Tag::new_text(id, key, s!(v)));
+        let a = Music::mk(&c)?;
+        text(a, TagKey::LocalMP3, "aaa.mp3")?;
+        text(a, TagKey::Title, "First")?;
+        text(a, TagKey::Artist, "Someone")?;
+        Tag::insert(
+            &c,
+            Tag {
+                integer: Some(185),
+                ..Tag::new_key(a, TagKey::Duration)
+            },
+        )?;
+        text(a, TagKey::UserTag(s!("mix")), "")?;
+        let b = Music::mk(&c)?;
+        text(b, TagKey::LocalOPUS, "b b.opus")?;
+        text(b, TagKey::Title, "Second & last")?;
+        text(b, TagKey::UserTag(s!("mix")), "")?;
+        let c_id = Music::mk(&c)?;
+        text(c_id, TagKey::LocalMP3, "ccc.mp3")?;
+        text(c_id, TagKey::Title, "Third")?;
+        text(c_id, TagKey::Artist, "Other")?;
+        // not playable, so not exported
+        let d = Music::mk(&c)?;
+        text(d, TagKey::Title, "Nowhere")?;
+        text(d, TagKey::UserTag(s!("mix")), "")?;
+        (a, b, c_id)
+    };
+
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .get("/api/export/:format", playlist_handlers::export)
+        .post("/api/import/:format", playlist_handlers::import);
+
+    // export
+    let (status, headers, m3u) = export(&router, "/api/export/m3u8?tag=mix").await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers.get(CONTENT_TYPE).unwrap(), "audio/x-mpegurl");
+    assert_eq!(
+        headers.get(CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"mix.m3u8\""
+    );
+    assert_eq!(
+        m3u,
+        format!(
+            "#EXTM3U\n#PLAYLIST:mix\n\
+             #EXTINF:185,Someone - First\nhttp://musidex.local:3200/api/stream/{}\n\
+             #EXTINF:-1,Second & last\nhttp://musidex.local:3200/api/stream/{}\n",
+            a.0, b.0
+        )
+    );
+    let (_, _, m3u) = export(&router, "/api/export/m3u8?tag=mix&paths=storage").await?;
+    assert!(m3u.ends_with("\naaa.mp3\n#EXTINF:-1,Second & last\nb b.opus\n"));
+
+    let (status, headers, xspf) =
+        export(&router, &format!("/api/export/xspf?ids={},{}", c_id.0, a.0)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers.get(CONTENT_TYPE).unwrap(), "application/xspf+xml");
+    let third = xspf.find("<title>Third</title>").unwrap();
+    let first = xspf.find("<title>First</title>").unwrap();
+    assert!(third < first);
+    assert!(xspf.contains("<duration>185000</duration>"));
+    let (_, _, xspf) = export(&router, "/api/export/xspf?tag=mix&paths=storage").await?;
+    assert!(xspf.contains("<location>b%20b.opus</location>"));
+    assert!(xspf.contains("<title>Second &amp; last</title>"));
+
+    assert_eq!(
+        export(&router, "/api/export/pls?tag=mix").await?.0,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        export(&router, "/api/export/m3u8").await?.0,
+        StatusCode::BAD_REQUEST
+    );
+
+    // import, matching by stream url, file name and title/artist
+    let body = format!(
+        "#EXTM3U\n#PLAYLIST:from mpv\n\
+         #EXTINF:10,Whatever\nhttp://elsewhere:3200/api/stream/{}\n\
+         /home/me/music/b%20b.opus\n\
+         #EXTINF:10,other - THIRD\n/not/here.mp3\n\
+         #EXTINF:10,Nobody - Nothing\n/not/here/either.mp3\n",
+        b.0
+    );
+    let (status, report) = import(&router, "/api/import/m3u8", &body).await?;
+    assert_eq!(status, StatusCode::OK);
+    let report = Report::deserialize_json(&report)?;
+    assert_eq!(report.tag, "from mpv");
+    assert_eq!(report.matched, vec![b.0, c_id.0]);
+    assert_eq!(report.unmatched, vec![s!("Nobody - Nothing")]);
+    {
+        let c = db.get().await;
+        let key = TagKey::UserTag(s!("from mpv"));
+        assert!(Tag::by_id_key(&c, b, key.clone())?.is_some());
+        assert!(Tag::by_id_key(&c, c_id, key.clone())?.is_some());
+        assert!(Tag::by_id_key(&c, a, key)?.is_none());
+    }
+
+    // the exported xspf goes back to the same musics
+    let (_, _, xspf) = export(&router, "/api/export/xspf?tag=mix").await?;
+    let (status, report) = import(&router, "/api/import/xspf?name=copy%20of%20mix", &xspf).await?;
+    assert_eq!(status, StatusCode::OK);
+    let report = Report::deserialize_json(&report)?;
+    assert_eq!(report.tag, "copy of mix");
+    assert_eq!(report.matched, vec![a.0, b.0]);
+    assert!(report.unmatched.is_empty());
+
+    for (path, body) in [
+        ("/api/import/xspf?name=x", "not xml"),
+        ("/api/import/m3u8", "/no/name.mp3\n"),
+    ] {
+        assert_eq!(
+            import(&router, path, body).await?.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use super::*;
+use crate::application::share_handlers;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::domain::share::{self, Share, ShareTarget};
+use crate::infrastructure::router::Router;
+use crate::utils::now;
+use anyhow::Result;
+use hyper::header::{CONTENT_TYPE, COOKIE, REFERRER_POLICY};
+use hyper::{HeaderMap, StatusCode};
+use nanoserde::DeJson;
+
+#[derive(DeJson)]
+struct Created {
+    token: String,
+    url: String,
+    expires: i64,
+}
+
+async fn get(router: &Router, path: &str) -> Result<(StatusCode, HeaderMap, Vec<u8>)> {
+    let r = router
+        .serve(Request::get(path).body(Body::empty())?)
+        .await?;
+    let (parts, body) = r.into_parts();
+    let body = hyper::body::to_bytes(body).await?.to_vec();
+    Ok((parts.status, parts.headers, body))
+}
+
+async fn create(router: &Router, body: &str) -> Result<(StatusCode, Vec<u8>)> {
+    let req = Request::post("/api/share")
+        .header(COOKIE, "cur_user=1")
+        .body(Body::from(body.to_string()))?;
+    let r = router.serve(req).await?;
+    let status = r.status();
+    Ok((status, hyper::body::to_bytes(r.into_body()).await?.to_vec()))
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_share() -> Result<()> {
+    let db = mk_db().await?;
+    let audio = b"not really an mp3".to_vec();
+    std::fs::write("./storage/test-share.mp3", &audio)?;
+    std::fs::write("./storage/test-share.jpg", b"not really a jpeg")?;
+
+    let (song, tagged, other) = {
+        let c = db.get().await;
+        let song = Music::mk(&c)?;
+        let text = |id, key, v: &str| Tag::insert(&c, Tag::new_text(id, key, s!(v)));
+        text(song, TagKey::LocalMP3, "test-share.mp3")?;
+        text(song, TagKey::Title, "<b>Song</b>")?;
+        text(song, TagKey::Artist, "Someone & co")?;
+        text(song, TagKey::Thumbnail, "test-share.jpg")?;
+        text(song, TagKey::UserTag(s!("road trip")), "")?;
+        let tagged = Music::mk(&c)?;
+        text(tagged, TagKey::LocalMP3, "test-share.mp3")?;
+        text(tagged, TagKey::Title, "Other song")?;
+        text(tagged, TagKey::UserTag(s!("road trip")), "")?;
+        let other = Music::mk(&c)?;
+        text(other, TagKey::LocalMP3, "test-share.mp3")?;
+        (song, tagged, other)
+    };
+
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .post("/api/share", share_handlers::create)
+        .get("/s/:token", share_handlers::page)
+        .get("/s/:token/stream/:musicid", share_handlers::stream)
+        .get("/s/:token/thumbnail/:musicid", share_handlers::thumbnail);
+
+    // creation
+    let (status, body) = create(&router, &format!("{{\"music_id\":{}}}", song.0)).await?;
+    assert_eq!(status, StatusCode::OK);
+    let created = Created::deserialize_json(std::str::from_utf8(&body)?)?;
+    assert_eq!(created.url, format!("/s/{}", created.token));
+    assert!((created.expires - now() - share::DEFAULT_EXPIRY_SECS).abs() < 10);
+
+    for (body, expected) in [
+        ("{}", StatusCode::BAD_REQUEST),
+        (
+            "{\"music_id\":1,\"tag\":\"road trip\"}",
+            StatusCode::BAD_REQUEST,
+        ),
+        ("{\"music_id\":1,\"expires_in\":0}", StatusCode::BAD_REQUEST),
+        (
+            "{\"music_id\":1,\"expires_in\":99999999999}",
+            StatusCode::BAD_REQUEST,
+        ),
+        ("{\"tag\":\"\"}", StatusCode::BAD_REQUEST),
+        ("{\"music_id\":999}", StatusCode::NOT_FOUND),
+        ("{\"tag\":\"nothing tagged\"}", StatusCode::NOT_FOUND),
+    ] {
+        assert_eq!(create(&router, body).await?.0, expected, "{}", body);
+    }
+    let anonymous = Request::post("/api/share").body(Body::from("{\"music_id\":1}"))?;
+    assert!(router.serve(anonymous).await.is_err());
+
+    // a single music
+    let (status, headers, page) = get(&router, &created.url).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        headers.get(CONTENT_TYPE).unwrap(),
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(headers.get(REFERRER_POLICY).unwrap(), "no-referrer");
+    let page = String::from_utf8(page)?;
+    assert!(page.contains("<title>&lt;b&gt;Song&lt;/b&gt;</title>"));
+    assert!(page.contains("Someone &amp; co"));
+    let stream_url = format!("{}/stream/{}", created.url, song.0);
+    let thumbnail_url = format!("{}/thumbnail/{}", created.url, song.0);
+    assert!(page.contains(&format!("src=\"{}\"", stream_url)));
+    assert!(page.contains(&format!("src=\"{}\"", thumbnail_url)));
+
+    let (status, headers, body) = get(&router, &stream_url).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers.get(CONTENT_TYPE).unwrap(), "audio/mpeg");
+    assert_eq!(body, audio);
+    let (status, _, body) = get(&router, &thumbnail_url).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"not really a jpeg");
+    for id in [tagged, other] {
+        let path = format!("{}/stream/{}", created.url, id.0);
+        assert_eq!(get(&router, &path).await?.0, StatusCode::NOT_FOUND);
+    }
+
+    // a user tag
+    let (_, body) = create(&router, "{\"tag\":\"road trip\",\"expires_in\":60}").await?;
+    let created = Created::deserialize_json(std::str::from_utf8(&body)?)?;
+    let page = String::from_utf8(get(&router, &created.url).await?.2)?;
+    assert!(page.contains("<h1>road trip</h1>"));
+    assert!(page.contains("Other song"));
+    for (id, expected) in [
+        (song, StatusCode::OK),
+        (tagged, StatusCode::OK),
+        (other, StatusCode::NOT_FOUND),
+    ] {
+        let path = format!("{}/stream/{}", created.url, id.0);
+        assert_eq!(get(&router, &path).await?.0, expected);
+    }
+
+    // forged and expired tokens
+    let secret = share::secret(&*db.get().await)?;
+    assert_eq!(secret, share::secret(&*db.get().await)?);
+    let forged = Share {
+        target: ShareTarget::Music(other),
+        expires: now() + 60,
+    }
+    .sign(b"guessed secret");
+    assert_eq!(
+        get(&router, &format!("/s/{}", forged)).await?.0,
+        StatusCode::NOT_FOUND
+    );
+    let expired = Share {
+        target: ShareTarget::Music(song),
+        expires: now() - 1,
+    }
+    .sign(&secret);
+    assert_eq!(
+        get(&router, &format!("/s/{}", expired)).await?.0,
+        StatusCode::GONE
+    );
+    assert_eq!(
+        get(&router, &format!("/s/{}/stream/{}", expired, song.0))
+            .await?
+            .0,
+        StatusCode::GONE
+    );
+    assert_eq!(get(&router, "/s/garbage").await?.0, StatusCode::NOT_FOUND);
+
+    // deleted musics are not shared anymore
+    let deleted = Share {
+        target: ShareTarget::Music(MusicID(999)),
+        expires: now() + 60,
+    }
+    .sign(&secret);
+    assert_eq!(
+        get(&router, &format!("/s/{}", deleted)).await?.0,
+        StatusCode::NOT_FOUND
+    );
+
+    std::fs::remove_file("./storage/test-share.mp3")?;
+    std::fs::remove_file("./storage/test-share.jpg")?;
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use super::*;
+use crate::domain::entity::{Music, Tag, TagKey};
+use crate::domain::stream::stream_music;
+use crate::infrastructure::file_server::{guess_content_type, serve_file};
+use crate::infrastructure::mp3;
+use anyhow::Result;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::StatusCode;
+use std::path::Path;
+
+async fn get(
+    p: &str,
+    window: Option<(u64, u64)>,
+    headers: &[(HeaderName, &str)],
+) -> Result<(StatusCode, HeaderMap, Vec<u8>)> {
+    let mut h = HeaderMap::new();
+    for (k, v) in headers {
+        h.insert(k, HeaderValue::from_str(v)?);
+    }
+    let r = serve_file(&h, Path::new(p), window, "audio/flac").await?;
+    let (parts, body) = r.into_parts();
+    let body = hyper::body::to_bytes(body).await?.to_vec();
+    if let Some(len) = parts.headers.get(hyper::header::CONTENT_LENGTH) {
+        assert_eq!(len.to_str()?, body.len().to_string());
+    }
+    Ok((parts.status, parts.headers, body))
+}
+
+fn header(h: &HeaderMap, name: HeaderName) -> String {
+    s!(h.get(name).unwrap().to_str().unwrap())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_serve_file() -> Result<()> {
+    use hyper::header::*;
+    // spans several chunks
+    let content: Vec<u8> = (0..5_000_000u32).map(|x| (x % 251) as u8).collect();
+    let p = "./storage/test-serve.flac";
+    std::fs::write(p, &content)?;
+
+    let (status, h, body) = get(p, None, &[]).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, content);
+    assert_eq!(header(&h, CONTENT_TYPE), "audio/flac");
+    assert_eq!(header(&h, ACCEPT_RANGES), "bytes");
+    let etag = header(&h, ETAG);
+    let last_modified = header(&h, LAST_MODIFIED);
+    assert!(etag.starts_with('"') && etag.ends_with('"'));
+
+    // conditional requests
+    let (status, h, body) = get(p, None, &[(IF_NONE_MATCH, &etag)]).await?;
+    assert_eq!(status, StatusCode::NOT_MODIFIED);
+    assert!(body.is_empty());
+    assert_eq!(header(&h, ETAG), etag);
+    let weak = format!("\"other\", W/{}", etag);
+    assert_eq!(
+        get(p, None, &[(IF_NONE_MATCH, &weak)]).await?.0,
+        StatusCode::NOT_MODIFIED
+    );
+    assert_eq!(
+        get(p, None, &[(IF_NONE_MATCH, "\"other\"")]).await?.0,
+        StatusCode::OK
+    );
+    assert_eq!(
+        get(p, None, &[(IF_MODIFIED_SINCE, &last_modified)])
+            .await?
+            .0,
+        StatusCode::NOT_MODIFIED
+    );
+    let past = "Sun, 06 Nov 1994 08:49:37 GMT";
+    assert_eq!(
+        get(p, None, &[(IF_MODIFIED_SINCE, past)]).await?.0,
+        StatusCode::OK
+    );
+    // If-None-Match takes precedence
+    let headers = [
+        (IF_MODIFIED_SINCE, last_modified.as_str()),
+        (IF_NONE_MATCH, "\"other\""),
+    ];
+    assert_eq!(get(p, None, &headers).await?.0, StatusCode::OK);
+
+    // ranges
+    let (status, h, body) = get(p, None, &[(RANGE, "bytes=100000-")]).await?;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body, &content[100_000..]);
+    assert_eq!(header(&h, CONTENT_RANGE), "bytes 100000-4999999/5000000");
+
+    let (status, _, body) = get(p, None, &[(RANGE, "bytes=-5")]).await?;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body, &content[4_999_995..]);
+
+    let (status, h, body) = get(p, None, &[(RANGE, "bytes=6000000-")]).await?;
+    assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(header(&h, CONTENT_RANGE), "bytes */5000000");
+    assert!(body.is_empty());
+
+    let (status, _, body) = get(p, None, &[(RANGE, "lines=1-2")]).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.len(), content.len());
+
+    for (if_range, expected) in [
+        (etag.as_str(), StatusCode::PARTIAL_CONTENT),
+        ("\"changed\"", StatusCode::OK),
+        (last_modified.as_str(), StatusCode::PARTIAL_CONTENT),
+        (past, StatusCode::OK),
+    ] {
+        let (status, _, body) =
+            get(p, None, &[(RANGE, "bytes=10-19"), (IF_RANGE, if_range)]).await?;
+        assert_eq!(status, expected, "{}", if_range);
+        if expected == StatusCode::OK {
+            assert_eq!(body.len(), content.len());
+        } else {
+            assert_eq!(body, &content[10..20]);
+        }
+    }
+
+    // multiple ranges
+    let (status, h, body) = get(p, None, &[(RANGE, "bytes=0-9,20-29")]).await?;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    let content_type = header(&h, CONTENT_TYPE);
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap();
+    let mut expected = vec![];
+    for (start, end) in [(0, 9), (20, 29)] {
+        expected.extend(
+            format!(
+                "\r\n--{}\r\nContent-Type: audio/flac\r\nContent-Range: bytes {}-{}/5000000\r\n\r\n",
+                boundary, start, end
+            )
+            .bytes(),
+        );
+        expected.extend(&content[start..=end]);
+    }
+    expected.extend(format!("\r\n--{}--\r\n", boundary).bytes());
+    assert_eq!(body, expected);
+
+    assert_eq!(
+        get("./storage/test-serve-missing.flac", None, &[]).await?.0,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(get("./storage/", None, &[]).await?.0, StatusCode::NOT_FOUND);
+
+    std::fs::remove_file(p)?;
+    Ok(())
+}
+
+#[test]
+pub fn test_guess_content_type() {
+    assert_eq!(
+        guess_content_type(Path::new("a/b.wasm")),
+        "application/wasm"
+    );
+    assert_eq!(guess_content_type(Path::new("thumb.jpg")), "image/jpeg");
+    assert_eq!(guess_content_type(Path::new("index.html")), "text/html");
+    assert_eq!(
+        guess_content_type(Path::new("no_extension")),
+        "application/octet-stream"
+    );
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_stream_chapter() -> Result<()> {
+    use hyper::header::*;
+    let db = mk_db().await?;
+    // silent MPEG-1 layer III frames at 128kbps, 26ms each
+    let mut frame = vec![0u8; 417];
+    frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+    let content = frame.repeat(100);
+    let p = "./storage/test-stream-chapter.mp3";
+    std::fs::write(p, &content)?;
+
+    let id = {
+        let c = db.get().await;
+        let id = Music::mk(&c)?;
+        Tag::insert(
+            &c,
+            Tag::new_text(id, TagKey::LocalMP3, s!("test-stream-chapter.mp3")),
+        )?;
+        Tag::insert(&c, Tag::new_text(id, TagKey::ChapterStart, s!("1")))?;
+        Tag::insert(&c, Tag::new_text(id, TagKey::ChapterEnd, s!("2")))?;
+        id
+    };
+
+    let file = stream_music(db.get().await, id).await?;
+    assert_eq!(file.content_type, "audio/mpeg");
+    let window = mp3::file_time_range_to_bytes(p.as_ref(), 1.0, Some(2.0))?;
+    assert_eq!(file.window, Some(window));
+    let (wstart, wend) = (window.0 as usize, window.1 as usize);
+    assert!(wstart > 0 && wend < content.len());
+
+    let (status, h, body) = get(p, file.window, &[]).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, &content[wstart..wend]);
+    let (_, whole, _) = get(p, None, &[]).await?;
+    assert_ne!(header(&h, ETAG), header(&whole, ETAG));
+
+    let (status, h, body) = get(p, file.window, &[(RANGE, "bytes=417-")]).await?;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body, &content[wstart + 417..wend]);
+    assert_eq!(
+        header(&h, CONTENT_RANGE),
+        format!("bytes 417-{}/{}", wend - wstart - 1, wend - wstart)
+    );
+
+    std::fs::remove_file(p)?;
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_stream_wav() -> Result<()> {
+    let db = mk_db().await?;
+    let id = {
+        let c = db.get().await;
+        let id = Music::mk(&c)?;
+        Tag::insert(
+            &c,
+            Tag::new_text(id, TagKey::from("local_wav"), s!("a.wav")),
+        )?;
+        assert!(Tag::find_untreated(&c, TagKey::Codec)?.is_some());
+        id
+    };
+    let file = stream_music(db.get().await, id).await?;
+    assert_eq!(file.content_type, "audio/wav");
+    assert_eq!(file.path, Path::new("./storage/a.wav"));
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use super::*;
+use crate::application::handlers;
+use crate::domain::changes;
+use crate::domain::entity::{Music, MusicID, MusidexMetadata, Tag, TagKey, User, UserID};
+use crate::domain::sync::{catch_up, fetch_metadata, SyncBroadcast, SyncScope};
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use hyper::header::COOKIE;
+use hyper::StatusCode;
+use rusqlite::Connection;
+
+fn scope(uri: &str) -> Option<SyncScope> {
+    SyncScope::from_req(&Request::get(uri).body(Body::empty()).unwrap())
+}
+
+fn tag_ids(m: &MusidexMetadata) -> Vec<(i32, String)> {
+    let mut v: Vec<_> = m
+        .tags
+        .iter()
+        .flatten()
+        .map(|t| (t.music_id.0, t.key.to_string()))
+        .collect();
+    v.sort();
+    v
+}
+
+fn setup(c: &Connection) -> Result<(UserID, UserID, [MusicID; 3])> {
+    let a = User::create(c, s!("a"))?;
+    let b = User::create(c, s!("b"))?;
+    let lib = |id, uid: UserID| Tag::insert(c, Tag::new_key(id, TagKey::UserLibrary(s!(uid))));
+    let m1 = Music::mk(c)?;
+    lib(m1, a)?;
+    let m2 = Music::mk(c)?;
+    lib(m2, b)?;
+    let m3 = Music::mk(c)?;
+    lib(m3, a)?;
+    lib(m3, b)?;
+    for m in [m1, m2, m3] {
+        Tag::insert(c, Tag::new_text(m, TagKey::Title, format!("music {}", m.0)))?;
+        c.execute(
+            "INSERT INTO tags (music_id, key, vector) VALUES (?1, 'embedding', x'0000803f')",
+            [m.0],
+        )?;
+    }
+    Ok((a, b, [m1, m2, m3]))
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_sync_scope() -> Result<()> {
+    assert_eq!(scope("/api/metadata"), Some(SyncScope::default()));
+    assert_eq!(
+        scope("/api/metadata?scope=library&user=3&vectors=false"),
+        Some(SyncScope {
+            library: Some(UserID(3)),
+            vectors: false
+        })
+    );
+    assert_eq!(scope("/api/metadata?scope=library"), None);
+    assert_eq!(scope("/api/metadata?scope=library&user=x"), None);
+    assert_eq!(scope("/api/metadata?scope=mine"), None);
+
+    let db = mk_db().await?;
+    let c = db.get().await;
+    let (a, _, [m1, m2, m3]) = setup(&c)?;
+
+    let all = fetch_metadata(&c, &SyncScope::default())?;
+    assert_eq!(all.musics, vec![m1, m2, m3]);
+    assert_eq!(tag_ids(&all).len(), 10);
+
+    let own = fetch_metadata(
+        &c,
+        &SyncScope {
+            library: Some(a),
+            vectors: false,
+        },
+    )?;
+    assert_eq!(own.musics, vec![m1, m3]);
+    assert_eq!(own.users.len(), all.users.len());
+    assert!(own.tags.iter().flatten().all(|t| t.vector.is_none()));
+    assert_eq!(
+        tag_ids(&own),
+        vec![
+            (m1.0, s!("title")),
+            (m1.0, format!("user_library:{}", a.0)),
+            (m3.0, s!("title")),
+            (m3.0, format!("user_library:{}", a.0)),
+            (m3.0, format!("user_library:{}", a.0 + 1)),
+        ]
+    );
+    drop(c);
+
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .get("/api/metadata", handlers::metadata);
+    let req = Request::get("/api/metadata?scope=library&vectors=false")
+        .header(COOKIE, format!("cur_user={}", a.0))
+        .body(Body::empty())?;
+    let body = hyper::body::to_bytes(router.serve(req).await?.into_body()).await?;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body.starts_with(&format!("{{\"musics\":[{},{}]", m1.0, m3.0)));
+    assert!(!body.contains("embedding"));
+    let req = Request::get("/api/metadata?scope=library").body(Body::empty())?;
+    assert_eq!(router.serve(req).await?.status(), StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+fn patches(m: &MusidexMetadata) -> Vec<(String, i32, String)> {
+    let mut v: Vec<_> = m
+        .patches
+        .iter()
+        .flatten()
+        .map(|p| (p.kind.clone(), p.tag.music_id.0, p.tag.key.to_string()))
+        .collect();
+    v.sort();
+    v
+}
+
+fn patch(kind: &str, id: MusicID, key: &str) -> (String, i32, String) {
+    (s!(kind), id.0, s!(key))
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_sync_broadcast_scopes() -> Result<()> {
+    let path = std::env::temp_dir().join("musidex-sync-broadcast.db");
+    let _ = std::fs::remove_file(&path);
+    let c = Connection::open(&path)?;
+    let mut migrations: Vec<_> = crate::MIGRATIONS.files().collect();
+    migrations.sort_by_key(|f| f.path());
+    for f in migrations {
+        c.execute_batch(f.contents_utf8().unwrap())?;
+    }
+    let (a, _, [m1, m2, m3]) = setup(&c)?;
+    let lib_a = format!("user_library:{}", a.0);
+    let own_scope = SyncScope {
+        library: Some(a),
+        vectors: false,
+    };
+
+    let (mut broadcast, sub) = SyncBroadcast::with_conn(Connection::open(&path)?);
+    let mut own = sub.subscribe(own_scope.clone());
+    let mut all = sub.subscribe(SyncScope::default());
+    let snapshot = catch_up(&c, &own_scope, None)?.unwrap();
+    assert_eq!(snapshot.musics, vec![m1, m3]);
+    assert!(snapshot.patches.is_none());
+    let start = snapshot.revision;
+    assert_eq!(start, changes::revision(&c)?);
+
+    // new scopes start at the current revision, nothing changed since
+    broadcast.refresh()?;
+    broadcast.refresh()?;
+    assert!(!own.has_changed()? && !all.has_changed()?);
+    assert!(catch_up(&c, &own_scope, Some(start))?.is_none());
+
+    // fingerprints are not logged
+    Tag::insert(&c, Tag::new_text(m1, TagKey::Fingerprint, s!("AQAA")))?;
+    assert_eq!(changes::revision(&c)?, start);
+
+    // neither are upserts that change nothing
+    Tag::insert(&c, Tag::new_text(m1, TagKey::Title, format!("music {}", m1.0)))?;
+    assert_eq!(changes::revision(&c)?, start);
+
+    // outside of the library of a
+    Tag::insert(&c, Tag::new_text(m2, TagKey::Artist, s!("someone")))?;
+    broadcast.refresh()?;
+    assert!(!own.has_changed()?);
+    let update = all.borrow_and_update().clone().unwrap();
+    assert_eq!(update.from, start);
+    assert_eq!(patches(&update.meta), vec![patch("add", m2, "artist")]);
+    let artist = update.meta.revision;
+
+    Tag::insert(&c, Tag::new_text(m3, TagKey::Title, s!("renamed")))?;
+    broadcast.refresh()?;
+    // the scope already got to the revision of the artist, invisible to it
+    let update = own.borrow_and_update().clone().unwrap();
+    assert_eq!(update.from, artist);
+    assert_eq!(patches(&update.meta), vec![patch("update", m3, "title")]);
+    let renamed = update.meta.revision;
+
+    // m2 joins the library of a, m1 leaves it
+    Tag::insert(&c, Tag::new_key(m2, TagKey::UserLibrary(s!(a))))?;
+    Tag::remove(&c, m1, TagKey::UserLibrary(s!(a)))?;
+    broadcast.refresh()?;
+    let update = own.borrow_and_update().clone().unwrap();
+    assert_eq!(update.from, renamed);
+    assert_eq!(update.meta.musics, vec![m2, m3]);
+    let lib_b = format!("user_library:{}", a.0 + 1);
+    assert_eq!(
+        patches(&update.meta),
+        vec![
+            patch("add", m2, "artist"),
+            patch("add", m2, "title"),
+            patch("add", m2, &lib_a),
+            patch("add", m2, &lib_b),
+            patch("remove", m1, "embedding"),
+            patch("remove", m1, "fingerprint"),
+            patch("remove", m1, "title"),
+            patch("remove", m1, &lib_a),
+        ]
+    );
+
+    // a client that was offline resumes from its revision
+    let resumed = catch_up(&c, &own_scope, Some(start))?.unwrap();
+    assert!(resumed.tags.is_none());
+    assert_eq!(resumed.revision, changes::revision(&c)?);
+    assert_eq!(resumed.musics, vec![m2, m3]);
+    let mut expected = patches(&update.meta);
+    expected.push(patch("update", m3, "title"));
+    assert_eq!(patches(&resumed), expected);
+
+    // removing a music logs the removal of its tags
+    Music::delete(&c, m3)?;
+    broadcast.refresh()?;
+    let update = all.borrow_and_update().clone().unwrap();
+    assert_eq!(update.meta.musics, vec![m1, m2]);
+    assert_eq!(
+        patches(&update.meta),
+        vec![
+            patch("remove", m3, "embedding"),
+            patch("remove", m3, "title"),
+            patch("remove", m3, &lib_a),
+            patch("remove", m3, &lib_b),
+        ]
+    );
+
+    // clients from before the log or from another database get a full snapshot
+    for revision in [-1, changes::revision(&c)? + 1] {
+        let full = catch_up(&c, &own_scope, Some(revision))?.unwrap();
+        assert!(full.patches.is_none());
+        assert_eq!(full.musics, vec![m2]);
+    }
+    Ok(())
+}
@@ -2,7 +2,7 @@ use super::*;
 use anyhow::Result;
 
 use crate::domain::entity::{Music, Tag, TagKey};
-use crate::domain::sync::fetch_metadata;
+use crate::domain::sync::{fetch_metadata, SyncScope};
 
 #[test_log::test(tokio::test)]
 pub async fn test_insert_tag() -> Result<()> {
@@ -13,7 +13,7 @@ pub async fn test_insert_tag() -> Result<()> {
     let tag = Tag::new_text(music, TagKey::Duration, s!("value"));
     Tag::insert(&c, tag.clone())?;
 
-    let metadata = fetch_metadata(&c)?;
+    let metadata = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(metadata.musics[0], music);
     assert_eq!(metadata.tags.unwrap()[0], tag);
 
@@ -29,13 +29,13 @@ pub async fn test_remove_tag() -> Result<()> {
     let tag = Tag::new_text(music, TagKey::Duration, s!("value"));
     Tag::insert(&c, tag.clone())?;
 
-    let metadata = fetch_metadata(&c)?;
+    let metadata = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(metadata.musics[0], music);
     assert_eq!(metadata.tags.unwrap()[0], tag);
 
     Tag::remove(&c, tag.music_id, tag.key)?;
 
-    let metadata = fetch_metadata(&c)?;
+    let metadata = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(metadata.tags.unwrap().len(), 0);
 
     Ok(())
@@ -53,7 +53,7 @@ pub async fn test_update_tag() -> Result<()> {
     let tag2 = Tag::new_text(music, TagKey::Duration, s!("456"));
     Tag::insert(&c, tag2.clone())?;
 
-    let metadata = fetch_metadata(&c)?;
+    let metadata = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(metadata.musics[0], music);
     assert_eq!(metadata.tags.unwrap()[0], tag2);
 
@@ -72,7 +72,7 @@ pub async fn test_nested_tagkey_roundtrip() -> Result<()> {
     let s: String = (&tag.key).into();
     assert_eq!(s, s!("user_library::test:test"));
 
-    let metadata = fetch_metadata(&c)?;
+    let metadata = fetch_metadata(&c, &SyncScope::default())?;
     assert_eq!(metadata.musics[0], music);
     assert_eq!(metadata.tags.unwrap()[0], tag);
 
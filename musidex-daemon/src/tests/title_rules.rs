@@ -0,0 +1,284 @@
+use super::*;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::domain::title_rules::{apply_to_library, TitleRule, TitleRules};
+use anyhow::Result;
+use regex::Regex;
+use rusqlite::Connection;
+
+const FEAT: &str = r"(?i)\s*[\(\[]?\b(?:feat\.|ft\.|featuring)\s+([^\)\]]+)[\)\]]?";
+
+fn push_rule(c: &mut Connection, kind: &str, pattern: &str) -> Result<()> {
+    let mut rules = TitleRule::list(c)?;
+    rules.push(TitleRule {
+        kind: s!(kind),
+        pattern: s!(pattern),
+        replacement: s!(""),
+    });
+    TitleRule::replace_all(c, &rules)?;
+    assert_eq!(TitleRule::list(c)?, rules);
+    Ok(())
+}
+
+fn mk_music(c: &Connection, original: &str, title: &str, artist: Option<&str>) -> Result<MusicID> {
+    let music = Music::mk(c)?;
+    Tag::insert(
+        c,
+        Tag::new_text(music, TagKey::YoutubeDLOriginalTitle, s!(original)),
+    )?;
+    Tag::insert(c, Tag::new_text(music, TagKey::Title, s!(title)))?;
+    if let Some(artist) = artist {
+        Tag::insert(c, Tag::new_text(music, TagKey::Artist, s!(artist)))?;
+    }
+    Ok(music)
+}
+
+fn text(c: &Connection, music: MusicID, key: TagKey) -> Result<Option<String>> {
+    Ok(Tag::by_id_key(c, music, key)?.and_then(|x| x.text))
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_default_rules_migrated() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let rules = TitleRule::list(&c)?;
+    let kinds: Vec<_> = rules.iter().map(|x| &*x.kind).collect();
+    assert_eq!(kinds, vec!["replace", "split"]);
+    assert_eq!(TitleRule::list_applied(&c)?, rules);
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_official_remover_regex() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let official_remover = Regex::new(&TitleRule::list(&c)?[0].pattern)?;
+    let should_match = &[
+        "(Official)",
+        "[Official]",
+        "[Official - Video]",
+        "(Video)",
+        "[HQ]",
+        "[HQ - Video]",
+        "(official video)",
+        "(official - video)",
+    ];
+
+    let should_not_match = &[
+        "",
+        "official",
+        "official video",
+        " - ",
+        "(official",
+        "official)",
+        "Video",
+        "(ft. Lil B)",
+        "(homer)",
+    ];
+
+    for v in should_match {
+        assert!(official_remover.is_match(v), "should match: {}", v);
+    }
+
+    for v in should_not_match {
+        assert!(!official_remover.is_match(v), "should not match: {}", v);
+    }
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_artist_split() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let rules = TitleRules::load(&c)?;
+    let should_split = &[
+        (
+            "Jamiroquai - Virtual Insanity (Official Video)",
+            "Jamiroquai",
+            "Virtual Insanity",
+        ),
+        (
+            "Earth, Wind & Fire - September (Official Video)",
+            "Earth, Wind & Fire",
+            "September",
+        ),
+        (
+            "Breakbot - Baby I'm Yours (feat. Irfane) [Official Video]",
+            "Breakbot",
+            "Baby I'm Yours (feat. Irfane)",
+        ),
+        (" a - b ", "a", "b"),
+        ("a - b", "a", "b"),
+        (" a - b - c ", "a", "b - c"),
+    ];
+
+    let should_not_split = &[
+        "Baby I'm Yours (feat. Irfane)",
+        "Mr. Blue. Sky",
+        "a -",
+        "-",
+        "a-a",
+        "a -ba",
+        "a- ba",
+        "Princess Mononoke Suite「03. TA-TA-RI-GAMI (The Demon God)」",
+        "",
+    ];
+
+    for (title, artist, track) in should_split {
+        let g = rules.apply(title);
+        assert_eq!(&*g.title, *track);
+        assert_eq!(&*g.artist.unwrap(), *artist);
+    }
+
+    for v in should_not_split {
+        let g = rules.apply(v);
+        assert!(g.artist.is_none());
+        assert_eq!(&*g.title, *v);
+    }
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_apply_rules() -> Result<()> {
+    let db = mk_db().await?;
+    let mut c = db.get().await;
+
+    push_rule(&mut c, "feat", FEAT)?;
+    push_rule(&mut c, "replace", r"(?i)\s*\(lyrics?\)")?;
+    let rules = TitleRules::load(&c)?;
+
+    let g = rules.apply("Breakbot - Baby I'm Yours (feat. Irfane) [Official Video]");
+    assert_eq!(g.title, "Baby I'm Yours");
+    assert_eq!(g.artist.as_deref(), Some("Breakbot"));
+    assert_eq!(g.featured, vec![s!("Irfane")]);
+
+    let g = rules.apply("Daft Punk ft. Pharrell Williams - Get Lucky (Lyrics)");
+    assert_eq!(g.title, "Get Lucky");
+    assert_eq!(g.artist.as_deref(), Some("Daft Punk"));
+    assert_eq!(g.featured, vec![s!("Pharrell Williams")]);
+
+    let g = rules.apply("Mr. Blue Sky");
+    assert_eq!(g.title, "Mr. Blue Sky");
+    assert!(g.artist.is_none());
+    assert!(g.featured.is_empty());
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_reapply_rules() -> Result<()> {
+    let db = mk_db().await?;
+    let mut c = db.get().await;
+
+    let music = mk_music(
+        &c,
+        "Breakbot - Baby I'm Yours (feat. Irfane) [Official Video]",
+        "Baby I'm Yours (feat. Irfane)",
+        Some("Breakbot"),
+    )?;
+    let edited = mk_music(
+        &c,
+        "Daft Punk - Get Lucky (feat. Pharrell Williams)",
+        "Get Lucky (Radio Edit)",
+        Some("Daft Punk"),
+    )?;
+    let edited_artist = mk_music(
+        &c,
+        "Justice - D.A.N.C.E. (feat. Someone)",
+        "D.A.N.C.E. (feat. Someone)",
+        Some("Justice (band)"),
+    )?;
+    Tag::insert(
+        &c,
+        Tag::new_text(edited_artist, TagKey::FeaturedArtist, s!("Kids Choir")),
+    )?;
+
+    push_rule(&mut c, "feat", r"\s*\(feat\. ([^\)]+)\)")?;
+
+    assert_eq!(apply_to_library(&mut c)?, 2);
+    assert_eq!(apply_to_library(&mut c)?, 0);
+
+    assert_eq!(
+        text(&c, music, TagKey::Title)?.as_deref(),
+        Some("Baby I'm Yours")
+    );
+    assert_eq!(
+        text(&c, music, TagKey::FeaturedArtist)?.as_deref(),
+        Some("Irfane")
+    );
+
+    // edited by hand, left alone
+    assert_eq!(
+        text(&c, edited, TagKey::Title)?.as_deref(),
+        Some("Get Lucky (Radio Edit)")
+    );
+    assert!(text(&c, edited, TagKey::FeaturedArtist)?.is_none());
+
+    // only the title follows the rules, the artists were edited by hand
+    assert_eq!(
+        text(&c, edited_artist, TagKey::Title)?.as_deref(),
+        Some("D.A.N.C.E.")
+    );
+    assert_eq!(
+        text(&c, edited_artist, TagKey::Artist)?.as_deref(),
+        Some("Justice (band)")
+    );
+    assert_eq!(
+        text(&c, edited_artist, TagKey::FeaturedArtist)?.as_deref(),
+        Some("Kids Choir")
+    );
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_reapply_rules_removes_stale_tags() -> Result<()> {
+    let db = mk_db().await?;
+    let mut c = db.get().await;
+
+    push_rule(&mut c, "feat", r"\s*\(feat\. ([^\)]+)\)")?;
+    let music = mk_music(
+        &c,
+        "Breakbot - Baby I'm Yours (feat. Irfane)",
+        "Baby I'm Yours",
+        Some("Breakbot"),
+    )?;
+    assert_eq!(apply_to_library(&mut c)?, 1);
+    assert_eq!(
+        text(&c, music, TagKey::FeaturedArtist)?.as_deref(),
+        Some("Irfane")
+    );
+
+    // only the feat rule goes away: a feat-only change still counts
+    let mut rules = TitleRule::list(&c)?;
+    rules.pop();
+    rules.push(TitleRule {
+        kind: s!("replace"),
+        pattern: s!(r"\s*\(feat\. [^\)]+\)"),
+        replacement: s!(""),
+    });
+    TitleRule::replace_all(&mut c, &rules)?;
+    assert_eq!(apply_to_library(&mut c)?, 1);
+    assert!(text(&c, music, TagKey::FeaturedArtist)?.is_none());
+    assert_eq!(
+        text(&c, music, TagKey::Title)?.as_deref(),
+        Some("Baby I'm Yours")
+    );
+
+    // without the split rule the artist guessed before is removed
+    rules.remove(1);
+    TitleRule::replace_all(&mut c, &rules)?;
+    assert_eq!(apply_to_library(&mut c)?, 1);
+    assert!(text(&c, music, TagKey::Artist)?.is_none());
+    assert_eq!(
+        text(&c, music, TagKey::Title)?.as_deref(),
+        Some("Breakbot - Baby I'm Yours")
+    );
+
+    Ok(())
+}
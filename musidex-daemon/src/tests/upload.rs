@@ -0,0 +1,93 @@
+use super::*;
+use crate::domain::config;
+use crate::domain::entity::{Tag, TagKey, UserID};
+use crate::domain::upload::youtube_upload;
+use anyhow::Result;
+use hyper::StatusCode;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+/// Writes a script pretending to be yt-dlp, it saves its arguments next to itself and prints `json`
+fn fake_ytdlp(name: &str, json: &str) -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("musidex-fake-ytdlp-{}", name));
+    std::fs::create_dir_all(&dir)?;
+    let script = dir.join("yt-dlp");
+    std::fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\necho \"$@\" > {}\ncat <<'EOF'\n{}\nEOF\n",
+            dir.join("args").display(),
+            json
+        ),
+    )?;
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+    Ok(dir)
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_upload_fake_ytdlp() -> Result<()> {
+    let db = mk_db().await?;
+    config::init(&db).await?;
+    let dir = fake_ytdlp(
+        "upload",
+        r#"{"id": "xyz", "title": "Jamiroquai - Virtual Insanity (Official Video)", "duration": 200.5, "webpage_url": "https://www.youtube.com/watch?v=xyz",
+            "album": "Travelling Without Moving", "release_year": 1996, "genre": "Acid jazz", "track_number": 2,
+            "channel": "JamiroquaiVEVO", "uploader": "Jamiroquai", "upload_date": "20091025",
+            "description": "Music video by Jamiroquai", "tags": ["jamiroquai", null, "funk"]}"#,
+    )?;
+
+    let _ytdlp = fake_ytdlp_env(&dir.join("yt-dlp"), "--force-ipv4  --no-cache-dir").await;
+    let mut c = db.get().await;
+    c.execute(
+        "INSERT INTO secrets (name, value) VALUES ('ytdlp_cookies', 'cookies.txt')",
+        [],
+    )?;
+    assert!(config::get_all(&c)?
+        .iter()
+        .all(|(k, _)| !k.contains("cookies")));
+
+    let status = youtube_upload(&mut c, s!("https://youtu.be/xyz"), UserID(1)).await?;
+    assert_eq!(status, StatusCode::OK);
+
+    let args = std::fs::read_to_string(dir.join("args"))?;
+    assert_eq!(
+        args.trim(),
+        "--cookies cookies.txt --force-ipv4 --no-cache-dir --no-playlist -J -- https://youtu.be/xyz"
+    );
+
+    let id = Tag::by_key(&c, TagKey::YoutubeDLVideoID)?[0].music_id;
+    let title = Tag::by_id_key(&c, id, TagKey::Title)?.unwrap();
+    assert_eq!(title.text.as_deref(), Some("Virtual Insanity"));
+    let artist = Tag::by_id_key(&c, id, TagKey::Artist)?.unwrap();
+    assert_eq!(artist.text.as_deref(), Some("Jamiroquai"));
+    let duration = Tag::by_id_key(&c, id, TagKey::Duration)?.unwrap();
+    assert_eq!(duration.integer, Some(201));
+
+    let get = |key| Tag::by_id_key(&c, id, key).unwrap().unwrap();
+    assert_eq!(
+        get(TagKey::Album).text.as_deref(),
+        Some("Travelling Without Moving")
+    );
+    assert_eq!(get(TagKey::Year).integer, Some(1996));
+    assert!(get(TagKey::Year).date.unwrap().starts_with("1996"));
+    assert_eq!(get(TagKey::Genre).text.as_deref(), Some("Acid jazz"));
+    assert_eq!(get(TagKey::TrackNumber).integer, Some(2));
+    assert_eq!(
+        get(TagKey::Uploader).text.as_deref(),
+        Some("JamiroquaiVEVO")
+    );
+    assert!(get(TagKey::UploadDate)
+        .date
+        .unwrap()
+        .starts_with("2009-10-25"));
+    assert_eq!(
+        get(TagKey::YoutubeDLTags).text.as_deref(),
+        Some("jamiroquai, funk")
+    );
+    assert!(get(TagKey::Description).integer.is_none());
+
+    let status = youtube_upload(&mut c, s!("https://youtu.be/xyz"), UserID(1)).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    Ok(())
+}
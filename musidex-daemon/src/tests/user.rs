@@ -1,6 +1,6 @@
 use super::*;
 use crate::domain::entity::{Music, Tag, TagKey, User};
-use crate::domain::sync::fetch_metadata;
+use crate::domain::sync::{fetch_metadata, SyncScope};
 use anyhow::{Context, Result};
 
 #[test_log::test(tokio::test)]
@@ -70,7 +70,7 @@ pub async fn test_sorted_users() -> Result<()> {
     let u2 = User::create(&c, s!("a"))?;
     let u3 = User::create(&c, s!("b"))?;
 
-    let meta = fetch_metadata(&c)?;
+    let meta = fetch_metadata(&c, &SyncScope::default())?;
 
     assert_eq!(
         meta.users.iter().map(|x| x.id).collect::<Vec<_>>(),
@@ -0,0 +1,55 @@
+use super::*;
+use crate::domain::entity::{Music, Tag, TagKey};
+use crate::domain::worker_analysis::{find_candidate, AnalysisWorker};
+use anyhow::Result;
+
+#[test_log::test(tokio::test)]
+pub async fn test_analysis_worker() -> Result<()> {
+    let db = mk_db().await?;
+
+    // an A minor chord with a kick at 90 bpm
+    let rate = 22050;
+    let period = 60.0 / 90.0 * rate as f32;
+    let samples: Vec<f32> = (0..rate * 20)
+        .map(|i| {
+            let t = i as f32 / rate as f32;
+            let chord: f32 = [220.0, 261.63, 329.63]
+                .iter()
+                .map(|f| (2.0 * std::f32::consts::PI * f * t).sin())
+                .sum();
+            let since_kick = (i as f32 % period) / rate as f32;
+            let kick = (2.0 * std::f32::consts::PI * 60.0 * t).sin() * (-since_kick * 30.0).exp();
+            chord * 0.1 + kick * 0.5
+        })
+        .collect();
+    let source = write_wav("analysis", rate as u32, 1, &samples)?;
+
+    let (music, long_mix) = {
+        let c = db.get().await;
+        let music = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(music, TagKey::LocalMP3, source.clone()))?;
+        let long_mix = Music::mk(&c)?;
+        Tag::insert(
+            &c,
+            Tag::new_text(long_mix, TagKey::LocalMP3, source.clone()),
+        )?;
+        Tag::insert(&c, Tag::new_parse(long_mix, TagKey::Duration, s!("4000")))?;
+        (music, long_mix)
+    };
+
+    let mut worker = AnalysisWorker::new(db.clone());
+    assert!(worker.step().await?);
+    assert!(!worker.step().await?);
+
+    let c = db.get().await;
+    assert!(find_candidate(&c)?.is_none());
+    assert!(Tag::by_id_key(&c, long_mix, TagKey::Bpm)?.is_none());
+
+    let bpm = Tag::by_id_key(&c, music, TagKey::Bpm)?.unwrap();
+    assert!((bpm.integer.unwrap() - 90).abs() <= 1, "{:?}", bpm);
+    let key = Tag::by_id_key(&c, music, TagKey::Key)?.unwrap();
+    assert_eq!(key.text.as_deref(), Some("A minor"));
+
+    std::fs::remove_file(format!("./storage/{}", source))?;
+    Ok(())
+}
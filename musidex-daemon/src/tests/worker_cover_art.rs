@@ -0,0 +1,125 @@
+use super::*;
+use crate::domain::cover_art::{save_cover, set_thumbnail};
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::domain::worker_cover_art::{find_candidate, CoverArtWorker};
+use anyhow::Result;
+use id3::TagLike;
+use image::{ImageOutputFormat, Rgb, RgbImage};
+
+fn image(format: ImageOutputFormat) -> Result<Vec<u8>> {
+    let img = RgbImage::from_pixel(32, 32, Rgb([200, 30, 30]));
+    let mut buf = vec![];
+    img.write_to(&mut std::io::Cursor::new(&mut buf), format)?;
+    Ok(buf)
+}
+
+/// Silent MPEG-1 layer III frames at 128kbps
+fn silent_mp3() -> Vec<u8> {
+    let mut frame = vec![0u8; 417];
+    frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+    frame.repeat(40)
+}
+
+fn thumbnail(c: &rusqlite::Connection, id: MusicID) -> Result<Option<String>> {
+    Ok(Tag::by_id_key(c, id, TagKey::Thumbnail)?.and_then(|x| x.text))
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_cover_art_worker() -> Result<()> {
+    let db = mk_db().await?;
+    std::fs::create_dir_all("./storage/test-cover-dir")?;
+
+    let embedded = "./storage/test-cover-embedded.mp3";
+    std::fs::write(embedded, silent_mp3())?;
+    let mut tag = id3::Tag::new();
+    tag.add_frame(id3::frame::Picture {
+        mime_type: s!("image/jpeg"),
+        picture_type: id3::frame::PictureType::CoverFront,
+        description: s!(""),
+        data: image(ImageOutputFormat::Jpeg(90))?,
+    });
+    tag.write_to_path(embedded, id3::Version::Id3v24)?;
+
+    std::fs::write("./storage/test-cover-dir/track.mp3", silent_mp3())?;
+    std::fs::write(
+        "./storage/test-cover-dir/Folder.png",
+        image(ImageOutputFormat::Png)?,
+    )?;
+    std::fs::write("./storage/test-cover-bare.mp3", silent_mp3())?;
+
+    let (m_embedded, m_sidecar, m_bare, m_youtube) = {
+        let c = db.get().await;
+        let mk = |source: &str| -> Result<MusicID> {
+            let id = Music::mk(&c)?;
+            Tag::insert(&c, Tag::new_text(id, TagKey::LocalMP3, s!(source)))?;
+            Ok(id)
+        };
+        let m_youtube = mk("test-cover-bare.mp3")?;
+        Tag::insert(
+            &c,
+            Tag::new_text(m_youtube, TagKey::Thumbnail, s!("yt.jpg")),
+        )?;
+        (
+            mk("test-cover-embedded.mp3")?,
+            mk("test-cover-dir/track.mp3")?,
+            mk("test-cover-bare.mp3")?,
+            m_youtube,
+        )
+    };
+
+    let mut worker = CoverArtWorker::new(db.clone());
+    for _ in 0..3 {
+        assert!(worker.step().await?);
+    }
+    assert!(!worker.step().await?);
+
+    let c = db.get().await;
+    assert!(find_candidate(&c)?.is_none());
+
+    let from_embedded = thumbnail(&c, m_embedded)?.unwrap();
+    let from_sidecar = thumbnail(&c, m_sidecar)?.unwrap();
+    for fname in [&from_embedded, &from_sidecar] {
+        let img = image::open(format!("./storage/{}", fname))?;
+        assert_eq!(img.width(), 32);
+        assert!(fname.ends_with(".jpg"));
+    }
+    assert_eq!(thumbnail(&c, m_bare)?, None);
+    assert_eq!(thumbnail(&c, m_youtube)?.as_deref(), Some("yt.jpg"));
+
+    for f in [&from_embedded, &from_sidecar] {
+        std::fs::remove_file(format!("./storage/{}", f))?;
+    }
+    std::fs::remove_file(embedded)?;
+    std::fs::remove_file("./storage/test-cover-bare.mp3")?;
+    std::fs::remove_dir_all("./storage/test-cover-dir")?;
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_user_thumbnail() -> Result<()> {
+    let db = mk_db().await?;
+    let c = db.get().await;
+
+    let music = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(music, TagKey::Thumbnail, s!("yt.jpg")))?;
+    Tag::insert(
+        &c,
+        Tag::new_text(music, TagKey::CompressedThumbnail, s!("compressed.yt.jpg")),
+    )?;
+
+    assert!(save_cover(music, b"not an image").is_err());
+
+    let fname = save_cover(music, &image(ImageOutputFormat::Png)?)?;
+    set_thumbnail(&c, music, fname.clone())?;
+
+    assert_eq!(thumbnail(&c, music)?, Some(fname.clone()));
+    // gets picked up again by the thumbnail worker
+    assert!(Tag::by_id_key(&c, music, TagKey::CompressedThumbnail)?.is_none());
+    assert_eq!(
+        crate::domain::worker_thumbnail_resize::find_candidate(&c)?,
+        Some(music)
+    );
+
+    std::fs::remove_file(format!("./storage/{}", fname))?;
+    Ok(())
+}
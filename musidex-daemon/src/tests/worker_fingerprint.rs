@@ -0,0 +1,62 @@
+use super::*;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::domain::fingerprint::{all_fingerprints, find_duplicates};
+use crate::domain::worker_fingerprint::{find_candidate, FingerprintWorker};
+use anyhow::Result;
+
+fn mk_music(c: &rusqlite::Connection, source: &str) -> Result<MusicID> {
+    let music = Music::mk(c)?;
+    Tag::insert(c, Tag::new_text(music, TagKey::LocalMP3, s!(source)))?;
+    Ok(music)
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_fingerprint_duplicates() -> Result<()> {
+    let db = mk_db().await?;
+
+    let original = melody(1, 44100, 30.0);
+    // the lyrics video starts 2 seconds later and is a bit quieter
+    let lyrics: Vec<f32> = original[2 * 44100..].iter().map(|x| x * 0.7).collect();
+    let other = melody(2, 44100, 30.0);
+
+    let original = write_wav("fp-original", 44100, 1, &original)?;
+    let lyrics = write_wav("fp-lyrics", 44100, 1, &lyrics)?;
+    let other = write_wav("fp-other", 44100, 1, &other)?;
+
+    let (m1, m2, m3, m4) = {
+        let c = db.get().await;
+        (
+            mk_music(&c, &original)?,
+            mk_music(&c, &other)?,
+            mk_music(&c, &lyrics)?,
+            mk_music(&c, "test-fp-missing.mp3")?,
+        )
+    };
+
+    let mut worker = FingerprintWorker::new(db.clone());
+    for _ in 0..4 {
+        assert!(worker.step().await?);
+    }
+    assert!(!worker.step().await?);
+
+    let c = db.get().await;
+    assert!(find_candidate(&c)?.is_none());
+    // missing files are marked as treated without a fingerprint
+    assert!(Tag::by_id_key(&c, m4, TagKey::Fingerprint)?
+        .unwrap()
+        .text
+        .is_none());
+
+    let fps = all_fingerprints(&c)?;
+    assert_eq!(fps.len(), 3);
+
+    let groups = find_duplicates(&fps);
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].musics, vec![m1, m3]);
+    assert!(!groups[0].musics.contains(&m2));
+
+    for f in [original, lyrics, other] {
+        std::fs::remove_file(format!("./storage/{}", f))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use super::*;
+use crate::domain::entity::{Music, Tag, TagKey};
+use crate::domain::loudness::ReplayGain;
+use crate::domain::worker_loudness::{find_candidate, LoudnessWorker};
+use anyhow::Result;
+
+#[test_log::test(tokio::test)]
+pub async fn test_loudness_worker() -> Result<()> {
+    let db = mk_db().await?;
+
+    // a 1kHz sine peaking at -10dBFS in both channels
+    let samples: Vec<f32> = (0..44100 * 3)
+        .flat_map(|i| {
+            let x = 0.316 * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / 44100.0).sin();
+            [x, x]
+        })
+        .collect();
+    let source = write_wav("loudness", 44100, 2, &samples)?;
+
+    let music = {
+        let c = db.get().await;
+        let music = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(music, TagKey::LocalMP3, source.clone()))?;
+        music
+    };
+
+    let mut worker = LoudnessWorker::new(db.clone());
+    assert!(worker.step().await?);
+    assert!(!worker.step().await?);
+
+    let c = db.get().await;
+    assert!(find_candidate(&c)?.is_none());
+
+    let get = |key| -> Result<f64> {
+        Ok(Tag::by_id_key(&c, music, key)?
+            .and_then(|x| x.text)
+            .unwrap()
+            .parse()?)
+    };
+    assert!((get(TagKey::Loudness)? + 10.0).abs() < 0.1);
+    assert!((get(TagKey::TruePeak)? + 10.0).abs() < 0.1);
+    assert!((get(TagKey::ReplayGainTrackGain)? + 8.0).abs() < 0.1);
+
+    let rg = ReplayGain::of(&c, music)?.unwrap();
+    assert!((rg.gain + 8.0).abs() < 0.1);
+    assert!((rg.peak - 0.316).abs() < 0.01);
+
+    std::fs::remove_file(format!("./storage/{}", source))?;
+    Ok(())
+}
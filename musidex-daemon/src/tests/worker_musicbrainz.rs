@@ -0,0 +1,224 @@
+use super::*;
+use crate::application::handlers;
+use crate::domain::config;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::domain::worker_musicbrainz::{find_candidate, MusicBrainzWorker};
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::sync::Mutex;
+
+const SEARCH_RESPONSE: &str = r#"{"count":2,"offset":0,"recordings":[
+    {"id":"live-mbid","score":100,"title":"Virtual Insanity (live)","length":400000,
+     "artist-credit":[{"name":"Jamiroquai","artist":{"id":"jq-mbid","name":"Jamiroquai"}}]},
+    {"id":"rec-mbid","score":95,"title":"Virtual Insanity","length":230000,
+     "artist-credit":[{"name":"Jamiroquai","artist":{"id":"jq-mbid","name":"Jamiroquai"}}],
+     "first-release-date":"1996-08-19",
+     "releases":[{"id":"twm-mbid","title":"Travelling Without Moving","status":"Official",
+                  "date":"1996-08-28","release-group":{"primary-type":"Album"}}]}]}"#;
+
+/// Serves SEARCH_RESPONSE to any request, returns the base url and the requested uris
+fn mock_musicbrainz() -> (String, Arc<Mutex<Vec<String>>>) {
+    mock_musicbrainz_with(StatusCode::OK)
+}
+
+fn mock_musicbrainz_with(status: StatusCode) -> (String, Arc<Mutex<Vec<String>>>) {
+    let requests = Arc::new(Mutex::new(vec![]));
+    let requests2 = requests.clone();
+    let make_svc = make_service_fn(move |_| {
+        let requests = requests2.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                requests.lock().unwrap().push(req.uri().to_string());
+                async move {
+                    let mut resp = Response::new(Body::from(SEARCH_RESPONSE));
+                    *resp.status_mut() = status;
+                    Ok::<_, Infallible>(resp)
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    (url, requests)
+}
+
+fn mk_music(c: &rusqlite::Connection, title: &str, artist: &str) -> Result<MusicID> {
+    let music = Music::mk(c)?;
+    Tag::insert(c, Tag::new_text(music, TagKey::Title, s!(title)))?;
+    Tag::insert(c, Tag::new_text(music, TagKey::Artist, s!(artist)))?;
+    Tag::insert(c, Tag::new_parse(music, TagKey::Duration, s!("232")))?;
+    Ok(music)
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_musicbrainz_suggestions() -> Result<()> {
+    let db = mk_db().await?;
+    config::init(&db).await?;
+    let (url, requests) = mock_musicbrainz();
+
+    let music = {
+        let c = db.get().await;
+        config::update(&c, "musicbrainz_url", &url)?;
+        mk_music(&c, "Virtual Insanity", "Jamiroquai")?
+    };
+
+    let mut worker = MusicBrainzWorker::new(db.clone());
+    assert!(
+        !worker.step().await?,
+        "worker should be disabled by default"
+    );
+
+    config::update(&*db.get().await, "musicbrainz_enabled", "true")?;
+    assert!(worker.step().await?);
+
+    {
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("/ws/2/recording?"));
+        assert!(requests[0].contains("recording%3A%22Virtual%20Insanity%22"));
+        assert!(requests[0].contains("artist%3A%22Jamiroquai%22"));
+    }
+
+    let mut c = db.get().await;
+    assert!(find_candidate(&c)?.is_none());
+
+    let suggestion = |key: TagKey| TagKey::Suggestion((&key).into());
+    let get = |c: &rusqlite::Connection, key| Tag::by_id_key(c, music, key).unwrap();
+
+    // the live version is too long, known information is not proposed again
+    assert!(get(&c, suggestion(TagKey::Title)).is_none());
+    assert!(get(&c, suggestion(TagKey::Artist)).is_none());
+    assert_eq!(
+        get(&c, suggestion(TagKey::MusicBrainzRecordingID))
+            .unwrap()
+            .text,
+        Some(s!("rec-mbid"))
+    );
+    assert_eq!(
+        get(&c, suggestion(TagKey::Album)).unwrap().text,
+        Some(s!("Travelling Without Moving"))
+    );
+    assert_eq!(
+        get(&c, suggestion(TagKey::Year)).unwrap().integer,
+        Some(1996)
+    );
+
+    assert_eq!(Music::accept_suggestions(&mut c, music)?, 5);
+    assert_eq!(
+        get(&c, TagKey::Album).unwrap().text,
+        Some(s!("Travelling Without Moving"))
+    );
+    assert_eq!(
+        get(&c, TagKey::MusicBrainzArtistID).unwrap().text,
+        Some(s!("jq-mbid"))
+    );
+    assert!(get(&c, suggestion(TagKey::Album)).is_none());
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_musicbrainz_reject() -> Result<()> {
+    let db = mk_db().await?;
+    config::init(&db).await?;
+    let (url, _) = mock_musicbrainz();
+
+    let music = {
+        let c = db.get().await;
+        config::update(&c, "musicbrainz_url", &url)?;
+        config::update(&c, "musicbrainz_enabled", "true")?;
+        mk_music(&c, "Virtual Insanity", "Jamirokwai")?
+    };
+
+    let mut worker = MusicBrainzWorker::new(db.clone());
+    assert!(worker.step().await?);
+    assert!(!worker.step().await?);
+
+    let c = db.get().await;
+    assert!(Tag::by_id(&c, music)?
+        .iter()
+        .any(|t| t.key.as_suggestion() == Some("artist")));
+
+    Music::reject_suggestions(&c, music)?;
+    assert!(Tag::by_id(&c, music)?
+        .iter()
+        .all(|t| t.key.as_suggestion().is_none()));
+    assert_eq!(
+        Tag::by_id_key(&c, music, TagKey::Artist)?.unwrap().text,
+        Some(s!("Jamirokwai"))
+    );
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_musicbrainz_unavailable() -> Result<()> {
+    let db = mk_db().await?;
+    config::init(&db).await?;
+    let (url, requests) = mock_musicbrainz_with(StatusCode::SERVICE_UNAVAILABLE);
+
+    let music = {
+        let c = db.get().await;
+        config::update(&c, "musicbrainz_url", &url)?;
+        config::update(&c, "musicbrainz_enabled", "true")?;
+        mk_music(&c, "Virtual Insanity", "Jamiroquai")?
+    };
+
+    // the worker backs off and looks the music up again later
+    let mut worker = MusicBrainzWorker::new(db.clone());
+    assert!(worker.step().await.is_err());
+    assert!(worker.step().await.is_err());
+    assert_eq!(requests.lock().unwrap().len(), 2);
+
+    let c = db.get().await;
+    assert_eq!(find_candidate(&c)?, Some(music));
+    assert!(Tag::by_id_key(&c, music, TagKey::MusicBrainzWorkerTreated)?.is_none());
+
+    Ok(())
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_musicbrainz_error_retry() -> Result<()> {
+    let db = mk_db().await?;
+    config::init(&db).await?;
+    let (url, requests) = mock_musicbrainz_with(StatusCode::BAD_REQUEST);
+
+    let music = {
+        let c = db.get().await;
+        config::update(&c, "musicbrainz_url", &url)?;
+        config::update(&c, "musicbrainz_enabled", "true")?;
+        mk_music(&c, "Virtual Insanity", "Jamiroquai")?
+    };
+
+    let mut worker = MusicBrainzWorker::new(db.clone());
+    assert!(worker.step().await?);
+    assert!(
+        !worker.step().await?,
+        "failed music should not be picked again"
+    );
+    assert_eq!(requests.lock().unwrap().len(), 1);
+
+    {
+        let c = db.get().await;
+        assert!(find_candidate(&c)?.is_none());
+        assert_eq!(
+            Tag::by_id_key(&c, music, TagKey::MusicBrainzWorkerTreated)?
+                .unwrap()
+                .text,
+            Some(s!("error"))
+        );
+        assert!(Tag::by_id(&c, music)?
+            .iter()
+            .all(|t| t.key.as_suggestion().is_none()));
+    }
+
+    let mut req = Request::new(Body::empty());
+    mk_db_extension(&mut req, db.clone());
+    handlers::retry_on_error(req).await?;
+    assert_eq!(find_candidate(&*db.get().await)?, Some(music));
+
+    Ok(())
+}
@@ -22,5 +22,11 @@ pub async fn test_insert_tag() -> Result<()> {
 
     assert!(!needs_embedding(&c)?);
 
+    // chapters get the embedding of their window of the mix
+    let chapter = Music::mk(&c)?;
+    Tag::insert(&c, Tag::new_text(chapter, TagKey::LocalMP3, s!("hi.mp3")))?;
+    Tag::insert(&c, Tag::new_text(chapter, TagKey::ChapterStart, s!("120")))?;
+    assert!(needs_embedding(&c)?);
+
     Ok(())
 }
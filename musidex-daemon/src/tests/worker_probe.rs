@@ -0,0 +1,100 @@
+use super::*;
+use crate::domain::entity::{Music, MusicID, Tag, TagKey};
+use crate::domain::worker_probe::{find_candidate, ProbeWorker};
+use anyhow::Result;
+
+fn integer(c: &rusqlite::Connection, id: MusicID, key: TagKey) -> Result<Option<i32>> {
+    Ok(Tag::by_id_key(c, id, key)?.and_then(|x| x.integer))
+}
+
+#[test_log::test(tokio::test)]
+pub async fn test_probe_worker() -> Result<()> {
+    let db = mk_db().await?;
+
+    let mono = write_wav("probe-mono", 44100, 1, &melody(1, 44100, 10.0))?;
+    let stereo: Vec<f32> = melody(2, 48000, 5.0)
+        .into_iter()
+        .flat_map(|x| [x, x])
+        .collect();
+    let stereo = write_wav("probe-stereo", 48000, 2, &stereo)?;
+
+    let (wrong_duration, chapter, no_duration, missing) = {
+        let c = db.get().await;
+        let wrong_duration = Music::mk(&c)?;
+        Tag::insert(
+            &c,
+            Tag::new_text(wrong_duration, TagKey::LocalMP3, mono.clone()),
+        )?;
+        Tag::insert(
+            &c,
+            Tag::new_parse(wrong_duration, TagKey::Duration, s!("100")),
+        )?;
+
+        let chapter = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(chapter, TagKey::LocalMP3, mono.clone()))?;
+        Tag::insert(&c, Tag::new_text(chapter, TagKey::ChapterStart, s!("2")))?;
+        Tag::insert(&c, Tag::new_text(chapter, TagKey::ChapterEnd, s!("5.5")))?;
+        Tag::insert(&c, Tag::new_text(chapter, TagKey::Duration, s!("3.5")))?;
+
+        let no_duration = Music::mk(&c)?;
+        Tag::insert(
+            &c,
+            Tag::new_text(no_duration, TagKey::LocalFLAC, stereo.clone()),
+        )?;
+
+        let missing = Music::mk(&c)?;
+        Tag::insert(
+            &c,
+            Tag::new_text(missing, TagKey::LocalMP3, s!("test-probe-missing.mp3")),
+        )?;
+        (wrong_duration, chapter, no_duration, missing)
+    };
+
+    let mut worker = ProbeWorker::new(db.clone());
+    for _ in 0..4 {
+        assert!(worker.step().await?);
+    }
+    assert!(!worker.step().await?);
+
+    let c = db.get().await;
+    assert!(find_candidate(&c)?.is_none());
+
+    assert_eq!(integer(&c, wrong_duration, TagKey::Duration)?, Some(10));
+    assert_eq!(
+        integer(&c, wrong_duration, TagKey::SampleRate)?,
+        Some(44100)
+    );
+    assert_eq!(integer(&c, wrong_duration, TagKey::Channels)?, Some(1));
+    assert_eq!(integer(&c, wrong_duration, TagKey::Bitrate)?, Some(705));
+    assert_eq!(
+        Tag::by_id_key(&c, wrong_duration, TagKey::Codec)?
+            .unwrap()
+            .text
+            .as_deref(),
+        Some("pcm_s16le")
+    );
+
+    // the chapter duration was right, it is left untouched
+    assert_eq!(
+        Tag::by_id_key(&c, chapter, TagKey::Duration)?
+            .unwrap()
+            .text
+            .as_deref(),
+        Some("3.5")
+    );
+
+    assert_eq!(integer(&c, no_duration, TagKey::Duration)?, Some(5));
+    assert_eq!(integer(&c, no_duration, TagKey::SampleRate)?, Some(48000));
+    assert_eq!(integer(&c, no_duration, TagKey::Channels)?, Some(2));
+
+    assert!(Tag::by_id_key(&c, missing, TagKey::Codec)?
+        .unwrap()
+        .text
+        .is_none());
+    assert!(Tag::by_id_key(&c, missing, TagKey::Duration)?.is_none());
+
+    for f in [mono, stereo] {
+        std::fs::remove_file(format!("./storage/{}", f))?;
+    }
+    Ok(())
+}
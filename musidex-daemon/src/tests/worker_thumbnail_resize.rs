@@ -1,7 +1,9 @@
 use super::*;
 use crate::domain::entity::{Music, Tag, TagKey};
-use crate::domain::worker_thumbnail_resize::find_candidate;
+use crate::domain::thumbnail::{pick, sized_name};
+use crate::domain::worker_thumbnail_resize::{find_candidate, SmallThumbnailWorker};
 use anyhow::Result;
+use image::{ImageOutputFormat, Rgb, RgbImage};
 
 #[test_log::test(tokio::test)]
 pub async fn test_find_candidate() -> Result<()> {
@@ -19,7 +21,105 @@ pub async fn test_find_candidate() -> Result<()> {
         Tag::new_text(music, TagKey::CompressedThumbnail, s!("smol.hi.jpg")),
     )?;
 
+    // thumbnails compressed before the blurhash existed are treated again
+    assert_eq!(Some(music), find_candidate(&c)?);
+
+    Tag::insert(
+        &c,
+        Tag::new_text(music, TagKey::Blurhash, s!("LEHV6nWB2yk8")),
+    )?;
+    Tag::insert(
+        &c,
+        Tag::new_text(music, TagKey::DominantColor, s!("#101010")),
+    )?;
+
     assert!(find_candidate(&c)?.is_none());
 
     Ok(())
 }
+
+#[test_log::test(tokio::test)]
+pub async fn test_thumbnail_sizes() -> Result<()> {
+    let db = mk_db().await?;
+    std::fs::create_dir_all("./storage/")?;
+
+    let img = RgbImage::from_fn(600, 300, |x, _| Rgb([(x / 3) as u8, 100, 50]));
+    let mut buf = vec![];
+    img.write_to(
+        &mut std::io::Cursor::new(&mut buf),
+        ImageOutputFormat::Jpeg(90),
+    )?;
+    let thumb = "test-thumb-sizes.jpg";
+    std::fs::write(format!("./storage/{}", thumb), buf)?;
+
+    let music = {
+        let c = db.get().await;
+        let music = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(music, TagKey::Thumbnail, s!(thumb)))?;
+        // not resized yet, the original is served
+        assert_eq!(
+            pick(&c, music, Some(64), true)?,
+            Some((s!(thumb), "image/jpeg"))
+        );
+        music
+    };
+
+    SmallThumbnailWorker::new(db.clone()).step().await?;
+
+    let c = db.get().await;
+    assert!(find_candidate(&c)?.is_none());
+    let blurhash = Tag::by_id_key(&c, music, TagKey::Blurhash)?
+        .unwrap()
+        .text
+        .unwrap();
+    assert_eq!(blurhash.len(), 28);
+
+    assert_eq!(
+        Tag::by_id_key(&c, music, TagKey::DominantColor)?
+            .unwrap()
+            .text
+            .unwrap()
+            .len(),
+        7
+    );
+
+    // cropped to a square, but never upscaled
+    for (size, w, h) in [(64, 64, 64), (256, 256, 256), (512, 300, 300)] {
+        let img = image::open(format!("./storage/{}", sized_name(thumb, size, false)))?;
+        assert_eq!((img.width(), img.height()), (w, h));
+        let data = std::fs::read(format!("./storage/{}", sized_name(thumb, size, true)))?;
+        let img = webp::Decoder::new(&data).decode().unwrap();
+        assert_eq!((img.width(), img.height()), (w, h));
+    }
+
+    assert_eq!(
+        pick(&c, music, Some(64), true)?,
+        Some((sized_name(thumb, 64, true), "image/webp"))
+    );
+    assert_eq!(
+        pick(&c, music, Some(100), false)?,
+        Some((sized_name(thumb, 256, false), "image/jpeg"))
+    );
+    assert_eq!(
+        pick(&c, music, Some(4000), false)?,
+        Some((sized_name(thumb, 512, false), "image/jpeg"))
+    );
+    assert_eq!(
+        pick(&c, music, None, true)?,
+        Some((sized_name(thumb, 512, true), "image/webp"))
+    );
+    assert_eq!(pick(&c, Music::mk(&c)?, None, true)?, None);
+
+    let compressed = Tag::by_id_key(&c, music, TagKey::CompressedThumbnail)?
+        .unwrap()
+        .text
+        .unwrap();
+    std::fs::remove_file(format!("./storage/{}", compressed))?;
+    for size in [64, 256, 512] {
+        for webp in [false, true] {
+            std::fs::remove_file(format!("./storage/{}", sized_name(thumb, size, webp)))?;
+        }
+    }
+    std::fs::remove_file(format!("./storage/{}", thumb))?;
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use super::*;
+use crate::application::handlers;
+use crate::domain::entity::{Music, Tag, TagKey};
+use crate::domain::worker_waveform::{find_candidate, WaveformWorker};
+use crate::infrastructure::router::Router;
+use anyhow::Result;
+use hyper::header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use hyper::StatusCode;
+
+#[test_log::test(tokio::test)]
+pub async fn test_waveform_worker() -> Result<()> {
+    let db = mk_db().await?;
+
+    // gets louder over 10 seconds
+    let samples: Vec<f32> = (0..44100 * 10)
+        .map(|i| {
+            let t = i as f32 / 44100.0;
+            (2.0 * std::f32::consts::PI * 440.0 * t).sin() * t / 10.0
+        })
+        .collect();
+    let source = write_wav("waveform", 44100, 1, &samples)?;
+
+    let (music, chapter) = {
+        let c = db.get().await;
+        let music = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(music, TagKey::LocalMP3, source.clone()))?;
+        let chapter = Music::mk(&c)?;
+        Tag::insert(&c, Tag::new_text(chapter, TagKey::LocalMP3, source.clone()))?;
+        Tag::insert(&c, Tag::new_text(chapter, TagKey::ChapterStart, s!("5")))?;
+        Tag::insert(&c, Tag::new_text(chapter, TagKey::ChapterEnd, s!("7.5")))?;
+        (music, chapter)
+    };
+
+    let mut worker = WaveformWorker::new(db.clone());
+    assert!(worker.step().await?);
+    assert!(worker.step().await?);
+    assert!(!worker.step().await?);
+
+    let c = db.get().await;
+    assert!(find_candidate(&c)?.is_none());
+
+    let read = |id| -> Result<String> {
+        let fname = Tag::by_id_key(&c, id, TagKey::Waveform)?
+            .unwrap()
+            .text
+            .unwrap();
+        Ok(std::fs::read_to_string(format!("./storage/{}", fname))?)
+    };
+
+    let full: String = read(music)?;
+    assert!(full.contains(r#""length":1000"#), "{}", &full[..100]);
+    assert!(full.contains(r#""samples_per_pixel":441"#));
+    assert!(full.ends_with("-127,127]}"));
+
+    let chapter_waveform = read(chapter)?;
+    assert!(chapter_waveform.contains(r#""length":431"#));
+    assert!(chapter_waveform.contains(r#""data":[-64,64,"#));
+    assert_ne!(
+        Tag::by_id_key(&c, music, TagKey::Waveform)?.unwrap().text,
+        Tag::by_id_key(&c, chapter, TagKey::Waveform)?.unwrap().text
+    );
+
+    let fnames = [music, chapter]
+        .iter()
+        .map(|&id| {
+            Ok(Tag::by_id_key(&c, id, TagKey::Waveform)?
+                .unwrap()
+                .text
+                .unwrap())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    drop(c);
+
+    // served with validators
+    let mut router = Router::new();
+    router
+        .state(db.clone())
+        .get("/api/waveform/:musicid", handlers::waveform);
+    let url = format!("/api/waveform/{}", music.0);
+    let r = router
+        .serve(Request::get(&url).body(Body::empty())?)
+        .await?;
+    assert_eq!(r.status(), StatusCode::OK);
+    assert_eq!(r.headers().get(CONTENT_TYPE).unwrap(), "application/json");
+    let etag = r.headers().get(ETAG).unwrap().clone();
+    assert_eq!(hyper::body::to_bytes(r.into_body()).await?, full.as_bytes());
+    let cached = Request::get(&url)
+        .header(IF_NONE_MATCH, etag)
+        .body(Body::empty())?;
+    assert_eq!(
+        router.serve(cached).await?.status(),
+        StatusCode::NOT_MODIFIED
+    );
+
+    for fname in fnames {
+        std::fs::remove_file(format!("./storage/{}", fname))?;
+    }
+    std::fs::remove_file(format!("./storage/{}", source))?;
+    Ok(())
+}
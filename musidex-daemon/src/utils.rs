@@ -1,8 +1,18 @@
 use anyhow::Result;
-use hyper::{Body, Response, StatusCode};
-use std::path::Path;
+use futures::{Stream, TryStreamExt};
+use hyper::body::Bytes;
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, Response, StatusCode};
 use std::str::FromStr;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader, SeekFrom};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+
+/// Unix timestamp in seconds
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
 
 pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
     match std::env::var(key) {
@@ -68,20 +78,106 @@ pub fn res_status(status: StatusCode) -> Response<Body> {
     r
 }
 
-pub async fn get_file_range<P: AsRef<Path>>(
-    file_path: P,
-    (start, end): (u64, u64),
-) -> tokio::io::Result<(Vec<u8>, u64)> {
-    if end < start {
-        return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+/// Size of the chunks file bodies are read in
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Streams `len` bytes of the file from `start` in chunks, so the file is never
+/// loaded in memory as a whole
+pub fn file_chunks(
+    mut f: tokio::fs::File,
+    start: u64,
+    len: u64,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send {
+    futures::stream::once(async move {
+        f.seek(SeekFrom::Start(start)).await?;
+        Ok::<_, std::io::Error>(f.take(len))
+    })
+    .map_ok(|r| {
+        futures::stream::try_unfold(r, |mut r| async move {
+            let mut buf = vec![0; FILE_CHUNK_SIZE];
+            let n = r.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            buf.truncate(n);
+            Ok(Some((Bytes::from(buf), r)))
+        })
+    })
+    .try_flatten()
+}
+
+/// Value of a parameter of the query string, not percent-decoded
+pub fn query_param<'a>(req: &'a Request<Body>, key: &str) -> Option<&'a str> {
+    req.uri()
+        .query()?
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+/// Whether a content negotiation header (Accept, Accept-Encoding...) allows the given value,
+/// values with a quality of 0 being refused
+pub fn header_accepts(header: Option<&HeaderValue>, value: &str) -> bool {
+    let header = unwrap_ret!(header.and_then(|x| x.to_str().ok()), false);
+    header.split(',').any(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let refused = parts.any(|p| {
+            p.strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        name.eq_ignore_ascii_case(value) && !refused
+    })
+}
+
+/// Escapes text for HTML and XML documents, in content and in quoted attributes
+pub fn escape_html(v: &str) -> String {
+    let mut out = String::with_capacity(v.len());
+    for ch in v.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_accepts() {
+        let h = HeaderValue::from_static("image/avif,image/webp;q=0.9, */*;q=0.8");
+        assert!(header_accepts(Some(&h), "image/webp"));
+        assert!(!header_accepts(Some(&h), "image/png"));
+        let h = HeaderValue::from_static("gzip;q=0, br");
+        assert!(!header_accepts(Some(&h), "gzip"));
+        assert!(header_accepts(Some(&h), "BR"));
+        assert!(!header_accepts(None, "br"));
+    }
+
+    #[test]
+    fn test_query_param() {
+        let req = Request::get("/api/thumbnail/3?size=64&x=y")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(query_param(&req, "size"), Some("64"));
+        assert_eq!(query_param(&req, "x"), Some("y"));
+        assert_eq!(query_param(&req, "z"), None);
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html("<script>\"a\" & 'b'</script>"),
+            "&lt;script&gt;&quot;a&quot; &amp; &#39;b&#39;&lt;/script&gt;"
+        );
     }
-    let mut f = tokio::fs::File::open(file_path).await?;
-    let tot_size = f.metadata().await?.len();
-    let mut buffer = Vec::new();
-    f.seek(SeekFrom::Start(start)).await?;
-    BufReader::new(f)
-        .take(end - start)
-        .read_to_end(&mut buffer)
-        .await?;
-    Ok((buffer, tot_size))
 }
@@ -2,6 +2,9 @@ from extractor import extractor
 import sqlite3 as sqlite
 import numpy as np
 import struct
+import subprocess
+import tempfile
+import os
 
 conn = sqlite.connect("storage/db.db")
 
@@ -19,8 +22,36 @@ def is_too_long(id):
     cur.execute("SELECT COUNT(1) FROM tags WHERE music_id=? AND key='duration' AND integer>30*60;", (id,))
     return cur.fetchone()[0] == 1
 
+def chapter_window(id):
+    cur = conn.cursor()
+    cur.execute("SELECT key, text FROM tags WHERE music_id=? AND key IN ('chapter_start', 'chapter_end');", (id,))
+    window = dict(cur.fetchall())
+    if "chapter_start" not in window:
+        return None
+    return (float(window["chapter_start"]), window.get("chapter_end") and float(window["chapter_end"]))
+
+# FFMPEG_PATH is inherited from the daemon that runs this script
+def ffmpeg_path():
+    return os.environ.get("FFMPEG_PATH", "ffmpeg")
+
+# chapters share the file of the whole mix, only their window is embedded
+def cut_window(name, window):
+    (start, end) = window
+    out = tempfile.NamedTemporaryFile(suffix=".wav", delete=False).name
+    args = [ffmpeg_path(), "-y", "-loglevel", "error", "-ss", str(start)]
+    if end is not None:
+        args += ["-t", str(end - start)]
+    args += ["-i", name, out]
+    try:
+        subprocess.run(args, check=True)
+    except Exception:
+        os.remove(out)
+        raise
+    return out
+
 names = []
 ids = []
+windows = []
 
 def vecToBlob(v):
     tot = bytearray()
@@ -29,7 +60,7 @@ def vecToBlob(v):
         tot.extend(ba)
     return tot
 
-for tag in conn.execute("SELECT * FROM tags WHERE key='local_mp3';"):
+for tag in conn.execute("SELECT * FROM tags WHERE key IN ('local_mp3', 'local_opus', 'local_ogg', 'local_m4a', 'local_webm', 'local_flac', 'local_wav');"):
     id = tag[0]
     value = tag[2]
     if has_embedding(id):
@@ -38,9 +69,21 @@ for tag in conn.execute("SELECT * FROM tags WHERE key='local_mp3';"):
         continue
     names.append("storage/"+str(value))
     ids.append(id)
+    windows.append(chapter_window(id))
 
-for id, name in zip(ids, names):
-    (taggram, labels, features) = next(extractor([name], model="MTT_musicnn"))
+for id, name, window in zip(ids, names, windows):
+    path = name
+    if window is not None:
+        try:
+            path = cut_window(name, window)
+        except Exception as e:
+            print("failed cutting chapter", id, name, e)
+            continue
+    try:
+        (taggram, labels, features) = next(extractor([path], model="MTT_musicnn"))
+    finally:
+        if path != name:
+            os.remove(path)
     vector = np.mean(features["penultimate"], axis=0)
     blob = vecToBlob(vector)
 